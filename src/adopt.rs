@@ -0,0 +1,249 @@
+//! `ddnsfw adopt`: takes over allow rules created by a predecessor.
+//!
+//!   ddnsfw adopt --comment OLD-COMMENT [--rewrite] [--dry-run]
+//!   ddnsfw adopt --port PORT[,PORT...] [--rewrite] [--dry-run]
+//!
+//! Shell scripts and earlier tools leave rules in filter/INPUT that ddnsfw
+//! does not see: they lack its comment. `adopt` picks the ones tagged
+//! OLD-COMMENT, or the untagged ones on the given ports, gives each a twin
+//! under this profile's comment and records it in the cache, owned by the
+//! configured entries that would install it. From the next sync on they are
+//! managed like any other rule: kept while an entry resolves to the address,
+//! removed once none does (run `ddnsfw plan` first to see which).
+//!
+//! Without `--rewrite` the originals stay where they are, for the old tool
+//! to remove (or by hand once it is gone); the twins go to the top of the
+//! chain as usual. With `--rewrite` each original is replaced by its twin
+//! at the same position, so the rule order is unchanged. With
+//! `dedicated_chain` the twins go to the dedicated chain instead, and
+//! `--rewrite` only removes the originals from INPUT.
+//!
+//! Only single-address ACCEPT rules whose matches are exactly those ddnsfw
+//! writes (`-s IP/32 -p tcp|udp -m tcp|udp --dport PORT`) are adopted;
+//! anything more specific (an interface, conntrack state, a port range) is
+//! listed as skipped and left alone. iptables backend only.
+
+use std::collections::HashSet;
+use std::io::{self, Write};
+
+use crate::events::{self, Value};
+use crate::rule::Rule;
+use crate::{
+    acquire_lock, add_rule, dedicated, exit_err, get_existing_rules, has_comment, iptables, iptables_run, now_secs, open_backend,
+    parse_config, parse_rule_line, profile, Backend, Cache, MAX_LISTING_LINES, MAX_RULES,
+};
+
+const USAGE: &str = "Usage: ddnsfw adopt (--comment OLD-COMMENT | --port PORT[,PORT...]) [--rewrite] [--dry-run]";
+
+/// Which foreign rules to take over
+enum Selection {
+    Comment(String),
+    Ports(HashSet<u16>),
+}
+
+impl Selection {
+    fn matches(&self, line: &str, port: u16) -> bool {
+        match self {
+            Selection::Comment(comment) => has_comment(line, comment),
+            Selection::Ports(ports) => ports.contains(&port) && !line.split_whitespace().any(|t| t == "--comment"),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Selection::Comment(comment) => format!("tagged '{}'", comment),
+            Selection::Ports(ports) => {
+                let mut ports: Vec<u16> = ports.iter().copied().collect();
+                ports.sort_unstable();
+                let ports: Vec<String> = ports.iter().map(u16::to_string).collect();
+                format!("untagged on port {}", ports.join(","))
+            }
+        }
+    }
+}
+
+/// A foreign rule to take over
+struct Candidate {
+    rule: Rule,
+    spec: String,     // The `-S` line without `-A `
+    position: usize,  // 1-based rule number in the chain
+}
+
+/// The `-S` tokens without the comment match, as ddnsfw would compare them
+fn without_comment(tokens: &[String]) -> Vec<String> {
+    let mut kept = Vec::new();
+    let mut i = 0;
+    while i < tokens.len() {
+        if tokens[i] == "-m" && tokens.get(i + 1).is_some_and(|t| t == "comment") && tokens.get(i + 2).is_some_and(|t| t == "--comment") {
+            i += 4;
+            continue;
+        }
+        kept.push(tokens[i].clone());
+        i += 1;
+    }
+    kept
+}
+
+/// Whether a rule line carries nothing but what `rule` would be written with
+fn is_exact(line: &str, rule: &Rule) -> bool {
+    let line: Vec<String> = line.split_whitespace().map(|t| t.trim_matches('"').to_string()).collect();
+    // `-t filter -A INPUT ...` against `-A INPUT ...`
+    let generated = rule.iptables_args("-A").split_off(2);
+    without_comment(&line) == without_comment(&generated)
+}
+
+fn parse_ports(list: &str) -> Option<HashSet<u16>> {
+    list.split(',').map(|p| p.parse::<u16>().ok().filter(|&p| p != 0)).collect()
+}
+
+pub fn command(args: &[String]) {
+    let mut selection = None;
+    let (mut rewrite, mut dry_run) = (false, false);
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--comment" if selection.is_none() => match iter.next() {
+                Some(comment) if !comment.is_empty() && comment != profile::current().comment() => {
+                    selection = Some(Selection::Comment(comment.clone()))
+                }
+                Some(_) => exit_err("The comment to adopt must differ from this profile's"),
+                None => exit_err(USAGE),
+            },
+            "--port" if selection.is_none() => match iter.next().and_then(|list| parse_ports(list)) {
+                Some(ports) => selection = Some(Selection::Ports(ports)),
+                None => exit_err(USAGE),
+            },
+            "--rewrite" => rewrite = true,
+            "--dry-run" => dry_run = true,
+            _ => exit_err(USAGE),
+        }
+    }
+    let Some(selection) = selection else {
+        exit_err(USAGE);
+    };
+
+    let config = parse_config();
+    let bin = match open_backend(&config) {
+        Some(Backend::Iptables(bin)) => bin,
+        Some(_) => exit_err("adopt works with the iptables backend only"),
+        None => exit_err("iptables not found"),
+    };
+    let Some(_lock) = acquire_lock() else {
+        exit_err("Could not acquire lock");
+    };
+    let Some(listing) = iptables(bin, &["-t", "filter", "-S", "INPUT"]) else {
+        exit_err("Cannot list filter/INPUT");
+    };
+
+    let mut candidates = Vec::new();
+    let mut skipped = 0;
+    let mut position = 0;
+    for line in listing.lines().take(MAX_LISTING_LINES) {
+        let Some(spec) = line.strip_prefix("-A ") else {
+            continue;
+        };
+        position += 1;
+        let Some(parsed) = parse_rule_line(line) else {
+            continue;
+        };
+        if !selection.matches(line, parsed.port) || candidates.len() >= MAX_RULES {
+            continue;
+        }
+        let rule = Rule { proto: parsed.proto, ..Rule::allow(parsed.ip, parsed.port) };
+        if parsed.target != "ACCEPT" || rule.port == 0 || !is_exact(line, &rule) {
+            println!("[ddnsfw] Skipping (not a plain allow): {}", line);
+            skipped += 1;
+            continue;
+        }
+        // With dedicated_chain the twin goes to the chain
+        let rule = Rule { chain: dedicated::inbound().to_string(), ..rule };
+        candidates.push(Candidate { rule, spec: spec.to_string(), position });
+    }
+    if candidates.is_empty() {
+        println!("[ddnsfw] No rules {} to adopt ({} skipped)", selection.describe(), skipped);
+        return;
+    }
+
+    if dedicated::is_enabled() && !dedicated::ensure(bin) {
+        exit_err(&format!("Cannot set up chain {}", dedicated::name()));
+    }
+    let from = match &selection {
+        Selection::Comment(comment) => comment.as_str(),
+        Selection::Ports(_) => "untagged",
+    };
+    let mut owned = get_existing_rules(bin, &HashSet::from([(candidates[0].rule.table, candidates[0].rule.chain.clone())]));
+    let mut cache = Cache::load();
+    let now = now_secs();
+    let (mut adopted, mut failed) = (0, 0);
+    // How far rule numbers moved since the listing: a rewrite inserts the
+    // twin above the original and deletes the original, which nets out
+    // unless one of the two did not happen
+    let mut shift: isize = 0;
+    for candidate in &candidates {
+        let rule = &candidate.rule;
+        let entries: Vec<String> = config.entries.iter().filter(|e| e.rules_for(rule.ip).contains(rule)).map(|e| e.key()).collect();
+        let owners = if entries.is_empty() { String::from("no entry, removed by the next sync") } else { entries.join(", ") };
+        if dry_run {
+            println!("[ddnsfw] Would adopt {} ({})", rule, owners);
+            continue;
+        }
+
+        print!("[ddnsfw] Adopting {} ({}) ... ", rule, owners);
+        let _ = io::stdout().flush();
+        let inserted = !owned.contains(rule);
+        let twin_ok = if !inserted {
+            true
+        } else if rewrite && rule.chain == "INPUT" {
+            let mut args = rule.iptables_args("-I");
+            args[4] = candidate.position.saturating_add_signed(shift).to_string();
+            iptables_run(bin, &args.iter().map(String::as_str).collect::<Vec<_>>())
+        } else {
+            add_rule(bin, rule)
+        };
+        if !twin_ok {
+            println!("FAILED (original kept)");
+            failed += 1;
+            continue;
+        }
+        owned.insert(rule.clone());
+        cache.rules.insert(rule.clone());
+        cache.since.entry(rule.clone()).or_insert(now);
+        if !entries.is_empty() {
+            let rule_owners = cache.owners.entry(rule.clone()).or_default();
+            for entry in entries {
+                if !rule_owners.contains(&entry) {
+                    rule_owners.push(entry);
+                }
+            }
+        }
+        let rule_str = rule.to_string();
+        events::emit("adopt", &[("rule", Value::Str(&rule_str)), ("from", Value::Str(from))]);
+        adopted += 1;
+
+        if rewrite {
+            let mut args = vec!["-t", "filter", "-D"];
+            args.extend(candidate.spec.split_whitespace().map(|t| t.trim_matches('"')));
+            let deleted = iptables_run(bin, &args);
+            if deleted {
+                println!("OK (rewritten)");
+            } else {
+                println!("OK (original could not be removed: -A {})", candidate.spec);
+            }
+            shift += isize::from(inserted && rule.chain == "INPUT") - isize::from(deleted);
+        } else {
+            println!("OK");
+        }
+    }
+    if dry_run {
+        println!("[ddnsfw] {} rules {} would be adopted, {} skipped", candidates.len(), selection.describe(), skipped);
+        return;
+    }
+    cache.save();
+    println!("[ddnsfw] Adopted {} rules {}, {} failed, {} skipped", adopted, selection.describe(), failed, skipped);
+    if adopted > 0 {
+        println!("[ddnsfw] Run ddnsfw plan to see which the next sync keeps");
+    }
+    if failed > 0 {
+        std::process::exit(1);
+    }
+}
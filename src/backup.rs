@@ -0,0 +1,244 @@
+//! Snapshots of a profile's config and state.
+//!
+//!   ddnsfw backup create            # snapshot now
+//!   ddnsfw backup list
+//!   ddnsfw backup restore NAME      # put a snapshot back, then sync
+//!
+//!   backup_keep = 20                # snapshots kept (default 20)
+//!   backup_interval = 86400         # also one per day from the timer (default 0: off)
+//!
+//! A snapshot is a gzipped tarball under /var/backups/ddnsfw (named
+//! profiles: /var/backups/ddnsfw/profiles/<name>), named
+//! `ddnsfw-<UTC time>-<reason>.tar.gz`. It holds the profile directory
+//! (config, files included next to it, hosts.override; not the binary or
+//! other profiles) and the state files wherever they live: cache, grants
+//! ledger, change history, events. Every command that rewrites the config
+//! (add, remove, disable, enable, rename) takes one first (`edit`), and so
+//! does a restore (`restore`), so any change can be undone; with
+//! `backup_interval`, periodic syncs take one (`scheduled`) once the newest
+//! is that old. Only the newest `backup_keep` are kept.
+//!
+//! A restore refuses archives with members outside this profile, extracts
+//! over the current files under the lock (files created since the snapshot
+//! stay), and syncs: the firewall follows the restored config, and the
+//! restored cache is corrected by the listing as on any sync.
+
+use std::fs::{self, DirBuilder, Permissions};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::UNIX_EPOCH;
+
+use crate::profile::{self, STATE_FILES};
+use crate::{acquire_lock, exit_err, format_utc, now_secs, parse_config, sync_firewall, Config, BINARY_PATH};
+
+const BACKUP_ROOT: &str = "/var/backups/ddnsfw";
+const PREFIX: &str = "ddnsfw-";
+const SUFFIX: &str = ".tar.gz";
+pub const DEFAULT_KEEP: usize = 20;
+pub const MAX_KEEP: usize = 1000;
+/// Files taken from the profile directory
+const MAX_MEMBERS: usize = 256;
+const USAGE: &str = "Usage: ddnsfw backup [create | list | restore NAME]";
+
+fn backup_dir() -> String {
+    match &profile::current().name {
+        Some(name) => format!("{}/profiles/{}", BACKUP_ROOT, name),
+        None => BACKUP_ROOT.to_string(),
+    }
+}
+
+/// What a snapshot holds, as absolute paths
+fn members() -> Vec<String> {
+    let profile = profile::current();
+    let mut members = Vec::new();
+    if let Ok(dir) = fs::read_dir(&profile.dir) {
+        for item in dir.flatten().take(MAX_MEMBERS) {
+            let name = item.file_name().to_string_lossy().into_owned();
+            let path = format!("{}/{}", profile.dir, name);
+            if path == BINARY_PATH || name == "profiles" || name == ".lock" || name.ends_with(".tmp") {
+                continue;
+            }
+            members.push(path);
+        }
+    }
+    if profile.state_dir != profile.dir {
+        for name in STATE_FILES.iter().chain(&["events.jsonl.1"]) {
+            let path = format!("{}/{}", profile.state_dir, name);
+            if Path::new(&path).exists() {
+                members.push(path);
+            }
+        }
+    }
+    members.sort();
+    members
+}
+
+/// Whether an archive member (relative to /) belongs to this profile
+fn is_own_member(member: &str) -> bool {
+    let profile = profile::current();
+    let path = format!("/{}", member.trim_end_matches('/'));
+    let within = |dir: &str| path == dir || path.starts_with(&format!("{}/", dir));
+    !member.split('/').any(|part| part == "..")
+        && path != BINARY_PATH
+        && !within(&format!("{}/profiles", profile.dir))
+        && (within(&profile.dir) || within(&profile.state_dir))
+}
+
+/// When a snapshot was taken (its mtime)
+fn taken_at(name: &str) -> Option<u64> {
+    let modified = fs::metadata(format!("{}/{}", backup_dir(), name)).ok()?.modified().ok()?;
+    Some(modified.duration_since(UNIX_EPOCH).ok()?.as_secs())
+}
+
+/// Snapshots, oldest first
+fn snapshots() -> Vec<String> {
+    let Ok(dir) = fs::read_dir(backup_dir()) else {
+        return Vec::new();
+    };
+    let mut found: Vec<(u64, String)> = dir
+        .flatten()
+        .map(|item| item.file_name().to_string_lossy().into_owned())
+        .filter(|name| name.starts_with(PREFIX) && name.ends_with(SUFFIX))
+        .map(|name| (taken_at(&name).unwrap_or(0), name))
+        .collect();
+    found.sort();
+    found.into_iter().map(|(_, name)| name).collect()
+}
+
+/// `20261016T101500Z`
+fn stamp(ts: u64) -> String {
+    let mut stamp: String = format_utc(ts).chars().filter(|c| !matches!(c, '-' | ':')).collect();
+    stamp = stamp.replace(' ', "T");
+    stamp.push('Z');
+    stamp
+}
+
+/// Takes a snapshot, then drops the oldest beyond `keep`; the path of the
+/// new snapshot, or why none was taken
+pub fn snapshot(reason: &str, keep: usize) -> Result<String, String> {
+    let dir = backup_dir();
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&dir)
+        .map_err(|e| format!("cannot create {}: {}", dir, e))?;
+    let members = members();
+    if members.is_empty() {
+        return Err(String::from("nothing to back up"));
+    }
+
+    let base = format!("{}/{}{}-{}", dir, PREFIX, stamp(now_secs()), reason);
+    let mut path = format!("{}{}", base, SUFFIX);
+    let mut n = 2;
+    while Path::new(&path).exists() {
+        path = format!("{}-{}{}", base, n, SUFFIX);
+        n += 1;
+    }
+    let relative: Vec<&str> = members.iter().map(|m| m.trim_start_matches('/')).collect();
+    let status = Command::new("tar")
+        .args(["-czf", &path, "-C", "/", "--"])
+        .args(&relative)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status();
+    if !status.is_ok_and(|s| s.success()) {
+        let _ = fs::remove_file(&path);
+        return Err(String::from("tar failed"));
+    }
+    let _ = fs::set_permissions(&path, Permissions::from_mode(0o600));
+
+    let existing = snapshots();
+    for old in existing.iter().take(existing.len().saturating_sub(keep)) {
+        let _ = fs::remove_file(format!("{}/{}", dir, old));
+    }
+    Ok(path)
+}
+
+/// Snapshot before a config rewrite; a failure only warns, the edit goes on
+pub fn before_edit() {
+    if let Err(reason) = snapshot("edit", parse_config().backup_keep) {
+        eprintln!("[ddnsfw] WARN: No snapshot before the edit: {}", reason);
+    }
+}
+
+/// Periodic syncs: a snapshot once the newest is `backup_interval` old
+pub fn scheduled(config: &Config) {
+    if config.backup_interval == 0 {
+        return;
+    }
+    let newest = snapshots().last().and_then(|name| taken_at(name)).unwrap_or(0);
+    if now_secs().saturating_sub(newest) < config.backup_interval {
+        return;
+    }
+    match snapshot("scheduled", config.backup_keep) {
+        Ok(path) => println!("[ddnsfw] Snapshot {}", path),
+        Err(reason) => eprintln!("[ddnsfw] WARN: Scheduled snapshot failed: {}", reason),
+    }
+}
+
+pub fn command(args: &[String]) {
+    match args.first().map(String::as_str) {
+        Some("create") if args.len() == 1 => match snapshot("manual", parse_config().backup_keep) {
+            Ok(path) => println!("[ddnsfw] Snapshot {}", path),
+            Err(reason) => exit_err(&format!("Snapshot failed: {}", reason)),
+        },
+        Some("list") if args.len() == 1 => list(),
+        Some("restore") if args.len() == 2 => restore(&args[1]),
+        _ => exit_err(USAGE),
+    }
+}
+
+fn list() {
+    let dir = backup_dir();
+    let names = snapshots();
+    if names.is_empty() {
+        println!("[ddnsfw] No snapshots in {}", dir);
+        return;
+    }
+    for name in names.iter().rev() {
+        let size = fs::metadata(format!("{}/{}", dir, name)).map_or(0, |m| m.len());
+        let taken = taken_at(name).map_or_else(|| String::from("unknown"), format_utc);
+        println!("{:<48} {:<20} {:>8} bytes", name, taken, size);
+    }
+}
+
+fn restore(name: &str) {
+    let name = if name.ends_with(SUFFIX) { name.to_string() } else { format!("{}{}", name, SUFFIX) };
+    if name.contains('/') || !name.starts_with(PREFIX) {
+        exit_err(&format!("Invalid snapshot name '{}' (see ddnsfw backup list)", name));
+    }
+    let path = format!("{}/{}", backup_dir(), name);
+    if !Path::new(&path).is_file() {
+        exit_err(&format!("No snapshot {} (see ddnsfw backup list)", path));
+    }
+
+    let listing = Command::new("tar")
+        .args(["-tzf", &path])
+        .stderr(Stdio::null())
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .unwrap_or_else(|| exit_err(&format!("Cannot read {}", path)));
+    let listing = String::from_utf8_lossy(&listing.stdout).into_owned();
+    if let Some(member) = listing.lines().find(|member| !is_own_member(member)) {
+        exit_err(&format!("Refusing {}: {} is not a file of this profile", name, member));
+    }
+
+    {
+        let Some(_lock) = acquire_lock() else {
+            exit_err("Could not acquire lock");
+        };
+        // Nothing pruned: the config may be the broken thing being undone
+        match snapshot("restore", MAX_KEEP) {
+            Ok(undo) => println!("[ddnsfw] Current state saved as {}", undo),
+            Err(reason) => exit_err(&format!("Cannot save the current state first: {}", reason)),
+        }
+        let status = Command::new("tar").args(["-xzf", &path, "-C", "/"]).stderr(Stdio::null()).status();
+        if !status.is_ok_and(|s| s.success()) {
+            exit_err(&format!("Restoring {} failed", name));
+        }
+    }
+    println!("[ddnsfw] Restored {}", name);
+    sync_firewall();
+}
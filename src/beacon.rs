@@ -0,0 +1,89 @@
+//! Signed HTTPS beacon cross-check.
+//!
+//! An entry with `beacon=https://...` only accepts a DNS answer when the
+//! home endpoint independently reports the same IP. The beacon body is one
+//! line:
+//!
+//!   <ip> <unix-timestamp> <hex hmac-sha256(beacon_key, "<hostname>|<ip>|<timestamp>")>
+//!
+//! Binding the hostname prevents replaying one entry's beacon for another,
+//! and the timestamp bounds replay of an old (possibly hijacked) address.
+//! A compromised DNS record alone therefore cannot move the firewall.
+
+use std::net::Ipv4Addr;
+use std::process::{Command, Stdio};
+
+use crate::crypto::{ct_eq, hex, hmac_sha256};
+use crate::{execs, DNS_TIMEOUT_SECS};
+
+const MAX_BEACON_BYTES: usize = 512;
+
+/// Why a beacon could not vouch for an address
+pub enum BeaconError {
+    Unreachable,
+    Malformed,
+    BadSignature,
+    Stale,
+}
+
+impl BeaconError {
+    pub fn describe(&self) -> &'static str {
+        match self {
+            BeaconError::Unreachable => "beacon unreachable",
+            BeaconError::Malformed => "beacon malformed",
+            BeaconError::BadSignature => "beacon signature invalid",
+            BeaconError::Stale => "beacon stale",
+        }
+    }
+}
+
+pub fn is_valid_url(url: &str) -> bool {
+    url.starts_with("https://") && url.len() < 2048 && !url.chars().any(char::is_whitespace)
+}
+
+fn fetch(url: &str) -> Option<String> {
+    let output = execs::output(
+        Command::new("curl")
+            .args([
+                "-fsS",
+                "--proto", "=https",
+                "--max-time", &DNS_TIMEOUT_SECS.to_string(),
+                "--max-filesize", &MAX_BEACON_BYTES.to_string(),
+                url,
+            ])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null()),
+    )
+    .ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Fetches and verifies the beacon, returning the IP it vouches for.
+pub fn verified_ip(url: &str, key: &[u8], hostname: &str, max_age: u64, now: u64) -> Result<Ipv4Addr, BeaconError> {
+    let body = fetch(url).ok_or(BeaconError::Unreachable)?;
+    let line = body.lines().next().ok_or(BeaconError::Malformed)?;
+
+    let mut fields = line.split_whitespace();
+    let (Some(ip_str), Some(ts_str), Some(mac)) = (fields.next(), fields.next(), fields.next()) else {
+        return Err(BeaconError::Malformed);
+    };
+    let ip: Ipv4Addr = ip_str.parse().map_err(|_| BeaconError::Malformed)?;
+    let timestamp: u64 = ts_str.parse().map_err(|_| BeaconError::Malformed)?;
+
+    let message = format!("{}|{}|{}", hostname, ip_str, ts_str);
+    let expected = hex(&hmac_sha256(key, message.as_bytes()));
+    if !ct_eq(expected.as_bytes(), mac.to_ascii_lowercase().as_bytes()) {
+        return Err(BeaconError::BadSignature);
+    }
+
+    // Small allowance for clocks running ahead on the publisher
+    if timestamp > now + 60 || now.saturating_sub(timestamp) > max_age {
+        return Err(BeaconError::Stale);
+    }
+
+    Ok(ip)
+}
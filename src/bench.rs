@@ -0,0 +1,85 @@
+//! Hidden benchmark: `ddnsfw --bench [entries]`.
+//!
+//! Times the real sync on a config of `entries` hostnames (default 1000)
+//! in the --chaos sandbox with injection off. Resolver and firewall are the
+//! sandbox mocks, so this measures the sync itself (config parsing, rule
+//! diffing, cache writes on every transition), not DNS or iptables latency.
+//! Three syncs run: cold (every rule added), steady (nothing changes) and
+//! churn (a tenth of the addresses move). Each must leave exactly the rules
+//! of the current answers and finish within the timer interval, or the run
+//! fails.
+
+use std::collections::HashSet;
+use std::fs;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+use crate::chaos::{read_firewall, run_child, sandbox, write_dns};
+use crate::rule::Rule;
+use crate::{exit_err, MAX_ENTRIES, TIMER_INTERVAL_SECS};
+
+const DEFAULT_ENTRIES: usize = 1000;
+/// Ports the entries are spread over (several entries per port, as on a
+/// real bastion)
+const PORTS: &[u16] = &[22, 443, 8443, 2222];
+const CHURN_PERCENT: usize = 10;
+
+/// 198.18.0.0/15 is reserved for benchmarks; `generation` picks the half
+fn address(index: usize, generation: u8) -> Ipv4Addr {
+    Ipv4Addr::new(198, 18 + generation, (index / 250) as u8, 1 + (index % 250) as u8)
+}
+
+fn hostname(index: usize) -> String {
+    format!("host{:04}.bench.test", index)
+}
+
+fn expected(entries: usize, answers: &[(String, Ipv4Addr)]) -> HashSet<Rule> {
+    (0..entries).map(|i| Rule::allow(answers[i].1, PORTS[i % PORTS.len()])).collect()
+}
+
+/// `ddnsfw --bench [entries]`
+pub fn run(args: &[String]) {
+    let entries = match args.first() {
+        None => DEFAULT_ENTRIES,
+        Some(n) => n
+            .parse()
+            .ok()
+            .filter(|n| (1..=MAX_ENTRIES).contains(n))
+            .unwrap_or_else(|| exit_err(&format!("Usage: ddnsfw --bench [entries] (1-{})", MAX_ENTRIES))),
+    };
+
+    let config: String = (0..entries).map(|i| format!("{}:{}\n", hostname(i), PORTS[i % PORTS.len()])).collect();
+    let dir = sandbox("bench", &config);
+    let mut answers: Vec<(String, Ipv4Addr)> = (0..entries).map(|i| (hostname(i), address(i, 0))).collect();
+    write_dns(&dir, &answers);
+
+    let budget = Duration::from_secs(TIMER_INTERVAL_SECS);
+    println!("[ddnsfw] bench: {} entries in {} (budget {}s per sync)", entries, dir, budget.as_secs());
+    let mut ok = true;
+    for phase in ["cold", "steady", "churn"] {
+        if phase == "churn" {
+            for (i, answer) in answers.iter_mut().enumerate().filter(|(i, _)| i % (100 / CHURN_PERCENT) == 0) {
+                answer.1 = address(i, 1);
+            }
+            write_dns(&dir, &answers);
+        }
+        let start = Instant::now();
+        let code = run_child(&dir, 1, true, false);
+        let elapsed = start.elapsed();
+        let converged = read_firewall(&dir) == expected(entries, &answers);
+        let verdict = match (code, converged, elapsed <= budget) {
+            (0, true, true) => "OK",
+            (0, true, false) => "TOO SLOW",
+            (0, false, _) => "NOT CONVERGED",
+            _ => "FAILED",
+        };
+        println!("[ddnsfw] bench: {:<6} {:>8.2}s  {}", phase, elapsed.as_secs_f64(), verdict);
+        ok &= verdict == "OK";
+    }
+
+    if !ok {
+        println!("[ddnsfw] bench: sandbox kept at {} (see chaos.log)", dir);
+        std::process::exit(1);
+    }
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,169 @@
+//! Offline installer bundles for air-gapped servers.
+//!
+//!   ddnsfw bundle [OUTPUT]                         # on a connected host
+//!   sudo sh ddnsfw-v2.2.1-x86_64.run [--profile NAME]  # on the target
+//!
+//! A bundle is one self-extracting shell script carrying this binary, the
+//! systemd units (plain and template), a config template and a SHA256SUMS
+//! manifest, each file base64-encoded in a heredoc. Running it extracts into
+//! a private temporary directory, checks the manifest with sha256sum, then
+//! runs the bundled binary with `--from-bundle DIR`. That checks the
+//! manifest again (a bundle unpacked by hand gets the same check) and
+//! installs from the directory: no prompts, no downloads. The config
+//! template is the current profile's config when there is one, so a bundle
+//! built on a configured host replicates its access list (a config.toml
+//! is not carried: the installer writes conf.conf).
+
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use crate::crypto::{base64_encode, hex, sha256};
+use crate::{
+    exit_err, format_utc, install, is_installed, now_secs, profile, service_unit, timer_unit, tomlconfig, BINARY_PATH,
+};
+
+const BINARY: &str = "ddnsfw";
+const CONFIG: &str = "conf.conf";
+const MANIFEST: &str = "SHA256SUMS";
+/// Heredoc terminator; '_' is not in the base64 alphabet
+const DELIMITER: &str = "DDNSFW_BUNDLE_EOF";
+const BASE64_LINE: usize = 76;
+const USAGE: &str = "Usage: ddnsfw bundle [OUTPUT]";
+
+const CONFIG_TEMPLATE: &str = "# DDNS Firewall Configuration\n\
+                               # Format: hostname:port\n\
+                               \n\
+                               schema = 1\n\
+                               \n\
+                               # home.example.net:22\n";
+
+/// Every file of a bundle except the manifest, in extraction order
+fn files() -> Vec<(&'static str, Vec<u8>)> {
+    let exe = env::current_exe().unwrap_or_else(|_| exit_err("Cannot get exe path"));
+    let binary = fs::read(&exe).unwrap_or_else(|_| exit_err("Cannot read the running binary"));
+    // The installer writes conf.conf: a config.toml stays behind
+    if tomlconfig::is_active() {
+        eprintln!("[ddnsfw] WARN: {} is not bundled, copy it to the target by hand", profile::current().config_path);
+    }
+    let config =
+        fs::read(&profile::current().legacy_config_path).unwrap_or_else(|_| CONFIG_TEMPLATE.as_bytes().to_vec());
+    vec![
+        (BINARY, binary),
+        ("ddnsfw.service", service_unit(false).as_bytes().to_vec()),
+        ("ddnsfw.timer", timer_unit(false).into_bytes()),
+        ("ddnsfw@.service", service_unit(true).as_bytes().to_vec()),
+        ("ddnsfw@.timer", timer_unit(true).into_bytes()),
+        (CONFIG, config),
+    ]
+}
+
+fn manifest(files: &[(&str, Vec<u8>)]) -> String {
+    files.iter().map(|(name, data)| format!("{}  {}\n", hex(&sha256(data)), name)).collect()
+}
+
+fn heredoc(script: &mut String, name: &str, data: &[u8]) {
+    script.push_str(&format!("base64 -d > \"$dir/{}\" <<'{}'\n", name, DELIMITER));
+    let encoded = base64_encode(data);
+    for line in encoded.as_bytes().chunks(BASE64_LINE) {
+        script.push_str(&String::from_utf8_lossy(line));
+        script.push('\n');
+    }
+    script.push_str(DELIMITER);
+    script.push('\n');
+}
+
+/// `ddnsfw bundle [OUTPUT]`
+pub fn create(args: &[String]) {
+    let output = match args {
+        [] => format!("ddnsfw-v{}-{}.run", env!("CARGO_PKG_VERSION"), env::consts::ARCH),
+        [path] if !path.starts_with('-') => path.clone(),
+        _ => exit_err(USAGE),
+    };
+
+    let files = files();
+    let manifest = manifest(&files);
+    let mut script = format!(
+        "#!/bin/sh\n\
+         # ddnsfw v{} offline bundle ({}), built {} UTC\n\
+         # Usage: sudo sh {} [--profile NAME]\n\
+         set -eu\n\
+         [ \"$(id -u)\" = 0 ] || {{ echo '[ddnsfw] ERROR: Must run as root' >&2; exit 1; }}\n\
+         dir=$(mktemp -d)\n\
+         trap 'rm -rf \"$dir\"' EXIT\n\
+         chmod 700 \"$dir\"\n",
+        env!("CARGO_PKG_VERSION"),
+        env::consts::ARCH,
+        format_utc(now_secs()),
+        Path::new(&output).file_name().map_or(output.clone(), |name| name.to_string_lossy().into_owned())
+    );
+    for (name, data) in &files {
+        heredoc(&mut script, name, data);
+    }
+    heredoc(&mut script, MANIFEST, manifest.as_bytes());
+    script.push_str(&format!(
+        "(cd \"$dir\" && sha256sum --quiet -c {}) || {{ echo '[ddnsfw] ERROR: Bundle is corrupt' >&2; exit 1; }}\n\
+         chmod 700 \"$dir/{}\"\n\
+         \"$dir/{}\" \"$@\" --from-bundle \"$dir\"\n",
+        MANIFEST, BINARY, BINARY
+    ));
+
+    // The config may hold provider credentials: owner-only, never overwritten
+    let file = OpenOptions::new().write(true).create_new(true).mode(0o700).open(&output);
+    let Ok(mut file) = file else {
+        exit_err(&format!("Cannot create {} (does it exist already?)", output));
+    };
+    if file.write_all(script.as_bytes()).and_then(|_| file.sync_all()).is_err() {
+        let _ = fs::remove_file(&output);
+        exit_err(&format!("Failed to write {}", output));
+    }
+    println!("[ddnsfw] Bundle written to {} ({} KiB)", output, script.len() / 1024);
+    for line in manifest.lines() {
+        println!("  {}", line);
+    }
+    println!("Install on the target with: sudo sh {} [--profile NAME]", output);
+}
+
+/// `ddnsfw --from-bundle DIR`: installs from an extracted bundle
+pub fn install_from(args: &[String]) {
+    let [dir] = args else {
+        exit_err("Usage: ddnsfw --from-bundle <dir>");
+    };
+    if is_installed() {
+        exit_err(&format!("Already installed at {}", BINARY_PATH));
+    }
+    let dir = Path::new(dir);
+    let read = |name: &str| fs::read(dir.join(name)).unwrap_or_else(|_| exit_err(&format!("Bundle has no {}", name)));
+
+    // Everything listed must match, and everything needed must be listed
+    let manifest = String::from_utf8(read(MANIFEST)).unwrap_or_else(|_| exit_err("Invalid SHA256SUMS"));
+    let mut verified = Vec::new();
+    for line in manifest.lines() {
+        let Some((digest, name)) = line.split_once("  ") else {
+            exit_err(&format!("Invalid SHA256SUMS line '{}'", line));
+        };
+        if name.contains('/') || hex(&sha256(&read(name))) != digest {
+            exit_err(&format!("Checksum mismatch for {}", name));
+        }
+        verified.push(name);
+    }
+    let template = profile::current().name.is_some();
+    let unit = if template { "ddnsfw@" } else { "ddnsfw" };
+    let (service, timer) = (format!("{}.service", unit), format!("{}.timer", unit));
+    for name in [BINARY, CONFIG, service.as_str(), timer.as_str()] {
+        if !verified.contains(&name) {
+            exit_err(&format!("{} is not in SHA256SUMS", name));
+        }
+    }
+    // The installer copies the running binary: it must be the bundled one
+    let exe = env::current_exe().unwrap_or_else(|_| exit_err("Cannot get exe path"));
+    if fs::read(&exe).ok() != Some(read(BINARY)) {
+        exit_err("Run the bundled binary to install from a bundle");
+    }
+
+    let text = |name: &str| String::from_utf8(read(name)).unwrap_or_else(|_| exit_err(&format!("{} is not text", name)));
+    println!("[ddnsfw] Installing from bundle {} (checksums OK)", dir.display());
+    install(&text(CONFIG), &text(&service), &text(&timer));
+}
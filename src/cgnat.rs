@@ -0,0 +1,86 @@
+//! CGNAT and DS-Lite awareness: IPv4 answers the server never sees.
+//!
+//!   cgnat = prefer-v6 | warn | off    # default prefer-v6
+//!
+//! A home connection behind carrier-grade NAT or DS-Lite has no IPv4
+//! address of its own: a DDNS client reporting the router's WAN address
+//! publishes one from the shared space 100.64.0.0/10 (RFC 6598) or the
+//! DS-Lite B4 range 192.0.0.0/29, while its IPv4 traffic reaches the server
+//! from the carrier's public address, shared with other customers. An
+//! allow for the published address never matches, and one for the public
+//! address would admit the neighbours too; only IPv6 identifies the
+//! connection.
+//!
+//! When an entry resolves to such an address, the sync checks whether the
+//! entry has an IPv6 side (`ipv6` on for it, no IPv4-only option, the
+//! iptables backend, ip6tables present). With `prefer-v6` it does, so the
+//! shared address is not allowed: the entry relies on its AAAA rule, and
+//! with no other IPv4 answer its IPv4 rules go as after an address change.
+//! Otherwise (or with `warn`) the address is allowed as before, with a
+//! warning that IPv4 allow-listing cannot work for it and that `ipv6=yes`
+//! would. A client reporting the carrier's public address instead cannot
+//! be told apart.
+//!
+//! When the server itself has an address in 100.64.0.0/10 (Tailscale, or a
+//! host inside the same carrier network), sources in that range do reach
+//! it, and only the DS-Lite range is treated as shared.
+
+use std::net::Ipv4Addr;
+
+use crate::{v6, Config, DdnsEntry};
+
+/// What the sync does with a shared-space answer
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    PreferV6,
+    Warn,
+    Off,
+}
+
+impl Mode {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "prefer-v6" => Some(Mode::PreferV6),
+            "warn" => Some(Mode::Warn),
+            "off" => Some(Mode::Off),
+            _ => None,
+        }
+    }
+}
+
+/// 100.64.0.0/10
+fn is_cgnat(ip: &Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    a == 100 && (b & 0xc0) == 64
+}
+
+/// 192.0.0.0/29
+fn is_dslite(ip: &Ipv4Addr) -> bool {
+    let [a, b, c, d] = ip.octets();
+    (a, b, c) == (192, 0, 0) && d < 8
+}
+
+/// The check of one sync, aware of the server's own addresses
+pub struct Check {
+    mode: Mode,
+    reachable: bool,  // The server is in 100.64.0.0/10 itself
+}
+
+impl Check {
+    pub fn new(mode: Mode, own_addrs: &[Ipv4Addr]) -> Self {
+        Check { mode, reachable: own_addrs.iter().any(is_cgnat) }
+    }
+
+    /// The answers the server cannot see as a source
+    pub fn shared(&self, ips: &[Ipv4Addr]) -> Vec<Ipv4Addr> {
+        if self.mode == Mode::Off {
+            return Vec::new();
+        }
+        ips.iter().copied().filter(|ip| is_dslite(ip) || (is_cgnat(ip) && !self.reachable)).collect()
+    }
+
+    /// Whether the entry drops shared answers for its IPv6 side
+    pub fn prefers_v6(&self, config: &Config, entry: &DdnsEntry, iptables: bool) -> bool {
+        self.mode == Mode::PreferV6 && iptables && v6::is_enabled_for(config, entry) && v6::find_ip6tables().is_some()
+    }
+}
@@ -0,0 +1,292 @@
+//! Hidden soak test: `ddnsfw --chaos [rounds]`.
+//!
+//! Makes the "no SSH loss" guarantee testable without touching the host.
+//! The driver creates a sandbox profile directory under /tmp and runs the
+//! real sync (this binary, as a child process) round after round, while
+//! DDNS answers move around. In the children:
+//!
+//! - DNS comes from the sandbox `dns.sim` and randomly fails
+//! - the firewall is the sandbox `firewall.sim`, whose adds and deletes
+//!   randomly fail
+//! - the process randomly "crashes" (exits) right after any cache state
+//!   transition or firewall change, leaving recovery to the next round
+//!
+//! The mock firewall checks the invariant on every delete: a configured port
+//! that had an allow rule never ends up with none. A final calm round (no
+//! injection) must converge to exactly the current DNS answers. The sandbox
+//! is kept for inspection when anything fails.
+
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::net::Ipv4Addr;
+use std::os::unix::fs::DirBuilderExt;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::rule::Rule;
+use crate::{exit_err, MAX_RULES};
+
+const DIR_ENV: &str = "DDNSFW_CHAOS_DIR";
+const SEED_ENV: &str = "DDNSFW_CHAOS_SEED";
+const CALM_ENV: &str = "DDNSFW_CHAOS_CALM";
+
+const DEFAULT_ROUNDS: u64 = 200;
+const DNS_FAIL_PERCENT: u64 = 15;
+const BACKEND_FAIL_PERCENT: u64 = 10;
+const CRASH_PERCENT: u64 = 4;
+const IP_CHANGE_PERCENT: u64 = 30;
+const RETRY_PERCENT: u64 = 30;  // Rounds that clear the backoff queue first
+
+const CRASH_EXIT: i32 = 86;
+const VIOLATION_EXIT: i32 = 87;
+
+/// Sandbox entries; two share a port so shared-rule paths are exercised
+const HOSTS: &[(&str, u16)] = &[("alpha.chaos.test", 22), ("beta.chaos.test", 22), ("gamma.chaos.test", 443)];
+
+pub struct Chaos {
+    dir: String,
+    calm: bool,
+    rng: AtomicU64,
+    dns: OnceLock<HashMap<String, Vec<Ipv4Addr>>>,  // dns.sim, read once per child
+}
+
+static CHAOS: OnceLock<Option<Chaos>> = OnceLock::new();
+
+/// The injection context when running as a chaos child
+pub fn active() -> Option<&'static Chaos> {
+    CHAOS
+        .get_or_init(|| {
+            let dir = env::var(DIR_ENV).ok()?;
+            let seed = env::var(SEED_ENV).ok().and_then(|s| s.parse().ok()).unwrap_or(1);
+            Some(Chaos { dir, calm: env::var(CALM_ENV).is_ok(), rng: AtomicU64::new(seed | 1), dns: OnceLock::new() })
+        })
+        .as_ref()
+}
+
+/// xorshift64: plenty for fault injection
+fn next(state: &AtomicU64) -> u64 {
+    let mut x = state.load(Ordering::Relaxed);
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    state.store(x, Ordering::Relaxed);
+    x
+}
+
+fn seed() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(1) | 1
+}
+
+impl Chaos {
+    pub fn dir(&self) -> &str {
+        &self.dir
+    }
+
+    fn roll(&self, percent: u64) -> bool {
+        !self.calm && next(&self.rng) % 100 < percent
+    }
+
+    /// Exits as if killed here
+    pub fn crash_point(&self, what: &str) {
+        if self.roll(CRASH_PERCENT) {
+            eprintln!("[ddnsfw] chaos: crash {}", what);
+            std::process::exit(CRASH_EXIT);
+        }
+    }
+
+    pub fn resolve(&self, hostname: &str) -> Vec<Ipv4Addr> {
+        if self.roll(DNS_FAIL_PERCENT) {
+            return Vec::new();
+        }
+        let dns = self.dns.get_or_init(|| {
+            let mut dns: HashMap<String, Vec<Ipv4Addr>> = HashMap::new();
+            for (host, ip) in read_dns(&self.dir) {
+                dns.entry(host).or_default().push(ip);
+            }
+            dns
+        });
+        dns.get(hostname).cloned().unwrap_or_default()
+    }
+
+    fn firewall_path(&self) -> String {
+        format!("{}/firewall.sim", self.dir)
+    }
+
+    pub fn existing_rules(&self) -> HashSet<Rule> {
+        read_firewall_file(&self.firewall_path())
+    }
+
+    pub fn add_rule(&self, rule: &Rule) -> bool {
+        if self.roll(BACKEND_FAIL_PERCENT) {
+            return false;
+        }
+        let mut rules = self.existing_rules();
+        rules.insert(rule.clone());
+        write_firewall(&self.firewall_path(), &rules);
+        self.crash_point("after firewall add");
+        true
+    }
+
+    pub fn delete_rule(&self, rule: &Rule) -> bool {
+        if self.roll(BACKEND_FAIL_PERCENT) {
+            return false;
+        }
+        let mut rules = self.existing_rules();
+        if !rules.remove(rule) {
+            return false;
+        }
+        write_firewall(&self.firewall_path(), &rules);
+        if !rules.iter().any(|r| r.port == rule.port) {
+            eprintln!("[ddnsfw] chaos: VIOLATION: deleting {} left port {} without an allow rule", rule, rule.port);
+            std::process::exit(VIOLATION_EXIT);
+        }
+        self.crash_point("after firewall delete");
+        true
+    }
+}
+
+fn read_dns(dir: &str) -> Vec<(String, Ipv4Addr)> {
+    fs::read_to_string(format!("{}/dns.sim", dir))
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| {
+            let (host, ip) = line.split_once(' ')?;
+            Some((host.to_string(), ip.parse().ok()?))
+        })
+        .collect()
+}
+
+pub fn write_dns(dir: &str, answers: &[(String, Ipv4Addr)]) {
+    let content: String = answers.iter().map(|(host, ip)| format!("{} {}\n", host, ip)).collect();
+    let _ = fs::write(format!("{}/dns.sim", dir), content);
+}
+
+pub fn read_firewall(dir: &str) -> HashSet<Rule> {
+    read_firewall_file(&format!("{}/firewall.sim", dir))
+}
+
+fn read_firewall_file(path: &str) -> HashSet<Rule> {
+    fs::read_to_string(path).unwrap_or_default().lines().take(MAX_RULES).filter_map(Rule::parse).collect()
+}
+
+fn write_firewall(path: &str, rules: &HashSet<Rule>) {
+    let content: String = rules.iter().map(|rule| format!("{}\n", rule)).collect();
+    let tmp = format!("{}.tmp", path);
+    if fs::write(&tmp, content).is_ok() {
+        let _ = fs::rename(&tmp, path);
+    }
+}
+
+fn random_ip(state: &AtomicU64) -> Ipv4Addr {
+    // Small pool, so hosts sometimes converge on the same IP
+    Ipv4Addr::new(198, 51, 100, 1 + (next(state) % 6) as u8)
+}
+
+/// Creates a sandbox directory holding `config`
+pub fn sandbox(kind: &str, config: &str) -> String {
+    let dir = format!("/tmp/ddnsfw-{}-{}", kind, std::process::id());
+    if fs::DirBuilder::new().mode(0o700).create(&dir).is_err() {
+        exit_err(&format!("Cannot create {}", dir));
+    }
+    if fs::write(format!("{}/conf.conf", dir), config).is_err() {
+        exit_err(&format!("Cannot write {}/conf.conf", dir));
+    }
+    dir
+}
+
+/// Runs one child sync (`retry` first clears the retry queue, as an
+/// operator would); returns its exit code
+pub fn run_child(dir: &str, seed: u64, calm: bool, retry: bool) -> i32 {
+    let Ok(exe) = env::current_exe() else {
+        exit_err("Cannot locate own binary");
+    };
+    let log = OpenOptions::new().append(true).create(true).open(format!("{}/chaos.log", dir));
+    let mut command = Command::new(exe);
+    if retry {
+        command.arg("retry");
+    }
+    command.env(DIR_ENV, dir).env(SEED_ENV, seed.to_string());
+    if calm {
+        command.env(CALM_ENV, "1");
+    }
+    if let Ok(log) = log {
+        if let Ok(err) = log.try_clone() {
+            command.stdout(log).stderr(err);
+        }
+    } else {
+        command.stdout(Stdio::null()).stderr(Stdio::null());
+    }
+    command.status().ok().and_then(|s| s.code()).unwrap_or(-1)
+}
+
+/// `ddnsfw --chaos [rounds]`
+pub fn run(args: &[String]) {
+    let rounds = match args.first() {
+        None => DEFAULT_ROUNDS,
+        Some(n) => n.parse().ok().filter(|&n| n > 0).unwrap_or_else(|| exit_err("Usage: ddnsfw --chaos [rounds]")),
+    };
+
+    let config: String = HOSTS.iter().map(|(host, port)| format!("{}:{}\n", host, port)).collect();
+    let dir = sandbox("chaos", &config);
+
+    let rng = AtomicU64::new(seed());
+    let mut answers: Vec<(String, Ipv4Addr)> = HOSTS.iter().map(|(host, _)| (host.to_string(), random_ip(&rng))).collect();
+    write_dns(&dir, &answers);
+
+    println!("[ddnsfw] chaos: {} rounds in {}", rounds, dir);
+    let (mut clean, mut crashes, mut errors) = (0u64, 0u64, 0u64);
+    for round in 1..=rounds {
+        if next(&rng) % 100 < IP_CHANGE_PERCENT {
+            let index = (next(&rng) % answers.len() as u64) as usize;
+            answers[index].1 = random_ip(&rng);
+            write_dns(&dir, &answers);
+        }
+        let retry = next(&rng) % 100 < RETRY_PERCENT;
+        match run_child(&dir, next(&rng), false, retry) {
+            0 => clean += 1,
+            CRASH_EXIT => crashes += 1,
+            VIOLATION_EXIT => {
+                println!("[ddnsfw] chaos: VIOLATION in round {} (see {}/chaos.log)", round, dir);
+                std::process::exit(1);
+            }
+            _ => errors += 1,
+        }
+        if round % 50 == 0 {
+            println!("[ddnsfw] chaos: round {}/{}", round, rounds);
+        }
+    }
+
+    // Calm rounds: recover from the last crash, then converge
+    run_child(&dir, 1, true, true);
+    run_child(&dir, 1, true, false);
+    let installed = read_firewall(&dir);
+    let expected: HashSet<Rule> = HOSTS
+        .iter()
+        .filter_map(|(host, port)| answers.iter().find(|(h, _)| h == host).map(|(_, ip)| Rule::allow(*ip, *port)))
+        .collect();
+
+    println!(
+        "[ddnsfw] chaos: {} clean runs, {} injected crashes, {} other exits, 0 invariant violations",
+        clean, crashes, errors
+    );
+    if installed != expected {
+        let mut got: Vec<String> = installed.iter().map(Rule::to_string).collect();
+        let mut want: Vec<String> = expected.iter().map(Rule::to_string).collect();
+        got.sort();
+        want.sort();
+        println!("[ddnsfw] chaos: did not converge: have [{}], want [{}]", got.join(", "), want.join(", "));
+        println!("[ddnsfw] chaos: sandbox kept at {}", dir);
+        std::process::exit(1);
+    }
+    if errors > 0 {
+        println!("[ddnsfw] chaos: sandbox kept at {} (unexpected exits)", dir);
+        std::process::exit(1);
+    }
+    println!("[ddnsfw] chaos: converged, all allow rules preserved");
+    let _ = fs::remove_dir_all(&dir);
+    let _ = std::io::stdout().flush();
+}
@@ -0,0 +1,168 @@
+//! Change-rate guardrail: entries whose hostname moves too often freeze.
+//!
+//!   max_changes_per_day = 10                  # every entry (default 0: off)
+//!   home.example.net:22 max_changes_per_day=4 # or per entry
+//!
+//! A hijacked DDNS record rotated through many addresses would walk the
+//! allow rule across all of them. Each sync compares the addresses an entry
+//! picked with those it saw last time; a new address is a change (one that
+//! only disappears, as when `dns_select = all` loses an uplink, is not). An
+//! entry that has seen more changes than its limit within the last 24 hours
+//! is frozen: its existing rules stay, the new address is not allowed, and a
+//! `change_frozen` event (syslog critical) and the `alert_command` (level
+//! `frozen`) fire once. Changes seen while frozen count too, so a record
+//! that keeps rotating stays frozen; the freeze lifts by itself once the
+//! last 24 hours hold no more than the limit (`change_unfrozen`), and the
+//! address seen then is applied as usual.
+//!
+//! Kept in the profile's `service.churn`, one line per entry:
+//! `host:port last_ip[,ip...] frozen(0|1) time,time,...`.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::net::Ipv4Addr;
+use std::os::unix::fs::OpenOptionsExt;
+
+use crate::escalate::{self, Policy};
+use crate::events::{self, Value};
+use crate::{profile, MAX_DNS_ADDRS, MAX_ENTRIES};
+
+const WINDOW_SECS: u64 = 86400;
+pub const MAX_CHANGES_PER_DAY: u32 = 1000;
+
+struct Record {
+    last: Vec<Ipv4Addr>,
+    frozen: bool,
+    changes: Vec<u64>,  // Change times within the window, oldest first
+}
+
+/// What a sync may do with an entry's new answer
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Verdict {
+    Allow,
+    /// Keep the existing rules: this many changes in the last 24 hours
+    Frozen(u32),
+}
+
+pub struct State {
+    records: HashMap<String, Record>,
+}
+
+impl State {
+    pub fn load() -> Self {
+        let content = fs::read_to_string(&profile::current().churn_path).unwrap_or_default();
+        let mut records = HashMap::new();
+        for line in content.lines().take(MAX_ENTRIES) {
+            let mut fields = line.split_whitespace();
+            let (Some(label), Some(last), Some(frozen)) = (fields.next(), fields.next(), fields.next()) else {
+                continue;
+            };
+            let Ok(last) = last.split(',').take(MAX_DNS_ADDRS).map(str::parse).collect::<Result<Vec<Ipv4Addr>, _>>() else {
+                continue;
+            };
+            let changes = fields
+                .next()
+                .unwrap_or_default()
+                .split(',')
+                .take(MAX_CHANGES_PER_DAY as usize + 1)
+                .filter_map(|t| t.parse().ok())
+                .collect();
+            records.insert(label.to_string(), Record { last, frozen: frozen == "1", changes });
+        }
+        State { records }
+    }
+
+    /// Records the addresses `label` resolved to, with `limit` changes per
+    /// day (0: no limit), alerting when the entry freezes or thaws
+    pub fn observe(&mut self, label: &str, ips: &[Ipv4Addr], limit: u32, policy: &Policy, now: u64) -> Verdict {
+        if limit == 0 {
+            self.records.remove(label);
+            return Verdict::Allow;
+        }
+        let record = self
+            .records
+            .entry(label.to_string())
+            .or_insert(Record { last: ips.to_vec(), frozen: false, changes: Vec::new() });
+        // A time "from the future" predates a clock step back: dropped
+        record.changes.retain(|&t| t <= now && now - t < WINDOW_SECS);
+        if ips.iter().any(|ip| !record.last.contains(ip)) {
+            record.changes.push(now);
+            // One beyond the limit is all a freeze needs
+            let excess = record.changes.len().saturating_sub(limit as usize + 1);
+            record.changes.drain(..excess);
+        }
+        record.last = ips.to_vec();
+
+        let count = record.changes.len() as u32;
+        let ip_str = ips.iter().map(Ipv4Addr::to_string).collect::<Vec<_>>().join(",");
+        let fields = [
+            ("entry", Value::Str(label)),
+            ("ip", Value::Str(&ip_str)),
+            ("changes", Value::Num(u64::from(count))),
+            ("limit", Value::Num(u64::from(limit))),
+        ];
+        if count > limit {
+            if !record.frozen {
+                record.frozen = true;
+                eprintln!(
+                    "[ddnsfw] ERROR: {} changed IP {} times in 24h (max_changes_per_day={}), freezing its rules",
+                    label, count, limit
+                );
+                events::emit("change_frozen", &fields);
+                escalate::run_command(&policy.alert_command, "frozen", label, count);
+            }
+            return Verdict::Frozen(count);
+        }
+        if record.frozen {
+            record.frozen = false;
+            println!("[ddnsfw] {} is back within max_changes_per_day={}, unfreezing", label, limit);
+            events::emit("change_unfrozen", &fields);
+        }
+        Verdict::Allow
+    }
+
+    /// Changes in the last 24 hours if `label` saw `ips` now (no side effects)
+    pub fn peek(&self, label: &str, ips: &[Ipv4Addr], now: u64) -> u32 {
+        let Some(record) = self.records.get(label) else {
+            return 0;
+        };
+        let recent = record.changes.iter().filter(|&&t| t <= now && now - t < WINDOW_SECS).count() as u32;
+        recent + u32::from(ips.iter().any(|ip| !record.last.contains(ip)))
+    }
+
+    /// Carries an entry's record over to its new `id=`
+    pub fn rename(&mut self, label: &str, id: &str) {
+        if !self.records.contains_key(id) {
+            if let Some(record) = self.records.remove(label) {
+                self.records.insert(id.to_string(), record);
+            }
+        }
+    }
+
+    /// Frozen entries, for status displays
+    pub fn frozen(&self) -> Vec<&str> {
+        let mut labels: Vec<&str> = self.records.iter().filter(|(_, r)| r.frozen).map(|(l, _)| l.as_str()).collect();
+        labels.sort();
+        labels
+    }
+
+    /// Keeps the entries still configured and writes the file atomically
+    pub fn save(&mut self, labels: &HashSet<String>) {
+        self.records.retain(|label, _| labels.contains(label));
+        let mut content = String::new();
+        for (label, record) in self.records.iter().take(MAX_ENTRIES) {
+            let changes: Vec<String> = record.changes.iter().map(u64::to_string).collect();
+            let last: Vec<String> = record.last.iter().map(Ipv4Addr::to_string).collect();
+            content.push_str(&format!("{} {} {} {}\n", label, last.join(","), u8::from(record.frozen), changes.join(",")));
+        }
+
+        let path = &profile::current().churn_path;
+        let temp_path = format!("{}.tmp", path);
+        if let Ok(mut file) = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&temp_path) {
+            if file.write_all(content.as_bytes()).is_ok() {
+                let _ = fs::rename(&temp_path, path);
+            }
+        }
+    }
+}
@@ -0,0 +1,99 @@
+//! Wall-clock jumps versus elapsed time.
+//!
+//! Expiries and backoffs are stored as wall-clock seconds, and the wall
+//! clock jumps: an NTP step after booting a machine without an RTC, a
+//! manual `date -s`, a VM restored from a snapshot. Files holding such
+//! timestamps (the cache, the grants ledger) also store a clock mark:
+//!
+//!   1767225600@86400@6a0f3c1e-...      (wall, CLOCK_BOOTTIME, boot id)
+//!
+//! On load within the same boot, the wall time that passed minus the boot
+//! time that passed is the size of any jump since the save, and the stored
+//! timestamps are shifted by it: a 30-minute grant still lasts 30 minutes
+//! after the clock was stepped a year forward, and a retry backoff does not
+//! stall for a year after a step back. Suspend needs no correction, since
+//! both clocks advance through it and whatever fell due fires on resume.
+//! Across a reboot the boot clock restarts and the wall clock is all there
+//! is.
+
+use std::fmt;
+use std::fs;
+use std::time::Duration;
+
+use crate::now_secs;
+
+/// Jumps below this are scheduling noise, not clock changes
+const TOLERANCE_SECS: i64 = 5;
+const BOOT_ID_PATH: &str = "/proc/sys/kernel/random/boot_id";
+
+/// Time since boot, suspend included (the monotonic clock stops during it)
+pub fn boottime() -> Duration {
+    let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    // SAFETY: valid clock id and output pointer
+    if unsafe { libc::clock_gettime(libc::CLOCK_BOOTTIME, &mut ts) } != 0 {
+        return Duration::ZERO;
+    }
+    Duration::new(ts.tv_sec.max(0) as u64, ts.tv_nsec.clamp(0, 999_999_999) as u32)
+}
+
+fn boot_id() -> Option<String> {
+    let id = fs::read_to_string(BOOT_ID_PATH).ok()?;
+    let id = id.trim();
+    (!id.is_empty() && id.len() <= 64 && id.chars().all(|c| c.is_ascii_hexdigit() || c == '-')).then(|| id.to_string())
+}
+
+/// Both clocks at one instant, tied to the boot they belong to
+#[derive(Debug, Clone)]
+pub struct Mark {
+    wall: u64,
+    boot: u64,
+    boot_id: String,
+}
+
+impl Mark {
+    pub fn now() -> Option<Self> {
+        Some(Mark { wall: now_secs(), boot: boottime().as_secs(), boot_id: boot_id()? })
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut fields = s.trim().splitn(3, '@');
+        let wall = fields.next()?.parse().ok()?;
+        let boot = fields.next()?.parse().ok()?;
+        let boot_id = fields.next()?.to_string();
+        Some(Mark { wall, boot, boot_id })
+    }
+
+    /// Seconds the wall clock jumped since this mark (positive: forward), or
+    /// 0 when it kept pace with elapsed time or the boot changed
+    pub fn jump(&self) -> i64 {
+        let Some(now) = Mark::now() else {
+            return 0;
+        };
+        if now.boot_id != self.boot_id || now.boot < self.boot {
+            return 0;
+        }
+        let wall_elapsed = now.wall as i64 - self.wall as i64;
+        let boot_elapsed = (now.boot - self.boot) as i64;
+        let jump = wall_elapsed - boot_elapsed;
+        if jump.abs() < TOLERANCE_SECS {
+            0
+        } else {
+            jump
+        }
+    }
+}
+
+impl fmt::Display for Mark {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}@{}@{}", self.wall, self.boot, self.boot_id)
+    }
+}
+
+/// Moves a stored timestamp into the current wall-clock frame (0 stays 0:
+/// it means "never")
+pub fn shift(ts: u64, jump: i64) -> u64 {
+    if ts == 0 {
+        return 0;
+    }
+    ts.saturating_add_signed(jump).max(1)
+}
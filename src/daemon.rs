@@ -0,0 +1,348 @@
+//! `ddnsfw daemon [--interval SECS]`: a long-running alternative to the timer.
+//!
+//! The interval is `--interval`, else the `interval = SECS` setting, else
+//! 120 seconds.
+//!
+//! Syncs every interval (with the configured jitter, skipping a round when
+//! another sync holds the lock, exactly like a timer run) and reacts to:
+//!
+//! - SIGUSR1: sync now (waits for a running sync), then restart the interval
+//! - SIGUSR2: log a status summary from the cache (rules, hostnames at the
+//!   escalation alert level), without touching the firewall
+//! - SIGHUP: re-read the interval and backoff_max (unless `--interval` was
+//!   given) and batch_window, then sync now; the other settings are read by
+//!   every sync anyway, except push_listen, webhook_listen and
+//!   metrics_listen, which need a restart
+//! - SIGTERM, SIGINT: exit, after the running sync if there is one
+//!
+//! With `push_listen` set, a listener thread accepts signed pushes (see
+//! push.rs) and requests a sync through SIGUSR1. With `webhook_listen`, a
+//! provider webhook does the same, but the sync only looks up the hostnames
+//! it named and the schedule stays as it was (see webhook.rs); a SIGUSR1
+//! arriving at the same moment is folded into that sync. `metrics_listen`
+//! serves Prometheus metrics (see metrics.rs).
+//!
+//! The listener threads never read the config file themselves (a broken
+//! config ends the process): they use the config the daemon last read, at
+//! the start, on SIGHUP and after every sync.
+//!
+//! With `backoff_max = SECS` in the config, every sync that changes nothing
+//! (no rule added or removed, no failed operation, no hostname failing to
+//! resolve) doubles the wait up to that limit; the first sync with a change
+//! drops back to the base interval. Quiet fleets then sync rarely while an
+//! address change is still picked up within one long interval (or at once
+//! with SIGUSR1).
+//!
+//! With `batch_window = SECS`, a sync request (SIGUSR1, a push, a webhook,
+//! the interval running out) first waits that long for others: everything
+//! that arrives in the window is served by one sync instead of several back
+//! to back. A full sync is run when any of them asked for one, else one sync
+//! of all the hostnames the webhooks named. A webhook folded into a full
+//! sync still has its hostnames looked up afresh afterwards when
+//! provider_intervals or dns_cache could hand the full sync an older answer.
+//!
+//! The schedule runs on the boot clock (CLOCK_BOOTTIME): wall-clock steps do
+//! not move it, and a sync that fell due during a suspend runs within
+//! RESUME_CHECK of the resume instead of a full interval later.
+//!
+//! The signals are blocked and collected with sigtimedwait, so they are never
+//! handled in the middle of a sync, and a signal sent during a sync is
+//! picked up right after it. Fatal errors still end the process (leave
+//! restarting to the service manager).
+//!
+//! Under a `Type=notify` unit the daemon reports READY=1 once the first
+//! sync is done, RELOADING=1 around a SIGHUP, STOPPING=1 on the way out and
+//! the time of the last sync as STATUS. With WatchdogSec set it sends
+//! WATCHDOG=1 at half that period between syncs, so a daemon stuck in a
+//! sync longer than WatchdogSec is restarted.
+
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::sys::{self, DaemonSignal};
+use crate::{
+    clock, escalate, metrics, push, exit_err, grants, format_utc, now_secs, parse_config, scheduled_sync, stats, sync_firewall,
+    sync_hostnames, webhook, Cache, Config,
+};
+
+const DEFAULT_INTERVAL_SECS: u64 = 120;
+pub const MIN_INTERVAL_SECS: u64 = 10;
+pub const MAX_INTERVAL_SECS: u64 = 86400;
+pub const MAX_BATCH_WINDOW_SECS: u64 = 60;
+/// Longest single wait: sigtimedwait runs on the monotonic clock, which
+/// stops during suspend, so the boot clock is checked this often
+const RESUME_CHECK: Duration = Duration::from_secs(30);
+
+/// The config as of the last sync or SIGHUP, for the listener threads
+static CURRENT: Mutex<Option<Arc<Config>>> = Mutex::new(None);
+
+/// Re-reads the config and hands it to the listener threads
+fn refresh() -> Arc<Config> {
+    let config = Arc::new(parse_config());
+    *CURRENT.lock().unwrap_or_else(|e| e.into_inner()) = Some(Arc::clone(&config));
+    config
+}
+
+/// The config the daemon last read; None outside the daemon
+pub fn current_config() -> Option<Arc<Config>> {
+    CURRENT.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// The wait between scheduled syncs, stretched while nothing changes
+struct Backoff {
+    base: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    fn new(base: Duration) -> Self {
+        Backoff { base, current: base }
+    }
+
+    /// Adjusts the wait after a sync; `max` is the configured backoff_max
+    fn after_sync(&mut self, max: Duration) {
+        let quiet = last_sync_was_quiet();
+        let next = if quiet && max > self.base { (self.current * 2).min(max) } else { self.base };
+        if next != self.current {
+            let reason = if quiet { "no changes" } else { "changes" };
+            println!("[ddnsfw] Next sync in {}s ({})", next.as_secs(), reason);
+        }
+        self.current = next;
+    }
+}
+
+/// Whether the last completed sync changed nothing and nothing failed
+fn last_sync_was_quiet() -> bool {
+    let counters = ["added", "removed", "failed"].map(|key| stats::last(key).unwrap_or(1));
+    counters.iter().all(|&n| n == 0) && Cache::load().failures.is_empty()
+}
+
+fn backoff_max() -> Duration {
+    Duration::from_secs(parse_config().backoff_max)
+}
+
+/// Sync requests served by one sync (batch_window)
+#[derive(Default)]
+struct Batch {
+    manual: bool,                // SIGUSR1 without a webhook: push, by hand
+    scheduled: bool,             // The interval ran out
+    hostnames: HashSet<String>,  // Named by webhooks
+    requests: u32,
+}
+
+impl Batch {
+    /// A SIGUSR1: the hostnames of the webhooks behind it, or a full sync
+    fn signal(&mut self) {
+        match webhook::take_pending() {
+            Some(hostnames) => self.hostnames.extend(hostnames),
+            None => self.manual = true,
+        }
+        self.requests += 1;
+    }
+
+    /// Waits out the batch window, adding the requests that arrive in it
+    /// (and the interval, if it runs out). A SIGHUP or SIGTERM ends the
+    /// window early and is returned, to be handled after the sync.
+    fn collect(&mut self, window: Duration, watchdog: Option<Duration>, next: Duration) -> Option<DaemonSignal> {
+        let end = clock::boottime() + window;
+        let mut deferred = None;
+        loop {
+            let left = end.saturating_sub(clock::boottime());
+            if left.is_zero() {
+                break;
+            }
+            if watchdog.is_some() {
+                sys::sd_notify("WATCHDOG=1");
+            }
+            match sys::wait_signal(left.min(RESUME_CHECK).min(watchdog.unwrap_or(RESUME_CHECK))) {
+                Some(DaemonSignal::SyncNow) => self.signal(),
+                Some(DaemonSignal::DumpStatus) => dump_status(next),
+                Some(signal) => {
+                    deferred = Some(signal);
+                    break;
+                }
+                None => {}
+            }
+        }
+        if !self.scheduled && clock::boottime() >= next {
+            self.scheduled = true;
+            self.requests += 1;
+        }
+        deferred
+    }
+
+    /// Runs the one sync the batch needs; true when it was a full sync
+    /// (which restarts the schedule)
+    fn run(self) -> bool {
+        if self.requests > 1 {
+            println!("[ddnsfw] {} sync requests within batch_window, syncing once", self.requests);
+        }
+        if !self.manual && !self.scheduled {
+            // Only the hostnames the webhooks named: the schedule stays
+            sync_hostnames(&self.hostnames);
+            return false;
+        }
+        if self.manual {
+            println!("[ddnsfw] SIGUSR1: syncing now");
+            sync_firewall();
+        } else {
+            scheduled_sync();
+        }
+        // A throttled or shared answer may predate the update the webhook announced
+        let config = parse_config();
+        if !self.hostnames.is_empty() && (!config.provider_intervals.is_empty() || config.dns_cache) {
+            sync_hostnames(&self.hostnames);
+        }
+        true
+    }
+}
+
+pub fn parse_interval(s: &str) -> Option<u64> {
+    s.parse().ok().filter(|s| (MIN_INTERVAL_SECS..=MAX_INTERVAL_SECS).contains(s))
+}
+
+/// The `interval` setting (DDNSFW_INTERVAL in containers), else the default
+fn configured_interval() -> u64 {
+    parse_config().interval.unwrap_or(DEFAULT_INTERVAL_SECS)
+}
+
+/// Tells a `Type=notify` service manager about the daemon (no-op otherwise);
+/// an empty `state` only updates the STATUS line
+fn notify(state: &str) {
+    let status = match stats::last("finished") {
+        Some(finished) => format!("Last sync {} UTC, {} failed", format_utc(finished), stats::last("failed").unwrap_or(0)),
+        None => String::from("No completed sync"),
+    };
+    let status = format!("STATUS={}", status);
+    sys::sd_notify(&if state.is_empty() { status } else { format!("{}\n{}", state, status) });
+}
+
+pub fn run(args: &[String]) {
+    let fixed = match args {
+        [] => None,
+        [flag, secs] if flag == "--interval" => Some(parse_interval(secs).unwrap_or_else(|| {
+            exit_err(&format!("Invalid interval '{}' ({}-{} seconds)", secs, MIN_INTERVAL_SECS, MAX_INTERVAL_SECS))
+        })),
+        _ => exit_err("Usage: ddnsfw daemon [--interval SECS]"),
+    };
+    let interval = fixed.unwrap_or_else(configured_interval);
+    // Before any thread exists, so every thread inherits the mask
+    if !sys::block_daemon_signals() {
+        exit_err("Cannot block the daemon signals");
+    }
+    let config = refresh();
+    push::listen(&config);
+    webhook::listen(&config);
+    metrics::listen(&config);
+    let listening = (config.push_listen, config.webhook_listen, config.metrics_listen);
+    println!(
+        "[ddnsfw] Daemon started (pid {}, every {}s; SIGUSR1: sync now, SIGUSR2: status, SIGHUP: reload)",
+        std::process::id(),
+        interval
+    );
+
+    let watchdog = sys::watchdog_interval();
+    let mut window = Duration::from_secs(config.batch_window);
+    let mut backoff = Backoff::new(Duration::from_secs(interval));
+    scheduled_sync();
+    refresh();
+    backoff.after_sync(backoff_max());
+    notify("READY=1");
+    // Deadlines on the boot clock: a sync that fell due while suspended
+    // runs right after resume, and wall-clock steps do not move it
+    let mut next = clock::boottime() + backoff.current;
+    // A SIGHUP or SIGTERM that cut a batch window short
+    let mut deferred = None;
+    loop {
+        if watchdog.is_some() {
+            sys::sd_notify("WATCHDOG=1");
+        }
+        let wait = next.saturating_sub(clock::boottime()).min(RESUME_CHECK).min(watchdog.unwrap_or(RESUME_CHECK));
+        let signal = match deferred.take() {
+            Some(signal) => Some(signal),
+            None => sys::wait_signal(wait),
+        };
+        let mut batch = Batch::default();
+        match signal {
+            Some(DaemonSignal::SyncNow) => batch.signal(),
+            Some(DaemonSignal::DumpStatus) => dump_status(next),
+            Some(DaemonSignal::Reload) => {
+                notify("RELOADING=1");
+                let interval = fixed.unwrap_or_else(configured_interval);
+                println!("[ddnsfw] SIGHUP: config reloaded (every {}s), syncing now", interval);
+                let config = refresh();
+                if (config.push_listen, config.webhook_listen, config.metrics_listen) != listening {
+                    eprintln!("[ddnsfw] WARN: push_listen, webhook_listen and metrics_listen changes take effect on restart");
+                }
+                window = Duration::from_secs(config.batch_window);
+                backoff = Backoff::new(Duration::from_secs(interval));
+                sync_firewall();
+                refresh();
+                backoff.after_sync(backoff_max());
+                next = clock::boottime() + backoff.current;
+                notify("READY=1");
+            }
+            Some(DaemonSignal::Stop) => {
+                notify("STOPPING=1");
+                println!("[ddnsfw] Daemon stopping");
+                return;
+            }
+            None if clock::boottime() >= next => {
+                batch.scheduled = true;
+                batch.requests += 1;
+            }
+            None => {}
+        }
+        if batch.requests == 0 {
+            continue;
+        }
+        deferred = batch.collect(window, watchdog, next);
+        let full = batch.run();
+        refresh();
+        if full {
+            backoff.after_sync(backoff_max());
+            next = clock::boottime() + backoff.current;
+            notify("");
+        }
+    }
+}
+
+/// SIGUSR2: one summary line plus one line per cached rule
+fn dump_status(next: Duration) {
+    let cache = Cache::load();
+    let last = match stats::last("finished") {
+        Some(finished) => format!(
+            "last sync {} UTC ({}s ago, {} failed)",
+            format_utc(finished),
+            now_secs().saturating_sub(finished),
+            stats::last("failed").unwrap_or(0)
+        ),
+        None => String::from("no completed sync"),
+    };
+    println!(
+        "[ddnsfw] Status: {} rules, {}, {} queued retries, {} grants, state {}, next sync in {}s",
+        cache.rules.len(),
+        last,
+        cache.retries.len(),
+        grants::Ledger::load().grants.len(),
+        cache.state.name(),
+        next.saturating_sub(clock::boottime()).as_secs()
+    );
+    let policy = parse_config().escalation;
+    for hostname in escalate::alerting(&cache.failures, &policy) {
+        let streak = &cache.failures[hostname];
+        println!(
+            "[ddnsfw] Status: ALERT {} failing to resolve ({} syncs since {} UTC)",
+            hostname,
+            streak.count,
+            format_utc(streak.since)
+        );
+    }
+    let mut rules: Vec<_> = cache.rules.iter().collect();
+    rules.sort_by_key(|rule| rule.to_string());
+    for rule in rules {
+        let owners = cache.owners.get(rule).map(|o| o.join(", ")).unwrap_or_else(|| String::from("unknown"));
+        println!("[ddnsfw] Status: {} ({})", rule, owners);
+    }
+}
@@ -0,0 +1,126 @@
+//! Dedicated chain: this profile's INPUT allows in a chain of their own.
+//!
+//!   dedicated_chain = yes
+//!
+//! Instead of one rule per address at the top of filter/INPUT, the allows
+//! go to the chain `DDNSFW` (`DDNSFW-<profile>` for named profiles), which
+//! INPUT enters through one tagged jump that never changes:
+//!
+//!   -A INPUT -m comment --comment DDNS-ACCESS -j DDNSFW
+//!
+//! A sync stages its changes to the chain against its listing, and the
+//! phase's `iptables-restore` (see `restore`) declares the chain, which
+//! flushes and refills it whole, so it never shows a half-applied state.
+//! Without iptables-restore the changes go one rule at a time, as in INPUT.
+//!
+//! The jump is put back when it goes missing, below the companions at the
+//! top of INPUT (connection limits, knock gates), which must see the
+//! traffic first. Rules someone else put in the chain are kept. Entries
+//! with `chain=` (`chain=INPUT` included) stay where they say, and the
+//! IPv6 side stays in ip6tables INPUT. Turned off, the next sync moves the
+//! allows back to INPUT, then drops the jump and the empty chain. iptables
+//! backend only, not in service user mode (its helper makes no chains).
+
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+
+use crate::rule::{is_valid_chain_name, Rule};
+use crate::{has_managed_comment, iptables, iptables_run, profile, MAX_LISTING_LINES};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static NAME: OnceLock<String> = OnceLock::new();
+
+/// Sets whether the allows go to the chain (once, from the config)
+pub fn configure(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// `DDNSFW` or `DDNSFW-<profile>`
+pub fn name() -> &'static str {
+    NAME.get_or_init(|| match &profile::current().name {
+        Some(name) => format!("DDNSFW-{}", name),
+        None => String::from("DDNSFW"),
+    })
+}
+
+/// Whether the chain name fits the iptables limit for this profile
+pub fn fits_profile() -> bool {
+    is_valid_chain_name(name())
+}
+
+/// The chain filter allows go to unless an entry says otherwise
+pub fn inbound() -> &'static str {
+    if is_enabled() {
+        name()
+    } else {
+        "INPUT"
+    }
+}
+
+/// A plain allow in INPUT or in the chain: what companions guard
+pub fn is_inbound_allow(rule: &Rule) -> bool {
+    rule.is_plain() || (rule.chain == name() && Rule { chain: String::from("INPUT"), ..rule.clone() }.is_plain())
+}
+
+fn jump_args<'a>(action: &'a str, position: Option<&'a str>) -> Vec<&'a str> {
+    let mut args = vec!["-t", "filter", action, "INPUT"];
+    args.extend(position);
+    args.extend_from_slice(&["-m", "comment", "--comment", profile::current().comment(), "-j", name()]);
+    args
+}
+
+/// Creates the chain and the jump into it where missing
+pub fn ensure(bin: &str) -> bool {
+    if iptables(bin, &["-t", "filter", "-S", name()]).is_none() {
+        println!("[ddnsfw] Creating chain {}", name());
+        if !iptables_run(bin, &["-t", "filter", "-N", name()]) {
+            return false;
+        }
+    }
+    if iptables_run(bin, &jump_args("-C", None)) {
+        return true;
+    }
+    // Below the companions leading INPUT (this profile's tagged rules)
+    let Some(listing) = iptables(bin, &["-t", "filter", "-S", "INPUT"]) else {
+        return false;
+    };
+    let leading = listing
+        .lines()
+        .take(MAX_LISTING_LINES)
+        .filter(|line| line.starts_with("-A "))
+        .take_while(|line| has_managed_comment(line))
+        .count();
+    let position = (leading + 1).to_string();
+    println!("[ddnsfw] Adding jump from INPUT to {} (rule {})", name(), position);
+    iptables_run(bin, &jump_args("-I", Some(&position)))
+}
+
+/// Dedicated chain turned off: drops the jump and the chain once no rule
+/// is left in it. True when the chain is gone.
+pub fn retire(bin: &str) -> bool {
+    let Some(listing) = iptables(bin, &["-t", "filter", "-S", name()]) else {
+        return true;
+    };
+    if listing.lines().any(|line| line.starts_with("-A ")) {
+        eprintln!("[ddnsfw] WARN: Chain {} still holds rules, keeping it and its jump", name());
+        return false;
+    }
+    print!("[ddnsfw] Removing chain {} and its jump from INPUT ... ", name());
+    let _ = io::stdout().flush();
+    let mut iteration = 0;
+    while iptables_run(bin, &jump_args("-D", None)) && iteration < MAX_LISTING_LINES {
+        iteration += 1;
+    }
+    if iptables_run(bin, &["-t", "filter", "-X", name()]) {
+        println!("OK");
+        true
+    } else {
+        println!("FAILED (still referenced)");
+        false
+    }
+}
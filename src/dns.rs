@@ -0,0 +1,427 @@
+//! In-process DNS: A lookups over UDP (TCP when truncated), no subprocess.
+//!
+//!   resolver = override, native       # the default chain
+//!   nameservers = 192.0.2.53, 1.1.1.1 # default: the nameserver lines of /etc/resolv.conf
+//!
+//! A stub resolver: one recursive query per hostname, asked of the
+//! nameservers in order until one answers. CNAME chains in the answer are
+//! followed to the A records. The whole lookup has one deadline, split
+//! between the servers still to ask, and every wait is a socket timeout: an
+//! unresponsive server costs its share of the deadline and leaves no blocked
+//! thread behind. A reply must match the query (server, ID, question) or it
+//! is ignored, so a stray or spoofed datagram is not trusted. NXDOMAIN and
+//! an answer without A records are an empty result; SERVFAIL, REFUSED and
+//! silence move on to the next server. Search domains and /etc/hosts are
+//! not consulted: entries name fully qualified hostnames, and local names
+//! belong in hosts.override (or the `getent` resolver).
+
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::MAX_LOOP_ITERATIONS;
+
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+/// As many as the libc resolver uses (MAXNS)
+pub const MAX_NAMESERVERS: usize = 3;
+const PORT: u16 = 53;
+const UDP_MAX: usize = 512;
+const TCP_MAX: usize = 65535;
+const MAX_NAME_LEN: usize = 253;
+const MAX_LABEL_LEN: usize = 63;
+const MAX_CNAME_HOPS: usize = 8;
+/// Compression pointers followed within one name
+const MAX_POINTERS: usize = 16;
+
+const TYPE_A: u16 = 1;
+const TYPE_CNAME: u16 = 5;
+const CLASS_IN: u16 = 1;
+const FLAG_QR: u16 = 0x8000;
+const FLAG_TC: u16 = 0x0200;
+const FLAG_RD: u16 = 0x0100;
+const RCODE_NXDOMAIN: u16 = 3;
+
+/// `nameserver` lines of /etc/resolv.conf; 127.0.0.1 when there are none,
+/// as for the libc resolver
+pub fn system_nameservers() -> Vec<IpAddr> {
+    let servers: Vec<IpAddr> = fs::read_to_string(RESOLV_CONF)
+        .unwrap_or_default()
+        .lines()
+        .take(MAX_LOOP_ITERATIONS)
+        .filter_map(|line| line.strip_prefix("nameserver"))
+        // Scoped link-local addresses (fe80::1%eth0) do not parse and are skipped
+        .filter_map(|rest| rest.split_whitespace().next()?.parse().ok())
+        .take(MAX_NAMESERVERS)
+        .collect();
+    if servers.is_empty() {
+        vec![IpAddr::V4(Ipv4Addr::LOCALHOST)]
+    } else {
+        servers
+    }
+}
+
+/// Whether /etc/resolv.conf names a nameserver (doctor)
+pub fn has_system_nameservers() -> bool {
+    fs::read_to_string(RESOLV_CONF).unwrap_or_default().lines().any(|line| line.starts_with("nameserver"))
+}
+
+/// `192.0.2.53, 2001:db8::53` (1 to MAX_NAMESERVERS addresses)
+pub fn parse_nameservers(s: &str) -> Option<Vec<IpAddr>> {
+    let mut servers = Vec::new();
+    for item in s.split(',').map(str::trim).filter(|i| !i.is_empty()) {
+        let ip: IpAddr = item.parse().ok()?;
+        if !servers.contains(&ip) {
+            servers.push(ip);
+        }
+    }
+    (!servers.is_empty() && servers.len() <= MAX_NAMESERVERS).then_some(servers)
+}
+
+/// The A records of `hostname` with their TTLs, following CNAMEs; None
+/// when no server answered
+pub fn lookup_a(hostname: &str, servers: &[IpAddr], timeout: Duration) -> Option<Vec<(Ipv4Addr, u32)>> {
+    let name = hostname.trim_end_matches('.').to_ascii_lowercase();
+    let query = encode_query(&name, query_id())?;
+    let deadline = Instant::now() + timeout;
+    for (i, server) in servers.iter().enumerate() {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+        let budget = remaining / (servers.len() - i) as u32;
+        let server = SocketAddr::new(*server, PORT);
+        let reply = match ask_udp(server, &query, budget) {
+            Some(Reply::Truncated) => ask_tcp(server, &query, deadline.saturating_duration_since(Instant::now())),
+            reply => reply,
+        };
+        if let Some(Reply::Answer(records)) = reply {
+            return Some(records);
+        }
+    }
+    None
+}
+
+// ============================================================================
+// Transport
+// ============================================================================
+
+enum Reply {
+    Answer(Vec<(Ipv4Addr, u32)>),
+    Truncated,
+}
+
+/// Query IDs are unpredictable, so off-path replies cannot guess them
+fn query_id() -> u16 {
+    let mut bytes = [0u8; 2];
+    match File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut bytes)) {
+        Ok(()) => u16::from_le_bytes(bytes),
+        Err(_) => SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos() as u16).unwrap_or(0),
+    }
+}
+
+fn ask_udp(server: SocketAddr, query: &[u8], budget: Duration) -> Option<Reply> {
+    let local: SocketAddr = match server {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local).ok()?;
+    // Connected: datagrams from any other address are dropped by the kernel
+    socket.connect(server).ok()?;
+    socket.send(query).ok()?;
+    let deadline = Instant::now() + budget;
+    let mut buf = [0u8; UDP_MAX];
+    for _ in 0..MAX_LOOP_ITERATIONS {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return None;
+        }
+        socket.set_read_timeout(Some(remaining)).ok()?;
+        let len = socket.recv(&mut buf).ok()?;
+        // Not a reply to this query: keep waiting for the real one
+        if let Some(reply) = parse_reply(&buf[..len], query) {
+            return reply;
+        }
+    }
+    None
+}
+
+fn ask_tcp(server: SocketAddr, query: &[u8], budget: Duration) -> Option<Reply> {
+    if budget.is_zero() {
+        return None;
+    }
+    let deadline = Instant::now() + budget;
+    let mut stream = TcpStream::connect_timeout(&server, budget).ok()?;
+    let remaining = deadline.saturating_duration_since(Instant::now()).max(Duration::from_millis(1));
+    stream.set_read_timeout(Some(remaining)).ok()?;
+    stream.set_write_timeout(Some(remaining)).ok()?;
+    let mut framed = (query.len() as u16).to_be_bytes().to_vec();
+    framed.extend_from_slice(query);
+    stream.write_all(&framed).ok()?;
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len).ok()?;
+    let mut buf = vec![0u8; usize::from(u16::from_be_bytes(len)).min(TCP_MAX)];
+    stream.read_exact(&mut buf).ok()?;
+    match parse_reply(&buf, query)? {
+        Some(Reply::Truncated) => None,
+        reply => reply,
+    }
+}
+
+// ============================================================================
+// Messages
+// ============================================================================
+
+fn encode_query(name: &str, id: u16) -> Option<Vec<u8>> {
+    if name.is_empty() || name.len() > MAX_NAME_LEN {
+        return None;
+    }
+    let mut query = Vec::with_capacity(18 + name.len());
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&FLAG_RD.to_be_bytes());
+    query.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);  // One question
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > MAX_LABEL_LEN {
+            return None;
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&TYPE_A.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Some(query)
+}
+
+fn read_u16(msg: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([*msg.get(pos)?, *msg.get(pos + 1)?]))
+}
+
+fn read_u32(msg: &[u8], pos: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(msg.get(pos..pos + 4)?.try_into().ok()?))
+}
+
+/// A (possibly compressed) name at `pos`, lowercased, and the position
+/// after it
+fn read_name(msg: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut end = None;
+    let mut pointers = 0;
+    loop {
+        let len = *msg.get(pos)?;
+        match len {
+            0 => break,
+            l if l & 0xC0 == 0xC0 => {
+                pointers += 1;
+                if pointers > MAX_POINTERS {
+                    return None;
+                }
+                end.get_or_insert(pos + 2);
+                pos = usize::from(read_u16(msg, pos)? & 0x3FFF);
+            }
+            l if l & 0xC0 == 0 => {
+                let label = msg.get(pos + 1..pos + 1 + usize::from(l))?;
+                labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+                pos += 1 + usize::from(l);
+            }
+            _ => return None,
+        }
+    }
+    let name = labels.join(".");
+    (name.len() <= MAX_NAME_LEN).then_some((name, end.unwrap_or(pos + 1)))
+}
+
+/// None when `msg` is not a reply to `query` (ignored); Some(None) when
+/// the server could not answer (next server)
+fn parse_reply(msg: &[u8], query: &[u8]) -> Option<Option<Reply>> {
+    let flags = read_u16(msg, 2)?;
+    if msg.get(..2)? != query.get(..2)? || flags & FLAG_QR == 0 || read_u16(msg, 4)? != 1 {
+        return None;
+    }
+    let (qname, pos) = read_name(msg, 12)?;
+    let (expected, qend) = read_name(query, 12)?;
+    if qname != expected || msg.get(pos..pos + 4)? != query.get(qend..qend + 4)? {
+        return None;
+    }
+    if flags & FLAG_TC != 0 {
+        return Some(Some(Reply::Truncated));
+    }
+    match flags & 0x000F {
+        0 => {}
+        RCODE_NXDOMAIN => return Some(Some(Reply::Answer(Vec::new()))),
+        _ => return Some(None),
+    }
+
+    let mut pos = pos + 4;
+    let mut cnames: Vec<(String, String)> = Vec::new();
+    let mut records: Vec<(String, Ipv4Addr, u32)> = Vec::new();
+    for _ in 0..usize::from(read_u16(msg, 6)?).min(MAX_LOOP_ITERATIONS) {
+        let Some((owner, next)) = read_name(msg, pos) else {
+            return Some(None);
+        };
+        let (Some(rtype), Some(class), Some(ttl), Some(rdlen)) =
+            (read_u16(msg, next), read_u16(msg, next + 2), read_u32(msg, next + 4), read_u16(msg, next + 8))
+        else {
+            return Some(None);
+        };
+        let rdata = next + 10;
+        let Some(data) = msg.get(rdata..rdata + usize::from(rdlen)) else {
+            return Some(None);
+        };
+        match (rtype, class) {
+            (TYPE_A, CLASS_IN) if data.len() == 4 => {
+                records.push((owner, Ipv4Addr::new(data[0], data[1], data[2], data[3]), ttl));
+            }
+            (TYPE_CNAME, CLASS_IN) => {
+                if let Some((target, _)) = read_name(msg, rdata) {
+                    cnames.push((owner, target));
+                }
+            }
+            _ => {}
+        }
+        pos = rdata + usize::from(rdlen);
+    }
+
+    // The A records of the queried name, or of the end of its CNAME chain
+    let mut names = vec![qname];
+    for _ in 0..MAX_CNAME_HOPS {
+        let last = names.last()?;
+        match cnames.iter().find(|(owner, _)| owner == last) {
+            Some((_, target)) if !names.contains(target) => names.push(target.clone()),
+            _ => break,
+        }
+    }
+    let answers = records.into_iter().filter(|(owner, _, _)| names.contains(owner)).map(|(_, ip, ttl)| (ip, ttl));
+    Some(Some(Reply::Answer(answers.collect())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: u16 = 0x1234;
+    /// A pointer to the question name
+    const QNAME: [u8; 2] = [0xC0, 12];
+
+    fn query() -> Vec<u8> {
+        encode_query("host.example.net", ID).unwrap()
+    }
+
+    /// A reply to query() carrying `ancount` answers
+    fn reply(flags: u16, ancount: u16, answers: &[u8]) -> Vec<u8> {
+        let mut msg = query();
+        msg[2..4].copy_from_slice(&(FLAG_QR | FLAG_RD | flags).to_be_bytes());
+        msg[6..8].copy_from_slice(&ancount.to_be_bytes());
+        msg.extend_from_slice(answers);
+        msg
+    }
+
+    fn record(owner: &[u8], rtype: u16, rdata: &[u8]) -> Vec<u8> {
+        let mut rr = owner.to_vec();
+        rr.extend_from_slice(&rtype.to_be_bytes());
+        rr.extend_from_slice(&CLASS_IN.to_be_bytes());
+        rr.extend_from_slice(&300u32.to_be_bytes());
+        rr.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        rr.extend_from_slice(rdata);
+        rr
+    }
+
+    /// None: ignored; Some(None): next server; Some(Some(..)): the answer
+    fn outcome(msg: &[u8]) -> Option<Option<Vec<(Ipv4Addr, u32)>>> {
+        parse_reply(msg, &query()).map(|reply| {
+            reply.map(|reply| match reply {
+                Reply::Answer(records) => records,
+                Reply::Truncated => panic!("truncated"),
+            })
+        })
+    }
+
+    #[test]
+    fn answers_and_cname_chains() {
+        let a = record(&QNAME, TYPE_A, &[192, 0, 2, 1]);
+        assert_eq!(outcome(&reply(0, 1, &a)), Some(Some(vec![(Ipv4Addr::new(192, 0, 2, 1), 300)])));
+
+        // host.example.net CNAME edge.example.net (compressed), its A record
+        let mut answers = record(&QNAME, TYPE_CNAME, &[4, b'e', b'd', b'g', b'e', 0xC0, 17]);
+        let target = query().len() + QNAME.len() + 10;
+        answers.extend(record(&[0xC0, target as u8], TYPE_A, &[198, 51, 100, 7]));
+        answers.extend(record(&[5, b'o', b't', b'h', b'e', b'r', 0], TYPE_A, &[203, 0, 113, 9]));
+        assert_eq!(outcome(&reply(0, 3, &answers)), Some(Some(vec![(Ipv4Addr::new(198, 51, 100, 7), 300)])));
+
+        assert_eq!(outcome(&reply(RCODE_NXDOMAIN, 0, &[])), Some(Some(Vec::new())));
+        assert_eq!(outcome(&reply(2, 0, &[])), Some(None));
+        assert!(matches!(parse_reply(&reply(FLAG_TC, 0, &[]), &query()), Some(Some(Reply::Truncated))));
+    }
+
+    #[test]
+    fn mismatched_replies_are_ignored() {
+        let a = record(&QNAME, TYPE_A, &[192, 0, 2, 1]);
+        let mut wrong_id = reply(0, 1, &a);
+        wrong_id[0] ^= 0xFF;
+        assert_eq!(outcome(&wrong_id), None);
+
+        let mut not_a_reply = reply(0, 1, &a);
+        not_a_reply[2] &= !0x80;
+        assert_eq!(outcome(&not_a_reply), None);
+
+        let mut other_name = reply(0, 1, &a);
+        other_name[13] = b'x';
+        assert_eq!(outcome(&other_name), None);
+
+        let mut other_type = reply(0, 1, &a);
+        let qtype = query().len() - 3;
+        other_type[qtype] = 28;
+        assert_eq!(outcome(&other_type), None);
+
+        assert_eq!(outcome(&query()[..11]), None);
+    }
+
+    #[test]
+    fn pointer_loops_end() {
+        // An owner pointing at itself, and two pointing at each other
+        let owner = query().len() as u16;
+        let self_loop = record(&(0xC000 | owner).to_be_bytes(), TYPE_A, &[192, 0, 2, 1]);
+        assert_eq!(outcome(&reply(0, 1, &self_loop)), Some(None));
+        let mut pair = reply(0, 0, &[]);
+        pair.extend_from_slice(&[0xC0, pair.len() as u8 + 2, 0xC0, pair.len() as u8]);
+        assert_eq!(read_name(&pair, pair.len() - 4), None);
+
+        // A question name that loops is no reply to anything
+        let mut question_loop = reply(0, 0, &[]);
+        question_loop.truncate(12);
+        question_loop.extend_from_slice(&[0xC0, 12, 0, 1, 0, 1]);
+        assert_eq!(outcome(&question_loop), None);
+
+        // A CNAME cycle stops without an answer
+        let mut cycle = record(&QNAME, TYPE_CNAME, &[1, b'b', 0]);
+        cycle.extend(record(&[1, b'b', 0], TYPE_CNAME, &QNAME));
+        assert_eq!(outcome(&reply(0, 2, &cycle)), Some(Some(Vec::new())));
+    }
+
+    #[test]
+    fn out_of_bounds_pointers_and_labels() {
+        let past_end = record(&[0xC0, 0xFF], TYPE_A, &[192, 0, 2, 1]);
+        assert_eq!(outcome(&reply(0, 1, &past_end)), Some(None));
+        let far = record(&[0xFF, 0xFF], TYPE_A, &[192, 0, 2, 1]);
+        assert_eq!(outcome(&reply(0, 1, &far)), Some(None));
+        // A label running past the end
+        assert_eq!(read_name(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 9, b'a'], 12), None);
+        // 0x40 and 0x80 label types are reserved
+        assert_eq!(read_name(&[0x40, b'a', 0], 0), None);
+    }
+
+    #[test]
+    fn truncated_answers_move_on() {
+        let a = record(&QNAME, TYPE_A, &[192, 0, 2, 1]);
+        let full = reply(0, 1, &a);
+        // Cut inside the record header, inside the rdata, and an answer
+        // count larger than the answers
+        for cut in [full.len() - 12, full.len() - 1] {
+            assert_eq!(outcome(&full[..cut]), Some(None), "cut at {}", cut);
+        }
+        assert_eq!(outcome(&reply(0, 2, &a)), Some(None));
+        // An A record with the wrong rdata length is skipped
+        let short = record(&QNAME, TYPE_A, &[192, 0, 2]);
+        assert_eq!(outcome(&reply(0, 1, &short)), Some(Some(Vec::new())));
+    }
+}
@@ -0,0 +1,135 @@
+//! DNS answer cache shared by all profiles on the host.
+//!
+//!   dns_cache = yes
+//!
+//! Profiles that opt in look network answers up in `/etc/ddnsfw/dns.cache`
+//! first, so a hostname referenced by several profiles is sent to the DNS
+//! provider once per TTL instead of once per profile. One line per answer:
+//!
+//!   home.example.net getent 1767225660 203.0.113.7,203.0.113.8
+//!
+//! (hostname, resolver chain, expiry, addresses). The chain is part of the
+//! key, so profiles asking different resolvers never share answers; chains
+//! with `api:` resolvers are never cached (accounts are per profile). The
+//! expiry is the record's TTL (looked up with dig), clamped to
+//! MIN_TTL_SECS..MAX_TTL_SECS. Failed lookups are not cached. The lookup and
+//! the store happen under one lock, so a profile arriving while another
+//! resolves the same name waits for its answer instead of asking again.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::net::Ipv4Addr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::resolver::{self, Spec};
+use crate::{profile, sys, DNS_TIMEOUT_SECS};
+
+const MIN_TTL_SECS: u64 = 30;
+const MAX_TTL_SECS: u64 = 3600;
+/// Assumed TTL when the record's TTL cannot be looked up
+const DEFAULT_TTL_SECS: u64 = 60;
+const MAX_RECORDS: usize = 1000;
+const LOCK_POLL_MS: u64 = 100;
+
+fn path() -> String {
+    format!("{}/dns.cache", profile::shared_state_dir())
+}
+
+/// Cache key of a resolver chain, or None when its answers must not be shared
+pub fn chain_key(specs: &[Spec]) -> Option<String> {
+    if specs.iter().any(|spec| matches!(spec, Spec::Api(_))) {
+        return None;
+    }
+    Some(specs.iter().map(Spec::to_string).collect::<Vec<_>>().join(","))
+}
+
+struct Record {
+    hostname: String,
+    chain: String,
+    expires: u64,
+    addrs: Vec<Ipv4Addr>,
+}
+
+impl Record {
+    fn parse(line: &str) -> Option<Self> {
+        let mut fields = line.split_whitespace();
+        let hostname = fields.next()?.to_string();
+        let chain = fields.next()?.to_string();
+        let expires = fields.next()?.parse().ok()?;
+        let addrs: Vec<Ipv4Addr> = fields.next()?.split(',').filter_map(|ip| ip.parse().ok()).collect();
+        (!addrs.is_empty()).then_some(Record { hostname, chain, expires, addrs })
+    }
+}
+
+/// Exclusive access to the shared cache (released on drop)
+pub struct Guard {
+    _lock: File,
+}
+
+impl Guard {
+    /// Waits for the lock as long as a peer's lookup may take; None when it
+    /// cannot be had (the caller then resolves uncached)
+    pub fn acquire() -> Option<Self> {
+        let lock = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o600)
+            .open(format!("{}.lock", path()))
+            .ok()?;
+        let deadline = Instant::now() + Duration::from_secs(DNS_TIMEOUT_SECS * 2);
+        while !sys::lock_exclusive(&lock, false) {
+            if Instant::now() >= deadline {
+                eprintln!("[ddnsfw] WARN: Shared DNS cache is busy, resolving uncached");
+                return None;
+            }
+            thread::sleep(Duration::from_millis(LOCK_POLL_MS));
+        }
+        Some(Guard { _lock: lock })
+    }
+
+    fn records(&self) -> Vec<Record> {
+        let content = fs::read_to_string(path()).unwrap_or_default();
+        content.lines().take(MAX_RECORDS).filter_map(Record::parse).collect()
+    }
+
+    /// The unexpired answer for `hostname` through `chain`
+    pub fn fresh(&self, hostname: &str, chain: &str, now: u64) -> Option<Vec<Ipv4Addr>> {
+        self.records()
+            .into_iter()
+            // Beyond the longest TTL: stored before the clock stepped back
+            .find(|r| r.hostname == hostname && r.chain == chain && r.expires > now && r.expires <= now + MAX_TTL_SECS)
+            .map(|r| r.addrs)
+    }
+
+    /// Stores an answer for its TTL; expired records are dropped on the way
+    pub fn store(&self, hostname: &str, chain: &str, addrs: &[Ipv4Addr], now: u64) {
+        if addrs.is_empty() {
+            return;
+        }
+        let ttl = resolver::lookup_ttl(hostname).unwrap_or(DEFAULT_TTL_SECS).clamp(MIN_TTL_SECS, MAX_TTL_SECS);
+        let mut records = self.records();
+        records.retain(|r| r.expires > now && !(r.hostname == hostname && r.chain == chain));
+        records.truncate(MAX_RECORDS - 1);
+        records.push(Record {
+            hostname: hostname.to_string(),
+            chain: chain.to_string(),
+            expires: now + ttl,
+            addrs: addrs.to_vec(),
+        });
+
+        let mut content = String::new();
+        for record in &records {
+            let addrs: Vec<String> = record.addrs.iter().map(Ipv4Addr::to_string).collect();
+            content.push_str(&format!("{} {} {} {}\n", record.hostname, record.chain, record.expires, addrs.join(",")));
+        }
+        let temp_path = format!("{}.tmp", path());
+        if let Ok(mut file) = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&temp_path) {
+            if file.write_all(content.as_bytes()).is_ok() {
+                let _ = fs::rename(&temp_path, path());
+            }
+        }
+    }
+}
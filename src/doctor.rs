@@ -0,0 +1,285 @@
+//! `ddnsfw doctor`: end-to-end health report with suggested fixes.
+//!
+//! Walks through everything a working installation needs (binary, file
+//! permissions, lock, config, resolver, iptables, systemd units, cache,
+//! last sync) and prints one line per check. Config parsing runs in a child
+//! `config validate` first, so a fatal config error is reported instead of
+//! ending the report. Exits non-zero when any check fails.
+
+use std::env;
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::process::Command;
+use std::time::Duration;
+
+use crate::retry::MAX_ATTEMPTS;
+use crate::{dns, escalate, execs, mirror, privsep, stats};
+use crate::{
+    find_iptables, format_utc, managed_scopes, now_secs, open_backend, parse_config, profile, resolver,
+    try_lock, Cache, DdnsEntry, BINARY_PATH,
+};
+
+/// A sync older than this (the timer runs every 2 minutes) is stale
+const STALE_SYNC_SECS: u64 = 600;
+/// Hostnames resolved by the resolver check
+const MAX_RESOLVE_CHECKS: usize = 5;
+const RESOLVE_TIMEOUT_SECS: u64 = 5;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Level {
+    Ok,
+    Warn,
+    Fail,
+}
+
+struct Report {
+    color: bool,
+    failures: usize,
+    warnings: usize,
+}
+
+impl Report {
+    fn line(&mut self, level: Level, check: &str, detail: &str, fix: Option<&str>) {
+        let (tag, code) = match level {
+            Level::Ok => (" OK ", "32"),
+            Level::Warn => ("WARN", "33"),
+            Level::Fail => ("FAIL", "31"),
+        };
+        match level {
+            Level::Ok => {}
+            Level::Warn => self.warnings += 1,
+            Level::Fail => self.failures += 1,
+        }
+        if self.color {
+            println!("[\x1b[{}m{}\x1b[0m] {:<12} {}", code, tag, check, detail);
+        } else {
+            println!("[{}] {:<12} {}", tag, check, detail);
+        }
+        if let Some(fix) = fix.filter(|_| level != Level::Ok) {
+            println!("       {:<12} fix: {}", "", fix);
+        }
+    }
+}
+
+/// Mode and ownership: root-owned and not writable by group/others (in the
+/// service user mode, the service user and its group count as root)
+fn check_file(report: &mut Report, check: &str, path: &str, fix_mode: &str) -> bool {
+    let Ok(meta) = fs::metadata(path) else {
+        report.line(Level::Fail, check, &format!("{} missing", path), Some("re-run the installer"));
+        return false;
+    };
+    let mode = meta.permissions().mode() & 0o777;
+    let service = privsep::configured();
+    let owner_ok = meta.uid() == 0 || service.is_some_and(|(uid, _)| meta.uid() == uid);
+    let group_ok = mode & 0o020 == 0 || service.is_some_and(|(_, gid)| meta.gid() == gid);
+    if !owner_ok || !group_ok || mode & 0o002 != 0 {
+        let fix = match service {
+            Some(_) => String::from("ddnsfw service-user enable (re-applies the ownership)"),
+            None => format!("chown root:root {} && chmod {} {}", path, fix_mode, path),
+        };
+        report.line(Level::Fail, check, &format!("{} (uid {}, mode {:o}) is writable by others", path, meta.uid(), mode), Some(&fix));
+        return false;
+    }
+    report.line(Level::Ok, check, &format!("{} (mode {:o})", path, mode), None);
+    true
+}
+
+pub fn systemctl(args: &[&str]) -> String {
+    execs::output(Command::new("systemctl").args(args))
+        .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string())
+        .unwrap_or_default()
+}
+
+/// When systemd runs the timer next, as it prints it; None when nothing
+/// is scheduled
+pub fn next_elapse(timer: &str) -> Option<String> {
+    // OnUnitActiveSec schedules on the monotonic clock, Persistent on the realtime one
+    let next = systemctl(&["show", "--property=NextElapseUSecMonotonic,NextElapseUSecRealtime", "--value", timer]);
+    next.lines().map(str::trim).find(|v| !matches!(*v, "" | "0" | "n/a" | "infinity")).map(String::from)
+}
+
+/// This binary run again with `args`, on the same profile: for checks that
+/// would end the process on a fatal error
+pub fn self_command(args: &[&str]) -> Command {
+    let mut command = Command::new(env::current_exe().unwrap_or_else(|_| BINARY_PATH.into()));
+    command.args(args);
+    if let Some(name) = &profile::current().name {
+        command.env("DDNSFW_PROFILE", name);
+    }
+    command
+}
+
+pub fn doctor(args: &[String]) {
+    if !args.is_empty() {
+        crate::exit_err("Usage: ddnsfw doctor");
+    }
+    let profile = profile::current();
+    let mut report = Report { color: io::stdout().is_terminal(), failures: 0, warnings: 0 };
+    println!("[ddnsfw] Doctor report for profile {}", profile.name.as_deref().unwrap_or("default"));
+
+    // Binary
+    let exe = env::current_exe().map(|p| p.display().to_string()).unwrap_or_default();
+    if check_file(&mut report, "binary", BINARY_PATH, "700") && exe != BINARY_PATH {
+        let detail = format!("running {} instead of the installed {}", exe, BINARY_PATH);
+        report.line(Level::Warn, "binary", &detail, Some(&format!("sudo {} doctor", BINARY_PATH)));
+    }
+
+    // Permissions
+    check_file(&mut report, "directory", &profile.dir, "700");
+    let config_present = check_file(&mut report, "config", &profile.config_path, "600");
+
+    // State
+    let detail = format!("{} (cache {})", profile.state_dir, mirror::cache_path());
+    if profile.is_relocated() {
+        report.line(Level::Ok, "state", &format!("{} is read-only, relocated to {}", profile.dir, detail), None);
+    } else {
+        report.line(Level::Ok, "state", &detail, None);
+    }
+
+    // Lock
+    match try_lock() {
+        Ok(Some(_lock)) => report.line(Level::Ok, "lock", "free", None),
+        Ok(None) => report.line(Level::Fail, "lock", &format!("cannot open {}", profile.lock_path), Some("check that the state directory is writable")),
+        Err(()) => report.line(Level::Warn, "lock", "held: a sync is running right now", Some("re-run doctor in a minute; if it persists, check for a hung sync (ps aux | grep ddnsfw)")),
+    }
+
+    // Config (in a child, so a fatal error does not end the report)
+    let mut config_ok = false;
+    if config_present {
+        match self_command(&["config", "validate"]).output() {
+            Ok(output) if output.status.success() => {
+                config_ok = true;
+                report.line(Level::Ok, "config", String::from_utf8_lossy(&output.stdout).trim().trim_start_matches("[ddnsfw] "), None);
+            }
+            Ok(output) => {
+                let stdout = String::from_utf8_lossy(&output.stdout);
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                // Diagnostics only: the config still loads
+                config_ok = stderr.contains("Config has problems");
+                let first = stdout.lines().chain(stderr.lines()).next().unwrap_or("invalid").trim_start_matches("[ddnsfw] ").to_string();
+                let level = if config_ok { Level::Warn } else { Level::Fail };
+                report.line(level, "config", &first, Some("ddnsfw config validate"));
+            }
+            Err(_) => report.line(Level::Fail, "config", "could not run config validate", None),
+        }
+    }
+    if !config_ok {
+        report.line(Level::Warn, "checks", "resolver, firewall and cache checks skipped (config unusable)", None);
+        return finish(&report);
+    }
+    let config = parse_config();
+
+    // Resolver
+    let hostnames: Vec<&DdnsEntry> = {
+        let mut seen: Vec<&DdnsEntry> = Vec::new();
+        for entry in &config.entries {
+            if !seen.iter().any(|e| e.hostname == entry.hostname) {
+                seen.push(entry);
+            }
+        }
+        seen
+    };
+    let chains: Vec<&Vec<resolver::Spec>> =
+        std::iter::once(&config.resolvers).chain(config.entries.iter().filter_map(|e| e.resolvers.as_ref())).collect();
+    let uses_getent = chains.iter().any(|chain| chain.contains(&resolver::Spec::Getent));
+    let uses_native = chains.iter().any(|chain| chain.contains(&resolver::Spec::Native));
+    let uses_resolved = chains.iter().any(|chain| chain.iter().any(|spec| matches!(spec, resolver::Spec::Resolved { .. })));
+    if uses_getent && Command::new("getent").arg("--help").output().is_err() {
+        report.line(Level::Fail, "resolver", "getent not found", Some("install libc-bin / glibc-common"));
+    } else if uses_native && config.nameservers.is_empty() && !dns::has_system_nameservers() {
+        report.line(Level::Warn, "resolver", "no nameserver in /etc/resolv.conf (the native resolver asks 127.0.0.1)", Some("set nameservers = IP[,IP] in the config"));
+    } else if uses_resolved && systemctl(&["is-active", "systemd-resolved"]) != "active" {
+        report.line(Level::Warn, "resolver", "systemd-resolved is not running (resolved resolver)", Some("systemctl enable --now systemd-resolved"));
+    } else if hostnames.is_empty() {
+        report.line(Level::Warn, "resolver", "no entries to resolve", Some("add hostname:port lines to the config"));
+    } else {
+        let checked: Vec<&DdnsEntry> = hostnames.iter().take(MAX_RESOLVE_CHECKS).copied().collect();
+        let failed: Vec<&str> = checked
+            .iter()
+            .filter(|e| e.resolver_chain(&config).resolve(&e.hostname, Duration::from_secs(RESOLVE_TIMEOUT_SECS)).is_empty())
+            .map(|e| e.hostname.as_str())
+            .collect();
+        if failed.is_empty() {
+            report.line(Level::Ok, "resolver", &format!("{} of {} hostnames resolve", checked.len(), hostnames.len()), None);
+        } else {
+            let detail = format!("no usable address for {}", failed.join(", "));
+            report.line(Level::Warn, "resolver", &detail, Some("check /etc/resolv.conf and the DDNS client updating these names"));
+        }
+    }
+
+    // Firewall tool
+    match find_iptables() {
+        Some(bin) => {
+            let version = Command::new(bin).arg("-V").output().map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string()).unwrap_or_default();
+            let variant = if version.contains("nf_tables") { "nf_tables" } else if version.contains("legacy") { "legacy" } else { "unknown variant" };
+            report.line(Level::Ok, "iptables", &format!("{} ({})", bin, variant), None);
+        }
+        None => report.line(Level::Fail, "iptables", "not found", Some("install iptables")),
+    }
+    let backend = open_backend(&config);
+    if backend.is_none() {
+        let detail = format!("{} not found", config.backend.tool());
+        report.line(Level::Fail, "backend", &detail, Some("install it or change `backend =`"));
+    }
+
+    // Units
+    let timer = format!("{}.timer", profile.unit());
+    let enabled = systemctl(&["is-enabled", &timer]);
+    let active = systemctl(&["is-active", &timer]);
+    if enabled == "enabled" && active == "active" {
+        report.line(Level::Ok, "timer", &format!("{} enabled and active", timer), None);
+    } else {
+        let detail = format!("{} is {} / {}", timer, if enabled.is_empty() { "unknown" } else { &enabled }, if active.is_empty() { "unknown" } else { &active });
+        report.line(Level::Fail, "timer", &detail, Some(&format!("systemctl enable --now {}", timer)));
+    }
+
+    // Cache vs firewall
+    let cache = Cache::load();
+    if !cache.state.is_idle() {
+        report.line(Level::Warn, "cache", "an operation was interrupted", Some("the next sync recovers it (or run: ddnsfw sync)"));
+    }
+    if let Some(backend) = &backend {
+        let live = backend.existing_rules(&managed_scopes(&config.entries, &cache));
+        let missing = cache.rules.difference(&live).count();
+        let unknown = live.difference(&cache.rules).count();
+        if missing == 0 && unknown == 0 {
+            report.line(Level::Ok, "cache", &format!("{} rules, matches the firewall", live.len()), None);
+        } else {
+            let detail = format!("{} cached rules missing from the firewall, {} live rules not cached", missing, unknown);
+            report.line(Level::Warn, "cache", &detail, Some("run a sync (ddnsfw sync) to reconcile"));
+        }
+    }
+    let alerting = escalate::alerting(&cache.failures, &config.escalation);
+    if !alerting.is_empty() {
+        let detail = format!("{} failing to resolve past escalate_alert: {}", alerting.len(), alerting.join(", "));
+        report.line(Level::Fail, "escalation", &detail, Some("check the DDNS client updating these names (rules are kept meanwhile)"));
+    }
+    let parked = cache.retries.iter().filter(|r| r.attempts >= MAX_ATTEMPTS).count();
+    if parked > 0 {
+        report.line(Level::Warn, "retries", &format!("{} operations parked", parked), Some("fix the backend, then: ddnsfw retry"));
+    } else if !cache.retries.is_empty() {
+        report.line(Level::Warn, "retries", &format!("{} operations awaiting retry", cache.retries.len()), None);
+    }
+
+    // Last sync
+    match stats::last("finished") {
+        None => report.line(Level::Warn, "last sync", "never completed", Some("run: ddnsfw sync")),
+        Some(finished) => {
+            let age = now_secs().saturating_sub(finished);
+            let failed = stats::last("failed").unwrap_or(0);
+            let detail = format!("{} UTC ({}s ago, {} failed)", format_utc(finished), age, failed);
+            let level = if failed > 0 || age > STALE_SYNC_SECS { Level::Warn } else { Level::Ok };
+            report.line(level, "last sync", &detail, Some("ddnsfw logs --only-changes"));
+        }
+    }
+
+    finish(&report);
+}
+
+fn finish(report: &Report) {
+    println!("[ddnsfw] {} failed, {} warnings", report.failures, report.warnings);
+    if report.failures > 0 {
+        std::process::exit(1);
+    }
+}
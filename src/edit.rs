@@ -0,0 +1,381 @@
+//! In-place config edits (`add`, `remove`, `enable`, `disable`, `rename`).
+//!
+//! Edits keep every other line, comment and option untouched. A disabled
+//! entry stays in the file behind a `#disabled ` prefix, which the parser
+//! treats as an ordinary comment, so its rules are removed on the next sync
+//! and `enable` restores the exact original line.
+//!
+//! Managed rules are keyed by IP and port, never by hostname, so a rename
+//! needs no rule or cache migration: the following sync keeps every rule
+//! the new name still resolves to, and if it moved, adds the new rule
+//! before removing the old one, like any other IP change. Entries are
+//! matched after `$VARIABLE` expansion, and a rename rewrites the variable
+//! definition when that is where the hostname lives.
+//!
+//! `add` and `remove` take entries as arguments or, with `--stdin`, one per
+//! line (e.g. piped from an inventory system). The whole batch is validated
+//! before anything is written: one bad line rejects it all. The config is
+//! then rewritten atomically and synced once.
+//!
+//! The edits work on conf.conf lines; with a config.toml (see tomlconfig)
+//! they refuse, and the file is edited by hand.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, Write};
+use std::os::unix::fs::OpenOptionsExt;
+
+use crate::rule::Proto;
+use crate::{
+    acquire_lock, backup, exit_err, expand_variables, parse_config, parse_entry_line, parse_variable_line, provider, strip_comment,
+    sync_firewall, profile, tomlconfig, MAX_ENTRIES,
+};
+
+const DISABLED_PREFIX: &str = "#disabled ";
+
+type Vars = HashMap<String, String>;
+
+/// Entry selector: `hostname` (every port), `hostname:port` (either
+/// protocol) or `hostname:port/udp`
+struct Selector {
+    hostname: String,
+    port: Option<u16>,
+    proto: Option<Proto>,
+}
+
+impl Selector {
+    fn parse(s: &str) -> Option<Self> {
+        match s.rsplit_once(':') {
+            Some((host, port)) => {
+                let (port, proto) = match port.split_once('/') {
+                    Some((port, proto)) => (port, Some(Proto::parse(proto).filter(Proto::has_ports)?)),
+                    None => (port, None),
+                };
+                Some(Selector {
+                    hostname: host.to_string(),
+                    port: Some(port.parse().ok().filter(|&p| p > 0)?),
+                    proto,
+                })
+            }
+            None if !s.is_empty() => Some(Selector { hostname: s.to_string(), port: None, proto: None }),
+            None => None,
+        }
+    }
+
+    fn matches(&self, line: &str, vars: &Vars) -> bool {
+        let Ok(line) = expand_variables(line, vars) else {
+            return false;
+        };
+        // A bundle line (`host:@admin`) is a unit: matched by hostname only
+        if let Some((host, _)) = line.split_once(":@") {
+            return self.port.is_none() && host == self.hostname;
+        }
+        parse_entry_line(strip_comment(&line)).is_some_and(|e| {
+            e.hostname == self.hostname
+                && self.port.is_none_or(|p| p == e.port)
+                && self.proto.is_none_or(|p| p == e.proto)
+        })
+    }
+}
+
+/// The config text. The edits rewrite classic lines: a config.toml is
+/// edited by hand.
+fn read_config() -> String {
+    let profile = profile::current();
+    if tomlconfig::is_active() {
+        exit_err(&format!("{} is edited by hand (add, remove, enable, disable and rename change conf.conf lines)", profile.config_path));
+    }
+    fs::read_to_string(&profile.config_path).unwrap_or_else(|_| exit_err("Cannot read config"))
+}
+
+/// Writes the config through a temp file + rename so a crash never leaves
+/// a truncated config behind, after a snapshot of the old one (backup.rs).
+pub fn write_config(content: &str) -> bool {
+    backup::before_edit();
+    let tmp = format!("{}.tmp", profile::current().config_path);
+    let written = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp)
+        .and_then(|mut f| f.write_all(content.as_bytes()).and_then(|_| f.sync_all()));
+    written.is_ok() && fs::rename(&tmp, &profile::current().config_path).is_ok()
+}
+
+/// Applies `edit` to each config line (with the variables defined above
+/// it); returns how many lines it changed. With `adds_entries`, refuses
+/// edits that would push the config past MAX_ENTRIES.
+fn rewrite_lines(adds_entries: bool, edit: impl Fn(&str, &Vars) -> Option<String>) -> usize {
+    let Some(_lock) = acquire_lock() else {
+        exit_err("Could not acquire lock");
+    };
+    let content = read_config();
+
+    let mut vars = Vars::new();
+    let mut changed = 0;
+    let mut out = String::with_capacity(content.len());
+    for line in content.lines() {
+        if let Some((name, value)) = parse_variable_line(strip_comment(line.trim())) {
+            vars.insert(name.to_string(), value.to_string());
+        }
+        match edit(line, &vars) {
+            Some(new_line) => {
+                changed += 1;
+                out.push_str(&new_line);
+            }
+            None => out.push_str(line),
+        }
+        out.push('\n');
+    }
+
+    if adds_entries && changed > 0 {
+        let config = parse_config();
+        let total = config.entries.len() + config.skipped_entries + changed;
+        if total > MAX_ENTRIES {
+            exit_err(&format!("Refusing change: capacity entries={}/{}", total, MAX_ENTRIES));
+        }
+    }
+
+    if changed > 0 && !write_config(&out) {
+        exit_err("Failed to write config");
+    }
+    changed
+}
+
+fn selector_arg(args: &[String], command: &str) -> Selector {
+    args.first()
+        .and_then(|s| Selector::parse(s))
+        .unwrap_or_else(|| exit_err(&format!("Usage: ddnsfw {} <hostname[:port]>", command)))
+}
+
+pub fn disable(args: &[String]) {
+    let selector = selector_arg(args, "disable");
+    let changed = rewrite_lines(false, |line, vars| {
+        let trimmed = line.trim();
+        (!trimmed.starts_with('#') && selector.matches(trimmed, vars))
+            .then(|| format!("{}{}", DISABLED_PREFIX, line))
+    });
+    if changed == 0 {
+        exit_err("No matching active entry");
+    }
+
+    println!("[ddnsfw] Disabled {} entr{}", changed, if changed == 1 { "y" } else { "ies" });
+    sync_firewall();
+}
+
+pub fn enable(args: &[String]) {
+    let selector = selector_arg(args, "enable");
+    let changed = rewrite_lines(true, |line, vars| {
+        let original = line.trim_start().strip_prefix(DISABLED_PREFIX)?;
+        selector.matches(original.trim(), vars).then(|| original.to_string())
+    });
+    if changed == 0 {
+        exit_err("No matching disabled entry");
+    }
+
+    println!("[ddnsfw] Enabled {} entr{}", changed, if changed == 1 { "y" } else { "ies" });
+    sync_firewall();
+}
+
+/// Replaces the hostname of an (optionally disabled) entry line or of a
+/// `$VARIABLE` definition, keeping everything else as written.
+fn renamed_line(line: &str, old: &str, new: &str) -> Option<String> {
+    if let Some((name, value)) = parse_variable_line(strip_comment(line.trim())) {
+        return (value == old).then(|| format!("${} = {}", name, new));
+    }
+    let (prefix, body) = match line.trim_start().strip_prefix(DISABLED_PREFIX) {
+        Some(body) => (DISABLED_PREFIX, body),
+        None => ("", line.trim_start()),
+    };
+    if body.starts_with('#') {
+        return None;
+    }
+    let hostname = match body.trim().split_once(":@") {
+        Some((host, _)) => host.to_string(),
+        None => parse_entry_line(strip_comment(body.trim()))?.hostname,
+    };
+    if hostname != old {
+        return None;
+    }
+    let rest = body.strip_prefix(old)?;
+    Some(format!("{}{}{}", prefix, new, rest))
+}
+
+pub fn rename(args: &[String]) {
+    let (Some(old), Some(new)) = (args.first(), args.get(1)) else {
+        exit_err("Usage: ddnsfw rename <old-hostname> <new-hostname>");
+    };
+    if new.is_empty() || new.contains(|c: char| c.is_whitespace() || c == ':' || c == '#') {
+        exit_err("Invalid hostname");
+    }
+
+    let changed = rewrite_lines(false, |line, _| renamed_line(line, old, new));
+    if changed == 0 {
+        exit_err(&format!("No entry for {}", old));
+    }
+
+    println!("[ddnsfw] Renamed {} entr{} {} -> {}", changed, if changed == 1 { "y" } else { "ies" }, old, new);
+    sync_firewall();
+}
+
+/// Batch input: the arguments, or stdin lines with `--stdin` (blank lines
+/// and comments skipped), each with its origin for error messages
+fn batch_input(args: &[String], command: &str) -> Vec<(String, String)> {
+    let usage = format!("Usage: ddnsfw {} <hostname:port [options]>... | --stdin", command);
+    let lines: Vec<(String, String)> = match args {
+        [] => exit_err(&usage),
+        [flag] if flag == "--stdin" => io::stdin()
+            .lock()
+            .lines()
+            .map_while(Result::ok)
+            .take(MAX_ENTRIES + 1)
+            .enumerate()
+            .map(|(i, line)| (format!("stdin:{}", i + 1), line.trim().to_string()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+            .collect(),
+        _ if args.iter().any(|a| a == "--stdin") => exit_err(&usage),
+        _ => args.iter().enumerate().map(|(i, a)| (format!("argument {}", i + 1), a.trim().to_string())).collect(),
+    };
+    if lines.is_empty() {
+        exit_err("Nothing to do: no entries given");
+    }
+    lines
+}
+
+fn report_invalid(errors: &[String]) -> ! {
+    for error in errors {
+        eprintln!("[ddnsfw] {}", error);
+    }
+    exit_err(&format!("Rejected: {} invalid line{}, config unchanged", errors.len(), if errors.len() == 1 { "" } else { "s" }))
+}
+
+pub fn add(args: &[String]) {
+    let lines = batch_input(args, "add");
+
+    let mut errors = Vec::new();
+    let mut batch: Vec<(String, String)> = Vec::new();  // (label, line)
+    for (origin, line) in &lines {
+        match parse_entry_line(line) {
+            Some(entry) if provider::is_valid_hostname(&entry.hostname) => {
+                let label = entry.label();
+                if batch.iter().any(|(l, _)| *l == label) {
+                    errors.push(format!("{}: {} appears twice", origin, label));
+                } else {
+                    batch.push((label, entry.spec));
+                }
+            }
+            Some(entry) => errors.push(format!("{}: invalid hostname '{}'", origin, entry.hostname)),
+            None => errors.push(format!("{}: not a hostname:port entry: {}", origin, line)),
+        }
+    }
+    if !errors.is_empty() {
+        report_invalid(&errors);
+    }
+
+    let added = {
+        let Some(_lock) = acquire_lock() else {
+            exit_err("Could not acquire lock");
+        };
+        let mut content = read_config();
+        let config = parse_config();
+        let present: HashSet<String> = config.entries.iter().map(|e| e.label()).collect();
+
+        let mut added = 0;
+        for (label, line) in &batch {
+            if present.contains(label) {
+                println!("[ddnsfw] {} is already configured, skipped", label);
+                continue;
+            }
+            if !content.is_empty() && !content.ends_with('\n') {
+                content.push('\n');
+            }
+            content.push_str(line);
+            content.push('\n');
+            added += 1;
+        }
+        let total = config.entries.len() + config.skipped_entries + added;
+        if total > MAX_ENTRIES {
+            exit_err(&format!("Refusing change: capacity entries={}/{}", total, MAX_ENTRIES));
+        }
+        if added > 0 && !write_config(&content) {
+            exit_err("Failed to write config");
+        }
+        added
+    };
+
+    println!("[ddnsfw] Added {} entr{}", added, if added == 1 { "y" } else { "ies" });
+    if added > 0 {
+        sync_firewall();
+    }
+}
+
+pub fn remove(args: &[String]) {
+    let lines = batch_input(args, "remove");
+
+    let mut errors = Vec::new();
+    let mut selectors = Vec::new();
+    for (origin, line) in &lines {
+        // Only the hostname[:port] part selects; options are tolerated so the
+        // same list can feed both add and remove
+        let target = line.split_whitespace().next().unwrap_or_default();
+        match Selector::parse(target) {
+            Some(selector) => selectors.push((target.to_string(), selector)),
+            None => errors.push(format!("{}: not a hostname[:port]: {}", origin, line)),
+        }
+    }
+    if !errors.is_empty() {
+        report_invalid(&errors);
+    }
+
+    let removed = {
+        let Some(_lock) = acquire_lock() else {
+            exit_err("Could not acquire lock");
+        };
+        let content = read_config();
+
+        let mut vars = Vars::new();
+        let mut matched = vec![false; selectors.len()];
+        let mut removed = 0;
+        let mut out = String::with_capacity(content.len());
+        for line in content.lines() {
+            let trimmed = line.trim();
+            if let Some((name, value)) = parse_variable_line(strip_comment(trimmed)) {
+                vars.insert(name.to_string(), value.to_string());
+            }
+            let mut hit = false;
+            if !trimmed.starts_with('#') {
+                for (i, (_, selector)) in selectors.iter().enumerate() {
+                    if selector.matches(trimmed, &vars) {
+                        matched[i] = true;
+                        hit = true;
+                    }
+                }
+            }
+            if hit {
+                removed += 1;
+            } else {
+                out.push_str(line);
+                out.push('\n');
+            }
+        }
+
+        // Validated as a whole: a selector that matches nothing is a typo
+        let missing: Vec<String> = selectors
+            .iter()
+            .zip(&matched)
+            .filter(|(_, &m)| !m)
+            .map(|((target, _), _)| format!("no active entry matches {}", target))
+            .collect();
+        if !missing.is_empty() {
+            report_invalid(&missing);
+        }
+        if !write_config(&out) {
+            exit_err("Failed to write config");
+        }
+        removed
+    };
+
+    println!("[ddnsfw] Removed {} entr{}", removed, if removed == 1 { "y" } else { "ies" });
+    sync_firewall();
+}
@@ -0,0 +1,120 @@
+//! Context for IP-change events: reverse DNS, GeoIP location and ASN.
+//!
+//!   enrich_rdns = yes
+//!   geoip_city_db = /usr/share/GeoIP/GeoLite2-City.mmdb
+//!   geoip_asn_db = /usr/share/GeoIP/GeoLite2-ASN.mmdb
+//!
+//! Add and delete events (and their syslog messages) then carry, for the
+//! new and the old IP, `new_rdns`/`old_rdns` (PTR name), `new_geo`/`old_geo`
+//! (`City, CC`) and `new_asn`/`old_asn` (`AS3320 Deutsche Telekom AG`), so
+//! a recipient can tell a move within the same ISP from a jump to a foreign
+//! hosting network at a glance. Lookups go through `getent hosts` and
+//! `mmdblookup` (libmaxminddb), are bounded by LOOKUP_TIMEOUT_SECS and
+//! memoized per sync; anything unknown is simply left out.
+
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::{Mutex, OnceLock};
+
+use crate::execs;
+
+const LOOKUP_TIMEOUT_SECS: u64 = 3;
+
+#[derive(Debug, Clone, Default)]
+pub struct Settings {
+    pub rdns: bool,
+    pub city_db: Option<String>,
+    pub asn_db: Option<String>,
+}
+
+impl Settings {
+    fn is_enabled(&self) -> bool {
+        self.rdns || self.city_db.is_some() || self.asn_db.is_some()
+    }
+}
+
+/// Databases must exist and be readable; a typo should not silently disable
+/// the enrichment
+pub fn is_valid_db(path: &str) -> bool {
+    path.starts_with('/') && Path::new(path).is_file()
+}
+
+/// What is known about one IP
+#[derive(Clone, Default)]
+pub struct Info {
+    pub rdns: Option<String>,
+    pub geo: Option<String>,
+    pub asn: Option<String>,
+}
+
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+static MEMO: OnceLock<Mutex<HashMap<Ipv4Addr, Info>>> = OnceLock::new();
+
+/// Sets the lookups for this process (once, from the config)
+pub fn configure(settings: Settings) {
+    let _ = SETTINGS.set(settings);
+}
+
+/// Lookups for `ip`, or None when enrichment is off
+pub fn lookup(ip: Ipv4Addr) -> Option<Info> {
+    let settings = SETTINGS.get().filter(|s| s.is_enabled())?;
+    let memo = MEMO.get_or_init(|| Mutex::new(HashMap::new()));
+    if let Some(info) = memo.lock().ok()?.get(&ip) {
+        return Some(info.clone());
+    }
+
+    let mut info = Info::default();
+    if settings.rdns {
+        info.rdns = run(&["getent", "hosts", &ip.to_string()])
+            .and_then(|out| out.split_whitespace().nth(1).map(|name| name.trim_end_matches('.').to_string()));
+    }
+    if let Some(db) = &settings.city_db {
+        let city = mmdb(db, ip, &["city", "names", "en"]);
+        let country = mmdb(db, ip, &["country", "iso_code"]);
+        info.geo = match (city, country) {
+            (Some(city), Some(country)) => Some(format!("{}, {}", city, country)),
+            (city, country) => city.or(country),
+        };
+    }
+    if let Some(db) = &settings.asn_db {
+        info.asn = mmdb(db, ip, &["autonomous_system_number"]).map(|number| {
+            match mmdb(db, ip, &["autonomous_system_organization"]) {
+                Some(org) => format!("AS{} {}", number, org),
+                None => format!("AS{}", number),
+            }
+        });
+    }
+    if let Ok(mut memo) = memo.lock() {
+        memo.insert(ip, info.clone());
+    }
+    Some(info)
+}
+
+/// Runs a lookup tool under `timeout`; stdout on success
+fn run(args: &[&str]) -> Option<String> {
+    let output = execs::output(
+        Command::new("timeout")
+            .arg(LOOKUP_TIMEOUT_SECS.to_string())
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null()),
+    )
+    .ok()?;
+    output.status.success().then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// One value from `mmdblookup`, which prints e.g. `"Berlin" <utf8_string>`
+/// or `3320 <uint32>`
+fn mmdb(db: &str, ip: Ipv4Addr, path: &[&str]) -> Option<String> {
+    let ip = ip.to_string();
+    let mut args = vec!["mmdblookup", "--file", db, "--ip", &ip];
+    args.extend_from_slice(path);
+    let output = run(&args)?;
+    let (value, _) = output.trim().split_once(" <")?;
+    let value = value.trim().trim_matches('"');
+    let clean = !value.is_empty() && value.len() <= 120 && !value.chars().any(char::is_control);
+    clean.then(|| value.to_string())
+}
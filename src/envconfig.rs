@@ -0,0 +1,77 @@
+//! Configuration from the environment, for container images.
+//!
+//!   DDNSFW_ENTRIES="home.example.net:22,office.example.net:443 ttl=120"
+//!   DDNSFW_BACKEND=nft
+//!   DDNSFW_INTERVAL=60
+//!
+//! Read after the config file, so both can be used together: entries in
+//! DDNSFW_ENTRIES are added to the file's, and every other `DDNSFW_<KEY>`
+//! is the setting `<key>` (lowercased) and overrides the file. Entries are
+//! separated by commas; a comma piece that does not start with
+//! `host:port` continues the previous entry, so option lists such as
+//! `knock=7000,8000` keep working. Variables owned by other features
+//! (DDNSFW_PROFILE, DDNSFW_CHAOS_*) are not settings.
+
+use std::env;
+
+use crate::{apply_setting, push_entries, Config, MAX_CONFIG_LINES, MAX_LOOP_ITERATIONS};
+
+const PREFIX: &str = "DDNSFW_";
+const ENTRIES_VAR: &str = "DDNSFW_ENTRIES";
+const RESERVED: &[&str] = &["DDNSFW_PROFILE"];
+const RESERVED_PREFIXES: &[&str] = &["DDNSFW_CHAOS_"];
+
+/// Whether a comma piece starts a new entry (`host:port` or `host:@bundle`)
+fn starts_entry(piece: &str) -> bool {
+    let first = piece.split_whitespace().next().unwrap_or_default();
+    match first.rsplit_once(':') {
+        Some((host, port)) => {
+            let port = port.split_once('/').map_or(port, |(port, _)| port);
+            !host.is_empty() && (port.starts_with('@') || port.parse::<u16>().is_ok())
+        }
+        None => false,
+    }
+}
+
+/// Splits DDNSFW_ENTRIES into entry lines
+fn split_entries(value: &str) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for piece in value.split(',').take(MAX_CONFIG_LINES) {
+        match lines.last_mut() {
+            Some(line) if !starts_entry(piece.trim()) => {
+                line.push(',');
+                line.push_str(piece);
+            }
+            _ => lines.push(piece.to_string()),
+        }
+    }
+    lines.into_iter().map(|line| line.trim().to_string()).filter(|line| !line.is_empty()).collect()
+}
+
+/// Applies the DDNSFW_* variables on top of the file config
+pub fn apply(config: &mut Config) {
+    let mut vars: Vec<(String, String)> = env::vars()
+        .filter(|(name, _)| name.starts_with(PREFIX))
+        .filter(|(name, _)| !RESERVED.contains(&name.as_str()))
+        .filter(|(name, _)| !RESERVED_PREFIXES.iter().any(|prefix| name.starts_with(prefix)))
+        .take(MAX_LOOP_ITERATIONS)
+        .collect();
+    // Deterministic order, whatever the environment's
+    vars.sort();
+
+    for (name, value) in vars {
+        let value = value.trim();
+        if name == ENTRIES_VAR {
+            for line in split_entries(value) {
+                push_entries(config, &line, ENTRIES_VAR);
+            }
+            continue;
+        }
+        let key = name[PREFIX.len()..].to_ascii_lowercase();
+        if key.is_empty() || value.is_empty() {
+            eprintln!("[ddnsfw] WARN: Ignoring empty {}", name);
+            continue;
+        }
+        apply_setting(config, &key, value);
+    }
+}
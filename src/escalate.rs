@@ -0,0 +1,147 @@
+//! Escalation of hostnames that keep failing to resolve.
+//!
+//!   escalate_notify = 3                       # consecutive failed syncs (0: never)
+//!   escalate_alert = 10
+//!   notify_command = /usr/local/bin/ddns-notify
+//!   alert_command = /usr/local/bin/page-oncall
+//!
+//! A single failure is only logged (rules are kept, as always). A hostname
+//! still failing after `escalate_notify` consecutive syncs triggers the
+//! low-priority channel: an `escalate_notify` event (syslog warning) and the
+//! notify command. At `escalate_alert` the high-priority channel fires: an
+//! `escalate_alert` event (syslog critical), the alert command, and the
+//! hostname is flagged in `doctor` and the daemon status until it resolves
+//! again, which emits `escalate_clear` (and runs the commands that fired,
+//! with level `clear`). Each level fires once per failure streak.
+//!
+//! Commands get `<level> <hostname> <consecutive failures>` as arguments;
+//! they must be root-owned and not group/world-writable, and are killed
+//! after COMMAND_TIMEOUT_SECS. Streaks live in the cache
+//! (`FAILS:hostname@count@first_failure_time`).
+
+use std::collections::{HashMap, HashSet};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use crate::events::{self, Value};
+use crate::{execs, format_utc};
+
+const COMMAND_TIMEOUT_SECS: u64 = 10;
+pub const MAX_THRESHOLD: u32 = 10000;
+
+/// A hostname's current failure streak
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Streak {
+    pub count: u32,
+    pub since: u64,
+}
+
+pub struct Policy {
+    pub notify_after: u32,
+    pub alert_after: u32,
+    pub notify_command: Option<String>,
+    pub alert_command: Option<String>,
+}
+
+impl Policy {
+    pub fn new() -> Self {
+        Policy { notify_after: 3, alert_after: 10, notify_command: None, alert_command: None }
+    }
+
+    /// Whether a streak has reached the high-priority level
+    pub fn is_alerting(&self, streak: &Streak) -> bool {
+        self.alert_after > 0 && streak.count >= self.alert_after
+    }
+
+    /// Whether a streak has reached the low-priority level
+    pub fn is_notified(&self, streak: &Streak) -> bool {
+        self.notify_after > 0 && streak.count >= self.notify_after
+    }
+}
+
+/// Command paths run as root: absolute, root-owned, not writable by others
+pub fn is_safe_command(path: &str) -> bool {
+    let Ok(meta) = Path::new(path).metadata() else {
+        return false;
+    };
+    path.starts_with('/') && meta.is_file() && meta.uid() == 0 && meta.permissions().mode() & 0o022 == 0
+}
+
+/// Runs a notify/alert command as `COMMAND <level> <subject> <count>`
+pub fn run_command(command: &Option<String>, level: &str, hostname: &str, count: u32) {
+    let Some(command) = command else {
+        return;
+    };
+    let status = execs::status(
+        Command::new("timeout")
+            .args([&COMMAND_TIMEOUT_SECS.to_string(), command.as_str(), level, hostname, &count.to_string()])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null()),
+    );
+    if !status.map(|s| s.success()).unwrap_or(false) {
+        eprintln!("[ddnsfw] WARN: {} command for {} failed", level, hostname);
+    }
+}
+
+fn emit(event: &str, hostname: &str, streak: &Streak) {
+    let since = format_utc(streak.since);
+    events::emit(
+        event,
+        &[
+            ("host", Value::Str(hostname)),
+            ("failures", Value::Num(u64::from(streak.count))),
+            ("since", Value::Str(&since)),
+        ],
+    );
+}
+
+/// Advances the streaks after a sync's resolution phase. `failed` are
+/// hostnames no entry could resolve, `resolved` those that did; streaks of
+/// hostnames in neither (no longer configured) are dropped.
+pub fn update(
+    streaks: &mut HashMap<String, Streak>,
+    failed: &HashSet<String>,
+    resolved: &HashSet<String>,
+    policy: &Policy,
+    now: u64,
+) {
+    streaks.retain(|hostname, streak| {
+        if failed.contains(hostname) {
+            return true;
+        }
+        if resolved.contains(hostname) && policy.is_notified(streak) {
+            println!("[ddnsfw] {} resolves again after {} failed syncs", hostname, streak.count);
+            emit("escalate_clear", hostname, streak);
+            run_command(&policy.notify_command, "clear", hostname, streak.count);
+            if policy.is_alerting(streak) {
+                run_command(&policy.alert_command, "clear", hostname, streak.count);
+            }
+        }
+        false
+    });
+
+    let mut failed: Vec<&String> = failed.iter().collect();
+    failed.sort();
+    for hostname in failed {
+        let streak = streaks.entry(hostname.clone()).or_insert(Streak { count: 0, since: now });
+        streak.count = streak.count.saturating_add(1);
+        if policy.alert_after > 0 && streak.count == policy.alert_after {
+            eprintln!("[ddnsfw] ERROR: {} failed to resolve {} times in a row, alerting", hostname, streak.count);
+            emit("escalate_alert", hostname, streak);
+            run_command(&policy.alert_command, "alert", hostname, streak.count);
+        } else if policy.notify_after > 0 && streak.count == policy.notify_after {
+            eprintln!("[ddnsfw] WARN: {} failed to resolve {} times in a row, notifying", hostname, streak.count);
+            emit("escalate_notify", hostname, streak);
+            run_command(&policy.notify_command, "notify", hostname, streak.count);
+        }
+    }
+}
+
+/// Hostnames at the alert level, for status displays
+pub fn alerting<'a>(streaks: &'a HashMap<String, Streak>, policy: &Policy) -> Vec<&'a str> {
+    let mut hosts: Vec<&str> = streaks.iter().filter(|(_, s)| policy.is_alerting(s)).map(|(h, _)| h.as_str()).collect();
+    hosts.sort();
+    hosts
+}
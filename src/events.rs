@@ -0,0 +1,104 @@
+//! Machine-readable event stream for SIEM pipelines.
+//!
+//! Each sync appends one JSON object per line to the profile's
+//! `events.jsonl` as things happen:
+//!
+//!   {"ts":1767225600,"event":"resolve","host":"home.dyndns.org","port":22,"ip":"203.0.113.7"}
+//!   {"ts":1767225601,"event":"add","rule":"203.0.113.7:22"}
+//!
+//! Events: resolve, resolve_failed, skip, add, add_failed, delete,
+//! delete_failed, escalate_notify, escalate_alert, escalate_clear,
+//! change_frozen, change_unfrozen, grant_add, grant_revoke, grant_expired,
+//! push, webhook, perms_repaired, sync.
+//! Change events carry the entries' `owner` and `description` when set, and
+//! rDNS/GeoIP/ASN context of both IPs when configured (see enrich).
+//! `ddnsfw events [--follow]` prints the stream and keeps following it
+//! across rotations. The file is rotated to `events.jsonl.1` once it exceeds
+//! MAX_EVENTS_BYTES.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::thread;
+use std::time::Duration;
+
+use crate::{exit_err, now_secs, profile, syslog};
+
+const MAX_EVENTS_BYTES: u64 = 1024 * 1024;
+const FOLLOW_POLL_MS: u64 = 500;
+
+/// A JSON field value
+pub enum Value<'a> {
+    Str(&'a str),
+    Num(u64),
+}
+
+fn path() -> String {
+    format!("{}/events.jsonl", profile::current().state_dir)
+}
+
+/// JSON string escaping (quotes, backslashes, control characters)
+pub fn json_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Appends one event. Best effort: the stream must never break a sync.
+pub fn emit(event: &str, fields: &[(&str, Value)]) {
+    let mut line = format!("{{\"ts\":{},\"event\":\"{}\"", now_secs(), event);
+    for (key, value) in fields {
+        match value {
+            Value::Str(s) => line.push_str(&format!(",\"{}\":\"{}\"", key, json_escape(s))),
+            Value::Num(n) => line.push_str(&format!(",\"{}\":{}", key, n)),
+        }
+    }
+    line.push_str("}\n");
+    syslog::forward(event, fields);
+
+    let path = path();
+    if fs::metadata(&path).map(|m| m.len() > MAX_EVENTS_BYTES).unwrap_or(false) {
+        let _ = fs::rename(&path, format!("{}.1", path));
+    }
+    if let Ok(mut file) = OpenOptions::new().append(true).create(true).mode(0o600).open(&path) {
+        let _ = file.write_all(line.as_bytes());
+    }
+}
+
+pub fn events(args: &[String]) {
+    let follow = match args.first().map(String::as_str) {
+        None => false,
+        Some("-f") | Some("--follow") => true,
+        _ => exit_err("Usage: ddnsfw events [--follow]"),
+    };
+
+    let path = path();
+    let mut offset = 0u64;
+    loop {
+        if let Ok(mut file) = File::open(&path) {
+            let len = file.metadata().map(|m| m.len()).unwrap_or(0);
+            if len < offset {
+                offset = 0;  // Rotated: start over on the new file
+            }
+            let mut chunk = String::new();
+            if file.seek(SeekFrom::Start(offset)).is_ok() && file.read_to_string(&mut chunk).is_ok() {
+                // Only print complete lines; a partial one is finished next poll
+                let complete = chunk.rfind('\n').map(|i| i + 1).unwrap_or(0);
+                print!("{}", &chunk[..complete]);
+                offset += complete as u64;
+            }
+        }
+        if !follow {
+            return;
+        }
+        thread::sleep(Duration::from_millis(FOLLOW_POLL_MS));
+    }
+}
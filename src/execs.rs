@@ -0,0 +1,85 @@
+//! Counts of the external commands a run executes.
+//!
+//! On small routers every fork shows: a sync of a few hostnames can cost a
+//! getent per entry, several iptables calls per rule and curl or dig for
+//! the providers. The commands of the sync path (resolvers, firewall tools,
+//! hooks) are started through `output`, `status` and `spawn` here, which
+//! count them by program. Each sync records its own counts in the stats
+//! file (`execs=`, `execs_iptables=`, ...), `ddnsfw status` shows those of
+//! the last one, and the "Sync complete" line carries the total.
+//!
+//!   ddnsfw --explain-execs sync
+//!
+//! also prints every command as it starts and, at the end of the sync, the
+//! counts by program and the command lines that ran more than once: the
+//! candidates for caching or batching.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+use std::process::{Child, Command, ExitStatus, Output};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+/// `--explain-execs` on the command line
+static EXPLAIN: AtomicBool = AtomicBool::new(false);
+/// Commands started so far, by program
+static COUNTS: Mutex<BTreeMap<String, u64>> = Mutex::new(BTreeMap::new());
+/// Command lines started so far, with `--explain-execs` only
+static LINES: Mutex<BTreeMap<String, u64>> = Mutex::new(BTreeMap::new());
+
+pub fn explain() {
+    EXPLAIN.store(true, Ordering::Relaxed);
+}
+
+fn note(command: &Command) {
+    let program = command.get_program().to_string_lossy();
+    let name = Path::new(program.as_ref()).file_name().map_or(program.to_string(), |n| n.to_string_lossy().into_owned());
+    *COUNTS.lock().unwrap_or_else(|e| e.into_inner()).entry(name).or_default() += 1;
+    if EXPLAIN.load(Ordering::Relaxed) {
+        let mut line = program.into_owned();
+        for arg in command.get_args() {
+            line.push(' ');
+            line.push_str(&arg.to_string_lossy());
+        }
+        eprintln!("[ddnsfw] exec: {}", line);
+        *LINES.lock().unwrap_or_else(|e| e.into_inner()).entry(line).or_default() += 1;
+    }
+}
+
+pub fn output(command: &mut Command) -> io::Result<Output> {
+    note(command);
+    command.output()
+}
+
+pub fn status(command: &mut Command) -> io::Result<ExitStatus> {
+    note(command);
+    command.status()
+}
+
+pub fn spawn(command: &mut Command) -> io::Result<Child> {
+    note(command);
+    command.spawn()
+}
+
+/// Counts by program since the start of the process
+pub fn counts() -> BTreeMap<String, u64> {
+    COUNTS.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// With `--explain-execs`: the counts of a sync and its repeated command
+/// lines, then starts over for the next one (daemon)
+pub fn report(counts: &BTreeMap<String, u64>) {
+    if !EXPLAIN.load(Ordering::Relaxed) {
+        return;
+    }
+    let by_program: Vec<String> = counts.iter().map(|(program, n)| format!("{} {}", program, n)).collect();
+    println!("[ddnsfw] Execs: {}", if by_program.is_empty() { String::from("none") } else { by_program.join(", ") });
+    let mut lines = LINES.lock().unwrap_or_else(|e| e.into_inner());
+    let mut repeated: Vec<(&String, &u64)> = lines.iter().filter(|(_, &n)| n > 1).collect();
+    repeated.sort_by(|a, b| b.1.cmp(a.1));
+    for (line, n) in repeated {
+        println!("  {}x {}", n, line);
+    }
+    lines.clear();
+}
@@ -0,0 +1,375 @@
+//! `ddnsfw export entries|rules|history [--format csv|json]`: access data
+//! as tables, for spreadsheets and ticketing systems.
+//!
+//! - entries: one row per configured entry, with its priority, owner,
+//!   description and the addresses it currently allows
+//! - rules: one row per managed rule found in the firewall, with the
+//!   entries that want it and when it was installed
+//! - history: the rule changes of the event stream (`add`, `delete` and
+//!   their failures) with the old and new address of each, newest last;
+//!   `--period` (default 30d, as for `report`) limits how far back
+//!
+//! CSV (the default) has a header row, quotes fields that need it, and
+//! prefixes a field starting with `=`, `+`, `-` or `@` with `'` so a
+//! spreadsheet shows it instead of evaluating it (owners and descriptions
+//! come from the config). Times are UTC (`YYYY-MM-DD HH:MM:SS`), lists are
+//! space-separated. JSON is one array of objects with the same fields.
+//!
+//! `rules --format terraform` prints the objects holding the rules as
+//! Terraform/OpenTofu resources instead, for IaC pipelines that want to
+//! reconcile with them: on Kubernetes one `kubernetes_manifest` per policy,
+//! with the exact manifest ddnsfw applies and an `import` block adopting
+//! the live object; on Proxmox one `proxmox_virtual_environment_firewall_ipset`
+//! per port set. Policies of `[credentials]` profiles use the provider alias
+//! of the profile's name. It is a snapshot: ddnsfw rewrites these objects
+//! on every address change. The local firewall backends have no provider
+//! and are refused.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::net::Ipv4Addr;
+
+use crate::events::json_escape;
+use crate::proxmox::json_field;
+use crate::report::{csv_field, json_num, parse_period, DEFAULT_PERIOD_SECS};
+use crate::rule::Rule;
+use crate::{
+    exit_err, format_utc, kube, managed_scopes, now_secs, open_backend, parse_config, profile, provider, proxmox, srv, Backend,
+    Cache,
+};
+
+const USAGE: &str = "Usage: ddnsfw export entries|rules|history [--format csv|json|terraform] [--period <N>d|<N>h|<N>w]";
+/// Events that change a rule
+const CHANGE_EVENTS: &[&str] = &["add", "delete", "add_failed", "delete_failed"];
+
+enum Table {
+    Entries,
+    Rules,
+    History,
+}
+
+#[derive(PartialEq)]
+enum Format {
+    Csv,
+    Json,
+    Terraform,
+}
+
+/// Prints `rows` under `header` as CSV or as a JSON array of objects
+fn print_table(header: &[&str], rows: &[Vec<String>], json: bool) {
+    if json {
+        let objects: Vec<String> = rows
+            .iter()
+            .map(|row| {
+                let fields: Vec<String> =
+                    header.iter().zip(row).map(|(key, value)| format!("\"{}\":\"{}\"", key, json_escape(value))).collect();
+                format!("{{{}}}", fields.join(","))
+            })
+            .collect();
+        println!("[{}]", objects.join(","));
+        return;
+    }
+    println!("{}", header.join(","));
+    for row in rows {
+        let fields: Vec<String> = row.iter().map(|value| csv_field(value)).collect();
+        println!("{}", fields.join(","));
+    }
+}
+
+fn ips(ips: &[Ipv4Addr]) -> String {
+    ips.iter().map(Ipv4Addr::to_string).collect::<Vec<_>>().join(" ")
+}
+
+pub fn command(args: &[String]) {
+    let mut iter = args.iter();
+    let table = match iter.next().map(String::as_str) {
+        Some("entries") => Table::Entries,
+        Some("rules") => Table::Rules,
+        Some("history") => Table::History,
+        _ => exit_err(USAGE),
+    };
+    let mut format = Format::Csv;
+    let mut period = DEFAULT_PERIOD_SECS;
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--format" => {
+                format = match iter.next().map(String::as_str) {
+                    Some("csv") => Format::Csv,
+                    Some("json") => Format::Json,
+                    Some("terraform") => Format::Terraform,
+                    _ => exit_err(USAGE),
+                }
+            }
+            "--period" if matches!(table, Table::History) => {
+                let value = iter.next().unwrap_or_else(|| exit_err(USAGE));
+                period = parse_period(value).unwrap_or_else(|| exit_err(&format!("Invalid period '{}'", value)));
+            }
+            _ => exit_err(USAGE),
+        }
+    }
+
+    if format == Format::Terraform && !matches!(table, Table::Rules) {
+        exit_err("--format terraform exports rules only");
+    }
+    let json = format == Format::Json;
+    if let Table::History = table {
+        return history(period, json);
+    }
+    let mut config = parse_config();
+    provider::expand(&mut config);
+    srv::expand(&mut config);
+    let Some(backend) = open_backend(&config) else {
+        exit_err(&format!("{} not found", config.backend.tool()));
+    };
+    let cache = Cache::load();
+    let mut rules: Vec<Rule> = backend.existing_rules(&managed_scopes(&config.entries, &cache)).into_iter().collect();
+    rules.sort_by_key(|rule| rule.to_string());
+    if format == Format::Terraform {
+        let header = format!("# ddnsfw {} rules, exported {} UTC; ddnsfw changes them on every address change\n", rules.len(), format_utc(now_secs()));
+        let blocks = match &backend {
+            Backend::Kubernetes(kube) => kube_blocks(&kube.applied()),
+            Backend::Proxmox { prefix, .. } => proxmox_blocks(prefix, profile::current().comment(), &rules),
+            _ => exit_err(&format!(
+                "--format terraform needs the proxmox or kubernetes backend (no Terraform provider manages {} rules)",
+                config.backend.tool()
+            )),
+        };
+        print!("{}{}", header, blocks);
+        return;
+    }
+    let since = |rule: &Rule| cache.since.get(rule).map(|&ts| format_utc(ts)).unwrap_or_default();
+
+    let mut rows = Vec::new();
+    if let Table::Rules = table {
+        for rule in &rules {
+            let owners = cache.owners.get(rule).cloned().unwrap_or_default();
+            rows.push(vec![
+                rule.to_string(),
+                rule.table.name().to_string(),
+                rule.chain.clone(),
+                rule.ip.to_string(),
+                rule.port.to_string(),
+                rule.proto.name().to_string(),
+                rule.target.to_string(),
+                owners.join(" "),
+                since(rule),
+            ]);
+        }
+        let header = ["rule", "table", "chain", "ip", "port", "proto", "target", "entries", "installed_utc"];
+        return print_table(&header, &rows, json);
+    }
+
+    let mut seen: Vec<String> = Vec::new();
+    for entry in &config.entries {
+        let key = entry.key();
+        if seen.contains(&key) {
+            continue;
+        }
+        let owned: Vec<&Rule> = rules.iter().filter(|r| cache.owners.get(*r).is_some_and(|o| o.contains(&key))).collect();
+        let mut current: Vec<Ipv4Addr> = owned.iter().map(|r| r.ip).collect();
+        current.sort_unstable();
+        current.dedup();
+        let installed = owned.iter().filter_map(|r| cache.since.get(*r)).max().map(|&ts| format_utc(ts)).unwrap_or_default();
+        rows.push(vec![
+            key.clone(),
+            entry.hostname.clone(),
+            entry.port.to_string(),
+            entry.proto.name().to_string(),
+            entry.priority.name().to_string(),
+            entry.meta.owner.clone().unwrap_or_default(),
+            entry.meta.description.clone().unwrap_or_default(),
+            ips(&current),
+            installed,
+        ]);
+        seen.push(key);
+    }
+    let header = ["entry", "hostname", "port", "proto", "priority", "owner", "description", "current_ips", "since_utc"];
+    print_table(&header, &rows, json);
+}
+
+/// A Terraform resource name from object names: `ddnsfw_admin_ddnsfw_allow`
+/// (names may start with a digit, resource names may not)
+fn resource_name(parts: &[&str]) -> String {
+    let name: String = parts.join("_").chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+    format!("ddnsfw_{}", name)
+}
+
+/// `key = value` lines with the `=` aligned, as `terraform fmt` leaves them
+fn attributes(indent: &str, pairs: &[(&str, String)]) -> String {
+    let width = pairs.iter().map(|(key, _)| key.len()).max().unwrap_or(0);
+    pairs.iter().map(|(key, value)| format!("{}{:<width$} = {}\n", indent, key, value, width = width)).collect()
+}
+
+/// One `kubernetes_manifest` and its `import` block per policy
+fn kube_blocks(policies: &[kube::Applied]) -> String {
+    let mut out = String::new();
+    for applied in policies {
+        let mut parts = vec![applied.namespace.as_str(), applied.policy.as_str()];
+        out.push('\n');
+        if let Some(profile) = &applied.profile {
+            let context = applied.context.as_deref().unwrap_or("the current context");
+            out.push_str(&format!("# [credentials {}]: provider \"kubernetes\" {{ alias = \"{}\" }} for {}\n", profile, profile, context));
+            parts.insert(0, profile);
+        }
+        let name = resource_name(&parts);
+        let provider = applied.profile.as_ref().map(|profile| ("provider", format!("kubernetes.{}", profile)));
+
+        let mut import = vec![("to", format!("kubernetes_manifest.{}", name)), ("id", format!("\"{}\"", applied.import_id))];
+        import.extend(provider.clone());
+        out.push_str(&format!("import {{\n{}}}\n\n", attributes("  ", &import)));
+
+        let mut resource: Vec<(&str, String)> = provider.into_iter().collect();
+        resource.push(("manifest", String::from("yamldecode(<<-EOT")));
+        out.push_str(&format!("resource \"kubernetes_manifest\" \"{}\" {{\n{}", name, attributes("  ", &resource)));
+        for line in applied.manifest.lines() {
+            out.push_str(&format!("    {}\n", line));
+        }
+        out.push_str("    EOT\n  )\n}\n");
+    }
+    out
+}
+
+/// One cluster `proxmox_virtual_environment_firewall_ipset` per port set
+fn proxmox_blocks(prefix: &str, comment: &str, rules: &[Rule]) -> String {
+    let mut sets: BTreeMap<u16, BTreeSet<Ipv4Addr>> = BTreeMap::new();
+    for rule in rules {
+        sets.entry(rule.port).or_default().insert(rule.ip);
+    }
+    let mut out = String::new();
+    for (port, ips) in sets {
+        let set = proxmox::ipset_name(prefix, port);
+        let quoted = |s: &str| format!("\"{}\"", s);
+        out.push_str(&format!(
+            "\nresource \"proxmox_virtual_environment_firewall_ipset\" \"{}\" {{\n{}",
+            resource_name(&[&set]),
+            attributes("  ", &[("name", quoted(&set)), ("comment", quoted(comment))])
+        ));
+        for ip in ips {
+            let cidr = attributes("    ", &[("name", quoted(&ip.to_string())), ("comment", quoted(comment))]);
+            out.push_str(&format!("\n  cidr {{\n{}  }}\n", cidr));
+        }
+        out.push_str("}\n");
+    }
+    out
+}
+
+/// Rule changes from the event stream (and its rotated predecessor)
+fn history(period: u64, json: bool) {
+    let start = now_secs().saturating_sub(period);
+    let path = format!("{}/events.jsonl", profile::current().state_dir);
+    let mut rows = Vec::new();
+    for file in [format!("{}.1", path), path] {
+        let Ok(content) = fs::read_to_string(&file) else {
+            continue;
+        };
+        for line in content.lines() {
+            let Some(ts) = json_num(line, "ts").filter(|&ts| ts >= start) else {
+                continue;
+            };
+            let Some(event) = json_field(line, "event").filter(|e| CHANGE_EVENTS.contains(&e.as_str())) else {
+                continue;
+            };
+            let field = |key: &str| json_field(line, key).unwrap_or_default();
+            rows.push(vec![
+                format_utc(ts),
+                event,
+                field("entry").replace(',', " "),
+                field("hostname").replace(',', " "),
+                json_num(line, "port").map(|port| port.to_string()).unwrap_or_default(),
+                field("rule"),
+                field("old_ip"),
+                field("new_ip"),
+            ]);
+        }
+    }
+    let header = ["time_utc", "event", "entries", "hostnames", "port", "rule", "old_ip", "new_ip"];
+    print_table(&header, &rows, json);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn proxmox_sets_per_port() {
+        let rules = [
+            Rule::allow(Ipv4Addr::new(203, 0, 113, 7), 22),
+            Rule::allow(Ipv4Addr::new(198, 51, 100, 9), 22),
+            Rule::allow(Ipv4Addr::new(203, 0, 113, 7), 8006),
+        ];
+        assert_eq!(
+            proxmox_blocks("ddnsfw", "DDNS-ACCESS", &rules),
+            "
+resource \"proxmox_virtual_environment_firewall_ipset\" \"ddnsfw_ddnsfw_22\" {
+  name    = \"ddnsfw-22\"
+  comment = \"DDNS-ACCESS\"
+
+  cidr {
+    name    = \"198.51.100.9\"
+    comment = \"DDNS-ACCESS\"
+  }
+
+  cidr {
+    name    = \"203.0.113.7\"
+    comment = \"DDNS-ACCESS\"
+  }
+}
+
+resource \"proxmox_virtual_environment_firewall_ipset\" \"ddnsfw_ddnsfw_8006\" {
+  name    = \"ddnsfw-8006\"
+  comment = \"DDNS-ACCESS\"
+
+  cidr {
+    name    = \"203.0.113.7\"
+    comment = \"DDNS-ACCESS\"
+  }
+}
+"
+        );
+    }
+
+    #[test]
+    fn kube_manifests_with_imports_and_aliases() {
+        let applied = |profile: Option<&str>, namespace: &str| kube::Applied {
+            profile: profile.map(String::from),
+            context: profile.map(|_| String::from("eu-west-1")),
+            namespace: namespace.to_string(),
+            policy: String::from("ddnsfw-allow"),
+            import_id: format!("apiVersion=networking.k8s.io/v1,kind=NetworkPolicy,namespace={},name=ddnsfw-allow", namespace),
+            manifest: String::from("apiVersion: networking.k8s.io/v1\nkind: NetworkPolicy\n"),
+        };
+        assert_eq!(
+            kube_blocks(&[applied(None, "0-admin"), applied(Some("eu"), "ops")]),
+            "
+import {
+  to = kubernetes_manifest.ddnsfw_0_admin_ddnsfw_allow
+  id = \"apiVersion=networking.k8s.io/v1,kind=NetworkPolicy,namespace=0-admin,name=ddnsfw-allow\"
+}
+
+resource \"kubernetes_manifest\" \"ddnsfw_0_admin_ddnsfw_allow\" {
+  manifest = yamldecode(<<-EOT
+    apiVersion: networking.k8s.io/v1
+    kind: NetworkPolicy
+    EOT
+  )
+}
+
+# [credentials eu]: provider \"kubernetes\" { alias = \"eu\" } for eu-west-1
+import {
+  to       = kubernetes_manifest.ddnsfw_eu_ops_ddnsfw_allow
+  id       = \"apiVersion=networking.k8s.io/v1,kind=NetworkPolicy,namespace=ops,name=ddnsfw-allow\"
+  provider = kubernetes.eu
+}
+
+resource \"kubernetes_manifest\" \"ddnsfw_eu_ops_ddnsfw_allow\" {
+  provider = kubernetes.eu
+  manifest = yamldecode(<<-EOT
+    apiVersion: networking.k8s.io/v1
+    kind: NetworkPolicy
+    EOT
+  )
+}
+"
+        );
+    }
+}
@@ -0,0 +1,203 @@
+//! firewalld backend: ipsets and rich rules that survive `--reload`.
+//!
+//!   backend = firewalld
+//!   firewalld_zone = public           # default: firewalld's default zone
+//!
+//! Where firewalld owns the ruleset, `firewall-cmd --reload` wipes rules
+//! added with iptables behind its back. This backend goes through
+//! firewall-cmd instead: each port gets a firewalld ipset `ddnsfw-<port>`
+//! (`ddnsfw-<profile>-<port>` for named profiles) of type hash:ip, and one
+//! rich rule in the zone accepting it:
+//!
+//!   rule family="ipv4" source ipset="ddnsfw-22" port port="22" protocol="tcp" accept
+//!
+//! Every change goes to the runtime and to the permanent configuration, so
+//! it holds across reloads and reboots. The runtime ipsets are the source
+//! of truth. firewalld cannot create or delete an ipset at runtime, so the
+//! first address of a new port, and the last one leaving, cost a reload;
+//! address changes on existing ports do not. After each sync a missing rich
+//! rule is put back and an empty ipset loses its rule and goes. Plain TCP
+//! allows only, like the other set-based backends.
+
+use std::collections::{HashMap, HashSet};
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use crate::{execs, ipset, MAX_LISTING_LINES, MAX_RULES};
+
+const FIREWALL_CMD_PATHS: &[&str] = &["/usr/bin/firewall-cmd", "/bin/firewall-cmd", "/usr/sbin/firewall-cmd"];
+const MAX_ZONE_NAME: usize = 17;
+
+pub fn find_firewall_cmd() -> Option<&'static str> {
+    FIREWALL_CMD_PATHS.iter().find(|p| Path::new(p).exists()).copied()
+}
+
+/// firewalld zone names: `[A-Za-z0-9_-]`, at most 17 characters
+pub fn is_valid_zone(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_ZONE_NAME
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+pub struct Firewalld {
+    bin: &'static str,
+    zone: Option<String>,
+}
+
+impl Firewalld {
+    pub fn open(bin: &'static str, zone: Option<String>) -> Self {
+        Firewalld { bin, zone }
+    }
+
+    fn command(&self, permanent: bool, args: &[&str]) -> Command {
+        let mut command = Command::new(self.bin);
+        if permanent {
+            command.arg("--permanent");
+        }
+        command.args(args).stderr(Stdio::null());
+        command
+    }
+
+    fn run(&self, permanent: bool, args: &[&str]) -> bool {
+        execs::status(self.command(permanent, args).stdout(Stdio::null())).map(|s| s.success()).unwrap_or(false)
+    }
+
+    /// Runtime and permanent configuration alike
+    fn run_both(&self, args: &[&str]) -> bool {
+        self.run(false, args) & self.run(true, args)
+    }
+
+    fn lines(&self, args: &[&str]) -> Option<String> {
+        let output = execs::output(self.command(false, args).stdout(Stdio::piped())).ok()?;
+        output.status.success().then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    fn reload(&self) -> bool {
+        println!("[ddnsfw] Reloading firewalld (ipsets changed)");
+        self.run(false, &["--reload"])
+    }
+
+    fn set(port: u16) -> String {
+        format!("{}{}", ipset::set_prefix(), port)
+    }
+
+    fn rich_rule(port: u16) -> String {
+        format!("rule family=\"ipv4\" source ipset=\"{}\" port port=\"{}\" protocol=\"tcp\" accept", Self::set(port), port)
+    }
+
+    /// `--zone=<zone>` when one is configured, then `option=value`
+    fn zone_args(&self, option: &str, value: &str) -> Vec<String> {
+        let mut args: Vec<String> = self.zone.iter().map(|zone| format!("--zone={}", zone)).collect();
+        args.push(format!("{}={}", option, value));
+        args
+    }
+
+    fn rich_rule_op(&self, permanent: bool, option: &str, port: u16) -> bool {
+        let args = self.zone_args(option, &Self::rich_rule(port));
+        self.run(permanent, &args.iter().map(String::as_str).collect::<Vec<_>>())
+    }
+
+    fn ensure_rich_rule(&self, port: u16) -> bool {
+        let runtime = self.rich_rule_op(false, "--query-rich-rule", port) || self.rich_rule_op(false, "--add-rich-rule", port);
+        let permanent = self.rich_rule_op(true, "--query-rich-rule", port) || self.rich_rule_op(true, "--add-rich-rule", port);
+        runtime && permanent
+    }
+
+    /// This profile's runtime ipsets by port, with their entries
+    fn sets(&self) -> HashMap<u16, HashSet<Ipv4Addr>> {
+        let mut sets: HashMap<u16, HashSet<Ipv4Addr>> = HashMap::new();
+        let Some(names) = self.lines(&["--get-ipsets"]) else {
+            return sets;
+        };
+        let prefix = ipset::set_prefix();
+        let mut members = 0;
+        for name in names.split_whitespace().take(MAX_LISTING_LINES) {
+            let Some(port) = name.strip_prefix(prefix.as_str()).and_then(|p| p.parse::<u16>().ok()) else {
+                continue;
+            };
+            let entries = self.lines(&[&format!("--ipset={}", name), "--get-entries"]).unwrap_or_default();
+            let ips = sets.entry(port).or_default();
+            for ip in entries.lines().take(MAX_LISTING_LINES).filter_map(|line| line.trim().parse::<Ipv4Addr>().ok()) {
+                if members >= MAX_RULES {
+                    eprintln!("[ddnsfw] WARN: Too many ipset entries, truncating");
+                    return sets;
+                }
+                ips.insert(ip);
+                members += 1;
+            }
+        }
+        sets
+    }
+
+    pub fn existing_rules(&self) -> HashSet<(Ipv4Addr, u16)> {
+        self.sets().into_iter().flat_map(|(port, ips)| ips.into_iter().map(move |ip| (ip, port))).collect()
+    }
+
+    pub fn rule_exists(&self, ip: Ipv4Addr, port: u16) -> bool {
+        self.run(false, &[&format!("--ipset={}", Self::set(port)), &format!("--query-entry={}", ip)])
+    }
+
+    /// A new port's ipset and rich rule are created in the permanent
+    /// configuration with its first address, then loaded by a reload
+    pub fn add_rule(&self, ip: Ipv4Addr, port: u16) -> bool {
+        let set = Self::set(port);
+        let (set_arg, entry_arg) = (format!("--ipset={}", set), format!("--add-entry={}", ip));
+        if self.run(false, &[&set_arg, "--get-entries"]) {
+            return self.run_both(&[&set_arg, &entry_arg]);
+        }
+        let new_set = format!("--new-ipset={}", set);
+        let created = self.run(true, &[&set_arg, "--get-entries"]) || self.run(true, &[&new_set, "--type=hash:ip"]);
+        created
+            && self.run(true, &[&set_arg, &entry_arg])
+            && (self.rich_rule_op(true, "--query-rich-rule", port) || self.rich_rule_op(true, "--add-rich-rule", port))
+            && self.reload()
+    }
+
+    pub fn delete_rule(&self, ip: Ipv4Addr, port: u16) -> bool {
+        self.run_both(&[&format!("--ipset={}", Self::set(port)), &format!("--remove-entry={}", ip)])
+    }
+
+    /// Removes a port's rich rule and ipset (effective after a reload)
+    fn drop_port(&self, port: u16) -> bool {
+        // Not there any more is fine: the rule is what goes
+        let _ = self.rich_rule_op(false, "--remove-rich-rule", port);
+        let _ = self.rich_rule_op(true, "--remove-rich-rule", port);
+        self.run(true, &[&format!("--delete-ipset={}", Self::set(port))])
+    }
+
+    /// After a sync: puts back missing rich rules, and drops the rule and
+    /// the ipset of every port left without addresses
+    pub fn tidy(&self) {
+        let mut dropped = false;
+        for (port, members) in self.sets() {
+            if !members.is_empty() {
+                if !self.ensure_rich_rule(port) {
+                    eprintln!("[ddnsfw] WARN: Could not restore the rich rule of ipset {}", Self::set(port));
+                }
+                continue;
+            }
+            if self.drop_port(port) {
+                dropped = true;
+            } else {
+                eprintln!("[ddnsfw] WARN: Could not delete empty ipset {}", Self::set(port));
+            }
+        }
+        if dropped && !self.reload() {
+            eprintln!("[ddnsfw] WARN: firewalld reload failed");
+        }
+    }
+
+    /// Uninstall: every rich rule and ipset of this profile
+    pub fn remove_all(&self) -> bool {
+        let mut ok = true;
+        let ports: Vec<u16> = self.sets().into_keys().collect();
+        for &port in &ports {
+            ok &= self.drop_port(port);
+        }
+        if !ports.is_empty() {
+            ok &= self.reload();
+        }
+        ok
+    }
+}
@@ -0,0 +1,337 @@
+//! Temporary grants: time-limited allows outside the config.
+//!
+//!   ddnsfw grants                                       # list
+//!   ddnsfw grants add <ip> <port> <minutes> <reason>    # manual allow
+//!   ddnsfw grants panic <minutes> <reason>              # caller IP, every configured port
+//!   ddnsfw grants revoke <ip>[:<port>]
+//!
+//! Grants of every kind (manual allows, panic mode, `otp-allow`) live in
+//! the profile's `service.grants` ledger, one per line:
+//!
+//!   manual 203.0.113.50:22 1767225600 1767229200 alice vendor maintenance window
+//!
+//! (kind, rule, created, expires, creator, reason), after a `clock` line
+//! (see clock.rs) that keeps expiries right across wall-clock jumps. Every
+//! sync reconciles the ledger under the lock: live grants are desired rules
+//! like any entry's (owner `<kind>-grant`), expired ones are dropped, and
+//! their rules go with the usual removal unless an entry still wants them. Panic mode is the
+//! "let me in now" button for an admin who is still connected: it grants
+//! the SSH caller address on every configured port.
+
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::net::Ipv4Addr;
+use std::os::unix::fs::OpenOptionsExt;
+
+use crate::{clock, dedicated};
+use crate::events::{self, Value};
+use crate::rule::Rule;
+use crate::{acquire_lock, exit_err, format_utc, now_secs, parse_config, parse_ip_port, profile, sync_firewall, MAX_RULES};
+
+pub const MAX_GRANT_MINUTES: u64 = 7 * 24 * 60;
+const MAX_REASON_LEN: usize = 200;
+const USAGE: &str = "Usage: ddnsfw grants [add <ip> <port> <minutes> <reason> | panic <minutes> <reason> | revoke <ip>[:<port>]]";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    Manual,
+    Panic,
+    Otp,
+}
+
+impl Kind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Kind::Manual => "manual",
+            Kind::Panic => "panic",
+            Kind::Otp => "otp",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "manual" => Some(Kind::Manual),
+            "panic" => Some(Kind::Panic),
+            "otp" => Some(Kind::Otp),
+            _ => None,
+        }
+    }
+
+    /// Owner label of the rule while the grant lives (no ':', so it is never
+    /// taken for a host:port entry label)
+    pub fn owner_label(&self) -> String {
+        format!("{}-grant", self.name())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TemporaryGrant {
+    pub kind: Kind,
+    pub ip: Ipv4Addr,
+    pub port: u16,
+    pub created: u64,
+    pub expires: u64,
+    pub creator: String,
+    pub reason: String,
+}
+
+impl TemporaryGrant {
+    /// A grant starting now, by the invoking user
+    pub fn new(kind: Kind, ip: Ipv4Addr, port: u16, minutes: u64, reason: &str) -> Self {
+        let now = now_secs();
+        TemporaryGrant {
+            kind,
+            ip,
+            port,
+            created: now,
+            expires: now + minutes * 60,
+            creator: creator(),
+            reason: clean_reason(reason),
+        }
+    }
+
+    /// The allow, in the dedicated chain when that is on
+    pub fn rule(&self) -> Rule {
+        Rule { chain: dedicated::inbound().to_string(), ..Rule::allow(self.ip, self.port) }
+    }
+
+    fn parse(line: &str) -> Option<Self> {
+        let mut fields = line.splitn(6, ' ');
+        let kind = Kind::parse(fields.next()?)?;
+        let (ip, port) = parse_ip_port(fields.next()?)?;
+        let created = fields.next()?.parse().ok()?;
+        let expires = fields.next()?.parse().ok()?;
+        let creator = fields.next()?.to_string();
+        let reason = fields.next().unwrap_or_default().to_string();
+        Some(TemporaryGrant { kind, ip, port, created, expires, creator, reason })
+    }
+
+    fn to_line(&self) -> String {
+        format!(
+            "{} {}:{} {} {} {} {}",
+            self.kind.name(),
+            self.ip,
+            self.port,
+            self.created,
+            self.expires,
+            self.creator,
+            self.reason
+        )
+    }
+
+    fn emit(&self, event: &str) {
+        let rule = self.rule().to_string();
+        let expires = format_utc(self.expires);
+        events::emit(
+            event,
+            &[
+                ("rule", Value::Str(&rule)),
+                ("port", Value::Num(u64::from(self.port))),
+                ("kind", Value::Str(self.kind.name())),
+                ("creator", Value::Str(&self.creator)),
+                ("reason", Value::Str(&self.reason)),
+                ("expires", Value::Str(&expires)),
+            ],
+        );
+    }
+}
+
+/// Who is granting: the sudo caller, else the login user
+fn creator() -> String {
+    ["SUDO_USER", "USER", "LOGNAME"]
+        .iter()
+        .filter_map(|var| env::var(var).ok())
+        .find(|name| !name.is_empty() && name.len() <= 64 && name.chars().all(|c| c.is_ascii_graphic()))
+        .unwrap_or_else(|| String::from("unknown"))
+}
+
+/// One line of printable text
+fn clean_reason(reason: &str) -> String {
+    let reason: String = reason.chars().map(|c| if c.is_control() { ' ' } else { c }).take(MAX_REASON_LEN).collect();
+    reason.trim().to_string()
+}
+
+pub struct Ledger {
+    pub grants: Vec<TemporaryGrant>,
+}
+
+impl Ledger {
+    pub fn load() -> Self {
+        let content = fs::read_to_string(&profile::current().grants_path).unwrap_or_default();
+        let mut grants: Vec<TemporaryGrant> = content.lines().take(MAX_RULES + 1).filter_map(TemporaryGrant::parse).collect();
+        // A wall-clock jump since the save must not end (or extend) grants
+        let jump = content.lines().find_map(|l| l.strip_prefix("clock ")).and_then(clock::Mark::parse).map_or(0, |m| m.jump());
+        if jump != 0 {
+            eprintln!("[ddnsfw] WARN: Wall clock moved {:+}s since the grants were saved, adjusting their expiry", jump);
+            for grant in &mut grants {
+                grant.created = clock::shift(grant.created, jump);
+                grant.expires = clock::shift(grant.expires, jump);
+            }
+        }
+        Ledger { grants }
+    }
+
+    /// Writes the ledger atomically
+    pub fn save(&self) {
+        let mut content: String = clock::Mark::now().map(|m| format!("clock {}\n", m)).unwrap_or_default();
+        content.extend(self.grants.iter().take(MAX_RULES).map(|g| g.to_line() + "\n"));
+        let path = &profile::current().grants_path;
+        let temp_path = format!("{}.tmp", path);
+        if let Ok(mut file) = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&temp_path) {
+            if file.write_all(content.as_bytes()).is_ok() && file.sync_all().is_ok() {
+                let _ = fs::rename(&temp_path, path);
+            }
+        }
+    }
+
+    /// Records a grant, replacing any grant for the same IP and port
+    pub fn grant(&mut self, grant: TemporaryGrant) {
+        self.grants.retain(|g| (g.ip, g.port) != (grant.ip, grant.port));
+        grant.emit("grant_add");
+        self.grants.push(grant);
+    }
+
+    /// Drops expired grants (sync, under the lock); returns the live ones
+    pub fn reconcile(&mut self, now: u64) -> &[TemporaryGrant] {
+        self.grants.retain(|grant| {
+            if grant.expires > now {
+                return true;
+            }
+            println!("[ddnsfw] Grant {}:{} ({}, {}) expired", grant.ip, grant.port, grant.kind.name(), grant.creator);
+            grant.emit("grant_expired");
+            false
+        });
+        &self.grants
+    }
+}
+
+/// `ddnsfw grants ...`
+pub fn command(args: &[String]) {
+    match args.first().map(String::as_str) {
+        None => list(),
+        Some("add") => {
+            let [_, ip, port, minutes, reason @ ..] = args else {
+                exit_err(USAGE);
+            };
+            let ip: Ipv4Addr = ip.parse().unwrap_or_else(|_| exit_err("Invalid IPv4 address"));
+            let port: u16 = port.parse().ok().filter(|&p| p > 0).unwrap_or_else(|| exit_err("Invalid port"));
+            let minutes = parse_minutes(minutes);
+            let reason = required_reason(reason);
+            record(vec![TemporaryGrant::new(Kind::Manual, ip, port, minutes, &reason)]);
+        }
+        Some("panic") => {
+            let [_, minutes, reason @ ..] = args else {
+                exit_err(USAGE);
+            };
+            let minutes = parse_minutes(minutes);
+            let reason = required_reason(reason);
+            let Some(ip) = crate::otp::caller_ip() else {
+                exit_err("Panic mode grants the SSH caller address, but SSH_CONNECTION is not set");
+            };
+            let mut ports: Vec<u16> = parse_config().entries.iter().map(|e| e.port).collect();
+            ports.sort();
+            ports.dedup();
+            if ports.is_empty() {
+                exit_err("No configured ports");
+            }
+            record(ports.into_iter().map(|port| TemporaryGrant::new(Kind::Panic, ip, port, minutes, &reason)).collect());
+        }
+        Some("revoke") => {
+            let [_, target] = args else {
+                exit_err(USAGE);
+            };
+            let (ip, port) = match parse_ip_port(target) {
+                Some((ip, port)) => (ip, Some(port)),
+                None => (target.parse().unwrap_or_else(|_| exit_err("Invalid IPv4 address")), None),
+            };
+            revoke(ip, port);
+        }
+        Some(_) => exit_err(USAGE),
+    }
+}
+
+fn parse_minutes(s: &str) -> u64 {
+    match s.parse() {
+        Ok(minutes) if (1..=MAX_GRANT_MINUTES).contains(&minutes) => minutes,
+        _ => exit_err(&format!("Invalid duration '{}' (minutes, 1-{})", s, MAX_GRANT_MINUTES)),
+    }
+}
+
+/// Grants are reviewed later: every one needs a reason
+fn required_reason(words: &[String]) -> String {
+    let reason = clean_reason(&words.join(" "));
+    if reason.is_empty() {
+        exit_err("A reason is required");
+    }
+    reason
+}
+
+/// Adds grants to the ledger, then syncs so they take effect now
+fn record(grants: Vec<TemporaryGrant>) {
+    {
+        let Some(_lock) = acquire_lock() else {
+            exit_err("Could not acquire lock");
+        };
+        let mut ledger = Ledger::load();
+        for grant in grants {
+            println!(
+                "[ddnsfw] Granting {}:{} until {} UTC ({}: {})",
+                grant.ip,
+                grant.port,
+                format_utc(grant.expires),
+                grant.kind.name(),
+                grant.reason
+            );
+            ledger.grant(grant);
+        }
+        ledger.save();
+    }
+    sync_firewall();
+}
+
+fn revoke(ip: Ipv4Addr, port: Option<u16>) {
+    {
+        let Some(_lock) = acquire_lock() else {
+            exit_err("Could not acquire lock");
+        };
+        let mut ledger = Ledger::load();
+        let before = ledger.grants.len();
+        ledger.grants.retain(|grant| {
+            let matches = grant.ip == ip && port.is_none_or(|p| p == grant.port);
+            if matches {
+                println!("[ddnsfw] Revoking {}:{} ({}, {})", grant.ip, grant.port, grant.kind.name(), grant.creator);
+                grant.emit("grant_revoke");
+            }
+            !matches
+        });
+        if ledger.grants.len() == before {
+            exit_err(&format!("No grant for {}", ip));
+        }
+        ledger.save();
+    }
+    sync_firewall();
+}
+
+fn list() {
+    let mut grants = Ledger::load().grants;
+    if grants.is_empty() {
+        println!("[ddnsfw] No temporary grants");
+        return;
+    }
+    grants.sort_by_key(|g| g.expires);
+    let now = now_secs();
+    for grant in &grants {
+        let state = if grant.expires > now { "" } else { " (expired, removed by the next sync)" };
+        println!(
+            "{:<22} {:<7} until {} UTC  by {} at {} UTC: {}{}",
+            format!("{}:{}", grant.ip, grant.port),
+            grant.kind.name(),
+            format_utc(grant.expires),
+            grant.creator,
+            format_utc(grant.created),
+            grant.reason,
+            state
+        );
+    }
+}
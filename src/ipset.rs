@@ -0,0 +1,188 @@
+//! ipset backend: one iptables rule per port, the addresses in a set.
+//!
+//!   backend = ipset
+//!
+//! Each port gets a `hash:ip` set `ddnsfw-<port>` (`ddnsfw-<profile>-<port>`
+//! for named profiles) and one rule at the top of filter/INPUT, tagged like
+//! every managed rule:
+//!
+//!   -A INPUT -p tcp -m tcp --dport 22 -m set --match-set ddnsfw-22 src -m comment --comment DDNS-ACCESS -j ACCEPT
+//!
+//! An address change is then an `ipset add`/`ipset del`, atomic in the
+//! kernel and without walking or renumbering the chain, and INPUT stays at
+//! one rule per port however many hostnames there are. The set members are
+//! the source of truth (one `ipset save` per listing). A set and its rule
+//! are created with the port's first address; after each sync a port rule
+//! that went missing (an INPUT flush) is put back, and an empty set loses
+//! its rule and is destroyed. Plain TCP allows only, like the other
+//! set-based backends.
+
+use std::collections::{HashMap, HashSet};
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use crate::{execs, iptables_run, profile, MAX_LISTING_LINES, MAX_RULES};
+
+const IPSET_PATHS: &[&str] = &["/usr/sbin/ipset", "/sbin/ipset", "/usr/bin/ipset"];
+/// Kernel limit on set names (IPSET_MAXNAMELEN - 1)
+const MAX_SET_NAME: usize = 31;
+
+pub fn find_ipset() -> Option<&'static str> {
+    IPSET_PATHS.iter().find(|p| Path::new(p).exists()).copied()
+}
+
+/// `ddnsfw-` or `ddnsfw-<profile>-` (firewalld ipsets too)
+pub fn set_prefix() -> String {
+    match &profile::current().name {
+        Some(name) => format!("ddnsfw-{}-", name),
+        None => String::from("ddnsfw-"),
+    }
+}
+
+/// Whether every port's set name fits the kernel limit for this profile
+pub fn fits_profile() -> bool {
+    set_prefix().len() + 5 <= MAX_SET_NAME
+}
+
+pub struct Ipset {
+    bin: &'static str,
+    iptables: &'static str,
+}
+
+impl Ipset {
+    pub fn open(bin: &'static str, iptables: &'static str) -> Self {
+        Ipset { bin, iptables }
+    }
+
+    /// The iptables binary holding the port rules
+    pub fn iptables(&self) -> &'static str {
+        self.iptables
+    }
+
+    fn run(&self, args: &[&str]) -> bool {
+        execs::status(
+            Command::new(self.bin)
+                .args(args)
+                .stdout(Stdio::null())
+                .stderr(Stdio::null()),
+        )
+        .map(|s| s.success())
+        .unwrap_or(false)
+    }
+
+    fn set(port: u16) -> String {
+        format!("{}{}", set_prefix(), port)
+    }
+
+    /// Arguments of the port rule for `action` (-C, -I, -D)
+    fn port_rule(&self, action: &str, port: u16) -> Vec<String> {
+        let mut args = vec![String::from("-t"), String::from("filter"), action.to_string(), String::from("INPUT")];
+        if action == "-I" {
+            args.push(String::from("1"));
+        }
+        let port_str = port.to_string();
+        let set = Self::set(port);
+        let spec = ["-p", "tcp", "-m", "tcp", "--dport", &port_str, "-m", "set", "--match-set", &set, "src"];
+        args.extend(spec.iter().map(|s| s.to_string()));
+        for arg in ["-m", "comment", "--comment", profile::current().comment(), "-j", "ACCEPT"] {
+            args.push(arg.to_string());
+        }
+        args
+    }
+
+    fn iptables_rule(&self, action: &str, port: u16) -> bool {
+        let args = self.port_rule(action, port);
+        iptables_run(self.iptables, &args.iter().map(String::as_str).collect::<Vec<_>>())
+    }
+
+    fn ensure_port_rule(&self, port: u16) -> bool {
+        self.iptables_rule("-C", port) || self.iptables_rule("-I", port)
+    }
+
+    /// This profile's sets by port, with their members (`ipset save`:
+    /// `create ddnsfw-22 hash:ip ...`, `add ddnsfw-22 192.0.2.1`)
+    fn sets(&self) -> HashMap<u16, HashSet<Ipv4Addr>> {
+        let mut sets: HashMap<u16, HashSet<Ipv4Addr>> = HashMap::new();
+        let Ok(output) = execs::output(Command::new(self.bin).arg("save").stdout(Stdio::piped()).stderr(Stdio::null())) else {
+            return sets;
+        };
+        if !output.status.success() {
+            return sets;
+        }
+        let prefix = set_prefix();
+        let mut members = 0;
+        for line in String::from_utf8_lossy(&output.stdout).lines().take(MAX_LISTING_LINES) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (verb, port) = match fields[..] {
+                [verb, name, ..] => (verb, name.strip_prefix(prefix.as_str()).and_then(|p| p.parse::<u16>().ok())),
+                _ => continue,
+            };
+            let Some(port) = port else {
+                continue;
+            };
+            match (verb, fields.get(2).and_then(|ip| ip.parse::<Ipv4Addr>().ok())) {
+                ("create", _) => {
+                    sets.entry(port).or_default();
+                }
+                ("add", Some(ip)) if members < MAX_RULES => {
+                    sets.entry(port).or_default().insert(ip);
+                    members += 1;
+                }
+                ("add", Some(_)) => {
+                    eprintln!("[ddnsfw] WARN: Too many set members, truncating");
+                    break;
+                }
+                _ => {}
+            }
+        }
+        sets
+    }
+
+    pub fn existing_rules(&self) -> HashSet<(Ipv4Addr, u16)> {
+        self.sets().into_iter().flat_map(|(port, ips)| ips.into_iter().map(move |ip| (ip, port))).collect()
+    }
+
+    pub fn rule_exists(&self, ip: Ipv4Addr, port: u16) -> bool {
+        self.run(&["test", &Self::set(port), &ip.to_string()])
+    }
+
+    /// Creates the set and its port rule with the port's first address
+    pub fn add_rule(&self, ip: Ipv4Addr, port: u16) -> bool {
+        let set = Self::set(port);
+        self.run(&["create", &set, "hash:ip", "family", "inet", "-exist"])
+            && self.run(&["add", &set, &ip.to_string(), "-exist"])
+            && self.ensure_port_rule(port)
+    }
+
+    pub fn delete_rule(&self, ip: Ipv4Addr, port: u16) -> bool {
+        self.run(&["del", &Self::set(port), &ip.to_string(), "-exist"])
+    }
+
+    /// After a sync: puts back missing port rules, and drops the rule and
+    /// the set of every port left without addresses
+    pub fn tidy(&self) {
+        for (port, members) in self.sets() {
+            if !members.is_empty() {
+                if !self.ensure_port_rule(port) {
+                    eprintln!("[ddnsfw] WARN: Could not restore the rule of set {}", Self::set(port));
+                }
+                continue;
+            }
+            while self.iptables_rule("-D", port) {}
+            if !self.run(&["destroy", &Self::set(port)]) {
+                eprintln!("[ddnsfw] WARN: Could not destroy empty set {}", Self::set(port));
+            }
+        }
+    }
+
+    /// Uninstall: every port rule and set of this profile
+    pub fn remove_all(&self) -> bool {
+        let mut ok = true;
+        for port in self.sets().into_keys() {
+            while self.iptables_rule("-D", port) {}
+            ok &= self.run(&["destroy", &Self::set(port)]);
+        }
+        ok
+    }
+}
@@ -0,0 +1,189 @@
+//! Port-knocking companion rules.
+//!
+//! For an entry `host:22 knock=7000,8000,9000`, access to port 22 requires
+//! BOTH the DDNS source IP and the knock sequence, using the `recent` module:
+//!
+//!   step i:  -s IP --dport K_i [recent --rcheck DDNSFW-22-(i-1)] recent --set DDNSFW-22-i  -j DROP
+//!   gate:    -s IP --dport 22  recent ! --rcheck DDNSFW-22-n                                 -j DROP
+//!
+//! The gate sits above the regular ACCEPT rule, so the ACCEPT only matches once
+//! the last knock was seen within KNOCK_OPEN_SECS. Every knock rule carries the
+//! managed comment and is reconciled on each sync like any other managed rule.
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::net::Ipv4Addr;
+
+use crate::{has_managed_comment, iptables, iptables_run, profile, MAX_LISTING_LINES, MAX_RULES};
+
+const MAX_KNOCK_PORTS: usize = 8;
+const KNOCK_STEP_SECS: u32 = 10;   // Max delay between two knocks
+const KNOCK_OPEN_SECS: u32 = 30;   // Window to connect after the last knock
+
+/// Parses `7000,8000,9000`. Ports must be distinct and differ from the target.
+pub fn parse_sequence(s: &str, target_port: u16) -> Option<Vec<u16>> {
+    let mut ports: Vec<u16> = Vec::new();
+    for p in s.split(',') {
+        let port: u16 = p.trim().parse().ok()?;
+        if port == 0 || port == target_port || ports.contains(&port) {
+            return None;
+        }
+        ports.push(port);
+    }
+    if ports.is_empty() || ports.len() > MAX_KNOCK_PORTS {
+        return None;
+    }
+    Some(ports)
+}
+
+fn list_name(port: u16, step: usize) -> String {
+    format!("DDNSFW-{}-{}", port, step)
+}
+
+/// Identity of a knock rule: what it matches and which list it touches
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct KnockKey {
+    ip: Ipv4Addr,
+    dport: u16,
+    name: String,
+    gate: bool,
+}
+
+fn strings(args: &[&str]) -> Vec<String> {
+    args.iter().map(|s| s.to_string()).collect()
+}
+
+/// Renders the desired knock rules for one allowed (ip, port).
+fn render(ip: Ipv4Addr, port: u16, sequence: &[u16]) -> Vec<(KnockKey, Vec<String>)> {
+    let mut rules = Vec::new();
+    let source = format!("{}/32", ip);
+
+    for (i, &knock_port) in sequence.iter().enumerate() {
+        let step = i + 1;
+        let mut args = strings(&["-s", &source, "-p", "tcp", "-m", "tcp", "--dport", &knock_port.to_string()]);
+        if step > 1 {
+            args.extend(strings(&[
+                "-m", "recent", "--rcheck",
+                "--seconds", &KNOCK_STEP_SECS.to_string(),
+                "--name", &list_name(port, step - 1),
+            ]));
+        }
+        args.extend(strings(&["-m", "recent", "--set", "--name", &list_name(port, step)]));
+        rules.push((
+            KnockKey { ip, dport: knock_port, name: list_name(port, step), gate: false },
+            args,
+        ));
+    }
+
+    let last = list_name(port, sequence.len());
+    let args = strings(&[
+        "-s", &source, "-p", "tcp", "-m", "tcp", "--dport", &port.to_string(),
+        "-m", "recent", "!", "--rcheck",
+        "--seconds", &KNOCK_OPEN_SECS.to_string(),
+        "--name", &last,
+    ]);
+    rules.push((KnockKey { ip, dport: port, name: last, gate: true }, args));
+
+    rules
+}
+
+/// Recognizes a knock rule in `iptables -S` output.
+fn parse_line(line: &str) -> Option<KnockKey> {
+    let parts: Vec<&str> = line.split_whitespace().take(60).collect();
+    let mut ip = None;
+    let mut dport = None;
+    let mut key_name = None;
+    let mut gate = false;
+
+    // Walk `-m recent ...` blocks; the one with --set (step) or ! --rcheck (gate) names the rule
+    let mut block_set = false;
+    let mut block_negated = false;
+    for i in 0..parts.len() {
+        let next = parts.get(i + 1).copied().unwrap_or("");
+        match parts[i] {
+            "-s" => ip = next.trim_end_matches("/32").parse().ok(),
+            "--dport" => dport = next.parse().ok(),
+            "-m" => {
+                block_set = false;
+                block_negated = false;
+            }
+            "--set" => block_set = true,
+            "!" if next == "--rcheck" => block_negated = true,
+            "--name" if block_set || block_negated => {
+                key_name = Some(next.to_string());
+                gate = block_negated;
+            }
+            _ => {}
+        }
+    }
+
+    let name = key_name?;
+    if !name.starts_with("DDNSFW-") {
+        return None;
+    }
+    Some(KnockKey { ip: ip?, dport: dport?, name, gate })
+}
+
+/// Existing knock rules with the exact `-S` spec needed to delete them
+fn existing(bin: &str) -> HashMap<KnockKey, Vec<String>> {
+    let mut rules = HashMap::new();
+
+    let Some(output) = iptables(bin, &["-S", "INPUT"]) else {
+        return rules;
+    };
+
+    for line in output.lines().take(MAX_LISTING_LINES) {
+        if !has_managed_comment(line) || rules.len() >= MAX_RULES {
+            continue;
+        }
+        if let Some(key) = parse_line(line) {
+            let spec: Vec<String> = line.split_whitespace().skip(2).map(String::from).collect();
+            rules.insert(key, spec);
+        }
+    }
+
+    rules
+}
+
+/// Reconciles knock rules for the given (ip, port, sequence) allowances.
+/// New rules are inserted first; stale ones are removed afterwards.
+pub fn sync(bin: &str, desired: &[(Ipv4Addr, u16, Vec<u16>)]) {
+    let current = existing(bin);
+    let mut wanted: HashMap<KnockKey, Vec<String>> = HashMap::new();
+    for (ip, port, sequence) in desired.iter().take(MAX_RULES) {
+        wanted.extend(render(*ip, *port, sequence));
+    }
+
+    for (key, args) in &wanted {
+        if current.contains_key(key) {
+            continue;
+        }
+        print!("[ddnsfw] Adding knock rule {}:{} ({}) ... ", key.ip, key.dport, key.name);
+        let _ = io::stdout().flush();
+
+        let mut full: Vec<&str> = vec!["-I", "INPUT", "1"];
+        full.extend(args.iter().map(String::as_str));
+        full.extend_from_slice(&["-m", "comment", "--comment", profile::current().comment(), "-j", "DROP"]);
+        if iptables_run(bin, &full) {
+            println!("OK");
+        } else {
+            println!("FAILED");
+        }
+    }
+
+    for (key, spec) in &current {
+        if wanted.contains_key(key) {
+            continue;
+        }
+        print!("[ddnsfw] Removing knock rule {}:{} ({}) ... ", key.ip, key.dport, key.name);
+        let _ = io::stdout().flush();
+
+        let mut full: Vec<&str> = vec!["-D", "INPUT"];
+        full.extend(spec.iter().map(String::as_str));
+        if iptables_run(bin, &full) {
+            println!("OK");
+        } else {
+            println!("FAILED (rule remains)");
+        }
+    }
+}
@@ -0,0 +1,647 @@
+//! Kubernetes NetworkPolicy / CiliumNetworkPolicy backend.
+//!
+//! Renders the allowed IPs into a single policy object and applies it with
+//! `kubectl apply`, so cluster-exposed admin services follow the DDNS IP.
+//! The managed rule set is stored in an annotation on the object itself,
+//! which makes the cluster (not the local cache) the source of truth.
+//!
+//! Every add/delete re-applies the whole manifest. kubectl apply replaces the
+//! object atomically, so new rules are live before old ones are dropped.
+//!
+//! An empty podSelector would select every pod in the namespace and a policy
+//! without ingress rules isolates the pods it selects, so `kube_selector` is
+//! required and the last rule is never deleted: the policy keeps it until
+//! another address replaces it.
+//!
+//! `[credentials NAME]` sections name further clusters (a kubeconfig and a
+//! context, e.g. one per region) and, optionally, the policy there; unset
+//! keys take the global `kube_*` settings. An entry with `credentials=NAME`
+//! is allowed in that policy instead of the default one. Its rules carry
+//! the profile name (`Rule::credentials`), so the same address and port in
+//! two clusters are two rules, each owned and removed on its own. `check`
+//! refuses unknown names and two profiles writing the same policy before
+//! anything is applied.
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::io::Write;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use crate::rule::Rule;
+use crate::{execs, parse_ip_port, BackendKind, Config, MAX_RULES};
+
+const KUBECTL_PATHS: &[&str] = &["/usr/local/bin/kubectl", "/usr/bin/kubectl", "/snap/bin/kubectl"];
+const RULES_ANNOTATION: &str = "ddnsfw/rules";
+/// Keys of a `[credentials NAME]` section
+pub const PROFILE_KEYS: &[&str] = &["kubeconfig", "context", "namespace", "policy", "selector"];
+
+/// Policy flavor to render
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Flavor {
+    NetworkPolicy,
+    Cilium,
+}
+
+impl Flavor {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "networkpolicy" => Some(Flavor::NetworkPolicy),
+            "cilium" => Some(Flavor::Cilium),
+            _ => None,
+        }
+    }
+
+    fn resource(&self) -> &'static str {
+        match self {
+            Flavor::NetworkPolicy => "networkpolicy",
+            Flavor::Cilium => "ciliumnetworkpolicy",
+        }
+    }
+
+    fn api_version(&self) -> &'static str {
+        match self {
+            Flavor::NetworkPolicy => "networking.k8s.io/v1",
+            Flavor::Cilium => "cilium.io/v2",
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            Flavor::NetworkPolicy => "NetworkPolicy",
+            Flavor::Cilium => "CiliumNetworkPolicy",
+        }
+    }
+}
+
+/// Policy settings from the config file
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub namespace: String,
+    pub policy: String,
+    pub selector: Vec<(String, String)>,
+    pub flavor: Flavor,
+    pub kubeconfig: Option<String>,  // None: kubectl's default
+    pub context: Option<String>,  // None: the kubeconfig's current context
+    pub profiles: BTreeMap<String, Profile>,  // [credentials NAME] sections
+}
+
+impl Settings {
+    pub fn new() -> Self {
+        Settings {
+            namespace: String::from("default"),
+            policy: String::from("ddnsfw-allow"),
+            selector: Vec::new(),
+            flavor: Flavor::NetworkPolicy,
+            kubeconfig: None,
+            context: None,
+            profiles: BTreeMap::new(),
+        }
+    }
+
+    /// The settings of a profile: its own keys over the global ones
+    fn with(&self, profile: &Profile) -> Settings {
+        Settings {
+            namespace: profile.namespace.clone().unwrap_or_else(|| self.namespace.clone()),
+            policy: profile.policy.clone().unwrap_or_else(|| self.policy.clone()),
+            selector: profile.selector.clone().unwrap_or_else(|| self.selector.clone()),
+            flavor: self.flavor,
+            kubeconfig: profile.kubeconfig.clone().or_else(|| self.kubeconfig.clone()),
+            context: profile.context.clone().or_else(|| self.context.clone()),
+            profiles: BTreeMap::new(),
+        }
+    }
+
+    /// Every policy with the profile it belongs to: the default one (None),
+    /// then one per profile
+    fn targets(&self) -> Vec<(Option<String>, Settings)> {
+        let default = Settings { profiles: BTreeMap::new(), ..self.clone() };
+        std::iter::once((None, default))
+            .chain(self.profiles.iter().map(|(name, profile)| (Some(name.clone()), self.with(profile))))
+            .collect()
+    }
+
+    /// `networkpolicy admin/ddnsfw-allow (context eu-west)` for messages
+    fn describe(&self) -> String {
+        let context = self.context.as_ref().map(|c| format!(" (context {})", c)).unwrap_or_default();
+        format!("{} {}/{}{}", self.flavor.resource(), self.namespace, self.policy, context)
+    }
+}
+
+/// A `[credentials NAME]` section: another cluster, reached through its
+/// own kubeconfig and context, and the policy there
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Profile {
+    pub kubeconfig: Option<String>,
+    pub context: Option<String>,
+    pub namespace: Option<String>,
+    pub policy: Option<String>,
+    pub selector: Option<Vec<(String, String)>>,
+}
+
+impl Profile {
+    /// Sets one of `PROFILE_KEYS`; Err says what is wrong with the value
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "kubeconfig" if value.starts_with('/') && !value.contains(char::is_whitespace) => {
+                self.kubeconfig = Some(value.to_string())
+            }
+            "context" if is_valid_context(value) => self.context = Some(value.to_string()),
+            "namespace" if is_valid_name(value) => self.namespace = Some(value.to_string()),
+            "policy" if is_valid_name(value) => self.policy = Some(value.to_string()),
+            "selector" => match parse_selector(value) {
+                Some(selector) => self.selector = Some(selector),
+                None => return Err(format!("invalid selector '{}'", value)),
+            },
+            "kubeconfig" => return Err(format!("kubeconfig must be an absolute path, not '{}'", value)),
+            _ => return Err(format!("invalid {} '{}'", key, value)),
+        }
+        Ok(())
+    }
+}
+
+/// RFC 1123 label: namespaces and object names
+pub fn is_valid_name(s: &str) -> bool {
+    !s.is_empty()
+        && s.len() <= 63
+        && s.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        && !s.starts_with('-')
+        && !s.ends_with('-')
+}
+
+/// kubeconfig context names are free-form (`arn:aws:eks:...`,
+/// `gke_project_europe-west1_prod`); refuse only what kubectl would read
+/// as another argument
+fn is_valid_context(s: &str) -> bool {
+    !s.is_empty() && s.len() <= 253 && !s.starts_with('-') && s.chars().all(|c| c.is_ascii_graphic())
+}
+
+/// Parses `app=admin,tier=ops` into matchLabels pairs (at least one).
+pub fn parse_selector(s: &str) -> Option<Vec<(String, String)>> {
+    let label_ok = |v: &str| {
+        v.len() <= 253
+            && v.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_' | '/'))
+    };
+
+    let mut labels = Vec::new();
+    for pair in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let (key, value) = pair.split_once('=')?;
+        let (key, value) = (key.trim(), value.trim());
+        if key.is_empty() || !label_ok(key) || !label_ok(value) {
+            return None;
+        }
+        labels.push((key.to_string(), value.to_string()));
+    }
+    (!labels.is_empty()).then_some(labels)
+}
+
+pub fn find_kubectl() -> Option<&'static str> {
+    KUBECTL_PATHS.iter().find(|p| Path::new(p).exists()).copied()
+}
+
+// ============================================================================
+// Manifest Rendering
+// ============================================================================
+
+fn render(settings: &Settings, rules: &HashSet<(Ipv4Addr, u16)>) -> String {
+    // Group sources per port; ordered so re-applies are byte-identical
+    let mut by_port: BTreeMap<u16, BTreeSet<Ipv4Addr>> = BTreeMap::new();
+    for &(ip, port) in rules {
+        by_port.entry(port).or_default().insert(ip);
+    }
+
+    let annotation: Vec<String> = by_port
+        .iter()
+        .flat_map(|(port, ips)| ips.iter().map(move |ip| format!("{}:{}", ip, port)))
+        .collect();
+
+    let mut labels = String::from("    matchLabels:\n");
+    for (key, value) in &settings.selector {
+        labels.push_str(&format!("      \"{}\": \"{}\"\n", key, value));
+    }
+
+    let mut out = format!("apiVersion: {}\nkind: {}\n", settings.flavor.api_version(), settings.flavor.kind());
+    out.push_str(&format!(
+        "metadata:\n  name: {}\n  namespace: {}\n  labels:\n    app.kubernetes.io/managed-by: ddnsfw\n  annotations:\n    {}: \"{}\"\nspec:\n",
+        settings.policy,
+        settings.namespace,
+        RULES_ANNOTATION,
+        annotation.join(","),
+    ));
+
+    match settings.flavor {
+        Flavor::NetworkPolicy => {
+            out.push_str("  podSelector:\n");
+            out.push_str(&labels);
+            out.push_str("  policyTypes:\n    - Ingress\n  ingress:\n");
+            for (port, ips) in &by_port {
+                out.push_str("    - from:\n");
+                for ip in ips {
+                    out.push_str(&format!("        - ipBlock:\n            cidr: {}/32\n", ip));
+                }
+                out.push_str(&format!("      ports:\n        - protocol: TCP\n          port: {}\n", port));
+            }
+        }
+        Flavor::Cilium => {
+            out.push_str("  endpointSelector:\n");
+            out.push_str(&labels);
+            out.push_str("  ingress:\n");
+            for (port, ips) in &by_port {
+                out.push_str("    - fromCIDR:\n");
+                for ip in ips {
+                    out.push_str(&format!("        - {}/32\n", ip));
+                }
+                out.push_str(&format!(
+                    "      toPorts:\n        - ports:\n            - port: \"{}\"\n              protocol: TCP\n",
+                    port
+                ));
+            }
+        }
+    }
+
+    out
+}
+
+// ============================================================================
+// Backend
+// ============================================================================
+
+/// Credentials need the kubernetes backend, entries may only name defined
+/// ones, and no two policies may be the same object (each apply would drop
+/// the other's rules)
+pub fn check(config: &Config) -> Result<(), String> {
+    let settings = &config.kube;
+    let named: Vec<_> = config.entries.iter().filter_map(|e| Some((e, e.credentials.as_ref()?))).collect();
+    if config.backend != BackendKind::Kubernetes {
+        if let Some(name) = settings.profiles.keys().next() {
+            return Err(format!("[credentials {}] needs backend = kubernetes", name));
+        }
+        if let Some((entry, _)) = named.first() {
+            return Err(format!("{}: credentials= needs backend = kubernetes", entry.origin));
+        }
+        return Ok(());
+    }
+
+    for (entry, name) in &named {
+        if !settings.profiles.contains_key(*name) {
+            return Err(format!("{}: unknown credentials '{}'", entry.origin, name));
+        }
+    }
+    // The default policy is only written for entries without credentials
+    let default_used = named.len() < config.entries.len() || settings.profiles.is_empty();
+    if default_used && settings.selector.is_empty() {
+        return Err(String::from(
+            "backend = kubernetes requires kube_selector (an empty selector would isolate every pod in the namespace)",
+        ));
+    }
+
+    let mut seen: Vec<(String, Settings)> = Vec::new();
+    for (profile, target) in settings.targets() {
+        let name = match &profile {
+            Some(profile) => format!("[credentials {}]", profile),
+            None => String::from("the default policy"),
+        };
+        if profile.is_some() && target.selector.is_empty() {
+            return Err(format!("{} needs a selector (or kube_selector)", name));
+        }
+        if let Some(path) = target.kubeconfig.as_deref().filter(|path| !Path::new(path).is_file()) {
+            return Err(format!("{}: kubeconfig {} not found", name, path));
+        }
+        let same = |other: &Settings| {
+            (&other.kubeconfig, &other.context, &other.namespace, &other.policy)
+                == (&target.kubeconfig, &target.context, &target.namespace, &target.policy)
+        };
+        if let Some((other, _)) = seen.iter().find(|(_, other)| same(other)) {
+            return Err(format!("{} writes the same {} as {}", name, target.describe(), other));
+        }
+        seen.push((name, target));
+    }
+    Ok(())
+}
+
+/// A policy holding rules, as `export --format terraform` shows it
+pub struct Applied {
+    pub profile: Option<String>,  // None: the default policy
+    pub context: Option<String>,
+    pub namespace: String,
+    pub policy: String,
+    pub import_id: String,  // kubernetes_manifest import ID
+    pub manifest: String,  // The YAML ddnsfw applies
+}
+
+/// One policy and the rule set currently applied in its cluster
+struct Policy {
+    bin: &'static str,
+    profile: Option<String>,  // None: the default policy
+    settings: Settings,
+    applied: HashSet<(Ipv4Addr, u16)>,
+}
+
+impl Policy {
+    fn open(bin: &'static str, profile: Option<String>, settings: Settings) -> Self {
+        let mut policy = Policy {
+            bin,
+            profile,
+            settings,
+            applied: HashSet::new(),
+        };
+        policy.applied = policy.fetch();
+        policy
+    }
+
+    /// kubectl, pointed at the policy's kubeconfig and context
+    fn kubectl(&self) -> Command {
+        let mut command = Command::new(self.bin);
+        if let Some(kubeconfig) = &self.settings.kubeconfig {
+            command.args(["--kubeconfig", kubeconfig]);
+        }
+        if let Some(context) = &self.settings.context {
+            command.args(["--context", context]);
+        }
+        command
+    }
+
+    /// Reads the managed rule set back from the policy annotation.
+    fn fetch(&self) -> HashSet<(Ipv4Addr, u16)> {
+        let jsonpath = format!("jsonpath={{.metadata.annotations.{}}}", RULES_ANNOTATION.replace('.', "\\."));
+        let output = execs::output(
+            self.kubectl()
+                .args([
+                    "get", self.settings.flavor.resource(), &self.settings.policy,
+                    "-n", &self.settings.namespace,
+                    "-o", &jsonpath,
+                ])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null()),
+        );
+
+        let mut rules = HashSet::new();
+        let Ok(output) = output else {
+            return rules;
+        };
+        if !output.status.success() {
+            return rules;
+        }
+
+        for rule in String::from_utf8_lossy(&output.stdout).split(',') {
+            if rules.len() >= MAX_RULES {
+                break;
+            }
+            if let Some(rule) = parse_ip_port(rule) {
+                rules.insert(rule);
+            }
+        }
+        rules
+    }
+
+    fn apply(&self, rules: &HashSet<(Ipv4Addr, u16)>) -> bool {
+        if rules.is_empty() || self.settings.selector.is_empty() {
+            eprintln!(
+                "[ddnsfw] WARN: Not applying {} without ingress rules or selector, the current policy stays",
+                self.settings.describe()
+            );
+            return false;
+        }
+        let manifest = render(&self.settings, rules);
+
+        let Ok(mut child) = execs::spawn(
+            self.kubectl()
+                .args(["apply", "-f", "-"])
+                .stdin(Stdio::piped())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null()),
+        )
+        else {
+            return false;
+        };
+
+        if let Some(mut stdin) = child.stdin.take() {
+            if stdin.write_all(manifest.as_bytes()).is_err() {
+                let _ = child.kill();
+                let _ = child.wait();
+                return false;
+            }
+        }
+
+        child.wait().map(|s| s.success()).unwrap_or(false)
+    }
+
+    /// Re-applies the policy with `ip:port` added or removed
+    fn change(&mut self, ip: Ipv4Addr, port: u16, add: bool) -> bool {
+        let mut next = self.applied.clone();
+        if add {
+            next.insert((ip, port));
+        } else {
+            next.remove(&(ip, port));
+        }
+        if self.apply(&next) {
+            self.applied = next;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Opened backend: the default policy and one per credentials profile
+pub struct Kube {
+    policies: Vec<Policy>,
+}
+
+impl Kube {
+    pub fn open(bin: &'static str, settings: Settings) -> Self {
+        let policies = settings
+            .targets()
+            .into_iter()
+            .map(|(profile, settings)| Policy::open(bin, profile, settings))
+            .collect();
+        Kube { policies }
+    }
+
+    /// The policy of a rule: a plain allow, in the policy of its
+    /// credentials profile (None: the default one)
+    fn policy(&self, rule: &Rule) -> Option<usize> {
+        let plain = Rule { credentials: None, ..rule.clone() };
+        if !plain.is_plain() {
+            return None;
+        }
+        self.policies.iter().position(|policy| policy.profile == rule.credentials)
+    }
+
+    /// The policies holding rules, each rendered as the next apply would
+    pub fn applied(&self) -> Vec<Applied> {
+        self.policies
+            .iter()
+            .filter(|policy| !policy.applied.is_empty())
+            .map(|policy| {
+                let settings = &policy.settings;
+                Applied {
+                    profile: policy.profile.clone(),
+                    context: settings.context.clone(),
+                    namespace: settings.namespace.clone(),
+                    policy: settings.policy.clone(),
+                    import_id: format!(
+                        "apiVersion={},kind={},namespace={},name={}",
+                        settings.flavor.api_version(),
+                        settings.flavor.kind(),
+                        settings.namespace,
+                        settings.policy
+                    ),
+                    manifest: render(settings, &policy.applied),
+                }
+            })
+            .collect()
+    }
+
+    pub fn supports(&self, rule: &Rule) -> bool {
+        self.policy(rule).is_some()
+    }
+
+    pub fn existing_rules(&self) -> HashSet<Rule> {
+        let mut rules = HashSet::new();
+        for policy in &self.policies {
+            for &(ip, port) in &policy.applied {
+                rules.insert(Rule { credentials: policy.profile.clone(), ..Rule::allow(ip, port) });
+            }
+        }
+        rules
+    }
+
+    pub fn rule_exists(&self, rule: &Rule) -> bool {
+        self.policy(rule).is_some_and(|index| self.policies[index].applied.contains(&(rule.ip, rule.port)))
+    }
+
+    pub fn add_rule(&mut self, rule: &Rule) -> bool {
+        self.policy(rule).is_some_and(|index| self.policies[index].change(rule.ip, rule.port, true))
+    }
+
+    pub fn delete_rule(&mut self, rule: &Rule) -> bool {
+        self.policy(rule).is_some_and(|index| self.policies[index].change(rule.ip, rule.port, false))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{load_config_file, parse_entry_line};
+    use std::collections::HashMap;
+    use std::fs;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn config(text: &str) -> Config {
+        static FILES: AtomicUsize = AtomicUsize::new(0);
+        let file = FILES.fetch_add(1, Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!("ddnsfw-kube-{}-{}.conf", std::process::id(), file));
+        fs::write(&path, text).unwrap();
+        let mut config = Config::new();
+        load_config_file(&path, &mut config, &mut HashMap::new(), 0);
+        let _ = fs::remove_file(&path);
+        config
+    }
+
+    const PROFILES: &str = "\
+backend = kubernetes
+kube_namespace = admin
+kube_selector = app=dashboard
+
+[credentials eu]
+context = arn:aws:eks:eu-west-1:111122223333:cluster/ops
+namespace = ops
+
+[credentials us]
+context = arn:aws:eks:us-east-1:444455556666:cluster/ops
+selector = app=console
+backup_keep = 3
+
+home.example.net:443
+home.example.net:443 credentials=eu
+office.example.net:22 credentials=us
+";
+
+    #[test]
+    fn sections_become_profiles_over_the_global_settings() {
+        let config = config(PROFILES);
+        assert_eq!(config.kube.profiles.len(), 2);
+        // `backup_keep` is no profile key: it closes the section
+        assert_eq!(config.backup_keep, 3);
+        assert_eq!(config.entries.len(), 3);
+
+        let targets = config.kube.targets();
+        let summary: Vec<_> = targets
+            .iter()
+            .map(|(profile, s)| (profile.as_deref(), s.context.as_deref(), s.namespace.as_str(), s.selector[0].1.as_str()))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (None, None, "admin", "dashboard"),
+                (Some("eu"), Some("arn:aws:eks:eu-west-1:111122223333:cluster/ops"), "ops", "dashboard"),
+                (Some("us"), Some("arn:aws:eks:us-east-1:444455556666:cluster/ops"), "admin", "console"),
+            ]
+        );
+        assert_eq!(check(&config), Ok(()));
+    }
+
+    #[test]
+    fn rules_go_to_the_policy_of_their_credentials() {
+        let config = config(PROFILES);
+        let ip = Ipv4Addr::new(203, 0, 113, 7);
+        let rules: Vec<Rule> = config.entries.iter().flat_map(|entry| entry.rules_for(ip)).collect();
+        // The profile is not a chain: rules keep INPUT and carry it on their own
+        assert!(rules.iter().all(|rule| rule.chain == "INPUT"));
+        let shown: Vec<_> = rules.iter().map(Rule::to_string).collect();
+        assert_eq!(shown, ["203.0.113.7:443", "203.0.113.7:443@eu", "203.0.113.7:22@us"]);
+        assert_eq!(Rule::parse(&shown[1]).as_ref(), Some(&rules[1]));
+        assert_eq!(Rule::parse("203.0.113.7:443@-eu"), None);
+
+        let policies = config.kube.targets().into_iter().map(|(profile, settings)| Policy {
+            bin: "/nonexistent/kubectl",
+            profile,
+            settings,
+            applied: HashSet::from([(ip, 443)]),
+        });
+        let kube = Kube { policies: policies.collect() };
+        // The same address and port is a rule per cluster
+        assert_eq!(kube.existing_rules().len(), 3);
+        assert!(kube.existing_rules().contains(&rules[1]));
+        assert!(kube.rule_exists(&rules[0]) && kube.rule_exists(&rules[1]));
+        assert!(!kube.rule_exists(&rules[2]));
+
+        let mut unknown = rules[1].clone();
+        unknown.credentials = Some(String::from("ap"));
+        let mut udp = rules[1].clone();
+        udp.proto = crate::rule::Proto::Udp;
+        assert!(kube.supports(&rules[2]) && !kube.supports(&unknown) && !kube.supports(&udp));
+    }
+
+    #[test]
+    fn check_refuses_what_would_fight_or_miss() {
+        let with = |extra: &str| check(&config(&format!("{}{}", PROFILES, extra)));
+        assert!(with("ap.example.net:22 credentials=ap\n").unwrap_err().ends_with("unknown credentials 'ap'"));
+        assert_eq!(
+            with("[credentials copy]\nnamespace = admin\n"),
+            Err(String::from("[credentials copy] writes the same networkpolicy admin/ddnsfw-allow as the default policy"))
+        );
+        assert_eq!(
+            with("[credentials lost]\nkubeconfig = /nonexistent/kubeconfig\n"),
+            Err(String::from("[credentials lost]: kubeconfig /nonexistent/kubeconfig not found"))
+        );
+
+        let mut other = config(PROFILES);
+        other.backend = BackendKind::Iptables;
+        assert_eq!(check(&other), Err(String::from("[credentials eu] needs backend = kubernetes")));
+        other.kube.profiles.clear();
+        assert!(check(&other).unwrap_err().ends_with("credentials= needs backend = kubernetes"));
+
+        // Without the default policy in use, kube_selector may stay empty
+        let mut named = config(PROFILES);
+        named.kube.selector.clear();
+        assert!(check(&named).unwrap_err().starts_with("backend = kubernetes requires kube_selector"));
+        named.entries.remove(0);
+        assert_eq!(check(&named), Err(String::from("[credentials eu] needs a selector (or kube_selector)")));
+        named.kube.profiles.get_mut("eu").unwrap().selector = parse_selector("app=ops");
+        assert_eq!(check(&named), Ok(()));
+
+        assert!(parse_entry_line("h:22 credentials=eu").is_some_and(|e| e.credentials.as_deref() == Some("eu")));
+    }
+}
@@ -0,0 +1,130 @@
+//! Lockdown companions: close an entry's port to every other source.
+//!
+//!   home.example.net:22 lockdown=drop      # silently drop everyone else
+//!   home.example.net:22 lockdown=reject    # refuse them with a TCP reset
+//!
+//! The allow rules only open a port; whether anybody else gets in depends
+//! on the rest of the firewall. With `lockdown=` the sync appends one tagged
+//! rule per port and protocol at the end of filter/INPUT, below the allows
+//! (which are inserted at the top), that handles every other source. `drop`
+//! makes the port look filtered; `reject` answers TCP with a reset
+//! (`--reject-with tcp-reset`, UDP with icmp-port-unreachable), so a
+//! legitimate user whose DNS is momentarily stale gets a fast "connection
+//! refused" instead of a hang until the next sync.
+//!
+//! A lockdown is only in place while its port has an active allow rule, so
+//! it never closes a port nobody can reach yet. When entries sharing a port
+//! disagree, the first in the config wins. A mode change adds the new rule
+//! before the old one goes. Rules earlier in INPUT that accept the port for
+//! everyone (a blanket SSH allow) still win. Filter-table entries and the
+//! iptables backend only.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Write};
+
+use crate::rule::Proto;
+use crate::{has_managed_comment, iptables, iptables_run, profile, MAX_LISTING_LINES, MAX_RULES};
+
+/// What happens to other sources
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Mode {
+    Drop,
+    Reject,
+}
+
+impl Mode {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "drop" => Some(Mode::Drop),
+            "reject" => Some(Mode::Reject),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Mode::Drop => "drop",
+            Mode::Reject => "reject",
+        }
+    }
+
+    /// iptables target of the rule
+    pub fn target(&self) -> &'static str {
+        match self {
+            Mode::Drop => "DROP",
+            Mode::Reject => "REJECT",
+        }
+    }
+}
+
+/// Full iptables arguments of a lockdown rule for `action` (-A, -D)
+fn args(action: &str, proto: Proto, port: u16, mode: Mode) -> Vec<String> {
+    let port = port.to_string();
+    let mut args = vec![action, "INPUT", "-p", proto.name(), "-m", proto.name(), "--dport", &port];
+    args.extend_from_slice(&["-m", "comment", "--comment", profile::current().comment(), "-j"]);
+    match (mode, proto) {
+        (Mode::Drop, _) => args.push(mode.target()),
+        (Mode::Reject, Proto::Tcp) => args.extend_from_slice(&["REJECT", "--reject-with", "tcp-reset"]),
+        (Mode::Reject, _) => args.extend_from_slice(&["REJECT", "--reject-with", "icmp-port-unreachable"]),
+    }
+    args.into_iter().map(String::from).collect()
+}
+
+/// Tagged rules without a source (allows and companions all have one)
+pub fn existing(bin: &str) -> HashSet<(Proto, u16, Mode)> {
+    let mut rules = HashSet::new();
+    let Some(output) = iptables(bin, &["-S", "INPUT"]) else {
+        return rules;
+    };
+    for line in output.lines().take(MAX_LISTING_LINES).filter(|l| has_managed_comment(l)) {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        let value = |flag: &str| tokens.iter().position(|t| *t == flag).and_then(|i| tokens.get(i + 1)).copied();
+        if value("-s").is_some() || rules.len() >= MAX_RULES {
+            continue;
+        }
+        let (Some(proto), Some(Ok(port))) = (value("-p").and_then(Proto::parse), value("--dport").map(str::parse)) else {
+            continue;
+        };
+        let mode = match value("-j") {
+            Some("DROP") => Mode::Drop,
+            Some("REJECT") => Mode::Reject,
+            _ => continue,
+        };
+        rules.insert((proto, port, mode));
+    }
+    rules
+}
+
+/// Adds missing lockdowns, then drops those whose mode changed or whose
+/// port has no active allow any more
+pub fn sync(bin: &str, desired: &HashMap<(Proto, u16), Mode>) {
+    let current = existing(bin);
+
+    for (&(proto, port), &mode) in desired.iter().take(MAX_RULES) {
+        if current.contains(&(proto, port, mode)) {
+            continue;
+        }
+        print!("[ddnsfw] Locking down {}/{} ({}) ... ", port, proto.name(), mode.name());
+        let _ = io::stdout().flush();
+        let args = args("-A", proto, port, mode);
+        if iptables_run(bin, &args.iter().map(String::as_str).collect::<Vec<_>>()) {
+            println!("OK");
+        } else {
+            println!("FAILED");
+        }
+    }
+
+    for &(proto, port, mode) in current.iter().take(MAX_RULES) {
+        if desired.get(&(proto, port)) == Some(&mode) {
+            continue;
+        }
+        print!("[ddnsfw] Removing lockdown {}/{} ({}) ... ", port, proto.name(), mode.name());
+        let _ = io::stdout().flush();
+        let args = args("-D", proto, port, mode);
+        if iptables_run(bin, &args.iter().map(String::as_str).collect::<Vec<_>>()) {
+            println!("OK");
+        } else {
+            println!("FAILED (rule remains)");
+        }
+    }
+}
@@ -0,0 +1,99 @@
+//! `ddnsfw logs`: readable journal view of this profile's sync runs.
+//!
+//!   ddnsfw logs [--since <time>] [--host <name>] [--only-changes] [-f]
+//!
+//! Wraps `journalctl -t <identifier>` so users need no journalctl syntax.
+//! `--since` is passed through (journalctl accepts "1h ago", "today",
+//! "2026-01-01 10:00"). `--host` keeps lines naming the host or one of the
+//! IPs it currently resolves to; `--only-changes` keeps rule additions,
+//! removals, failures and config edits.
+
+use std::io::{BufRead, BufReader};
+use std::net::Ipv4Addr;
+use std::process::{Command, Stdio};
+
+use crate::{exit_err, profile, resolve_dns};
+
+/// Message fragments of lines that record a change or a failure
+const CHANGE_MARKERS: &[&str] = &[
+    "Adding ", "Removing ", "FAILED", "Recovery", "expired", "OTP ", "Renamed ", "Enabled ", "Disabled ",
+];
+
+struct Filter {
+    since: Option<String>,
+    host: Option<String>,
+    host_ips: Vec<String>,
+    only_changes: bool,
+    follow: bool,
+}
+
+fn parse_args(args: &[String]) -> Filter {
+    let mut filter = Filter { since: None, host: None, host_ips: Vec::new(), only_changes: false, follow: false };
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--since" => filter.since = Some(iter.next().cloned().unwrap_or_else(|| exit_err("--since needs a value"))),
+            "--host" => filter.host = Some(iter.next().cloned().unwrap_or_else(|| exit_err("--host needs a value"))),
+            "--only-changes" => filter.only_changes = true,
+            "-f" | "--follow" => filter.follow = true,
+            _ => exit_err("Usage: ddnsfw logs [--since <time>] [--host <name>] [--only-changes] [-f]"),
+        }
+    }
+    // Rule lines name IPs, not hosts: match the host's current address too
+    if let Some(host) = &filter.host {
+        filter.host_ips = resolve_dns(host).iter().map(Ipv4Addr::to_string).collect();
+    }
+    filter
+}
+
+impl Filter {
+    fn keeps(&self, message: &str) -> bool {
+        if self.only_changes && !CHANGE_MARKERS.iter().any(|m| message.contains(m)) {
+            return false;
+        }
+        match &self.host {
+            Some(host) => message.contains(host.as_str()) || self.host_ips.iter().any(|ip| message.contains(ip.as_str())),
+            None => true,
+        }
+    }
+}
+
+/// `2026-10-16T10:00:00+0000 host ddnsfw[42]: [ddnsfw] Adding ...` ->
+/// `2026-10-16 10:00:00  Adding ...`
+fn translate(line: &str) -> Option<String> {
+    let (timestamp, rest) = line.split_once(' ')?;
+    let (_, message) = rest.split_once(": ")?;
+    let message = message.strip_prefix("[ddnsfw] ").unwrap_or(message);
+    let timestamp = timestamp.get(..19).unwrap_or(timestamp).replacen('T', " ", 1);
+    Some(format!("{}  {}", timestamp, message))
+}
+
+pub fn logs(args: &[String]) {
+    let filter = parse_args(args);
+    let identifier = profile::current().syslog_identifier();
+
+    let mut command = Command::new("journalctl");
+    command.args(["-t", &identifier, "-o", "short-iso", "--no-pager", "-q"]);
+    if let Some(since) = &filter.since {
+        command.args(["--since", since]);
+    }
+    if filter.follow {
+        command.arg("-f");
+    }
+
+    let Ok(mut child) = command.stdout(Stdio::piped()).stderr(Stdio::inherit()).spawn() else {
+        exit_err("journalctl not found");
+    };
+    let Some(stdout) = child.stdout.take() else {
+        exit_err("Cannot read journalctl output");
+    };
+
+    for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+        if let Some(entry) = translate(&line) {
+            if filter.keeps(&entry) {
+                println!("{}", entry);
+            }
+        }
+    }
+    let _ = child.wait();
+}
@@ -16,16 +16,88 @@
 //! - File locking prevents concurrent execution
 //! - Strict permissions prevent privilege escalation
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::fs::{self, File, OpenOptions};
 use std::io::{self, BufRead, BufReader, Write};
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
-use std::os::unix::io::AsRawFd;
 use std::path::Path;
 use std::process::{Command, Stdio};
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+mod adopt;
+mod backup;
+mod beacon;
+mod bench;
+mod bundle;
+mod cgnat;
+mod chaos;
+mod churn;
+mod clock;
+mod crypto;
+mod daemon;
+mod dedicated;
+mod dns;
+mod dnscache;
+mod doctor;
+mod edit;
+mod enrich;
+mod envconfig;
+mod escalate;
+mod events;
+mod export;
+mod firewalld;
+mod execs;
+mod grants;
+mod ipset;
+mod knock;
+mod kube;
+mod lockdown;
+mod metrics;
+mod migrate;
+mod nft;
+mod logs;
+mod mirror;
+mod otp;
+mod perms;
+mod plan;
+mod priority;
+mod privsep;
+mod profile;
+mod provider;
+mod push;
+mod prune;
+mod proxmox;
+mod report;
+mod resolver;
+mod restore;
+mod retry;
+mod simulate;
+mod srv;
+mod rule;
+mod state;
+mod stats;
+mod status;
+mod syslog;
+mod sys;
+mod throttle;
+mod tomlconfig;
+mod uninstall;
+mod v6;
+mod verify;
+mod vpn;
+mod watchdog;
+mod webhook;
+mod why;
+
+use rule::{Position, Rule, Table, Target};
+use events::Value;
+use priority::Priority;
+use retry::{Gate, Op};
+use state::{Effect, Event, Recovery, SyncState};
+use stats::{Phase, SyncStats};
 
 // ============================================================================
 // Constants
@@ -33,17 +105,41 @@ use std::time::Duration;
 
 const INSTALL_DIR: &str = "/etc/ddnsfw";
 const BINARY_PATH: &str = "/etc/ddnsfw/run";
-const CONFIG_PATH: &str = "/etc/ddnsfw/conf.conf";
-const CACHE_PATH: &str = "/etc/ddnsfw/service.cache";
 const SERVICE_PATH: &str = "/etc/systemd/system/ddnsfw.service";
+const TEMPLATE_SERVICE_PATH: &str = "/etc/systemd/system/ddnsfw@.service";
 const TIMER_PATH: &str = "/etc/systemd/system/ddnsfw.timer";
-const IPTABLES_COMMENT: &str = "DDNS-ACCESS";
+const TEMPLATE_TIMER_PATH: &str = "/etc/systemd/system/ddnsfw@.timer";
 const DNS_TIMEOUT_SECS: u64 = 10;
-
-// Safety limits
-const MAX_ENTRIES: usize = 100;      // Max config entries
-const MAX_RULES: usize = 100;        // Max iptables rules to process
-const MAX_LOOP_ITERATIONS: usize = 200;  // Absolute max iterations in any loop
+const TIMER_INTERVAL_SECS: u64 = 120;  // OnUnitActiveSec of the timer; a sync must fit in it
+/// Longest grace_seconds: an old address stays allowed at most a day
+const MAX_GRACE_SECS: u64 = 86400;
+/// Most addresses an entry keeps allowed with history = N
+const MAX_HISTORY: usize = 10;
+
+// Safety limits. Loops over entries, rules and listings are bounded by the
+// limit of what they walk; MAX_LOOP_ITERATIONS only bounds loops over
+// things that do not grow with the config (options, answers, env vars).
+const MAX_ENTRIES: usize = 2000;     // Max config entries
+const MAX_RULES: usize = 4000;       // Max iptables rules to process
+const MAX_CONFIG_LINES: usize = 4 * MAX_ENTRIES;  // Config lines read (settings, comments, bundles)
+const MAX_LISTING_LINES: usize = 4 * MAX_RULES;   // Chain listing lines read (foreign rules, companions)
+const CAPACITY_WARN_PERCENT: usize = 80;  // Warn when a limit is this full
+const MAX_META_LEN: usize = 120;          // owner= / description= length
+const MAX_ENTRY_ID_LEN: usize = 64;       // id= length
+const MAX_LOOP_ITERATIONS: usize = 200;  // Max iterations of any other loop
+const MAX_CACHE_LINES: usize = 16;      // Cache file lines read (corrupt cache protection)
+const MAX_DNS_ADDRS: usize = 16;        // dns_max_addrs limit
+const DEFAULT_DNS_ADDRS: usize = 4;
+
+// Format versions of the state that guards access; bump on any change an
+// older binary would misread
+const CACHE_SCHEMA: u32 = 3;   // 1: caches without a SCHEMA line (up to v2.2), 2: no RULES6 line
+const CONFIG_SCHEMA: u32 = 1;
+
+/// `--strict` on the command line (the `strict` setting does the same)
+static STRICT_FLAG: AtomicBool = AtomicBool::new(false);
+/// `--verbose` on the command line (the `verbose` setting does the same)
+static VERBOSE_FLAG: AtomicBool = AtomicBool::new(false);
 
 const IPTABLES_PATHS: &[&str] = &[
     "/usr/sbin/iptables",
@@ -51,100 +147,360 @@ const IPTABLES_PATHS: &[&str] = &[
     "/usr/bin/iptables",
 ];
 
-const LOCK_PATH: &str = "/etc/ddnsfw/.lock";
 
 // ============================================================================
 // Cache Structure (Crash Recovery)
 // ============================================================================
 
-#[derive(Debug, Clone, PartialEq)]
-enum CacheState {
-    Idle,
-    Adding,
-    Deleting,
-}
-
 #[derive(Debug, Clone)]
 struct Cache {
-    state: CacheState,
-    rules: HashSet<(Ipv4Addr, u16)>,
-    pending: Option<(Ipv4Addr, u16)>,
+    state: SyncState,
+    rules: HashSet<Rule>,
+    rules6: HashSet<v6::Rule6>,         // ip6tables rules (listed on every sync)
+    legacy_grants: Vec<(Ipv4Addr, u16, u64)>, // GRANTS line of older versions, moved to the grants ledger
+    otp_last_step: u64,                 // Last accepted TOTP step (replay guard)
+    owners: HashMap<Rule, Vec<String>>, // Entries that want each installed rule
+    since: HashMap<Rule, u64>,          // When each rule was first installed
+    retries: Vec<retry::Retry>,         // Failed operations awaiting retry
+    meta: HashMap<String, EntryMeta>,   // Owner/description per host:port
+    comment: Option<String>,            // Comment the rules carry (None: no cache yet)
+    failures: HashMap<String, escalate::Streak>, // Consecutive failed resolutions per hostname
+    retiring: HashMap<Rule, u64>,       // Old addresses kept for grace_seconds, since when
+    history: HashMap<String, Vec<Ipv4Addr>>, // Recent addresses per entry, newest first (history)
+    schema: u32,                        // Format the cache was read in (migrated on the next save)
 }
 
 impl Cache {
     fn new() -> Self {
         Cache {
-            state: CacheState::Idle,
+            state: SyncState::Idle,
             rules: HashSet::new(),
-            pending: None,
+            rules6: HashSet::new(),
+            legacy_grants: Vec::new(),
+            otp_last_step: 0,
+            owners: HashMap::new(),
+            since: HashMap::new(),
+            retries: Vec::new(),
+            meta: HashMap::new(),
+            comment: None,
+            failures: HashMap::new(),
+            retiring: HashMap::new(),
+            history: HashMap::new(),
+            schema: CACHE_SCHEMA,
         }
     }
 
     fn load() -> Self {
-        let Ok(file) = File::open(CACHE_PATH) else {
+        let Ok(file) = File::open(mirror::cache_path()) else {
             return Cache::new();
         };
 
         let reader = BufReader::new(file);
         let mut cache = Cache::new();
+        // Caches without a COMMENT line predate comment_prefix
+        cache.comment = Some(profile::current().default_comment());
+        cache.schema = 1;
         let mut line_count = 0;
+        let mut state_name = String::new();
+        let mut pending = None;
+        let mut mark = None;
 
         for line in reader.lines().map_while(Result::ok) {
             line_count += 1;
-            if line_count > 10 {
+            if line_count > MAX_CACHE_LINES {
                 break; // Corrupt cache protection
             }
 
-            if let Some(state_str) = line.strip_prefix("STATE:") {
-                cache.state = match state_str {
-                    "ADDING" => CacheState::Adding,
-                    "DELETING" => CacheState::Deleting,
-                    _ => CacheState::Idle,
-                };
+            if let Some(schema_str) = line.strip_prefix("SCHEMA:") {
+                // A newer format is refused before anything is acted on
+                cache.schema = check_schema(
+                    "cache",
+                    mirror::cache_path(),
+                    schema_str,
+                    CACHE_SCHEMA,
+                    "move it aside to rebuild it from the firewall's tagged rules",
+                );
+            } else if let Some(clock_str) = line.strip_prefix("CLOCK:") {
+                mark = clock::Mark::parse(clock_str);
+            } else if let Some(state_str) = line.strip_prefix("STATE:") {
+                state_name = state_str.to_string();
             } else if let Some(rules_str) = line.strip_prefix("RULES:") {
                 let mut rule_count = 0;
                 for rule in rules_str.split(',') {
                     if rule_count >= MAX_RULES {
                         break;
                     }
-                    if let Some((ip, port)) = parse_ip_port(rule) {
-                        cache.rules.insert((ip, port));
+                    if let Some(rule) = Rule::parse(rule) {
+                        cache.rules.insert(rule);
                         rule_count += 1;
                     }
                 }
+            } else if let Some(rules_str) = line.strip_prefix("RULES6:") {
+                cache.rules6 = rules_str.split(',').take(MAX_RULES).filter_map(v6::Rule6::parse).collect();
             } else if let Some(pending_str) = line.strip_prefix("PENDING:") {
-                cache.pending = parse_ip_port(pending_str);
+                pending = Rule::parse(pending_str);
+            } else if let Some(grants_str) = line.strip_prefix("GRANTS:") {
+                for grant in grants_str.split(',').take(MAX_RULES) {
+                    let Some((rule, expiry)) = grant.split_once('@') else {
+                        continue;
+                    };
+                    if let (Some((ip, port)), Ok(expiry)) = (parse_ip_port(rule), expiry.parse()) {
+                        cache.legacy_grants.push((ip, port, expiry));
+                    }
+                }
+            } else if let Some(step_str) = line.strip_prefix("OTP:") {
+                cache.otp_last_step = step_str.trim().parse().unwrap_or(0);
+            } else if let Some(owners_str) = line.strip_prefix("OWNERS:") {
+                for item in owners_str.split(',').take(MAX_RULES) {
+                    let Some((rule, owners)) = item.split_once('>') else {
+                        continue;
+                    };
+                    if let Some(rule) = Rule::parse(rule) {
+                        let owners = owners.split('|').take(MAX_ENTRIES).map(String::from).collect();
+                        cache.owners.insert(rule, owners);
+                    }
+                }
+            } else if let Some(meta_str) = line.strip_prefix("META:") {
+                for item in meta_str.split(',').take(MAX_ENTRIES) {
+                    let mut fields = item.splitn(3, '>');
+                    let (Some(label), Some(owner), Some(description)) = (fields.next(), fields.next(), fields.next()) else {
+                        continue;
+                    };
+                    let meta = EntryMeta {
+                        owner: Some(meta_unescape(owner)).filter(|o| !o.is_empty()),
+                        description: Some(meta_unescape(description)).filter(|d| !d.is_empty()),
+                    };
+                    cache.meta.insert(meta_unescape(label), meta);
+                }
+            } else if let Some(comment_str) = line.strip_prefix("COMMENT:") {
+                if profile::is_valid_comment_prefix(comment_str.trim()) {
+                    cache.comment = Some(comment_str.trim().to_string());
+                }
+            } else if let Some(fails_str) = line.strip_prefix("FAILS:") {
+                for item in fails_str.split(',').take(MAX_ENTRIES) {
+                    let mut fields = item.splitn(3, '@');
+                    let (Some(hostname), Some(count), Some(since)) = (fields.next(), fields.next(), fields.next()) else {
+                        continue;
+                    };
+                    if let (Ok(count), Ok(since)) = (count.parse(), since.parse()) {
+                        cache.failures.insert(hostname.to_string(), escalate::Streak { count, since });
+                    }
+                }
+            } else if let Some(retry_str) = line.strip_prefix("RETRY:") {
+                cache.retries = retry_str.split(',').take(MAX_RULES).filter_map(retry::Retry::parse).collect();
+            } else if let Some(retiring_str) = line.strip_prefix("RETIRING:") {
+                for item in retiring_str.split(',').take(MAX_RULES) {
+                    let Some((rule, since)) = item.rsplit_once('@') else {
+                        continue;
+                    };
+                    if let (Some(rule), Ok(since)) = (Rule::parse(rule), since.parse()) {
+                        cache.retiring.insert(rule, since);
+                    }
+                }
+            } else if let Some(history_str) = line.strip_prefix("HISTORY:") {
+                for item in history_str.split(',').take(MAX_ENTRIES) {
+                    let Some((label, ips)) = item.split_once('>') else {
+                        continue;
+                    };
+                    let ips: Vec<Ipv4Addr> = ips.split('|').take(MAX_HISTORY).filter_map(|ip| ip.parse().ok()).collect();
+                    if !ips.is_empty() {
+                        cache.history.insert(label.to_string(), ips);
+                    }
+                }
+            } else if let Some(since_str) = line.strip_prefix("SINCE:") {
+                for item in since_str.split(',').take(MAX_RULES) {
+                    let Some((rule, since)) = item.rsplit_once('@') else {
+                        continue;
+                    };
+                    if let (Some(rule), Ok(since)) = (Rule::parse(rule), since.parse()) {
+                        cache.since.insert(rule, since);
+                    }
+                }
             }
         }
 
+        cache.state = SyncState::parse(&state_name, pending);
+
+        // Timestamps written before a wall-clock jump move with it
+        let jump = mark.map_or(0, |mark| mark.jump());
+        if jump != 0 {
+            eprintln!(
+                "[ddnsfw] WARN: Wall clock moved {:+}s (beyond elapsed time) since the cache was saved, adjusting its timestamps",
+                jump
+            );
+            for retry in &mut cache.retries {
+                retry.next_at = clock::shift(retry.next_at, jump);
+            }
+            for streak in cache.failures.values_mut() {
+                streak.since = clock::shift(streak.since, jump);
+            }
+            for since in cache.since.values_mut().chain(cache.retiring.values_mut()) {
+                *since = clock::shift(*since, jump);
+            }
+            for (_, _, expiry) in &mut cache.legacy_grants {
+                *expiry = clock::shift(*expiry, jump);
+            }
+        }
         cache
     }
 
+    /// Until when an installed rule no entry wants any more stays, when it
+    /// is in its grace_seconds: the old address of an entry still in
+    /// `labels` (removed entries and expired grants go at once)
+    fn grace_until(&self, rule: &Rule, labels: &HashSet<String>, grace: u64, now: u64) -> Option<u64> {
+        if grace == 0 || !self.owners.get(rule).is_some_and(|owners| owners.iter().any(|o| labels.contains(o))) {
+            return None;
+        }
+        let until = self.retiring.get(rule).copied().unwrap_or(now).saturating_add(grace);
+        (now < until).then_some(until)
+    }
+
+    /// The previous addresses of an entry that stay allowed next to `ips`:
+    /// the most recent ones, up to `keep` addresses in all (history)
+    fn recent(&self, label: &str, ips: &[Ipv4Addr], keep: usize) -> Vec<Ipv4Addr> {
+        let room = keep.saturating_sub(ips.len());
+        self.history.get(label).into_iter().flatten().filter(|ip| !ips.contains(ip)).take(room).copied().collect()
+    }
+
+    /// Moves the state of entries given an `id=` from their label to the
+    /// id, unless the id already has some; returns the (label, id) moved
+    fn rekey(&mut self, entries: &[DdnsEntry]) -> Vec<(String, String)> {
+        let mut moved = Vec::new();
+        for entry in entries.iter().filter(|entry| entry.id.is_some()) {
+            let (label, key) = (entry.label(), entry.key());
+            let owns = |name: &str| self.owners.values().any(|owners| owners.iter().any(|o| o == name));
+            if owns(&key) || !owns(&label) || moved.iter().any(|(l, _)| *l == label) {
+                continue;
+            }
+            for owner in self.owners.values_mut().flatten().filter(|o| **o == label) {
+                *owner = key.clone();
+            }
+            if let Some(meta) = self.meta.remove(&label) {
+                self.meta.insert(key.clone(), meta);
+            }
+            if let Some(history) = self.history.remove(&label) {
+                self.history.insert(key.clone(), history);
+            }
+            moved.push((label, key));
+        }
+        moved
+    }
+
     fn save(&self) {
         // Limit rules in cache
         let rules_to_save: Vec<_> = self.rules.iter().take(MAX_RULES).collect();
 
         let rules_str: String = rules_to_save
             .iter()
-            .map(|(ip, port)| format!("{}:{}", ip, port))
+            .map(|rule| rule.to_string())
             .collect::<Vec<_>>()
             .join(",");
 
-        let state_str = match self.state {
-            CacheState::Idle => "IDLE",
-            CacheState::Adding => "ADDING",
-            CacheState::Deleting => "DELETING",
-        };
+        let rules6_str: String = self
+            .rules6
+            .iter()
+            .take(MAX_RULES)
+            .map(|rule| rule.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+
+        let state_str = self.state.name();
+        let pending_str = self.state.pending().map(|rule| rule.to_string()).unwrap_or_default();
+
+        // Kept until a sync has moved them to the ledger
+        let grants_str: String = self
+            .legacy_grants
+            .iter()
+            .take(MAX_RULES)
+            .map(|(ip, port, expiry)| format!("{}:{}@{}", ip, port, expiry))
+            .collect::<Vec<_>>()
+            .join(",");
+
+        let owners_str: String = self
+            .owners
+            .iter()
+            .take(MAX_RULES)
+            .map(|(rule, owners)| format!("{}>{}", rule, owners.join("|")))
+            .collect::<Vec<_>>()
+            .join(",");
+
+        let since_str: String = self
+            .since
+            .iter()
+            .take(MAX_RULES)
+            .map(|(rule, since)| format!("{}@{}", rule, since))
+            .collect::<Vec<_>>()
+            .join(",");
+
+        let retiring_str: String = self
+            .retiring
+            .iter()
+            .take(MAX_RULES)
+            .map(|(rule, since)| format!("{}@{}", rule, since))
+            .collect::<Vec<_>>()
+            .join(",");
+
+        let history_str: String = self
+            .history
+            .iter()
+            .take(MAX_ENTRIES)
+            .map(|(label, ips)| format!("{}>{}", label, ips.iter().map(Ipv4Addr::to_string).collect::<Vec<_>>().join("|")))
+            .collect::<Vec<_>>()
+            .join(",");
+
+        let retry_str: String = self
+            .retries
+            .iter()
+            .take(MAX_RULES)
+            .map(|r| r.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
 
-        let pending_str = self
-            .pending
-            .map(|(ip, port)| format!("{}:{}", ip, port))
-            .unwrap_or_default();
+        let meta_str: String = self
+            .meta
+            .iter()
+            .take(MAX_ENTRIES)
+            .map(|(label, meta)| {
+                format!(
+                    "{}>{}>{}",
+                    meta_escape(label),
+                    meta_escape(meta.owner.as_deref().unwrap_or_default()),
+                    meta_escape(meta.description.as_deref().unwrap_or_default())
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+
+        let fails_str: String = self
+            .failures
+            .iter()
+            .take(MAX_ENTRIES)
+            .map(|(hostname, streak)| format!("{}@{}@{}", hostname, streak.count, streak.since))
+            .collect::<Vec<_>>()
+            .join(",");
 
-        let content = format!("STATE:{}\nRULES:{}\nPENDING:{}\n", state_str, rules_str, pending_str);
+        let content = format!(
+            "SCHEMA:{}\nCLOCK:{}\nSTATE:{}\nRULES:{}\nRULES6:{}\nPENDING:{}\nGRANTS:{}\nOTP:{}\nOWNERS:{}\nSINCE:{}\nRETRY:{}\nMETA:{}\nCOMMENT:{}\nFAILS:{}\nRETIRING:{}\nHISTORY:{}\n",
+            CACHE_SCHEMA,
+            clock::Mark::now().map(|mark| mark.to_string()).unwrap_or_default(),
+            state_str,
+            rules_str,
+            rules6_str,
+            pending_str,
+            grants_str,
+            self.otp_last_step,
+            owners_str,
+            since_str,
+            retry_str,
+            meta_str,
+            self.comment.as_deref().unwrap_or_default(),
+            fails_str,
+            retiring_str,
+            history_str
+        );
 
         // Atomic write
-        let temp_path = format!("{}.tmp", CACHE_PATH);
+        let temp_path = format!("{}.tmp", mirror::cache_path());
         if let Ok(mut file) = OpenOptions::new()
             .write(true)
             .create(true)
@@ -153,43 +509,39 @@ impl Cache {
             .open(&temp_path)
         {
             let _ = file.write_all(content.as_bytes());
-            let _ = file.sync_all();
-            let _ = fs::rename(&temp_path, CACHE_PATH);
+            if mirror::durable() {
+                let _ = file.sync_all();
+            }
+            let _ = fs::rename(&temp_path, mirror::cache_path());
         }
     }
 
-    fn set_idle(&mut self) {
-        self.state = CacheState::Idle;
-        self.pending = None;
-        self.save();
-    }
-
-    fn set_adding(&mut self, ip: Ipv4Addr, port: u16) {
-        self.state = CacheState::Adding;
-        self.pending = Some((ip, port));
-        self.save();
-    }
-
-    fn set_deleting(&mut self, ip: Ipv4Addr, port: u16) {
-        self.state = CacheState::Deleting;
-        self.pending = Some((ip, port));
-        self.save();
-    }
-
-    fn add_rule(&mut self, ip: Ipv4Addr, port: u16) {
-        if self.rules.len() < MAX_RULES {
-            self.rules.insert((ip, port));
+    /// Applies a state machine event and persists the result before
+    /// anything else happens
+    fn transition(&mut self, event: Event) {
+        match self.state.on(event) {
+            Ok((state, effect)) => {
+                match effect {
+                    Effect::None => {}
+                    Effect::Insert(rule) => {
+                        if self.rules.len() < MAX_RULES {
+                            self.rules.insert(rule);
+                        }
+                    }
+                    Effect::Remove(rule) => {
+                        self.rules.remove(&rule);
+                    }
+                }
+                self.state = state;
+            }
+            // A bug, not a firewall problem: never guess, drop the operation
+            Err(invalid) => {
+                eprintln!("[ddnsfw] ERROR: {}", invalid);
+                self.state = SyncState::Idle;
+            }
         }
-        self.state = CacheState::Idle;
-        self.pending = None;
-        self.save();
-    }
-
-    fn remove_rule(&mut self, ip: Ipv4Addr, port: u16) {
-        self.rules.remove(&(ip, port));
-        self.state = CacheState::Idle;
-        self.pending = None;
         self.save();
+        chaos_point(&format!("after {}", self.state.name()));
     }
 }
 
@@ -208,11 +560,45 @@ fn parse_ip_port(s: &str) -> Option<(Ipv4Addr, u16)> {
 // Minimal Error Handling
 // ============================================================================
 
+/// Simulated crash site for --chaos (no-op otherwise)
+fn chaos_point(what: &str) {
+    if let Some(chaos) = chaos::active() {
+        chaos.crash_point(what);
+    }
+}
+
 fn exit_err(msg: &str) -> ! {
     eprintln!("[ddnsfw] ERROR: {}", msg);
     std::process::exit(1);
 }
 
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Formats a unix timestamp as `YYYY-MM-DD HH:MM:SS` UTC
+fn format_utc(ts: u64) -> String {
+    // Civil-from-days (Howard Hinnant), valid for all u64 timestamps we see
+    let days = (ts / 86400) as i64;
+    let secs = ts % 86400;
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year, month, day, secs / 3600, secs % 3600 / 60, secs % 60
+    )
+}
+
 // ============================================================================
 // File Locking (Prevents Concurrent Execution)
 // ============================================================================
@@ -220,20 +606,34 @@ fn exit_err(msg: &str) -> ! {
 /// Acquires an exclusive lock on the lock file.
 /// Returns the lock file handle (must be kept alive during operation).
 /// If another instance is running, waits up to 30 seconds then exits.
-fn acquire_lock() -> Option<File> {
+fn open_lock_file() -> Option<File> {
     // Create lock file if it doesn't exist
-    let lock_file = OpenOptions::new()
+    OpenOptions::new()
         .write(true)
         .create(true)
+        .truncate(false)
         .mode(0o600)
-        .open(LOCK_PATH)
-        .ok()?;
+        .open(&profile::current().lock_path)
+        .ok()
+}
 
-    // Try to acquire exclusive lock (non-blocking first)
-    let fd = lock_file.as_raw_fd();
-    let result = unsafe { libc::flock(fd, libc::LOCK_EX | libc::LOCK_NB) };
+/// Non-blocking lock attempt. Err(()) when another instance holds the lock.
+fn try_lock() -> Result<Option<File>, ()> {
+    let Some(lock_file) = open_lock_file() else {
+        return Ok(None);
+    };
+    if sys::lock_exclusive(&lock_file, false) {
+        Ok(Some(lock_file))
+    } else {
+        Err(())
+    }
+}
+
+fn acquire_lock() -> Option<File> {
+    let lock_file = open_lock_file()?;
 
-    if result == 0 {
+    // Try to acquire exclusive lock (non-blocking first)
+    if sys::lock_exclusive(&lock_file, false) {
         // Lock acquired immediately
         return Some(lock_file);
     }
@@ -245,16 +645,16 @@ fn acquire_lock() -> Option<File> {
     use std::sync::mpsc;
     use std::thread;
 
+    // The duplicate shares the open file description, and so the lock
+    let waiter = lock_file.try_clone().ok()?;
     let (tx, rx) = mpsc::channel();
-    let fd_copy = fd;
 
     thread::spawn(move || {
-        let result = unsafe { libc::flock(fd_copy, libc::LOCK_EX) };
-        let _ = tx.send(result);
+        let _ = tx.send(sys::lock_exclusive(&waiter, true));
     });
 
     match rx.recv_timeout(Duration::from_secs(30)) {
-        Ok(0) => Some(lock_file),
+        Ok(true) => Some(lock_file),
         _ => {
             eprintln!("[ddnsfw] ERROR: Timeout waiting for lock (another instance running too long)");
             None
@@ -266,8 +666,24 @@ fn acquire_lock() -> Option<File> {
 // System Checks
 // ============================================================================
 
-fn is_root() -> bool {
-    unsafe { libc::geteuid() == 0 }
+/// The schema version of a state file, refusing formats newer than
+/// `supported`: a misread cache or config could drop SSH access, so a
+/// rollback stops here instead of guessing
+fn check_schema(what: &str, path: &str, value: &str, supported: u32, hint: &str) -> u32 {
+    match value.trim().parse::<u32>() {
+        Ok(schema) if (1..=supported).contains(&schema) => schema,
+        Ok(schema) if schema > supported => exit_err(&format!(
+            "{} uses {} schema {}, but ddnsfw v{} only understands up to {}. It was written by a newer version; \
+             reinstall that version, or {}. Nothing was changed.",
+            path,
+            what,
+            schema,
+            env!("CARGO_PKG_VERSION"),
+            supported,
+            hint
+        )),
+        _ => exit_err(&format!("{}: invalid {} schema '{}'", path, what, value.trim())),
+    }
 }
 
 fn find_iptables() -> Option<&'static str> {
@@ -275,7 +691,7 @@ fn find_iptables() -> Option<&'static str> {
 }
 
 fn is_installed() -> bool {
-    Path::new(BINARY_PATH).exists() && Path::new(CONFIG_PATH).exists()
+    Path::new(BINARY_PATH).exists() && Path::new(&profile::current().config_path).exists()
 }
 
 fn is_running_installed() -> bool {
@@ -288,37 +704,107 @@ fn is_running_installed() -> bool {
 // DNS Resolution (Synchronous - no async overhead)
 // ============================================================================
 
-fn resolve_dns(hostname: &str) -> Option<Ipv4Addr> {
-    let output = Command::new("getent")
-        .args(["ahostsv4", hostname])
-        .stdout(Stdio::piped())
-        .stderr(Stdio::null())
-        .output()
-        .ok()?;
+/// Which of several A records an entry allows
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DnsSelect {
+    First,   // Resolver order (default)
+    Lowest,  // Numerically lowest: stable under round-robin rotation
+    Sticky,  // Keep the allowed IP while it is still among the answers
+    All,     // Every answer, up to dns_max_addrs (multi-uplink failover)
+}
 
-    if !output.status.success() {
-        return None;
+impl DnsSelect {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "first" => Some(DnsSelect::First),
+            "lowest" => Some(DnsSelect::Lowest),
+            "sticky" => Some(DnsSelect::Sticky),
+            "all" => Some(DnsSelect::All),
+            _ => None,
+        }
     }
 
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    let first_line = stdout.lines().next()?;
-    let ip_str = first_line.split_whitespace().next()?;
-    ip_str.parse().ok()
+    /// Picks from the (deduplicated, resolver-ordered) answers; `current`
+    /// are the IPs the entry has allowed so far. Empty when there is none.
+    fn pick(self, addrs: &[Ipv4Addr], current: &[Ipv4Addr], max: usize) -> Vec<Ipv4Addr> {
+        match self {
+            DnsSelect::First => addrs.first().copied().into_iter().collect(),
+            DnsSelect::Lowest => addrs.iter().min().copied().into_iter().collect(),
+            DnsSelect::Sticky => addrs.iter().find(|ip| current.contains(ip)).or(addrs.first()).copied().into_iter().collect(),
+            // Over the cap, allowed addresses stay ahead of new ones
+            DnsSelect::All => {
+                let (kept, new): (Vec<Ipv4Addr>, Vec<Ipv4Addr>) = addrs.iter().partition(|ip| current.contains(ip));
+                kept.into_iter().chain(new).take(max).collect()
+            }
+        }
+    }
 }
 
-fn resolve_dns_timeout(hostname: &str, timeout: Duration) -> Option<Ipv4Addr> {
-    use std::sync::mpsc;
-    use std::thread;
+/// Addresses that can never be a remote peer's public source address
+fn is_usable_ipv4(ip: &Ipv4Addr) -> bool {
+    !(ip.is_unspecified() || ip.is_loopback() || ip.is_link_local() || ip.is_multicast() || ip.is_broadcast())
+}
 
-    let hostname = hostname.to_string();
-    let (tx, rx) = mpsc::channel();
+/// Usable IPv4 addresses from `getent ahostsv4` output, deduplicated in
+/// resolver order. Every line is checked (getent repeats each address per
+/// socket type, and some NSS setups print IPv4-mapped IPv6 forms); anything
+/// that does not parse is ignored rather than trusted.
+fn parse_getent(output: &str) -> Vec<Ipv4Addr> {
+    let mut addrs = Vec::new();
+    for line in output.lines().take(MAX_LOOP_ITERATIONS) {
+        let ip = match line.split_whitespace().next().and_then(|t| t.parse::<IpAddr>().ok()) {
+            Some(IpAddr::V4(ip)) => ip,
+            Some(IpAddr::V6(ip)) => match ip.to_ipv4_mapped() {
+                Some(ip) => ip,
+                None => continue,
+            },
+            None => continue,
+        };
+        if is_usable_ipv4(&ip) && !addrs.contains(&ip) {
+            addrs.push(ip);
+        }
+    }
+    addrs
+}
 
-    thread::spawn(move || {
-        let result = resolve_dns(&hostname);
-        let _ = tx.send(result);
-    });
+/// All usable IPv4 addresses of `hostname` (empty when resolution failed)
+fn resolve_dns(hostname: &str) -> Vec<Ipv4Addr> {
+    if let Some(chaos) = chaos::active() {
+        return chaos.resolve(hostname);
+    }
+    let Ok(output) =
+        execs::output(Command::new("getent").args(["ahostsv4", hostname]).stdout(Stdio::piped()).stderr(Stdio::null()))
+    else {
+        return Vec::new();
+    };
+
+    if !output.status.success() {
+        return Vec::new();
+    }
+    parse_getent(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Looks a hostname up in the optional hosts.override file (/etc/hosts
+/// format: `IP hostname [alias ...]`), which takes precedence over DNS.
+fn lookup_override(hostname: &str) -> Option<Ipv4Addr> {
+    let meta = fs::metadata(&profile::current().hosts_override_path).ok()?;
+    if meta.permissions().mode() & 0o022 != 0 {
+        eprintln!("[ddnsfw] WARN: Ignoring group/world-writable {}", profile::current().hosts_override_path);
+        return None;
+    }
 
-    rx.recv_timeout(timeout).ok().flatten()
+    let content = fs::read_to_string(&profile::current().hosts_override_path).ok()?;
+    for line in content.lines().take(MAX_CONFIG_LINES) {
+        let line = line.split('#').next().unwrap_or("");
+        let mut fields = line.split_whitespace();
+        let Some(ip) = fields.next().and_then(|ip| ip.parse().ok()) else {
+            continue;
+        };
+        if fields.any(|name| name.eq_ignore_ascii_case(hostname)) {
+            return Some(ip);
+        }
+    }
+    None
 }
 
 // ============================================================================
@@ -326,12 +812,7 @@ fn resolve_dns_timeout(hostname: &str, timeout: Duration) -> Option<Ipv4Addr> {
 // ============================================================================
 
 fn iptables(bin: &str, args: &[&str]) -> Option<String> {
-    let output = Command::new(bin)
-        .args(args)
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .output()
-        .ok()?;
+    let output = execs::output(privsep::iptables_command(bin, args).stdout(Stdio::piped()).stderr(Stdio::piped())).ok()?;
 
     if output.status.success() {
         Some(String::from_utf8_lossy(&output.stdout).into_owned())
@@ -341,106 +822,285 @@ fn iptables(bin: &str, args: &[&str]) -> Option<String> {
 }
 
 fn iptables_run(bin: &str, args: &[&str]) -> bool {
-    Command::new(bin)
-        .args(args)
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .status()
+    execs::status(privsep::iptables_command(bin, args).stdout(Stdio::null()).stderr(Stdio::null()))
         .map(|s| s.success())
         .unwrap_or(false)
 }
 
-fn get_existing_rules(bin: &str) -> HashSet<(Ipv4Addr, u16)> {
+/// True when an `iptables -S` line carries exactly this profile's comment
+/// (a plain substring test would match other profiles' `DDNS-ACCESS-<name>`)
+fn has_managed_comment(line: &str) -> bool {
+    has_comment(line, profile::current().comment())
+}
+
+fn has_comment(line: &str, comment: &str) -> bool {
+    let mut tokens = line.split_whitespace();
+    while let Some(token) = tokens.next() {
+        if token == "--comment" && tokens.next().map(|c| c.trim_matches('"')) == Some(comment) {
+            return true;
+        }
+    }
+    false
+}
+
+/// Lists managed rules in the given (table, chain) scopes.
+fn get_existing_rules(bin: &str, scopes: &HashSet<(Table, String)>) -> HashSet<Rule> {
+    get_tagged_rules(bin, scopes, profile::current().comment())
+}
+
+/// Lists rules carrying `comment` in the given (table, chain) scopes.
+fn get_tagged_rules(bin: &str, scopes: &HashSet<(Table, String)>, comment: &str) -> HashSet<Rule> {
     let mut rules = HashSet::new();
 
-    let Some(output) = iptables(bin, &["-S", "INPUT"]) else {
-        return rules;
-    };
+    for (table, chain) in scopes.iter().take(MAX_LOOP_ITERATIONS) {
+        let Some(output) = iptables(bin, &["-t", table.name(), "-S", chain]) else {
+            continue;
+        };
 
-    let mut iteration = 0;
-    for line in output.lines() {
-        iteration += 1;
-        if iteration > MAX_LOOP_ITERATIONS {
-            eprintln!("[ddnsfw] WARN: Too many iptables rules, truncating");
-            break;
+        let mut iteration = 0;
+        for line in output.lines() {
+            iteration += 1;
+            if iteration > MAX_LISTING_LINES {
+                eprintln!("[ddnsfw] WARN: Too many iptables rules, truncating");
+                break;
+            }
+
+            if !has_comment(line, comment) {
+                continue;
+            }
+
+            if rules.len() >= MAX_RULES {
+                break;
+            }
+
+            if let Some(rule) = managed_rule(line, *table, chain) {
+                rules.insert(rule);
+            }
         }
+    }
+
+    rules
+}
+
+/// The managed rule an `iptables -S` line of (table, chain) describes.
+/// Companion rules (connlimit, knock) share the comment but are not managed
+/// rules.
+fn managed_rule(line: &str, table: Table, chain: &str) -> Option<Rule> {
+    let parsed = parse_rule_line(line)?;
+    let target = match (parsed.target, parsed.mark) {
+        ("ACCEPT", _) if parsed.connlimit.is_none() => Target::Accept,
+        ("MARK", Some(mark)) => Target::Mark(mark),
+        ("CT", _) if parsed.notrack => Target::NoTrack,
+        ("TCPMSS", _) => Target::ClampMss,
+        ("RETURN", _) => Target::Return,
+        (chain, _) if rule::is_valid_chain_name(chain) => Target::Jump(chain.to_string()),
+        _ => return None,
+    };
+    Some(Rule {
+        table,
+        chain: chain.to_string(),
+        ip: parsed.ip,
+        port: parsed.port,
+        proto: parsed.proto,
+        target,
+        credentials: None,
+    })
+}
+
+/// The options of a rule, in order (`-A`, `-s`, `-p`, `-m`, `--dport`,
+/// ...): what tells the rule ddnsfw wrote from a similar one with more
+/// matches. `-S` prints `--set-mark` as `--set-xmark`.
+fn match_signature<'a>(tokens: impl Iterator<Item = &'a str>) -> Vec<&'a str> {
+    tokens.filter(|t| t.starts_with('-')).map(|t| if t == "--set-xmark" { "--set-mark" } else { t }).collect()
+}
+
+/// Ownership fence before a delete: re-reads the rule's chain and checks it
+/// still holds the rule with this profile's comment and exactly the matches
+/// ddnsfw writes, so another tool's similar rule is never the one deleted
+fn is_own_rule(bin: &str, rule: &Rule) -> bool {
+    let Some(listing) = iptables(bin, &["-t", rule.table.name(), "-S", &rule.chain]) else {
+        return false;
+    };
+    lists_own_rule(listing.lines().take(MAX_LISTING_LINES), rule)
+}
+
+/// Whether one of these `-S` lines is `rule` as ddnsfw writes it
+fn lists_own_rule<'a>(mut lines: impl Iterator<Item = &'a str>, rule: &Rule) -> bool {
+    let args = rule.iptables_args("-A");
+    let expected = match_signature(args.iter().skip(2).map(String::as_str));
+    lines.any(|line| {
+        has_managed_comment(line)
+            && managed_rule(line, rule.table, &rule.chain).as_ref() == Some(rule)
+            && match_signature(line.split_whitespace()) == expected
+    })
+}
 
-        if !line.contains(IPTABLES_COMMENT) {
+/// Deletes every rule (companions included) carrying `comment` in the
+/// given scopes by replaying its `-S` line as `-D`. True if all went.
+fn purge_comment(bin: &str, scopes: &HashSet<(Table, String)>, comment: &str) -> bool {
+    let mut ok = true;
+    for (table, chain) in scopes.iter().take(MAX_LOOP_ITERATIONS) {
+        let Some(output) = iptables(bin, &["-t", table.name(), "-S", chain]) else {
+            ok = false;
             continue;
+        };
+        for line in output.lines().take(MAX_LISTING_LINES).filter(|l| has_comment(l, comment)) {
+            let Some(spec) = line.strip_prefix("-A ") else {
+                continue;
+            };
+            let mut args = vec!["-t", table.name(), "-D"];
+            args.extend(spec.split_whitespace().map(|t| t.trim_matches('"')));
+            if !iptables_run(bin, &args) {
+                eprintln!("[ddnsfw] WARN: Could not remove '{}' rule: {}", comment, line);
+                ok = false;
+            }
         }
+    }
+    ok
+}
 
-        if rules.len() >= MAX_RULES {
-            break;
+/// First half of a comment_prefix change: gives every rule tagged with the
+/// old comment a twin under the current one, so the normal sync manages it
+/// from now on. The old rules stay until the whole sync succeeded.
+fn retag_rules(bin: &str, scopes: &HashSet<(Table, String)>, legacy: &str) -> bool {
+    let current = get_existing_rules(bin, scopes);
+    let mut ok = true;
+    for rule in get_tagged_rules(bin, scopes, legacy).difference(&current) {
+        print!("[ddnsfw] Re-tagging {} ({} -> {}) ... ", rule, legacy, profile::current().comment());
+        let _ = io::stdout().flush();
+        if add_rule(bin, rule) {
+            println!("OK");
+        } else {
+            println!("FAILED (keeping old rule)");
+            ok = false;
         }
+    }
+    ok
+}
 
-        let parts: Vec<&str> = line.split_whitespace().collect();
-        let mut ip: Option<Ipv4Addr> = None;
-        let mut port: Option<u16> = None;
+/// Fields of a managed rule as printed by `iptables -S`
+struct ParsedRule<'a> {
+    ip: Ipv4Addr,
+    port: u16,
+    proto: rule::Proto,
+    target: &'a str,
+    connlimit: Option<u32>,
+    mark: Option<u32>,
+    notrack: bool,
+}
 
-        for i in 0..parts.len().min(50) {  // Limit parsing iterations
-            if parts[i] == "-s" && i + 1 < parts.len() {
-                ip = parts[i + 1].trim_end_matches("/32").parse().ok();
-            }
-            if parts[i] == "--dport" && i + 1 < parts.len() {
-                port = parts[i + 1].parse().ok();
-            }
+fn parse_rule_line(line: &str) -> Option<ParsedRule<'_>> {
+    let parts: Vec<&str> = line.split_whitespace().collect();
+    let mut ip: Option<Ipv4Addr> = None;
+    let mut port: Option<u16> = None;
+    let mut proto = rule::Proto::Tcp;
+    let mut target = "";
+    let mut connlimit = None;
+    let mut mark = None;
+    let mut notrack = false;
+
+    for i in 0..parts.len().min(50) {  // Limit parsing iterations
+        let next = parts.get(i + 1).copied().unwrap_or("");
+        match parts[i] {
+            "-s" => ip = next.trim_end_matches("/32").parse().ok(),
+            "--dport" => port = next.parse().ok(),
+            "-p" => proto = rule::Proto::parse(next)?,
+            "-j" => target = next,
+            "--connlimit-above" => connlimit = next.parse().ok(),
+            // -S prints --set-xmark VALUE/MASK
+            "--set-mark" | "--set-xmark" => mark = parse_mark(next.split('/').next().unwrap_or("")),
+            "--notrack" => notrack = true,
+            _ => {}
         }
+    }
+
+    // Rules without --dport (ESP, MSS clamp) match any port
+    let port = match port {
+        Some(port) => port,
+        None if !proto.has_ports() || target == "TCPMSS" => 0,
+        None => return None,
+    };
+    Some(ParsedRule { ip: ip?, port, proto, target, connlimit, mark, notrack })
+}
+
+/// Parses a firewall mark in decimal or 0x-prefixed hex.
+fn parse_mark(s: &str) -> Option<u32> {
+    let mark = match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+        None => s.parse().ok()?,
+    };
+    (mark != 0).then_some(mark)
+}
+
+/// Lists connlimit companion rules as (ip, port, limit).
+fn get_existing_connlimits(bin: &str) -> HashSet<(Ipv4Addr, u16, u32)> {
+    let mut rules = HashSet::new();
+
+    let Some(output) = iptables(bin, &["-S", "INPUT"]) else {
+        return rules;
+    };
 
-        if let (Some(ip), Some(port)) = (ip, port) {
-            rules.insert((ip, port));
+    for line in output.lines().take(MAX_LISTING_LINES) {
+        if !has_managed_comment(line) || rules.len() >= MAX_RULES {
+            continue;
+        }
+        if let Some(rule) = parse_rule_line(line) {
+            if let Some(limit) = rule.connlimit {
+                rules.insert((rule.ip, rule.port, limit));
+            }
         }
     }
 
     rules
 }
 
-fn rule_exists(bin: &str, ip: Ipv4Addr, port: u16) -> bool {
-    iptables_run(
-        bin,
-        &[
-            "-C", "INPUT",
-            "-s", &format!("{}/32", ip),
-            "-p", "tcp",
-            "-m", "tcp",
-            "--dport", &port.to_string(),
-            "-m", "comment",
-            "--comment", IPTABLES_COMMENT,
-            "-j", "ACCEPT",
-        ],
-    )
+fn rule_exists(bin: &str, rule: &Rule) -> bool {
+    let args = rule.iptables_args("-C");
+    iptables_run(bin, &args.iter().map(String::as_str).collect::<Vec<_>>())
 }
 
-/// Add rule - appends to end (not position 1) to maintain order
-fn add_rule(bin: &str, ip: Ipv4Addr, port: u16) -> bool {
-    iptables_run(
-        bin,
-        &[
-            "-I", "INPUT", "1",  // Still insert at 1 for priority over other rules
-            "-s", &format!("{}/32", ip),
-            "-p", "tcp",
-            "-m", "tcp",
-            "--dport", &port.to_string(),
-            "-m", "comment",
-            "--comment", IPTABLES_COMMENT,
-            "-j", "ACCEPT",
-        ],
-    )
+fn add_rule(bin: &str, rule: &Rule) -> bool {
+    let args = rule.iptables_args("-I");
+    iptables_run(bin, &args.iter().map(String::as_str).collect::<Vec<_>>())
 }
 
-fn delete_rule(bin: &str, ip: Ipv4Addr, port: u16) -> bool {
-    iptables_run(
-        bin,
-        &[
-            "-D", "INPUT",
-            "-s", &format!("{}/32", ip),
-            "-p", "tcp",
-            "-m", "tcp",
-            "--dport", &port.to_string(),
-            "-m", "comment",
-            "--comment", IPTABLES_COMMENT,
-            "-j", "ACCEPT",
-        ],
-    )
+/// Adds a rule at `position`; a rule number past the end of the chain
+/// (which iptables refuses) appends instead
+fn add_rule_at(bin: &str, rule: &Rule, position: Position) -> bool {
+    let run = |position| {
+        let args = rule.add_args(position);
+        iptables_run(bin, &args.iter().map(String::as_str).collect::<Vec<_>>())
+    };
+    run(position) || (matches!(position, Position::At(_)) && run(Position::Bottom))
+}
+
+fn delete_rule(bin: &str, rule: &Rule) -> bool {
+    let args = rule.iptables_args("-D");
+    iptables_run(bin, &args.iter().map(String::as_str).collect::<Vec<_>>())
+}
+
+/// Connection-limit companion: rejects connections beyond `limit` from the
+/// allowed IP. Inserted at position 1 so it is evaluated before the ACCEPT.
+fn connlimit_rule(bin: &str, action: &str, ip: Ipv4Addr, port: u16, limit: u32) -> bool {
+    let mut args = vec![action, "INPUT"];
+    if action == "-I" {
+        args.push("1");
+    }
+    let source = format!("{}/32", ip);
+    let port = port.to_string();
+    let limit = limit.to_string();
+    args.extend_from_slice(&[
+        "-s", &source,
+        "-p", "tcp",
+        "-m", "tcp",
+        "--dport", &port,
+        "-m", "connlimit",
+        "--connlimit-above", &limit,
+        "-m", "comment",
+        "--comment", profile::current().comment(),
+        "-j", "REJECT",
+    ]);
+    iptables_run(bin, &args)
 }
 
 // ============================================================================
@@ -450,232 +1110,2641 @@ fn delete_rule(bin: &str, ip: Ipv4Addr, port: u16) -> bool {
 struct DdnsEntry {
     hostname: String,
     port: u16,
+    proto: rule::Proto,  // tcp, or udp from `hostname:port/udp`
+    max_conns: Option<u32>,
+    knock: Option<Vec<u16>>,
+    beacon: Option<String>,
+    mark: Option<u32>,
+    notrack: bool,
+    vpn: Option<vpn::Kind>,
+    table: Table,
+    ipv6: Option<bool>,  // Also allow the AAAA address (None: the global setting)
+    v6_prefix: Option<u8>,  // Allow the whole IPv6 prefix of the resolved address
+    resolvers: Option<Vec<resolver::Spec>>,  // Replaces the global resolver chain
+    jump: Option<String>,  // User chain the allow jumps to instead of ACCEPT
+    returns: bool,  // RETURN instead of ACCEPT (action=return)
+    chain: Option<String>,  // Chain of the allow (None: the table's inbound chain)
+    credentials: Option<String>,  // [credentials NAME] of the policy (kubernetes)
+    position: Position,  // Where in the chain the allow is added
+    max_changes: Option<u32>,  // IP changes per day before freezing (None: the global setting)
+    history: Option<usize>,  // Recent addresses kept allowed (None: the global setting)
+    id: Option<String>,  // Stable name the entry's state is kept under (None: the label)
+    lockdown: Option<lockdown::Mode>,  // Close the port to every other source
+    priority: Priority,  // Class the sync handles it in (critical first)
+    meta: EntryMeta,
+    origin: String,  // file:line the entry was read from
+    spec: String,    // Normalized (expanded, single-spaced) entry text
 }
 
-fn parse_config() -> Vec<DdnsEntry> {
-    let Ok(content) = fs::read_to_string(CONFIG_PATH) else {
-        return Vec::new();
-    };
+impl DdnsEntry {
+    fn new(hostname: String, port: u16) -> Self {
+        DdnsEntry {
+            origin: String::new(),
+            spec: format!("{}:{}", hostname, port),
+            hostname,
+            port,
+            proto: rule::Proto::Tcp,
+            max_conns: None,
+            knock: None,
+            beacon: None,
+            mark: None,
+            notrack: false,
+            vpn: None,
+            table: Table::Filter,
+            ipv6: None,
+            v6_prefix: None,
+            resolvers: None,
+            jump: None,
+            returns: false,
+            chain: None,
+            credentials: None,
+            position: Position::Top,
+            max_changes: None,
+            history: None,
+            id: None,
+            lockdown: None,
+            priority: Priority::Normal,
+            meta: EntryMeta::default(),
+        }
+    }
 
-    let mut entries = Vec::new();
-    let mut iteration = 0;
+    /// The entry's own resolver chain, or the global one
+    fn resolver_chain(&self, config: &Config) -> resolver::Chain {
+        resolver::Chain::build(
+            self.resolvers.as_deref().unwrap_or(&config.resolvers),
+            &config.provider_accounts,
+            &config.nameservers,
+        )
+    }
 
-    for line in content.lines() {
-        iteration += 1;
-        if iteration > MAX_LOOP_ITERATIONS {
-            eprintln!("[ddnsfw] WARN: Config file too large, truncating");
-            break;
-        }
+    /// Display label of the entry (`host:port`, `host:port/udp`)
+    fn label(&self) -> String {
+        format!("{}:{}{}", self.hostname, self.port, self.proto_suffix())
+    }
 
-        if entries.len() >= MAX_ENTRIES {
-            eprintln!("[ddnsfw] WARN: Max {} entries allowed", MAX_ENTRIES);
-            break;
-        }
+    /// What the entry's state (owners, metadata, history, change rate) is
+    /// kept under: its `id=`, or else the label
+    fn key(&self) -> String {
+        self.id.clone().unwrap_or_else(|| self.label())
+    }
 
-        let line = line.trim();
-        if line.is_empty() || line.starts_with('#') {
-            continue;
+    /// `/udp` for UDP entries, nothing for the default TCP
+    fn proto_suffix(&self) -> String {
+        match self.proto {
+            rule::Proto::Tcp => String::new(),
+            proto => format!("/{}", proto.name()),
         }
+    }
 
-        if let Some(colon) = line.rfind(':') {
-            let hostname = line[..colon].trim().to_string();
-            if let Ok(port) = line[colon + 1..].trim().parse::<u16>() {
-                if !hostname.is_empty() && port > 0 {
-                    entries.push(DdnsEntry { hostname, port });
-                }
-            }
+    /// Protocol of the entry's allow rule (VPN peers are always UDP)
+    fn allow_proto(&self) -> rule::Proto {
+        if self.vpn.is_some() {
+            rule::Proto::Udp
+        } else {
+            self.proto
         }
     }
 
-    entries
-}
+    /// Chain the entry's allow goes to (filter: the dedicated chain when on)
+    fn allow_chain(&self) -> &str {
+        match (&self.chain, self.table) {
+            (Some(chain), _) => chain,
+            (None, Table::Filter) => dedicated::inbound(),
+            (None, table) => table.default_chain(),
+        }
+    }
 
-// ============================================================================
-// Crash Recovery
-// ============================================================================
+    /// User chains the entry's rules need: its `chain=` and `jump=`
+    fn user_chains(&self) -> Vec<&str> {
+        let chain = Some(self.allow_chain()).filter(|chain| !self.table.builtin_chains().contains(chain));
+        chain.into_iter().chain(self.jump.as_deref()).collect()
+    }
 
-fn recover_from_crash(iptables_bin: &str, cache: &mut Cache) {
-    match cache.state {
-        CacheState::Idle => {}
-        CacheState::Adding => {
-            if let Some((ip, port)) = cache.pending {
-                println!("[ddnsfw] Recovery: Checking pending add {}:{}", ip, port);
-                if !rule_exists(iptables_bin, ip, port) {
-                    println!("[ddnsfw] Recovery: Re-adding rule {}:{}", ip, port);
-                    if add_rule(iptables_bin, ip, port) {
-                        cache.add_rule(ip, port);
-                    } else {
-                        cache.set_idle();
-                    }
-                } else {
-                    cache.add_rule(ip, port);
-                }
-            } else {
-                cache.set_idle();
+    /// Rules this entry wants for a resolved IP. `mark` on a mangle entry and
+    /// `notrack` on a raw entry are the rule itself; on any other table they
+    /// add a companion in mangle/raw. `jump` (or `action=return`) replaces
+    /// the ACCEPT. VPN entries allow UDP and bring their own companions.
+    fn rules_for(&self, ip: Ipv4Addr) -> Vec<Rule> {
+        let mut main = Rule::allow(ip, self.port);
+        main.table = self.table;
+        main.chain = self.allow_chain().to_string();
+        main.proto = self.allow_proto();
+        main.credentials = self.credentials.clone();
+
+        let mut rules = Vec::new();
+        match self.mark {
+            Some(mark) if self.table == Table::Mangle => main.target = Target::Mark(mark),
+            Some(mark) => {
+                let mut companion = main.clone();
+                companion.table = Table::Mangle;
+                companion.chain = Table::Mangle.default_chain().to_string();
+                companion.target = Target::Mark(mark);
+                rules.push(companion);
             }
+            None => {}
         }
-        CacheState::Deleting => {
-            if let Some((ip, port)) = cache.pending {
-                println!("[ddnsfw] Recovery: Delete interrupted for {}:{}, ignoring", ip, port);
+        if self.notrack {
+            if self.table == Table::Raw {
+                main.target = Target::NoTrack;
+            } else {
+                let mut companion = main.clone();
+                companion.table = Table::Raw;
+                companion.chain = Table::Raw.default_chain().to_string();
+                companion.target = Target::NoTrack;
+                rules.push(companion);
             }
-            cache.set_idle();
         }
+        if let Some(chain) = self.jump.as_ref().filter(|_| main.target == Target::Accept) {
+            main.target = Target::Jump(chain.clone());
+        } else if self.returns && main.target == Target::Accept {
+            main.target = Target::Return;
+        }
+        rules.insert(0, main);
+        if let Some(kind) = self.vpn {
+            rules.extend(vpn::companions(kind, ip));
+        }
+        rules
     }
 }
 
-// ============================================================================
-// Core Sync Algorithm (CRITICAL - Zero Bug Tolerance)
-// ============================================================================
+/// Who an entry belongs to and what it is for, so alerts are attributable
+#[derive(Debug, Clone, Default, PartialEq)]
+struct EntryMeta {
+    owner: Option<String>,
+    description: Option<String>,
+}
 
-fn sync_firewall() {
-    // Acquire exclusive lock to prevent concurrent execution
-    let _lock = match acquire_lock() {
+impl EntryMeta {
+    fn is_empty(&self) -> bool {
+        self.owner.is_none() && self.description.is_none()
+    }
+
+    /// `office VPN exit — owned by ops@`
+    fn summary(&self) -> String {
+        match (&self.description, &self.owner) {
+            (Some(description), Some(owner)) => format!("{} — owned by {}", description, owner),
+            (Some(description), None) => description.clone(),
+            (None, Some(owner)) => format!("owned by {}", owner),
+            (None, None) => String::new(),
+        }
+    }
+}
+
+/// Cache-field escaping for free text (separators and newlines)
+fn meta_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '%' | ',' | '>' | '|' | '\n' | '\r' => out.push_str(&format!("%{:02X}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn meta_unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(index) = rest.find('%') {
+        out.push_str(&rest[..index]);
+        match rest.get(index + 1..index + 3).and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+            Some(byte) => {
+                out.push(char::from(byte));
+                rest = &rest[index + 3..];
+            }
+            None => {
+                out.push('%');
+                rest = &rest[index + 1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Splits an entry line on whitespace, keeping `"double quoted"` runs
+/// (quotes removed) together so option values may contain spaces
+fn split_tokens(line: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut started = false;
+    for c in line.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                started = true;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if started {
+                    tokens.push(std::mem::take(&mut current));
+                    started = false;
+                }
+            }
+            c => {
+                current.push(c);
+                started = true;
+            }
+        }
+    }
+    if started {
+        tokens.push(current);
+    }
+    tokens
+}
+
+/// IPv6 prefix length for `v6_prefix` (privacy addresses rotate within /64)
+fn parse_v6_prefix(s: &str) -> Option<u8> {
+    s.trim_start_matches('/').parse().ok().filter(|len| (16..=128).contains(len))
+}
+
+/// `tcp` or `udp` after an entry's port (ESP has no ports)
+fn parse_entry_proto(s: &str) -> Option<rule::Proto> {
+    rule::Proto::parse(s).filter(|proto| proto.has_ports())
+}
+
+/// Parses `hostname:port[/tcp|/udp] [option=value ...]`.
+fn parse_entry_line(line: &str) -> Option<DdnsEntry> {
+    let tokens = split_tokens(line);
+    let mut tokens = tokens.iter().map(String::as_str);
+    let target = tokens.next()?;
+
+    let colon = target.rfind(':')?;
+    let hostname = target[..colon].to_string();
+    let (port, proto) = match target[colon + 1..].split_once('/') {
+        Some((port, proto)) => (port, parse_entry_proto(proto)?),
+        None => (&target[colon + 1..], rule::Proto::Tcp),
+    };
+    let port: u16 = port.parse().ok()?;
+    if hostname.is_empty() || port == 0 {
+        return None;
+    }
+
+    let mut entry = DdnsEntry::new(hostname, port);
+    entry.proto = proto;
+    entry.spec = line.split_whitespace().collect::<Vec<_>>().join(" ");
+
+    for option in tokens.take(MAX_LOOP_ITERATIONS) {
+        let Some((key, value)) = option.split_once('=') else {
+            eprintln!("[ddnsfw] WARN: Ignoring malformed option '{}' for {}", option, target);
+            continue;
+        };
+        match key {
+            "max_conns" => match value.parse::<u32>() {
+                Ok(n) if n > 0 => entry.max_conns = Some(n),
+                _ => eprintln!("[ddnsfw] WARN: Invalid max_conns '{}' for {}", value, target),
+            },
+            "knock" => match knock::parse_sequence(value, port) {
+                Some(sequence) => entry.knock = Some(sequence),
+                None => eprintln!("[ddnsfw] WARN: Invalid knock sequence '{}' for {}", value, target),
+            },
+            "mark" => match parse_mark(value) {
+                Some(mark) => entry.mark = Some(mark),
+                None => eprintln!("[ddnsfw] WARN: Invalid mark '{}' for {}", value, target),
+            },
+            "notrack" => match value {
+                "yes" | "true" | "1" => entry.notrack = true,
+                "no" | "false" | "0" => entry.notrack = false,
+                _ => eprintln!("[ddnsfw] WARN: Invalid notrack '{}' for {}", value, target),
+            },
+            "vpn" => match vpn::Kind::parse(value) {
+                Some(kind) => entry.vpn = Some(kind),
+                // A plain TCP allow would not let the tunnel up
+                None => exit_err(&format!("Unknown vpn '{}' for {} (wireguard, ipsec)", value, target)),
+            },
+            "ipv6" => match value {
+                "yes" | "true" | "1" => entry.ipv6 = Some(true),
+                "no" | "false" | "0" => entry.ipv6 = Some(false),
+                _ => eprintln!("[ddnsfw] WARN: Invalid ipv6 '{}' for {}", value, target),
+            },
+            "v6_prefix" => match parse_v6_prefix(value) {
+                Some(len) => entry.v6_prefix = Some(len),
+                None => eprintln!("[ddnsfw] WARN: Invalid v6_prefix '{}' for {}", value, target),
+            },
+            "resolver" => match resolver::parse_chain(value) {
+                Some(chain) => entry.resolvers = Some(chain),
+                None => eprintln!("[ddnsfw] WARN: Invalid resolver '{}' for {}", value, target),
+            },
+            "jump" => {
+                if rule::is_valid_chain_name(value) {
+                    entry.jump = Some(value.to_string());
+                } else {
+                    // Falling back to ACCEPT would skip the chain's filtering
+                    exit_err(&format!("Invalid jump chain '{}' for {}", value, target));
+                }
+            }
+            "action" => match value {
+                "accept" => (entry.jump, entry.returns) = (None, false),
+                "return" => (entry.jump, entry.returns) = (None, true),
+                chain if rule::is_valid_chain_name(chain) => (entry.jump, entry.returns) = (Some(chain.to_string()), false),
+                _ => exit_err(&format!("Invalid action '{}' for {} (accept, return or a user chain)", value, target)),
+            },
+            "chain" => {
+                // Checked against the table once all options are read
+                if value.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') && !value.is_empty() {
+                    entry.chain = Some(value.to_string());
+                } else {
+                    exit_err(&format!("Invalid chain '{}' for {}", value, target));
+                }
+            }
+            "credentials" => {
+                // Checked against the [credentials] sections once the config is read
+                if kube::is_valid_name(value) {
+                    entry.credentials = Some(value.to_string());
+                } else {
+                    exit_err(&format!("Invalid credentials '{}' for {}", value, target));
+                }
+            }
+            "position" => match Position::parse(value) {
+                Some(position) => entry.position = position,
+                None => eprintln!("[ddnsfw] WARN: Invalid position '{}' for {} (top, bottom or a rule number)", value, target),
+            },
+            "max_changes_per_day" => match value.parse::<u32>() {
+                Ok(n) if n <= churn::MAX_CHANGES_PER_DAY => entry.max_changes = Some(n),
+                _ => eprintln!("[ddnsfw] WARN: Invalid max_changes_per_day '{}' for {}", value, target),
+            },
+            "history" => match value.parse::<usize>() {
+                Ok(n) if (1..=MAX_HISTORY).contains(&n) => entry.history = Some(n),
+                _ => eprintln!("[ddnsfw] WARN: Invalid history '{}' for {}", value, target),
+            },
+            "lockdown" => match lockdown::Mode::parse(value) {
+                Some(mode) => entry.lockdown = Some(mode),
+                // Leaving the port open would be the opposite of what was asked
+                None => exit_err(&format!("Unknown lockdown '{}' for {} (drop, reject)", value, target)),
+            },
+            "priority" => match Priority::parse(value) {
+                Some(priority) => entry.priority = priority,
+                None => eprintln!("[ddnsfw] WARN: Invalid priority '{}' for {} (critical, normal, best-effort)", value, target),
+            },
+            "table" => match Table::parse(value) {
+                Some(table) => entry.table = table,
+                // A rule in the wrong table would silently not apply
+                None => exit_err(&format!("Unknown table '{}' for {}", value, target)),
+            },
+            "id" => {
+                if is_valid_entry_id(value) {
+                    entry.id = Some(value.to_string());
+                } else {
+                    // Falling back to the label would orphan the entry's state
+                    exit_err(&format!(
+                        "Invalid id '{}' for {} (1-{} letters, digits, '.', '_', '-'; not ending in -grant)",
+                        value, target, MAX_ENTRY_ID_LEN
+                    ));
+                }
+            }
+            "owner" | "description" => {
+                let value = value.trim();
+                if value.is_empty() || value.len() > MAX_META_LEN {
+                    eprintln!("[ddnsfw] WARN: Invalid {} for {} (1-{} characters)", key, target, MAX_META_LEN);
+                } else if key == "owner" {
+                    entry.meta.owner = Some(value.to_string());
+                } else {
+                    entry.meta.description = Some(value.to_string());
+                }
+            }
+            "beacon" => {
+                if beacon::is_valid_url(value) {
+                    entry.beacon = Some(value.to_string());
+                } else {
+                    // Dropping the option would silently weaken the entry
+                    exit_err(&format!("Invalid beacon URL '{}' for {} (https required)", value, target));
+                }
+            }
+            _ => eprintln!("[ddnsfw] WARN: Unknown option '{}' for {}", key, target),
+        }
+    }
+
+    // A chain of another table would put the allow where no packet passes
+    if let Some(chain) = &entry.chain {
+        // A builtin name (mangle has them all) must be one of this table's
+        let builtin = Table::Mangle.builtin_chains().contains(&chain.as_str());
+        if builtin != entry.table.builtin_chains().contains(&chain.as_str()) || !(builtin || rule::is_valid_chain_name(chain)) {
+            exit_err(&format!("Chain '{}' for {} is not a chain of table {}", chain, target, entry.table.name()));
+        }
+        if entry.jump.as_ref() == Some(chain) {
+            exit_err(&format!("Chain {} for {} would jump into itself", chain, target));
+        }
+    }
+    // Connection limits, knocks and lockdowns guard filter/INPUT
+    if entry.table == Table::Filter && entry.chain.as_ref().is_some_and(|chain| chain != "INPUT") && (entry.max_conns.is_some() || entry.knock.is_some() || entry.lockdown.is_some()) {
+        eprintln!("[ddnsfw] WARN: max_conns, knock and lockdown only apply to INPUT, ignoring them for {}", target);
+        entry.max_conns = None;
+        entry.knock = None;
+        entry.lockdown = None;
+    }
+    // The companions match TCP connections only
+    if entry.allow_proto() != rule::Proto::Tcp && (entry.max_conns.is_some() || entry.knock.is_some()) {
+        eprintln!("[ddnsfw] WARN: max_conns and knock only apply to TCP entries, ignoring them for {}", target);
+        entry.max_conns = None;
+        entry.knock = None;
+    }
+    if entry.lockdown.is_some() && entry.table != Table::Filter {
+        eprintln!("[ddnsfw] WARN: lockdown only applies to filter-table entries, ignoring it for {}", target);
+        entry.lockdown = None;
+    }
+    if entry.lockdown.is_some() && entry.position == Position::Bottom {
+        eprintln!("[ddnsfw] WARN: position=bottom would put the allow below the lockdown of {}, inserting it at the top", target);
+        entry.position = Position::Top;
+    }
+
+    Some(entry)
+}
+
+/// `id=` values: no `:`, so they never collide with a label, and not a
+/// grant's owner name
+fn is_valid_entry_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_ENTRY_ID_LEN
+        && id.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+        && !id.ends_with("-grant")
+}
+
+/// Firewall backend the resolved rules are written to
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BackendKind {
+    Iptables,
+    Proxmox,
+    Kubernetes,
+    Nftables,
+    Ipset,
+    Firewalld,
+}
+
+impl BackendKind {
+    /// External tool the backend drives
+    fn tool(&self) -> &'static str {
+        match self {
+            BackendKind::Iptables => "iptables",
+            BackendKind::Proxmox => "pvesh",
+            BackendKind::Kubernetes => "kubectl",
+            BackendKind::Nftables => "nft",
+            BackendKind::Ipset => "ipset",
+            BackendKind::Firewalld => "firewall-cmd",
+        }
+    }
+}
+
+struct Config {
+    backend: BackendKind,
+    proxmox_ipset: String,
+    kube: kube::Settings,
+    nft: nft::Settings,
+    firewalld_zone: Option<String>,  // None: firewalld's default zone
+    dedicated_chain: bool,  // INPUT allows in their own chain, behind one jump
+    otp_secret: Option<String>,
+    otp_minutes: u64,
+    beacon_key: Option<String>,
+    beacon_max_age: u64,
+    push_listen: Option<SocketAddr>,  // UDP address for signed pushes (daemon)
+    push_key: Option<String>,
+    push_max_age: u64,                // How long a push outranks the resolvers
+    webhook_listen: Option<SocketAddr>,  // HTTP address for provider webhooks (daemon)
+    webhook_token: Option<String>,
+    metrics_file: Option<String>,        // node_exporter textfile, rewritten each sync
+    metrics_listen: Option<SocketAddr>,  // HTTP address for /metrics (daemon)
+    backup_keep: usize,     // Snapshots kept under /var/backups/ddnsfw
+    backup_interval: u64,   // Scheduled snapshot age (0: only before edits)
+    perms_check: perms::Check,  // Permissions audit after timer syncs
+    syslog: Option<syslog::Target>,
+    enrich: enrich::Settings,
+    ipv6: bool,  // Entries also allow their AAAA address (per-entry ipv6= overrides)
+    v6_prefix: Option<u8>,
+    dns_select: DnsSelect,
+    dns_max_addrs: usize,  // Addresses an entry allows with dns_select = all
+    nameservers: Vec<IpAddr>,  // For the native resolver (empty: /etc/resolv.conf)
+    resolvers: Vec<resolver::Spec>,             // Global resolver chain
+    jitter: u64,                                // Max random delay (s) before a scheduled sync
+    dns_cache: bool,                            // Share network answers with other profiles
+    self_ips: Vec<Ipv4Addr>,                    // This server's addresses not on an interface (NAT)
+    cgnat: cgnat::Mode,                         // Answers in CGNAT/DS-Lite space
+    interval: Option<u64>,                      // Daemon interval (s) when --interval is not given
+    backoff_max: u64,                           // Longest daemon interval (s) while nothing changes (0: off)
+    batch_window: u64,                          // Daemon triggers within this many seconds make one sync (0: off)
+    provider_intervals: Vec<(String, u64)>,     // Domain suffix -> minimum seconds between lookups
+    watchdogs: Vec<watchdog::Watchdog>,         // Always-on management ACCEPTs
+    state_retention: u64,                       // Days a due retry is kept before pruning
+    grace_seconds: u64,                         // How long an entry's old address stays allowed (0: off)
+    history: usize,                             // Recent addresses per entry kept allowed (1: the current only)
+    escalation: escalate::Policy,               // Failure streak thresholds and commands
+    max_changes_per_day: u32,                   // IP changes per entry before freezing (0: off)
+    strict: bool,                               // Any partial failure fails the run
+    verbose: bool,                              // Log unchanged entries too
+    bundles: HashMap<String, Vec<u16>>,  // [bundle NAME] -> ports
+    provider_accounts: Vec<provider::Account>,  // Credentials for wildcard entries
+    wildcards: Vec<provider::Wildcard>,  // provider:account/*:port lines, expanded per sync
+    srv_entries: Vec<srv::SrvEntry>,  // _service._tcp.domain lines, expanded per sync
+    entries: Vec<DdnsEntry>,
+    skipped_entries: usize,  // Entries beyond MAX_ENTRIES (not loaded)
+    diagnostics: Vec<String>,  // Line-numbered problems found while parsing
+}
+
+impl Config {
+    fn new() -> Self {
+        Config {
+            backend: BackendKind::Iptables,
+            proxmox_ipset: String::from("ddnsfw"),
+            kube: kube::Settings::new(),
+            nft: nft::Settings::new(),
+            firewalld_zone: None,
+            dedicated_chain: false,
+            otp_secret: None,
+            otp_minutes: 60,
+            beacon_key: None,
+            beacon_max_age: 600,
+            push_listen: None,
+            push_key: None,
+            push_max_age: 900,
+            webhook_listen: None,
+            metrics_file: None,
+            metrics_listen: None,
+            webhook_token: None,
+            backup_keep: backup::DEFAULT_KEEP,
+            backup_interval: 0,
+            perms_check: perms::Check::Repair,
+            syslog: None,
+            enrich: enrich::Settings::default(),
+            ipv6: false,
+            v6_prefix: None,
+            dns_select: DnsSelect::First,
+            dns_max_addrs: DEFAULT_DNS_ADDRS,
+            nameservers: Vec::new(),
+            resolvers: resolver::default_chain(),
+            jitter: 0,
+            dns_cache: false,
+            self_ips: Vec::new(),
+            cgnat: cgnat::Mode::PreferV6,
+            interval: None,
+            backoff_max: 0,
+            batch_window: 0,
+            provider_intervals: Vec::new(),
+            watchdogs: Vec::new(),
+            state_retention: prune::DEFAULT_RETENTION_DAYS,
+            grace_seconds: 0,
+            history: 1,
+            escalation: escalate::Policy::new(),
+            max_changes_per_day: 0,
+            strict: false,
+            verbose: false,
+            bundles: HashMap::new(),
+            provider_accounts: Vec::new(),
+            wildcards: Vec::new(),
+            srv_entries: Vec::new(),
+            entries: Vec::new(),
+            skipped_entries: 0,
+            diagnostics: Vec::new(),
+        }
+    }
+}
+
+/// Drops a trailing comment: a `#` after whitespace and outside double
+/// quotes, so `history=3   # or per entry` reads as `history=3`
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    let mut after_space = false;
+    for (index, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '#' if after_space && !quoted => return line[..index].trim_end(),
+            _ => {}
+        }
+        after_space = c.is_whitespace();
+    }
+    line
+}
+
+/// Splits a `key = value` settings line. Entry lines never contain '='.
+fn parse_setting_line(line: &str) -> Option<(&str, &str)> {
+    let (key, value) = line.split_once('=')?;
+    let key = key.trim();
+    if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return None;
+    }
+    Some((key, value.trim()))
+}
+
+/// Splits a `$NAME = value` variable definition.
+fn parse_variable_line(line: &str) -> Option<(&str, &str)> {
+    parse_setting_line(line.strip_prefix('$')?)
+}
+
+/// Substitutes `$NAME` references; on failure returns the undefined name.
+fn expand_variables(line: &str, vars: &HashMap<String, String>) -> Result<String, String> {
+    let mut out = String::with_capacity(line.len());
+    let mut rest = line;
+    while let Some(pos) = rest.find('$') {
+        out.push_str(&rest[..pos]);
+        let after = &rest[pos + 1..];
+        let len = after
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(after.len());
+        let name = &after[..len];
+        match vars.get(name) {
+            Some(value) => out.push_str(value),
+            None => return Err(name.to_string()),
+        }
+        rest = &after[len..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+fn apply_setting(config: &mut Config, key: &str, value: &str) {
+    match key {
+        "backend" => {
+            config.backend = match value {
+                "iptables" => BackendKind::Iptables,
+                "proxmox" => BackendKind::Proxmox,
+                "kubernetes" => BackendKind::Kubernetes,
+                "nftables" | "nft" => BackendKind::Nftables,
+                "ipset" => BackendKind::Ipset,
+                "firewalld" => BackendKind::Firewalld,
+                // Refuse to guess: writing to the wrong firewall is never safe
+                _ => exit_err(&format!(
+                    "Unknown backend '{}' (iptables, proxmox, kubernetes, nftables, ipset, firewalld)",
+                    value
+                )),
+            };
+        }
+        "schema" => {
+            check_schema(
+                "config",
+                &profile::current().config_path,
+                value,
+                CONFIG_SCHEMA,
+                "remove the settings it introduced and lower the schema line",
+            );
+        }
+        "comment_prefix" => {
+            // A wrong comment would make every managed rule look foreign
+            if !profile::is_valid_comment_prefix(value) {
+                exit_err(&format!("Invalid comment_prefix '{}' (use A-Z, a-z, 0-9, '-', '_')", value));
+            }
+            if !profile::current().set_comment_prefix(value) {
+                exit_err("comment_prefix changed while running");
+            }
+        }
+        "cache_store" => {
+            if !mirror::select(value) {
+                exit_err("cache_store changed while running");
+            }
+        }
+        "proxmox_ipset" => {
+            if proxmox::is_valid_ipset_name(value) {
+                config.proxmox_ipset = value.to_string();
+            } else {
+                eprintln!("[ddnsfw] WARN: Invalid proxmox_ipset '{}', using default", value);
+            }
+        }
+        "kube_namespace" | "kube_policy" => {
+            if !kube::is_valid_name(value) {
+                exit_err(&format!("Invalid {} '{}'", key, value));
+            }
+            if key == "kube_namespace" {
+                config.kube.namespace = value.to_string();
+            } else {
+                config.kube.policy = value.to_string();
+            }
+        }
+        "kube_kubeconfig" | "kube_context" => {
+            // Checked as in a [credentials] section
+            let mut profile = kube::Profile::default();
+            if let Err(reason) = profile.set(&key["kube_".len()..], value) {
+                exit_err(&format!("{} for {}", reason, key));
+            }
+            if key == "kube_kubeconfig" {
+                config.kube.kubeconfig = profile.kubeconfig;
+            } else {
+                config.kube.context = profile.context;
+            }
+        }
+        "kube_selector" => {
+            config.kube.selector = kube::parse_selector(value)
+                .unwrap_or_else(|| exit_err(&format!("Invalid kube_selector '{}'", value)));
+        }
+        "kube_flavor" => {
+            config.kube.flavor = kube::Flavor::parse(value)
+                .unwrap_or_else(|| exit_err(&format!("Unknown kube_flavor '{}'", value)));
+        }
+        "nft_table" => {
+            if !nft::is_valid_table_name(value) {
+                // Writing to a differently named table would abandon the sets
+                exit_err(&format!("Invalid nft_table '{}'", value));
+            }
+            config.nft.table = value.to_string();
+        }
+        "firewalld_zone" => {
+            if !firewalld::is_valid_zone(value) {
+                // Rules in another zone would not apply where expected
+                exit_err(&format!("Invalid firewalld_zone '{}'", value));
+            }
+            config.firewalld_zone = Some(value.to_string());
+        }
+        "nft_grace" => match value.parse::<u64>() {
+            Ok(secs) if (nft::MIN_GRACE_SECS..=nft::MAX_GRACE_SECS).contains(&secs) => config.nft.grace = secs,
+            _ => eprintln!(
+                "[ddnsfw] WARN: Invalid nft_grace '{}' ({}-{} seconds), using {}",
+                value,
+                nft::MIN_GRACE_SECS,
+                nft::MAX_GRACE_SECS,
+                config.nft.grace
+            ),
+        },
+        "otp_secret" => config.otp_secret = Some(value.to_string()),
+        "otp_minutes" => match value.parse::<u64>() {
+            Ok(m) if (1..=1440).contains(&m) => config.otp_minutes = m,
+            _ => eprintln!("[ddnsfw] WARN: Invalid otp_minutes '{}', using {}", value, config.otp_minutes),
+        },
+        "beacon_key" => config.beacon_key = Some(value.to_string()),
+        "beacon_max_age" => match value.parse::<u64>() {
+            Ok(secs) if secs >= 60 => config.beacon_max_age = secs,
+            _ => eprintln!("[ddnsfw] WARN: Invalid beacon_max_age '{}', using {}", value, config.beacon_max_age),
+        },
+        "push_listen" => match value.parse::<SocketAddr>() {
+            Ok(addr) => config.push_listen = Some(addr),
+            Err(_) => exit_err(&format!("Invalid push_listen '{}' (use ip:port)", value)),
+        },
+        "push_key" if value.len() >= push::MIN_KEY_LEN => config.push_key = Some(value.to_string()),
+        "push_key" => exit_err(&format!("push_key is too short (at least {} characters)", push::MIN_KEY_LEN)),
+        "push_max_age" => match value.parse::<u64>() {
+            Ok(secs) if (60..=86400).contains(&secs) => config.push_max_age = secs,
+            _ => eprintln!("[ddnsfw] WARN: Invalid push_max_age '{}', using {}", value, config.push_max_age),
+        },
+        "backup_keep" => match value.parse::<usize>() {
+            Ok(n) if (1..=backup::MAX_KEEP).contains(&n) => config.backup_keep = n,
+            _ => eprintln!("[ddnsfw] WARN: Invalid backup_keep '{}' (1-{}), using {}", value, backup::MAX_KEEP, config.backup_keep),
+        },
+        "backup_interval" => match value.parse::<u64>() {
+            Ok(secs) if secs == 0 || (3600..=30 * 86400).contains(&secs) => config.backup_interval = secs,
+            _ => eprintln!("[ddnsfw] WARN: Invalid backup_interval '{}' (0 or 3600-2592000 seconds)", value),
+        },
+        "webhook_listen" => match value.parse::<SocketAddr>() {
+            Ok(addr) => config.webhook_listen = Some(addr),
+            Err(_) => exit_err(&format!("Invalid webhook_listen '{}' (use ip:port)", value)),
+        },
+        "metrics_file" if metrics::is_valid_file(value) => config.metrics_file = Some(value.to_string()),
+        "metrics_file" => eprintln!("[ddnsfw] WARN: Invalid metrics_file '{}' (absolute path ending in .prom)", value),
+        "metrics_listen" => match value.parse::<SocketAddr>() {
+            Ok(addr) => config.metrics_listen = Some(addr),
+            Err(_) => exit_err(&format!("Invalid metrics_listen '{}' (use ip:port)", value)),
+        },
+        "webhook_token" if webhook::is_valid_token(value) => config.webhook_token = Some(value.to_string()),
+        "webhook_token" => exit_err(&format!(
+            "Invalid webhook_token (at least {} letters, digits, '-' or '_')",
+            webhook::MIN_TOKEN_LEN
+        )),
+        "watchdog" => match watchdog::Watchdog::parse(value) {
+            Some(watchdog) if !config.watchdogs.contains(&watchdog) => config.watchdogs.push(watchdog),
+            Some(_) => {}
+            // Silently missing the fallback path defeats its purpose
+            None => exit_err(&format!("Invalid watchdog '{}' (use ip[/len]:port[/udp])", value)),
+        },
+        "jitter" => match value.parse::<u64>() {
+            Ok(secs) if secs <= throttle::MAX_JITTER_SECS => config.jitter = secs,
+            _ => eprintln!("[ddnsfw] WARN: Invalid jitter '{}' (0-{} seconds)", value, throttle::MAX_JITTER_SECS),
+        },
+        "dedicated_chain" => match value {
+            "yes" | "true" | "1" => config.dedicated_chain = true,
+            "no" | "false" | "0" => config.dedicated_chain = false,
+            _ => eprintln!("[ddnsfw] WARN: Invalid dedicated_chain '{}' (yes/no)", value),
+        },
+        "dns_cache" => match value {
+            "yes" | "true" | "1" => config.dns_cache = true,
+            "no" | "false" | "0" => config.dns_cache = false,
+            _ => eprintln!("[ddnsfw] WARN: Invalid dns_cache '{}' (yes/no)", value),
+        },
+        "self_ip" => match value.split(',').map(|ip| ip.trim().parse()).collect::<Result<Vec<Ipv4Addr>, _>>() {
+            Ok(ips) => config.self_ips.extend(ips),
+            Err(_) => eprintln!("[ddnsfw] WARN: Invalid self_ip '{}' (use IP[,IP...])", value),
+        },
+        "interval" => match daemon::parse_interval(value) {
+            Some(secs) => config.interval = Some(secs),
+            None => eprintln!(
+                "[ddnsfw] WARN: Invalid interval '{}' ({}-{} seconds)",
+                value,
+                daemon::MIN_INTERVAL_SECS,
+                daemon::MAX_INTERVAL_SECS
+            ),
+        },
+        "backoff_max" => match value.parse::<u64>() {
+            Ok(secs) if secs <= daemon::MAX_INTERVAL_SECS => config.backoff_max = secs,
+            _ => eprintln!("[ddnsfw] WARN: Invalid backoff_max '{}' (0-{} seconds)", value, daemon::MAX_INTERVAL_SECS),
+        },
+        "batch_window" => match value.parse::<u64>() {
+            Ok(secs) if secs <= daemon::MAX_BATCH_WINDOW_SECS => config.batch_window = secs,
+            _ => eprintln!("[ddnsfw] WARN: Invalid batch_window '{}' (0-{} seconds)", value, daemon::MAX_BATCH_WINDOW_SECS),
+        },
+        "provider_interval" => match throttle::parse_interval(value) {
+            Some((domain, secs)) => {
+                config.provider_intervals.retain(|(d, _)| *d != domain);
+                config.provider_intervals.push((domain, secs));
+            }
+            None => eprintln!("[ddnsfw] WARN: Invalid provider_interval '{}' (use domain:seconds)", value),
+        },
+        "state_retention" => match value.parse::<u64>() {
+            Ok(days) if days <= prune::MAX_RETENTION_DAYS => config.state_retention = days,
+            _ => eprintln!(
+                "[ddnsfw] WARN: Invalid state_retention '{}' (0-{} days), using {}",
+                value,
+                prune::MAX_RETENTION_DAYS,
+                config.state_retention
+            ),
+        },
+        "grace_seconds" => match value.parse::<u64>() {
+            Ok(secs) if secs <= MAX_GRACE_SECS => config.grace_seconds = secs,
+            _ => eprintln!(
+                "[ddnsfw] WARN: Invalid grace_seconds '{}' (0-{} seconds), using {}",
+                value,
+                MAX_GRACE_SECS,
+                config.grace_seconds
+            ),
+        },
+        "history" => match value.parse::<usize>() {
+            Ok(n) if (1..=MAX_HISTORY).contains(&n) => config.history = n,
+            _ => eprintln!("[ddnsfw] WARN: Invalid history '{}' (1-{} addresses), using {}", value, MAX_HISTORY, config.history),
+        },
+        "escalate_notify" | "escalate_alert" => match value.parse::<u32>() {
+            Ok(n) if n <= escalate::MAX_THRESHOLD => {
+                if key == "escalate_notify" {
+                    config.escalation.notify_after = n;
+                } else {
+                    config.escalation.alert_after = n;
+                }
+            }
+            _ => eprintln!("[ddnsfw] WARN: Invalid {} '{}' (0-{} failed syncs)", key, value, escalate::MAX_THRESHOLD),
+        },
+        "max_changes_per_day" => match value.parse::<u32>() {
+            Ok(n) if n <= churn::MAX_CHANGES_PER_DAY => config.max_changes_per_day = n,
+            _ => eprintln!(
+                "[ddnsfw] WARN: Invalid max_changes_per_day '{}' (0-{}, 0: off)",
+                value,
+                churn::MAX_CHANGES_PER_DAY
+            ),
+        },
+        "notify_command" | "alert_command" => {
+            if !escalate::is_safe_command(value) {
+                eprintln!("[ddnsfw] WARN: Ignoring {} '{}' (must be an absolute path, root-owned, not group/world-writable)", key, value);
+            } else if key == "notify_command" {
+                config.escalation.notify_command = Some(value.to_string());
+            } else {
+                config.escalation.alert_command = Some(value.to_string());
+            }
+        }
+        "provider_account" => match provider::Account::parse(value) {
+            Ok(account) => {
+                config.provider_accounts.retain(|a| a.name() != account.name());
+                config.provider_accounts.push(account);
+            }
+            // Its wildcard entries would vanish, and their rules with them
+            Err(reason) => exit_err(&format!("Invalid provider_account: {}", reason)),
+        },
+        "resolver" => match resolver::parse_chain(value) {
+            Some(chain) => config.resolvers = chain,
+            None => eprintln!(
+                "[ddnsfw] WARN: Invalid resolver '{}' (override, native, getent, system, resolved, dns:IP, doh:URL, api:ACCOUNT), using override, native",
+                value
+            ),
+        },
+        "dns_select" => match DnsSelect::parse(value) {
+            Some(select) => config.dns_select = select,
+            None => eprintln!("[ddnsfw] WARN: Invalid dns_select '{}', using first", value),
+        },
+        "nameservers" => match dns::parse_nameservers(value) {
+            Some(servers) => config.nameservers = servers,
+            None => eprintln!(
+                "[ddnsfw] WARN: Invalid nameservers '{}' (1-{} IP addresses), using /etc/resolv.conf",
+                value,
+                dns::MAX_NAMESERVERS
+            ),
+        },
+        "dns_max_addrs" => match value.parse::<usize>() {
+            Ok(n) if (1..=MAX_DNS_ADDRS).contains(&n) => config.dns_max_addrs = n,
+            _ => eprintln!(
+                "[ddnsfw] WARN: Invalid dns_max_addrs '{}' (1-{}), using {}",
+                value, MAX_DNS_ADDRS, DEFAULT_DNS_ADDRS
+            ),
+        },
+        "v6_prefix" => match parse_v6_prefix(value) {
+            Some(len) => config.v6_prefix = Some(len),
+            None => eprintln!("[ddnsfw] WARN: Invalid v6_prefix '{}' (16-128)", value),
+        },
+        "perms_check" => match perms::Check::parse(value) {
+            Some(check) => config.perms_check = check,
+            None => eprintln!("[ddnsfw] WARN: Invalid perms_check '{}' (repair, warn, off)", value),
+        },
+        "syslog_target" => match syslog::Target::parse(value) {
+            Some(target) => config.syslog = Some(target),
+            None => eprintln!("[ddnsfw] WARN: Invalid syslog_target '{}' (udp|tcp|tls://host:port)", value),
+        },
+        "strict" => match value {
+            "yes" | "true" | "1" => config.strict = true,
+            "no" | "false" | "0" => config.strict = false,
+            _ => eprintln!("[ddnsfw] WARN: Invalid strict '{}' (yes/no)", value),
+        },
+        "cgnat" => match cgnat::Mode::parse(value) {
+            Some(mode) => config.cgnat = mode,
+            None => eprintln!("[ddnsfw] WARN: Invalid cgnat '{}' (prefer-v6, warn, off)", value),
+        },
+        "ipv6" => match value {
+            "yes" | "true" | "1" => config.ipv6 = true,
+            "no" | "false" | "0" => config.ipv6 = false,
+            _ => eprintln!("[ddnsfw] WARN: Invalid ipv6 '{}' (yes/no)", value),
+        },
+        "verbose" => match value {
+            "yes" | "true" | "1" => config.verbose = true,
+            "no" | "false" | "0" => config.verbose = false,
+            _ => eprintln!("[ddnsfw] WARN: Invalid verbose '{}' (yes/no)", value),
+        },
+        "enrich_rdns" => match value {
+            "yes" | "true" | "1" => config.enrich.rdns = true,
+            "no" | "false" | "0" => config.enrich.rdns = false,
+            _ => eprintln!("[ddnsfw] WARN: Invalid enrich_rdns '{}' (yes/no)", value),
+        },
+        "geoip_city_db" | "geoip_asn_db" => {
+            if !enrich::is_valid_db(value) {
+                eprintln!("[ddnsfw] WARN: Ignoring {} '{}' (not an existing absolute path)", key, value);
+            } else if key == "geoip_city_db" {
+                config.enrich.city_db = Some(value.to_string());
+            } else {
+                config.enrich.asn_db = Some(value.to_string());
+            }
+        }
+        _ => eprintln!("[ddnsfw] WARN: Unknown setting '{}'", key),
+    }
+}
+
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+fn parse_config() -> Config {
+    let mut config = Config::new();
+    let profile = profile::current();
+    if tomlconfig::is_active() {
+        tomlconfig::load(Path::new(&profile.config_path), &mut config, 0);
+        if Path::new(&profile.legacy_config_path).exists() {
+            config.diagnostics.push(format!("{}: not read, {} is the config", profile.legacy_config_path, profile.config_path));
+        }
+    } else {
+        let mut vars: HashMap<String, String> = HashMap::new();
+        load_config_file(Path::new(&profile.config_path), &mut config, &mut vars, 0);
+    }
+    envconfig::apply(&mut config);
+    provider::check(&config);
+    let chains = std::iter::once(&config.resolvers).chain(config.entries.iter().filter_map(|e| e.resolvers.as_ref()));
+    for chain in chains {
+        if let Some(name) = resolver::unknown_accounts(chain, &config.provider_accounts).first() {
+            exit_err(&format!("Unknown provider_account '{}' in resolver api:{}", name, name));
+        }
+    }
+    if let Err(reason) = kube::check(&config) {
+        exit_err(&reason);
+    }
+    if config.push_listen.is_some() && config.push_key.is_none() {
+        exit_err("push_listen requires push_key");
+    }
+    if config.webhook_listen.is_some() && config.webhook_token.is_none() {
+        exit_err("webhook_listen requires webhook_token");
+    }
+    if config.dedicated_chain && !dedicated::fits_profile() {
+        exit_err("Profile name too long for the dedicated chain name (at most 21 characters)");
+    }
+    if privsep::routed() {
+        if let Err(reason) = privsep::check_config(&config) {
+            exit_err(&reason);
+        }
+    }
+    // The service user's helper makes no chains
+    dedicated::configure(config.dedicated_chain && matches!(config.backend, BackendKind::Iptables) && !privsep::routed());
+    let overlaps = find_overlaps(&config.entries);
+    config.diagnostics.extend(overlaps);
+    config
+}
+
+/// Reads one config file into `config`, following `include` directives.
+fn load_config_file(path: &Path, config: &mut Config, vars: &mut HashMap<String, String>, depth: usize) {
+    let Ok(content) = fs::read_to_string(path) else {
+        if depth > 0 {
+            exit_err(&format!("Cannot read included config {}", path.display()));
+        }
+        return;
+    };
+
+    let mut bundle: Option<String> = None;  // Open [bundle NAME] section
+    let mut credentials: Option<String> = None;  // Open [credentials NAME] section
+
+    for (index, line) in content.lines().enumerate() {
+        if index >= MAX_CONFIG_LINES {
+            eprintln!("[ddnsfw] WARN: Config file {} too large, truncating", path.display());
+            break;
+        }
+        let origin = format!("{}:{}", path.display(), index + 1);
+
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = strip_comment(line);
+
+        if let Some((directive, target)) = line.split_once(char::is_whitespace) {
+            let required = match directive {
+                "include" => Some(true),
+                "include-if-exists" => Some(false),
+                _ => None,
+            };
+            if let Some(required) = required {
+                include_config_file(path, target.trim(), required, config, vars, depth);
+                continue;
+            }
+        }
+
+        // An entry must never silently lose its host to a typo
+        let expand = |text: &str| match expand_variables(text, vars) {
+            Ok(text) => text,
+            Err(name) => exit_err(&format!("Undefined variable '${}' in {}", name, path.display())),
+        };
+
+        // `[bundle NAME]` and `[credentials NAME]` open a section, `[global]` closes it
+        if let Some(header) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            (bundle, credentials) = match header.split_whitespace().collect::<Vec<_>>()[..] {
+                ["bundle", name] if is_valid_bundle_name(name) => {
+                    config.bundles.entry(name.to_string()).or_default();
+                    (Some(name.to_string()), None)
+                }
+                ["credentials", name] if kube::is_valid_name(name) => {
+                    config.kube.profiles.entry(name.to_string()).or_default();
+                    (None, Some(name.to_string()))
+                }
+                ["global"] => (None, None),
+                _ => exit_err(&format!("{}: invalid section '{}'", origin, line)),
+            };
+            continue;
+        }
+
+        // Inside a credentials section, its keys belong to it; anything else
+        // closes the section and is read as usual
+        if let Some(name) = credentials.clone() {
+            match parse_setting_line(line) {
+                Some((key, value)) if kube::PROFILE_KEYS.contains(&key) => {
+                    let profile = config.kube.profiles.entry(name.clone()).or_default();
+                    if let Err(reason) = profile.set(key, &expand(value)) {
+                        exit_err(&format!("{}: {} in [credentials {}]", origin, reason, name));
+                    }
+                    continue;
+                }
+                _ => credentials = None,
+            }
+        }
+
+        // Inside a bundle, `ports` and `hosts` belong to it; anything else
+        // closes the section and is read as usual
+        if let Some(name) = bundle.clone() {
+            match parse_setting_line(line) {
+                Some(("ports", value)) => {
+                    let Some(ports) = parse_port_list(&expand(value)) else {
+                        exit_err(&format!("{}: invalid ports '{}' for bundle {}", origin, value, name));
+                    };
+                    config.bundles.insert(name, ports);
+                    continue;
+                }
+                Some(("hosts", value)) => {
+                    for host in expand(value).split(',').map(str::trim).filter(|h| !h.is_empty()) {
+                        push_entries(config, &format!("{}:@{}", host, name), &origin);
+                    }
+                    continue;
+                }
+                _ => bundle = None,
+            }
+        }
+
+        if let Some((name, value)) = parse_variable_line(line) {
+            vars.insert(name.to_string(), value.to_string());
+            continue;
+        }
+
+        if let Some((key, value)) = parse_setting_line(line) {
+            apply_setting(config, key, value);
+            continue;
+        }
+
+        push_entries(config, &expand(line), &origin);
+    }
+}
+
+fn is_valid_bundle_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= 32 && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Parses `22,443,9090` (at least one port, no zeros)
+fn parse_port_list(s: &str) -> Option<Vec<u16>> {
+    let mut ports = Vec::new();
+    for port in s.split(',').map(str::trim) {
+        let port: u16 = port.parse().ok().filter(|&p| p > 0)?;
+        if !ports.contains(&port) {
+            ports.push(port);
+        }
+    }
+    (!ports.is_empty()).then_some(ports)
+}
+
+/// Adds the entries of an (expanded) entry line. `hostname:@bundle` yields
+/// one entry per bundle port, all sharing the line's options, so the set is
+/// added and removed as a unit.
+fn push_entries(config: &mut Config, line: &str, origin: &str) {
+    if let Some(wildcard) = provider::Wildcard::parse(line, origin) {
+        config.wildcards.push(wildcard);
+        return;
+    }
+    if let Some(entry) = srv::SrvEntry::parse(line, origin) {
+        config.srv_entries.push(entry);
+        return;
+    }
+    let lines = match line.split_once(":@") {
+        Some((host, rest)) => {
+            let (name, options) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+            let Some(ports) = config.bundles.get(name) else {
+                // Dropping the line would silently delete the bundle's rules
+                exit_err(&format!("{}: unknown bundle '{}'", origin, name));
+            };
+            ports.iter().map(|port| format!("{}:{} {}", host, port, options)).collect()
+        }
+        None => vec![line.to_string()],
+    };
+
+    for line in lines {
+        let Some(mut entry) = parse_entry_line(&line) else {
+            config.diagnostics.push(format!("{}: unrecognized line '{}'", origin, line));
+            continue;
+        };
+        if config.entries.len() >= MAX_ENTRIES {
+            // Keep counting so the warning reports what is being dropped
+            config.skipped_entries += 1;
+            continue;
+        }
+        entry.origin = origin.to_string();
+        config.entries.push(entry);
+    }
+}
+
+/// Reports exact duplicates and entries sharing hostname, port and protocol with
+/// different options. The latter are not independent: both sets of rules
+/// are installed, so e.g. a plain entry makes a `knock=` twin pointless.
+/// Different entries with the same `id=` would share their state.
+fn find_overlaps(entries: &[DdnsEntry]) -> Vec<String> {
+    let mut diagnostics = Vec::new();
+    // Entries with other credentials write to another policy
+    let mut firsts: HashMap<(String, u16, rule::Proto, Option<&str>), &DdnsEntry> = HashMap::new();
+    let mut ids: HashMap<&str, &DdnsEntry> = HashMap::new();
+    for entry in entries {
+        if let Some(id) = entry.id.as_deref() {
+            let first = *ids.entry(id).or_insert(entry);
+            if first.label() != entry.label() {
+                diagnostics.push(format!("{}: id={} is also the id of {} at {} (their state would be shared)", entry.origin, id, first.label(), first.origin));
+            }
+        }
+        let key = (entry.hostname.to_ascii_lowercase(), entry.port, entry.proto, entry.credentials.as_deref());
+        let first = *firsts.entry(key).or_insert(entry);
+        if std::ptr::eq(first, entry) {
+            continue;
+        }
+        if first.spec == entry.spec {
+            diagnostics.push(format!("{}: duplicate of {} ({})", entry.origin, first.origin, entry.spec));
+        } else {
+            diagnostics.push(format!(
+                "{}: '{}' overlaps '{}' at {} (rules of both apply)",
+                entry.origin, entry.spec, first.spec, first.origin
+            ));
+        }
+    }
+    diagnostics
+}
+
+/// `ddnsfw list`: installed rules with the entries that own them and when
+/// they were first installed (from the cache, so it survives reboots).
+fn list() {
+    let config = parse_config();
+    let Some(backend) = open_backend(&config) else {
+        exit_err(&format!("{} not found", config.backend.tool()));
+    };
+    let cache = Cache::load();
+
+    let mut rules: Vec<Rule> = backend
+        .existing_rules(&managed_scopes(&config.entries, &cache))
+        .into_iter()
+        .collect();
+    rules.sort_by_key(|rule| rule.to_string());
+
+    if rules.is_empty() {
+        println!("[ddnsfw] No managed rules");
+        return;
+    }
+    for rule in &rules {
+        let rule_owners = cache.owners.get(rule).cloned().unwrap_or_default();
+        let owners = if rule_owners.is_empty() { String::from("unknown") } else { rule_owners.join(", ") };
+        let since = cache.since.get(rule).map(|&ts| format_utc(ts)).unwrap_or_else(|| String::from("unknown"));
+        let summaries: Vec<String> = rule_owners.iter().filter_map(|o| cache.meta.get(o)).map(EntryMeta::summary).collect();
+        let about = join_unique(summaries.iter().map(String::as_str).filter(|s| !s.is_empty()));
+        if about.is_empty() {
+            println!("{:<40} {:<20} {}", rule.to_string(), since, owners);
+        } else {
+            println!("{:<40} {:<20} {} ({})", rule.to_string(), since, owners, about);
+        }
+    }
+}
+
+/// `ddnsfw config validate`: parses the config and exits non-zero when
+/// there is anything to report.
+fn config_command(args: &[String]) {
+    match args.first().map(String::as_str) {
+        Some("validate") => {}
+        _ => exit_err("Usage: ddnsfw config validate"),
+    }
+
+    let config = parse_config();
+    for diagnostic in &config.diagnostics {
+        println!("{}", diagnostic);
+    }
+    if config.skipped_entries > 0 {
+        println!("{}: {} entries beyond the limit of {}", profile::current().config_path, config.skipped_entries, MAX_ENTRIES);
+    }
+
+    if config.diagnostics.is_empty() && config.skipped_entries == 0 {
+        println!("[ddnsfw] Config OK ({} entries)", config.entries.len());
+    } else {
+        exit_err("Config has problems");
+    }
+}
+
+/// Structured capacity warning (`capacity <kind>=<count>/<max>`) once a
+/// limit is CAPACITY_WARN_PERCENT full. Returns false when over the limit.
+fn check_capacity(kind: &str, count: usize, max: usize) -> bool {
+    if count > max {
+        eprintln!("[ddnsfw] WARN: capacity {}={}/{} exceeded, {} not applied", kind, count, max, count - max);
+        return false;
+    }
+    if count * 100 >= max * CAPACITY_WARN_PERCENT {
+        eprintln!("[ddnsfw] WARN: capacity {}={}/{}", kind, count, max);
+    }
+    true
+}
+
+/// Follows an include. A missing required include or an unsafe one aborts:
+/// silently losing its entries would delete their rules.
+fn include_config_file(
+    parent: &Path,
+    target: &str,
+    required: bool,
+    config: &mut Config,
+    vars: &mut HashMap<String, String>,
+    depth: usize,
+) {
+    if depth >= MAX_INCLUDE_DEPTH {
+        exit_err(&format!("Includes nested too deep at {} (loop?)", parent.display()));
+    }
+
+    // Relative paths are resolved against the including file
+    let path = match parent.parent() {
+        Some(dir) if !target.starts_with('/') => dir.join(target),
+        _ => Path::new(target).to_path_buf(),
+    };
+
+    let Ok(meta) = fs::metadata(&path) else {
+        if required {
+            exit_err(&format!("Included config {} not found", path.display()));
+        }
+        return;
+    };
+    if meta.permissions().mode() & 0o022 != 0 {
+        exit_err(&format!("Refusing group/world-writable include {}", path.display()));
+    }
+
+    if path.extension().is_some_and(|ext| ext == "toml") {
+        tomlconfig::load(&path, config, depth + 1);
+    } else {
+        load_config_file(&path, config, vars, depth + 1);
+    }
+}
+
+// ============================================================================
+// Backend Dispatch
+// ============================================================================
+
+/// Opened backend, holding the path of the tool it drives
+enum Backend {
+    Iptables(&'static str),
+    Proxmox { bin: &'static str, prefix: String },
+    Kubernetes(kube::Kube),
+    Nftables(nft::Nft),
+    Ipset(ipset::Ipset),
+    Firewalld(firewalld::Firewalld),
+    Mock(&'static chaos::Chaos),  // --chaos sandbox firewall
+}
+
+fn open_backend(config: &Config) -> Option<Backend> {
+    if let Some(chaos) = chaos::active() {
+        return Some(Backend::Mock(chaos));
+    }
+    if privsep::routed() && !matches!(config.backend, BackendKind::Iptables) {
+        exit_err("The service user mode only supports the iptables backend");
+    }
+    match config.backend {
+        BackendKind::Iptables => find_iptables().map(Backend::Iptables),
+        BackendKind::Proxmox => proxmox::find_pvesh().map(|bin| Backend::Proxmox {
+            bin,
+            prefix: config.proxmox_ipset.clone(),
+        }),
+        BackendKind::Kubernetes => kube::find_kubectl()
+            .map(|bin| Backend::Kubernetes(kube::Kube::open(bin, config.kube.clone()))),
+        BackendKind::Nftables => nft::find_nft().map(|bin| Backend::Nftables(nft::Nft::open(bin, config.nft.clone()))),
+        BackendKind::Ipset if !ipset::fits_profile() => {
+            exit_err("Profile name too long for ipset set names (at most 18 characters)")
+        }
+        BackendKind::Ipset => {
+            ipset::find_ipset().zip(find_iptables()).map(|(bin, iptables)| Backend::Ipset(ipset::Ipset::open(bin, iptables)))
+        }
+        BackendKind::Firewalld if !ipset::fits_profile() => {
+            exit_err("Profile name too long for firewalld ipset names (at most 18 characters)")
+        }
+        BackendKind::Firewalld => firewalld::find_firewall_cmd()
+            .map(|bin| Backend::Firewalld(firewalld::Firewalld::open(bin, config.firewalld_zone.clone()))),
+    }
+}
+
+impl Backend {
+    /// Non-iptables backends only understand plain (ip, port) allows
+    fn supports(&self, rule: &Rule) -> bool {
+        match self {
+            Backend::Iptables(_) => true,
+            Backend::Kubernetes(kube) => kube.supports(rule),
+            _ => rule.is_plain(),
+        }
+    }
+
+    fn existing_rules(&self, scopes: &HashSet<(Table, String)>) -> HashSet<Rule> {
+        let plain = |rules: HashSet<(Ipv4Addr, u16)>| -> HashSet<Rule> {
+            rules.into_iter().map(|(ip, port)| Rule::allow(ip, port)).collect()
+        };
+        match self {
+            Backend::Iptables(bin) => get_existing_rules(bin, scopes),
+            Backend::Proxmox { bin, prefix } => plain(proxmox::get_existing_rules(bin, prefix)),
+            Backend::Kubernetes(kube) => kube.existing_rules(),
+            Backend::Nftables(nft) => plain(nft.existing_rules()),
+            Backend::Ipset(ipset) => plain(ipset.existing_rules()),
+            Backend::Firewalld(firewalld) => plain(firewalld.existing_rules()),
+            Backend::Mock(chaos) => chaos.existing_rules(),
+        }
+    }
+
+    fn rule_exists(&self, rule: &Rule) -> bool {
+        match self {
+            Backend::Iptables(bin) => rule_exists(bin, rule),
+            Backend::Proxmox { bin, prefix } => proxmox::rule_exists(bin, prefix, rule.ip, rule.port),
+            Backend::Kubernetes(kube) => kube.rule_exists(rule),
+            Backend::Nftables(nft) => nft.rule_exists(rule.ip, rule.port),
+            Backend::Ipset(ipset) => ipset.rule_exists(rule.ip, rule.port),
+            Backend::Firewalld(firewalld) => firewalld.rule_exists(rule.ip, rule.port),
+            Backend::Mock(chaos) => chaos.existing_rules().contains(rule),
+        }
+    }
+
+    /// Adds a rule; `position` only matters where rules are ordered
+    fn add_rule(&mut self, rule: &Rule, position: Position) -> bool {
+        match self {
+            Backend::Iptables(bin) => add_rule_at(bin, rule, position),
+            Backend::Proxmox { bin, prefix } => proxmox::add_rule(bin, prefix, rule.ip, rule.port),
+            Backend::Kubernetes(kube) => kube.add_rule(rule),
+            Backend::Nftables(nft) => nft.add_rule(rule.ip, rule.port),
+            Backend::Ipset(ipset) => ipset.add_rule(rule.ip, rule.port),
+            Backend::Firewalld(firewalld) => firewalld.add_rule(rule.ip, rule.port),
+            Backend::Mock(chaos) => chaos.add_rule(rule),
+        }
+    }
+
+    /// Whether a rule about to be deleted is still the one ddnsfw wrote
+    /// (the set-based backends only hold their own addresses)
+    fn owns(&self, rule: &Rule) -> bool {
+        match self {
+            Backend::Iptables(bin) => is_own_rule(bin, rule),
+            _ => true,
+        }
+    }
+
+    fn delete_rule(&mut self, rule: &Rule) -> bool {
+        match self {
+            Backend::Iptables(bin) => delete_rule(bin, rule),
+            Backend::Proxmox { bin, prefix } => proxmox::delete_rule(bin, prefix, rule.ip, rule.port),
+            Backend::Kubernetes(kube) => kube.delete_rule(rule),
+            Backend::Nftables(nft) => nft.delete_rule(rule.ip, rule.port),
+            Backend::Ipset(ipset) => ipset.delete_rule(rule.ip, rule.port),
+            Backend::Firewalld(firewalld) => firewalld.delete_rule(rule.ip, rule.port),
+            Backend::Mock(chaos) => chaos.delete_rule(rule),
+        }
+    }
+}
+
+// ============================================================================
+// Crash Recovery
+// ============================================================================
+
+fn recover_from_crash(backend: &mut Backend, cache: &mut Cache) {
+    if let SyncState::Adding(rule) = &cache.state {
+        println!("[ddnsfw] Recovery: Checking pending add {}", rule);
+    }
+    match state::recovery(&cache.state, |rule| backend.rule_exists(rule)) {
+        Recovery::Nothing => {}
+        Recovery::Adopt(_) => cache.transition(Event::Succeeded),
+        Recovery::Readd(rule) => {
+            println!("[ddnsfw] Recovery: Re-adding rule {}", rule);
+            let added = backend.add_rule(&rule, Position::Top);
+            cache.transition(if added { Event::Succeeded } else { Event::Failed });
+        }
+        Recovery::Forget(rule) => {
+            println!("[ddnsfw] Recovery: Delete interrupted for {}, ignoring", rule);
+            cache.transition(Event::Failed);
+        }
+    }
+}
+
+// ============================================================================
+// Core Sync Algorithm (CRITICAL - Zero Bug Tolerance)
+// ============================================================================
+
+/// Emits a rule change event with the owning hostnames and, when the change
+/// is an IP move, the IP on the other side (looked up among `others`, the
+/// owners map from before/after the sync), plus the owning entries'
+/// owner/description metadata.
+fn emit_change(
+    event: &str,
+    rule: &Rule,
+    owners: &HashMap<Rule, Vec<String>>,
+    others: &HashMap<Rule, Vec<String>>,
+    meta: &HashMap<String, EntryMeta>,
+    entries: &[DdnsEntry],
+) {
+    let rule_owners = owners.get(rule).cloned().unwrap_or_default();
+    let counterpart = others
+        .iter()
+        .find(|(other, other_owners)| {
+            other.ip != rule.ip
+                && other.port == rule.port
+                && other.scope() == rule.scope()
+                && other_owners.iter().any(|o| rule_owners.contains(o))
+        })
+        .map(|(other, _)| other.ip.to_string());
+
+    // An id= names the entry; its hostname comes from the config
+    let hosts: Vec<&str> = rule_owners
+        .iter()
+        .map(|o| match entries.iter().find(|e| e.key() == *o) {
+            Some(entry) => entry.hostname.as_str(),
+            None => o.rsplit_once(':').map_or(o.as_str(), |(h, _)| h),
+        })
+        .collect();
+    let hosts = hosts.join(",");
+    let keys = rule_owners.join(",");
+    let rule_str = rule.to_string();
+    let ip = rule.ip.to_string();
+    let (new_ip, old_ip) = if event.starts_with("add") {
+        (Some(ip.as_str()), counterpart.as_deref())
+    } else {
+        (counterpart.as_deref(), Some(ip.as_str()))
+    };
+
+    let mut fields = vec![("rule", Value::Str(&rule_str)), ("port", Value::Num(u64::from(rule.port)))];
+    if !hosts.is_empty() {
+        fields.push(("hostname", Value::Str(&hosts)));
+        fields.push(("entry", Value::Str(&keys)));
+    }
+    if let Some(old_ip) = old_ip {
+        fields.push(("old_ip", Value::Str(old_ip)));
+    }
+    if let Some(new_ip) = new_ip {
+        fields.push(("new_ip", Value::Str(new_ip)));
+    }
+    // rDNS/GeoIP/ASN of both sides (enrich settings)
+    let mut context: Vec<(&str, String)> = Vec::new();
+    for (side, ip) in [("new", new_ip), ("old", old_ip)] {
+        let Some(info) = ip.and_then(|ip| ip.parse().ok()).and_then(enrich::lookup) else {
+            continue;
+        };
+        let keys = if side == "new" { ["new_rdns", "new_geo", "new_asn"] } else { ["old_rdns", "old_geo", "old_asn"] };
+        for (key, value) in keys.into_iter().zip([info.rdns, info.geo, info.asn]) {
+            if let Some(value) = value {
+                context.push((key, value));
+            }
+        }
+    }
+    fields.extend(context.iter().map(|(key, value)| (*key, Value::Str(value))));
+    let metas: Vec<&EntryMeta> = rule_owners.iter().filter_map(|o| meta.get(o)).collect();
+    let owner = join_unique(metas.iter().filter_map(|m| m.owner.as_deref()));
+    let description = join_unique(metas.iter().filter_map(|m| m.description.as_deref()));
+    if !owner.is_empty() {
+        fields.push(("owner", Value::Str(&owner)));
+    }
+    if !description.is_empty() {
+        fields.push(("description", Value::Str(&description)));
+    }
+    events::emit(event, &fields);
+}
+
+fn join_unique<'a>(items: impl Iterator<Item = &'a str>) -> String {
+    let mut unique: Vec<&str> = Vec::new();
+    for item in items {
+        if !unique.contains(&item) {
+            unique.push(item);
+        }
+    }
+    unique.join(", ")
+}
+
+/// Tables/chains to inspect: the default, every configured one, and every
+/// previously managed one (so rules of removed entries are cleaned up)
+fn managed_scopes(entries: &[DdnsEntry], cache: &Cache) -> HashSet<(Table, String)> {
+    let mut scopes: HashSet<(Table, String)> = HashSet::new();
+    scopes.insert((Table::Filter, String::from("INPUT")));
+    for entry in entries {
+        scopes.extend(entry.rules_for(Ipv4Addr::UNSPECIFIED).iter().map(Rule::scope));
+    }
+    scopes.extend(cache.rules.iter().map(Rule::scope));
+    scopes
+}
+
+/// Existing rules by port, and VPN companions by address, built once per
+/// sync: the fail-safe path can be taken for every entry, and must not scan
+/// all rules each time
+struct PortIndex {
+    by_port: HashMap<u16, Vec<Rule>>,
+    companions: HashMap<Ipv4Addr, Vec<Rule>>,
+}
+
+impl PortIndex {
+    fn new(rules: &HashSet<Rule>) -> Self {
+        let mut index = PortIndex { by_port: HashMap::new(), companions: HashMap::new() };
+        for rule in rules {
+            index.by_port.entry(rule.port).or_default().push(rule.clone());
+            if vpn::is_companion(rule) {
+                index.companions.entry(rule.ip).or_default().push(rule.clone());
+            }
+        }
+        index
+    }
+}
+
+/// Fail-safe: keep every existing rule for a port whose new state is unknown,
+/// with the VPN companions of the addresses it allows
+fn keep_existing_port(desired_rules: &mut HashSet<Rule>, existing: &PortIndex, port: u16) {
+    for rule in existing.by_port.get(&port).into_iter().flatten() {
+        desired_rules.insert(rule.clone());
+        for companion in existing.companions.get(&rule.ip).into_iter().flatten() {
+            desired_rules.insert(companion.clone());
+        }
+    }
+}
+
+/// Sync after an explicit change (otp-allow, enable, ...): waits for a
+/// running sync so the change is applied. False when the sync failed.
+fn sync_firewall() -> bool {
+    // Acquire exclusive lock to prevent concurrent execution
+    let _lock = match acquire_lock() {
         Some(lock) => lock,
         None => {
             eprintln!("[ddnsfw] ERROR: Could not acquire lock");
-            return;
+            return false;
+        }
+    };
+    // Lock is held until _lock goes out of scope
+
+    let ok = run_sync(None);
+    mirror::checkpoint();
+    ok
+}
+
+/// Sync after a provider webhook: only `hostnames` are looked up again, the
+/// other entries keep the address of the last sync. Waits like sync_firewall.
+fn sync_hostnames(hostnames: &HashSet<String>) -> bool {
+    let Some(_lock) = acquire_lock() else {
+        eprintln!("[ddnsfw] ERROR: Could not acquire lock");
+        return false;
+    };
+    let ok = run_sync(Some(hostnames));
+    mirror::checkpoint();
+    ok
+}
+
+/// Periodic sync: if another sync is still running (slow DNS), this run is
+/// redundant, so it is counted and skipped instead of queueing on the lock.
+fn scheduled_sync() -> bool {
+    // Spread the timer runs of many hosts; before the lock, so nobody waits on it
+    let config = parse_config();
+    throttle::sleep_jitter(config.jitter);
+
+    match try_lock() {
+        Ok(Some(_lock)) => {
+            let ok = run_sync(None);
+            mirror::checkpoint();
+            backup::scheduled(&config);
+            perms::scheduled(&config);
+            ok
+        }
+        Ok(None) => {
+            eprintln!("[ddnsfw] ERROR: Could not acquire lock");
+            !is_strict(&config)
+        }
+        Err(()) => {
+            let skipped = record_skip();
+            println!("[ddnsfw] Another sync active, skipping (skipped {} so far)", skipped);
+            true
+        }
+    }
+}
+
+/// Bumps the skipped-run counter (`<count> <last unix time>`), returning it
+fn record_skip() -> u64 {
+    let count = read_skips().0 + 1;
+    let temp_path = format!("{}.tmp", profile::current().skips_path);
+    if let Ok(mut file) = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&temp_path)
+    {
+        let _ = writeln!(file, "{} {}", count, now_secs());
+        let _ = fs::rename(&temp_path, &profile::current().skips_path);
+    }
+    count
+}
+
+/// (skipped runs, time of the last skip)
+fn read_skips() -> (u64, u64) {
+    let content = fs::read_to_string(&profile::current().skips_path).unwrap_or_default();
+    let mut fields = content.split_whitespace().map(|f| f.parse().unwrap_or(0));
+    (fields.next().unwrap_or(0), fields.next().unwrap_or(0))
+}
+
+fn is_strict(config: &Config) -> bool {
+    config.strict || STRICT_FLAG.load(Ordering::Relaxed)
+}
+
+/// One line per entry even when nothing changed
+fn is_verbose(config: &Config) -> bool {
+    config.verbose || VERBOSE_FLAG.load(Ordering::Relaxed)
+}
+
+/// Runs one sync. Only in strict mode can it come back false: when the
+/// sync failed, or anything in it (a hostname, an add, a delete) did not go
+/// through. With `focus`, only those hostnames are resolved (webhook.rs).
+fn run_sync(focus: Option<&HashSet<String>>) -> bool {
+    let mut stats = SyncStats::start();
+    let mut config = parse_config();
+    let strict = is_strict(&config);
+    syslog::configure(config.syslog.clone());
+    enrich::configure(config.enrich.clone());
+    for diagnostic in &config.diagnostics {
+        eprintln!("[ddnsfw] WARN: {}", diagnostic);
+    }
+
+    let Some(mut backend) = open_backend(&config) else {
+        eprintln!("[ddnsfw] ERROR: {} not found", config.backend.tool());
+        return !strict;
+    };
+
+    // Watchdogs first: the fallback path must not depend on anything below
+    match &backend {
+        Backend::Iptables(bin) => watchdog::sync(bin, &config.watchdogs),
+        _ if !config.watchdogs.is_empty() => {
+            eprintln!("[ddnsfw] WARN: watchdog is only supported by the iptables backend");
+        }
+        _ => {}
+    }
+
+    if config.dedicated_chain && !dedicated::is_enabled() && !matches!(backend, Backend::Mock(_)) {
+        eprintln!("[ddnsfw] WARN: dedicated_chain needs the iptables backend and is not available in service user mode");
+    }
+
+    provider::expand(&mut config);
+    srv::expand(&mut config);
+
+    // Load cache and recover if needed
+    let mut cache = Cache::load();
+    if cache.schema < CACHE_SCHEMA {
+        // Older formats are read as they were written; saving upgrades them
+        println!("[ddnsfw] Migrating cache from schema {} to {}", cache.schema, CACHE_SCHEMA);
+    }
+    if !cache.state.is_idle() {
+        println!("[ddnsfw] Detected incomplete operation, recovering...");
+        recover_from_crash(&mut backend, &mut cache);
+    }
+
+    let mut entries = std::mem::take(&mut config.entries);
+    if entries.is_empty() {
+        println!("[ddnsfw] No entries in config");
+        return true;
+    }
+    // Critical entries first, config order within a class (see priority)
+    entries.sort_by_key(|entry| entry.priority);
+
+    println!("[ddnsfw] Syncing {} entries...", entries.len());
+    check_capacity("entries", entries.len() + config.skipped_entries, MAX_ENTRIES);
+
+    let scopes = managed_scopes(&entries, &cache);
+
+    // comment_prefix changed since the last sync: adopt the old rules under
+    // the new comment (old ones are purged at the end, once all went well)
+    // (no cache at all: a fresh install, whose comment is never "legacy")
+    let legacy_comment = cache.comment.clone().unwrap_or_else(|| profile::current().comment().to_string());
+    let mut migration_ok = true;
+    let migrating = legacy_comment != profile::current().comment();
+    if migrating {
+        match &backend {
+            Backend::Iptables(bin) => migration_ok = retag_rules(bin, &scopes, &legacy_comment),
+            Backend::Proxmox { .. } => {
+                // Entries would be seen as foreign and re-added on top
+                eprintln!(
+                    "[ddnsfw] ERROR: comment_prefix cannot change with the proxmox backend (entries are tagged '{}')",
+                    legacy_comment
+                );
+                return !strict;
+            }
+            // ipset: tidy() tags the port rules anew, the old ones go at the end
+            // (firewalld rules carry no comment)
+            Backend::Kubernetes(_)
+            | Backend::Nftables(_)
+            | Backend::Ipset(_)
+            | Backend::Firewalld(_)
+            | Backend::Mock(_) => {}
+        }
+    }
+
+    // The entries' rules go to the dedicated chain: it and its jump first
+    if let Backend::Iptables(bin) = &backend {
+        if dedicated::is_enabled() && !dedicated::ensure(bin) {
+            eprintln!("[ddnsfw] WARN: Could not set up chain {} and its jump from INPUT", dedicated::name());
+        }
+    }
+
+    // Get actual firewall state (source of truth)
+    let phase_start = Instant::now();
+    let existing_rules = backend.existing_rules(&scopes);
+    stats.record(Phase::List, phase_start);
+
+    // Update cache with actual state
+    cache.rules = existing_rules.clone();
+    cache.save();
+
+    // Entries that were just given an id= keep what they had
+    let rekeyed = cache.rekey(&entries);
+    for (label, id) in &rekeyed {
+        println!("[ddnsfw] State of {} now kept under id={}", label, id);
+    }
+
+    let mut sync = SyncRun::new(config, entries, backend, cache, stats, existing_rules);
+    sync.resolve(focus, &rekeyed);
+    sync.add_grants();
+
+    // iptables changes are staged, and applied in one iptables-restore at
+    // the end of each phase
+    let mut batch = match &sync.backend {
+        Backend::Iptables(bin) => restore::Batch::open(bin),
+        _ => None,
+    };
+    let phase_start = Instant::now();
+    sync.add(&mut batch);
+    sync.stats.record(Phase::Add, phase_start);
+    let phase_start = Instant::now();
+    sync.delete(&mut batch, &scopes);
+    sync.stats.record(Phase::Delete, phase_start);
+    sync.record_owners();
+
+    // IPv6 side of the opted-in entries (ip6tables, same guarantees)
+    if let Backend::Iptables(_) = &sync.backend {
+        sync.failed_hosts.extend(v6::sync(&sync.config, &sync.entries, &mut sync.cache, &mut sync.stats));
+    } else if sync.config.ipv6 || sync.entries.iter().any(|e| e.ipv6 == Some(true)) {
+        eprintln!("[ddnsfw] WARN: ipv6 is only supported by the iptables backend");
+    }
+
+    let phase_start = Instant::now();
+    sync.companions();
+    sync.stats.record(Phase::Companions, phase_start);
+
+    let SyncRun { config, backend, mut cache, mut stats, failed_hosts, held_back, unchanged, .. } = sync;
+
+    if migrating {
+        if let Backend::Iptables(bin) = &backend {
+            migration_ok = migration_ok
+                && purge_comment(bin, &scopes, &legacy_comment)
+                && purge_comment(bin, &scopes, &format!("{}-WATCHDOG", legacy_comment));
+        }
+        if let Backend::Ipset(ipset) = &backend {
+            let input = [(Table::Filter, String::from("INPUT"))].into_iter().collect();
+            migration_ok = migration_ok && purge_comment(ipset.iptables(), &input, &legacy_comment);
+        }
+        if migration_ok {
+            println!("[ddnsfw] Rules now tagged '{}' (was '{}')", profile::current().comment(), legacy_comment);
+        } else {
+            eprintln!("[ddnsfw] WARN: Keeping rules tagged '{}', migration retried next sync", legacy_comment);
+        }
+    }
+    if migration_ok {
+        cache.comment = Some(profile::current().comment().to_string());
+    }
+
+    cache.transition(Event::Reset);
+    stats.dns_failures = failed_hosts.len() as u64;
+    stats.save(now_secs());
+    metrics::write(&config, cache.rules.len(), cache.rules6.len());
+    if unchanged > 0 {
+        println!("[ddnsfw] Sync complete ({} unchanged, {})", unchanged, stats.summary());
+    } else {
+        println!("[ddnsfw] Sync complete ({})", stats.summary());
+    }
+    events::emit("sync", &[
+        ("added", Value::Num(stats.added)),
+        ("removed", Value::Num(stats.removed)),
+        ("failed", Value::Num(stats.failed)),
+        ("total_ms", Value::Num(stats.total_ms())),
+        ("execs", Value::Num(stats.execs().values().sum())),
+    ]);
+    execs::report(&stats.execs());
+
+    // Hostnames no entry could resolve, plus operations that failed or waited
+    let problems = failed_hosts.len() as u64 + stats.failed + held_back;
+    if strict && problems > 0 {
+        eprintln!(
+            "[ddnsfw] ERROR: Strict mode: {} unresolved hostnames, {} failed and {} held-back operations",
+            failed_hosts.len(),
+            stats.failed,
+            held_back
+        );
+        return false;
+    }
+    true
+}
+
+/// One sync's state, handed from phase to phase
+struct SyncRun {
+    config: Config,
+    entries: Vec<DdnsEntry>,
+    backend: Backend,
+    cache: Cache,
+    stats: SyncStats,
+    verbose: bool,
+    existing_rules: HashSet<Rule>,  // Listed at the start (source of truth)
+    existing_index: PortIndex,
+    meta: HashMap<String, EntryMeta>,
+    // Track desired rules and what needs to be added. Entries converging on
+    // the same rule share it: one rule, several owners, removed only once no
+    // owner wants it any more.
+    desired_rules: HashSet<Rule>,
+    rules_to_add: Vec<Rule>,
+    queued: HashSet<Rule>,  // rules_to_add, for lookups
+    positions: HashMap<Rule, Position>,  // Entries' position= other than top
+    owners: HashMap<Rule, Vec<String>>,
+    classes: HashMap<Rule, Priority>,  // Highest class wanting the rule
+    failed_hosts: HashSet<String>,
+    resolved_hosts: HashSet<String>,
+    held_back: u64,  // Adds/deletes not attempted (capacity, retry backoff)
+    unchanged: usize,  // Entries whose "OK (no change)" line was not printed
+}
+
+impl SyncRun {
+    fn new(config: Config, entries: Vec<DdnsEntry>, backend: Backend, cache: Cache, stats: SyncStats, existing_rules: HashSet<Rule>) -> Self {
+        // Metadata of configured entries, falling back to the cached copy for
+        // entries that were removed (so their deletions stay attributable)
+        let mut meta = cache.meta.clone();
+        for entry in &entries {
+            if entry.meta.is_empty() {
+                meta.remove(&entry.key());
+            } else {
+                meta.insert(entry.key(), entry.meta.clone());
+            }
+        }
+        SyncRun {
+            verbose: is_verbose(&config),
+            existing_index: PortIndex::new(&existing_rules),
+            config,
+            entries,
+            backend,
+            cache,
+            stats,
+            existing_rules,
+            meta,
+            desired_rules: HashSet::new(),
+            rules_to_add: Vec::new(),
+            queued: HashSet::new(),
+            positions: HashMap::new(),
+            owners: HashMap::new(),
+            classes: HashMap::new(),
+            failed_hosts: HashSet::new(),
+            resolved_hosts: HashSet::new(),
+            held_back: 0,
+            unchanged: 0,
+        }
+    }
+
+    /// Phase 1: resolves every entry (no firewall changes yet) into the
+    /// rules it wants and the adds they need
+    fn resolve(&mut self, focus: Option<&HashSet<String>>, rekeyed: &[(String, String)]) {
+        // Addresses each entry was allowed from, by key (dns_select keeps them)
+        let mut previous_ips: HashMap<String, Vec<(u16, Ipv4Addr)>> = HashMap::new();
+        for (rule, rule_owners) in &self.cache.owners {
+            for owner in rule_owners {
+                previous_ips.entry(owner.clone()).or_default().push((rule.port, rule.ip));
+            }
+        }
+
+        let mut throttled = throttle::State::load();
+        let mut churn = churn::State::load();
+        for (label, id) in rekeyed {
+            churn.rename(label, id);
         }
-    };
-    // Lock is held until _lock goes out of scope
+        let pushes = self.config.push_listen.is_some().then(push::Store::load);
+        // A hostname pointing at this server is a broken update client, not a peer
+        let mut own_addrs = sys::local_ipv4_addrs();
+        own_addrs.extend(&self.config.self_ips);
+        let shared_space = cgnat::Check::new(self.config.cgnat, &own_addrs);
+        let mut user_chains: HashMap<(Table, String), bool> = HashMap::new();
+        let mut iteration = 0;
+        for entry in &self.entries {
+            iteration += 1;
+            if iteration > MAX_ENTRIES {
+                eprintln!("[ddnsfw] WARN: Loop protection triggered in phase 1");
+                break;
+            }
 
-    let Some(iptables_bin) = find_iptables() else {
-        eprintln!("[ddnsfw] ERROR: iptables not found");
-        return;
-    };
+            // Printed once the outcome is known: unchanged entries stay quiet
+            let mut line = format!("[ddnsfw] {} -> ", entry.label());
+
+            // A webhook sync only looks up the hostnames it was told about; the
+            // other entries keep the address of the last sync
+            let unfocused = focus.is_some_and(|hosts| !hosts.contains(&entry.hostname));
+            let label = entry.key();
+            let current: Vec<Ipv4Addr> = previous_ips
+                .get(&label)
+                .into_iter()
+                .flatten()
+                .filter(|(port, _)| *port == entry.port)
+                .map(|(_, ip)| *ip)
+                .collect();
+            let last = if unfocused {
+                if current.is_empty() {
+                    keep_existing_port(&mut self.desired_rules, &self.existing_index, entry.port);
+                    continue;
+                }
+                Some(resolver::Answer { addrs: current.clone(), via: String::from("last sync"), fallback: false })
+            } else {
+                None
+            };
+
+            // A rule in (or a jump into) a chain that does not exist would fail every add
+            let missing = match &self.backend {
+                Backend::Iptables(bin) => entry.user_chains().into_iter().find(|chain| {
+                    !*user_chains
+                        .entry((entry.table, chain.to_string()))
+                        .or_insert_with(|| iptables(bin, &["-t", entry.table.name(), "-S", chain]).is_some())
+                }),
+                _ => None,
+            };
+            if let Some(chain) = missing {
+                println!("{}SKIP (chain {} missing, keeping existing)", line, chain);
+                eprintln!("[ddnsfw] WARN: Chain {} does not exist in table {} (needed by {})", chain, entry.table.name(), entry.label());
+                events::emit("skip", &[
+                    ("host", Value::Str(&entry.hostname)),
+                    ("port", Value::Num(u64::from(entry.port))),
+                    ("reason", Value::Str("chain missing")),
+                ]);
+                keep_existing_port(&mut self.desired_rules, &self.existing_index, entry.port);
+                self.held_back += 1;
+                continue;
+            }
 
-    // Load cache and recover if needed
-    let mut cache = Cache::load();
-    if cache.state != CacheState::Idle {
-        println!("[ddnsfw] Detected incomplete operation, recovering...");
-        recover_from_crash(iptables_bin, &mut cache);
-    }
+            let phase_start = Instant::now();
+            let chain = entry.resolver_chain(&self.config);
+            let pushed = pushes.as_ref().and_then(|store| store.answer(&entry.hostname, self.config.push_max_age, now_secs()));
+            let resolved = match last.or(pushed).or_else(|| chain.resolve_local(&entry.hostname)) {
+                Some(answer) => {
+                    line.push_str(&format!("({}) ", answer.via));
+                    self.config.dns_select.pick(&answer.addrs, &current, self.config.dns_max_addrs)
+                }
+                None => {
+                    let interval = throttle::interval_for(&self.config.provider_intervals, &entry.hostname);
+                    // After a webhook the record just changed: no throttled or shared answer
+                    let addrs = match interval.filter(|_| focus.is_none()).and_then(|secs| throttled.fresh(&entry.hostname, secs, now_secs())) {
+                        Some(addrs) => {
+                            line.push_str("(throttled) ");
+                            addrs
+                        }
+                        None => {
+                            // Held until the answer is stored, so other profiles wait for it
+                            let shared = self.config
+                                .dns_cache
+                                .then(|| dnscache::chain_key(entry.resolvers.as_deref().unwrap_or(&self.config.resolvers)))
+                                .flatten()
+                                .and_then(|key| Some((dnscache::Guard::acquire()?, key)));
+                            let cached = shared
+                                .as_ref()
+                                .filter(|_| focus.is_none())
+                                .and_then(|(guard, key)| guard.fresh(&entry.hostname, key, now_secs()));
+                            let addrs = match cached {
+                                Some(addrs) => {
+                                    line.push_str("(shared cache) ");
+                                    addrs
+                                }
+                                None => {
+                                    let answer = chain.resolve_network(&entry.hostname, Duration::from_secs(DNS_TIMEOUT_SECS));
+                                    if let Some(answer) = answer.as_ref().filter(|a| a.fallback) {
+                                        line.push_str(&format!("(via {}) ", answer.via));
+                                    }
+                                    let addrs = answer.map(|a| a.addrs).unwrap_or_default();
+                                    if let Some((guard, key)) = &shared {
+                                        guard.store(&entry.hostname, key, &addrs, now_secs());
+                                    }
+                                    addrs
+                                }
+                            };
+                            if interval.is_some() {
+                                throttled.record(&entry.hostname, now_secs(), &addrs);
+                            }
+                            addrs
+                        }
+                    };
+                    if addrs.len() > 1 {
+                        line.push_str(&format!("({} records) ", addrs.len()));
+                    }
+                    self.config.dns_select.pick(&addrs, &current, self.config.dns_max_addrs)
+                }
+            };
+            self.stats.record(Phase::Dns, phase_start);
+            let host = Value::Str(&entry.hostname);
+            let port = Value::Num(u64::from(entry.port));
+            if resolved.is_empty() {
+                println!("{}SKIP (DNS failed, keeping existing)", line);
+                events::emit("resolve_failed", &[("host", host), ("port", port)]);
+                keep_existing_port(&mut self.desired_rules, &self.existing_index, entry.port);
+                self.failed_hosts.insert(entry.hostname.clone());
+                continue;
+            }
+            if !unfocused {
+                self.resolved_hosts.insert(entry.hostname.clone());
+            }
 
-    let entries = parse_config();
-    if entries.is_empty() {
-        println!("[ddnsfw] No entries in config");
-        return;
-    }
+            let ip_list: Vec<String> = resolved.iter().map(Ipv4Addr::to_string).collect();
+            line.push_str(&format!("{} ", ip_list.join(",")));
+            for ip_str in &ip_list {
+                events::emit("resolve", &[
+                    ("host", Value::Str(&entry.hostname)),
+                    ("port", Value::Num(u64::from(entry.port))),
+                    ("ip", Value::Str(ip_str)),
+                ]);
+            }
 
-    println!("[ddnsfw] Syncing {} entries...", entries.len());
+            // With dns_select = all, the other addresses are still allowed
+            let (own, mut ips): (Vec<Ipv4Addr>, Vec<Ipv4Addr>) = resolved.into_iter().partition(|ip| own_addrs.contains(ip));
+            for ip in &own {
+                eprintln!(
+                    "[ddnsfw] WARN: {} resolves to {}, an address of this server; check the DDNS update client",
+                    entry.hostname, ip
+                );
+                events::emit("skip", &[
+                    ("host", Value::Str(&entry.hostname)),
+                    ("port", Value::Num(u64::from(entry.port))),
+                    ("reason", Value::Str("resolves to this server")),
+                ]);
+            }
+            if ips.is_empty() {
+                println!("{}SKIP (this server's own address, keeping existing)", line);
+                keep_existing_port(&mut self.desired_rules, &self.existing_index, entry.port);
+                continue;
+            }
+            for ip in &own {
+                line.push_str(&format!("(ignoring {}: this server) ", ip));
+            }
 
-    // Get actual iptables state (source of truth)
-    let existing_rules = get_existing_rules(iptables_bin);
+            // Behind CGNAT or DS-Lite only IPv6 identifies the connection
+            let shared = shared_space.shared(&ips);
+            if !shared.is_empty() {
+                let list: Vec<String> = shared.iter().map(Ipv4Addr::to_string).collect();
+                if !shared_space.prefers_v6(&self.config, entry, matches!(self.backend, Backend::Iptables(_))) {
+                    eprintln!(
+                        "[ddnsfw] WARN: {} resolves to {}, a CGNAT/DS-Lite address: an IPv4 allow cannot match the connection, only IPv6 can (ipv6=yes)",
+                        entry.hostname,
+                        list.join(", ")
+                    );
+                } else {
+                    ips.retain(|ip| !shared.contains(ip));
+                    line.push_str(&format!("(ignoring {}: CGNAT, IPv6 preferred) ", list.join(",")));
+                    events::emit("skip", &[
+                        ("host", Value::Str(&entry.hostname)),
+                        ("port", Value::Num(u64::from(entry.port))),
+                        ("reason", Value::Str("CGNAT address, IPv6 preferred")),
+                    ]);
+                    if ips.is_empty() {
+                        // Its IPv4 rules go, as after an address change
+                        if self.verbose || !current.is_empty() {
+                            println!("{}IPv6 only", line);
+                        } else {
+                            self.unchanged += 1;
+                        }
+                        continue;
+                    }
+                }
+            }
 
-    // Update cache with actual state
-    cache.rules = existing_rules.clone();
-    cache.save();
+            // A record rotating faster than its limit could be hijacked
+            let limit = entry.max_changes.unwrap_or(self.config.max_changes_per_day);
+            if let churn::Verdict::Frozen(changes) = churn.observe(&label, &ips, limit, &self.config.escalation, now_secs()) {
+                println!("{}SKIP (frozen, {} changes in 24h, keeping existing)", line, changes);
+                events::emit("skip", &[
+                    ("host", Value::Str(&entry.hostname)),
+                    ("port", Value::Num(u64::from(entry.port))),
+                    ("reason", Value::Str("max_changes_per_day exceeded")),
+                ]);
+                keep_existing_port(&mut self.desired_rules, &self.existing_index, entry.port);
+                self.held_back += 1;
+                continue;
+            }
 
-    // Track desired rules and what needs to be added
-    let mut desired_rules: HashSet<(Ipv4Addr, u16)> = HashSet::new();
-    let mut rules_to_add: Vec<(Ipv4Addr, u16)> = Vec::new();
+            // Signed beacon must vouch for the same IP before anything moves
+            if let Some(url) = &entry.beacon {
+                let phase_start = Instant::now();
+                let verdict = match &self.config.beacon_key {
+                    Some(key) => beacon::verified_ip(url, key.as_bytes(), &entry.hostname, self.config.beacon_max_age, now_secs())
+                        .map_err(|e| e.describe().to_string()),
+                    None => Err(String::from("beacon_key not configured")),
+                };
+                self.stats.record(Phase::Dns, phase_start);
+                // Of several addresses, only the one the beacon vouches for
+                let mismatch = match verdict {
+                    Ok(beacon_ip) if ips.contains(&beacon_ip) => {
+                        ips = vec![beacon_ip];
+                        None
+                    }
+                    Ok(beacon_ip) => Some(format!("beacon reports {}", beacon_ip)),
+                    Err(reason) => Some(reason),
+                };
+                if let Some(reason) = mismatch {
+                    println!("{}SKIP ({}, keeping existing)", line, reason);
+                    events::emit("skip", &[
+                        ("host", Value::Str(&entry.hostname)),
+                        ("port", Value::Num(u64::from(entry.port))),
+                        ("reason", Value::Str(&reason)),
+                    ]);
+                    keep_existing_port(&mut self.desired_rules, &self.existing_index, entry.port);
+                    continue;
+                }
+            }
 
-    // Phase 1: Resolve all DNS first (no iptables changes yet)
-    let mut iteration = 0;
-    for entry in &entries {
-        iteration += 1;
-        if iteration > MAX_LOOP_ITERATIONS {
-            eprintln!("[ddnsfw] WARN: Loop protection triggered in phase 1");
-            break;
-        }
+            // Addresses it flapped away from stay allowed (history); a webhook
+            // sync reuses the last addresses and leaves their order alone
+            let keep = entry.history.unwrap_or(self.config.history);
+            let recent: Vec<Ipv4Addr> = self.cache.recent(&label, &ips, keep).into_iter().filter(|ip| !own_addrs.contains(ip)).collect();
+            if keep <= 1 {
+                self.cache.history.remove(&label);
+            } else if !unfocused {
+                self.cache.history.insert(label.clone(), ips.iter().chain(&recent).copied().collect());
+            }
+            for ip in &recent {
+                line.push_str(&format!("(keeping {}: history) ", ip));
+            }
 
-        print!("[ddnsfw] {}:{} -> ", entry.hostname, entry.port);
-        let _ = io::stdout().flush();
+            let mut pending = 0;
+            let mut verified = false;
+            for rule in ips.iter().chain(&recent).flat_map(|&ip| entry.rules_for(ip)) {
+                if !self.backend.supports(&rule) {
+                    line.push_str(&format!("(ignoring {}: needs iptables backend) ", rule));
+                    continue;
+                }
 
-        let Some(ip) = resolve_dns_timeout(&entry.hostname, Duration::from_secs(DNS_TIMEOUT_SECS)) else {
-            println!("SKIP (DNS failed, keeping existing)");
-            // Keep existing rules for this port
-            for &(existing_ip, existing_port) in &existing_rules {
-                if existing_port == entry.port {
-                    desired_rules.insert((existing_ip, existing_port));
+                self.desired_rules.insert(rule.clone());
+                self.classes.entry(rule.clone()).or_insert(entry.priority);
+                let rule_owners = self.owners.entry(rule.clone()).or_default();
+                if !rule_owners.contains(&label) {
+                    rule_owners.push(label.clone());
                 }
-            }
-            continue;
-        };
 
-        print!("{} ", ip);
-        let _ = io::stdout().flush();
+                // Check if rule already exists - if yes, NO OPERATION needed
+                if self.existing_rules.contains(&rule) {
+                    continue;
+                }
 
-        desired_rules.insert((ip, entry.port));
+                // Also check with the backend directly (belt and suspenders)
+                if self.backend.rule_exists(&rule) {
+                    verified = true;
+                    continue;
+                }
 
-        // Check if rule already exists - if yes, NO OPERATION needed
-        if existing_rules.contains(&(ip, entry.port)) {
-            println!("OK (no change)");
-            continue;
+                // Need to add this rule
+                if self.queued.insert(rule.clone()) {
+                    if entry.position != Position::Top && rule.table == entry.table && rule.chain == entry.allow_chain() {
+                        self.positions.insert(rule.clone(), entry.position);
+                    }
+                    self.rules_to_add.push(rule);
+                }
+                pending += 1;
+            }
+
+            match (pending, verified) {
+                (0, false) if self.verbose => println!("{}OK (no change)", line),
+                (0, false) => self.unchanged += 1,
+                (0, true) => println!("{}OK (exists)", line),
+                _ => println!("{}PENDING", line),
+            }
         }
+        throttled.save(&self.entries.iter().map(|e| e.hostname.as_str()).collect::<Vec<_>>());
+        churn.save(&self.entries.iter().map(DdnsEntry::key).collect());
+
+        // Consecutive failures escalate (entries sharing a hostname resolve together)
+        // (a webhook sync leaves the streaks of the hostnames it did not look up)
+        self.failed_hosts.retain(|hostname| !self.resolved_hosts.contains(hostname));
+        let untouched: Vec<(String, escalate::Streak)> = match focus {
+            Some(hosts) => self.cache.failures.iter().filter(|(h, _)| !hosts.contains(*h)).map(|(h, s)| (h.clone(), *s)).collect(),
+            None => Vec::new(),
+        };
+        escalate::update(&mut self.cache.failures, &self.failed_hosts, &self.resolved_hosts, &self.config.escalation, now_secs());
+        self.cache.failures.extend(untouched);
+    }
 
-        // Also check with iptables directly (belt and suspenders)
-        if rule_exists(iptables_bin, ip, entry.port) {
-            println!("OK (exists)");
-            continue;
+    /// Temporary grants, the capacity check and the queued retries, once
+    /// the entries' rules are known
+    fn add_grants(&mut self) {
+        // Temporary grants stay desired until they expire
+        let now = now_secs();
+        let mut ledger = grants::Ledger::load();
+        for (ip, port, expiry) in self.cache.legacy_grants.drain(..) {
+            let grant = grants::TemporaryGrant {
+                kind: grants::Kind::Otp,
+                ip,
+                port,
+                created: now,
+                expires: expiry,
+                creator: String::from("unknown"),
+                reason: String::from("carried over from the cache"),
+            };
+            ledger.grants.push(grant);
         }
+        for grant in ledger.reconcile(now) {
+            let rule = grant.rule();
+            if !self.existing_rules.contains(&rule) && self.queued.insert(rule.clone()) {
+                self.rules_to_add.push(rule.clone());
+            }
+            let owner = grant.kind.owner_label();
+            let rule_owners = self.owners.entry(rule.clone()).or_default();
+            if !rule_owners.contains(&owner) {
+                rule_owners.push(owner);
+            }
+            self.desired_rules.insert(rule);
+        }
+        ledger.save();
+        self.cache.save();
 
-        // Need to add this rule
-        rules_to_add.push((ip, entry.port));
-        println!("PENDING");
-    }
+        check_capacity("rules", self.desired_rules.len(), MAX_RULES);
 
-    // Phase 2: Add new rules (safe - only adds, preserves existing)
-    iteration = 0;
-    for (ip, port) in &rules_to_add {
-        iteration += 1;
-        if iteration > MAX_LOOP_ITERATIONS {
-            eprintln!("[ddnsfw] WARN: Loop protection triggered in phase 2");
-            break;
+        let mut shared: Vec<_> = self.owners.iter().filter(|(_, o)| o.len() > 1).collect();
+        shared.sort_by_key(|(rule, _)| rule.to_string());
+        for (rule, rule_owners) in shared {
+            println!("[ddnsfw] {} shared by {} entries ({})", rule, rule_owners.len(), rule_owners.join(", "));
         }
 
-        print!("[ddnsfw] Adding {}:{} ... ", ip, port);
-        let _ = io::stdout().flush();
+        // Queued retries only stay while their operation is still needed
+        self.cache.retries.retain(|r| match r.op {
+            Op::Add => self.desired_rules.contains(&r.rule) && !self.existing_rules.contains(&r.rule),
+            Op::Delete => !self.desired_rules.contains(&r.rule) && self.existing_rules.contains(&r.rule),
+        });
+        self.cache.save();
+    }
+
+    /// Phase 2: adds the new rules (safe - only adds, preserves existing)
+    fn add(&mut self, batch: &mut Option<restore::Batch>) {
+        let mut iteration = 0;
+        // Class by class, so the adds of critical entries never wait for the others
+        let class = |rule: &Rule| self.classes.get(rule).copied().unwrap_or_default();
+        for priority in Priority::ALL {
+            for rule in self.rules_to_add.iter().filter(|rule| class(rule) == priority) {
+                iteration += 1;
+                if iteration > MAX_RULES {
+                    eprintln!("[ddnsfw] WARN: Loop protection triggered in phase 2");
+                    break;
+                }
 
-        cache.set_adding(*ip, *port);
+                print!("[ddnsfw] Adding {} ... ", rule);
+                let _ = io::stdout().flush();
 
-        if add_rule(iptables_bin, *ip, *port) {
-            cache.add_rule(*ip, *port);
-            println!("OK");
-        } else {
-            // Retry once
-            if add_rule(iptables_bin, *ip, *port) {
-                cache.add_rule(*ip, *port);
-                println!("OK (retry)");
-            } else {
-                cache.set_idle();
-                println!("FAILED (keeping existing)");
-                // Keep existing rules for this port
-                for &(existing_ip, existing_port) in &existing_rules {
-                    if existing_port == *port {
-                        desired_rules.insert((existing_ip, existing_port));
+                // Never install a rule the cache could not track (staged ones included)
+                let tracked = self.cache.rules.len() + batch.as_ref().map_or(0, restore::Batch::staged);
+                if tracked >= MAX_RULES {
+                    println!("SKIPPED (capacity rules={}/{}, keeping existing)", tracked, MAX_RULES);
+                    self.held_back += 1;
+                    keep_existing_port(&mut self.desired_rules, &self.existing_index, rule.port);
+                    continue;
+                }
+
+                match retry::gate(&self.cache.retries, Op::Add, rule, now_secs()) {
+                    Gate::Go => {}
+                    Gate::Deferred(at) => {
+                        println!("DEFERRED (retry at {}, keeping existing)", format_utc(at));
+                        self.held_back += 1;
+                        keep_existing_port(&mut self.desired_rules, &self.existing_index, rule.port);
+                        continue;
+                    }
+                    Gate::Exhausted(attempts) => {
+                        println!("PARKED after {} attempts (run 'ddnsfw retry', keeping existing)", attempts);
+                        self.held_back += 1;
+                        keep_existing_port(&mut self.desired_rules, &self.existing_index, rule.port);
+                        continue;
+                    }
+                }
+
+                if let Some(batch) = batch.as_mut() {
+                    batch.add(rule, self.positions.get(rule).copied().unwrap_or(Position::Top));
+                    println!("STAGED");
+                    continue;
+                }
+
+                self.cache.transition(Event::BeginAdd(rule.clone()));
+
+                if self.backend.add_rule(rule, self.positions.get(rule).copied().unwrap_or(Position::Top)) {
+                    retry::clear(&mut self.cache.retries, Op::Add, rule);
+                    self.cache.transition(Event::Succeeded);
+                    self.stats.added += 1;
+                    println!("OK");
+                    emit_change("add", rule, &self.owners, &self.cache.owners, &self.meta, &self.entries);
+                } else {
+                    // Retry once
+                    if self.backend.add_rule(rule, self.positions.get(rule).copied().unwrap_or(Position::Top)) {
+                        retry::clear(&mut self.cache.retries, Op::Add, rule);
+                        self.cache.transition(Event::Succeeded);
+                        self.stats.added += 1;
+                        println!("OK (retry)");
+                        emit_change("add", rule, &self.owners, &self.cache.owners, &self.meta, &self.entries);
+                    } else {
+                        let attempts = retry::record_failure(&mut self.cache.retries, Op::Add, rule, now_secs());
+                        self.cache.transition(Event::Failed);
+                        self.stats.failed += 1;
+                        println!("FAILED (attempt {}/{}, keeping existing)", attempts, retry::MAX_ATTEMPTS);
+                        emit_change("add_failed", rule, &self.owners, &self.cache.owners, &self.meta, &self.entries);
+                        keep_existing_port(&mut self.desired_rules, &self.existing_index, rule.port);
                     }
                 }
             }
+
+            // The class's staged adds, in one go before the next class and any delete
+            for (rule, added) in batch.as_mut().map(restore::Batch::commit).unwrap_or_default() {
+                if added {
+                    self.cache.transition(Event::BeginAdd(rule.clone()));
+                    self.cache.transition(Event::Succeeded);
+                    retry::clear(&mut self.cache.retries, Op::Add, &rule);
+                    self.stats.added += 1;
+                    emit_change("add", &rule, &self.owners, &self.cache.owners, &self.meta, &self.entries);
+                } else {
+                    let attempts = retry::record_failure(&mut self.cache.retries, Op::Add, &rule, now_secs());
+                    self.stats.failed += 1;
+                    println!("[ddnsfw] Adding {} FAILED (attempt {}/{}, keeping existing)", rule, attempts, retry::MAX_ATTEMPTS);
+                    emit_change("add_failed", &rule, &self.owners, &self.cache.owners, &self.meta, &self.entries);
+                    keep_existing_port(&mut self.desired_rules, &self.existing_index, rule.port);
+                }
+            }
         }
     }
 
-    // Phase 3: Delete old rules (safe - new rules already active)
-    iteration = 0;
-    for &(ip, port) in &existing_rules {
-        iteration += 1;
-        if iteration > MAX_LOOP_ITERATIONS {
-            eprintln!("[ddnsfw] WARN: Loop protection triggered in phase 3");
-            break;
-        }
+    /// Phase 3: deletes the old rules (safe - new rules already active)
+    fn delete(&mut self, batch: &mut Option<restore::Batch>, scopes: &HashSet<(Table, String)>) {
+        let labels: HashSet<String> = self.entries.iter().map(DdnsEntry::key).collect();
+        let mut graced: HashSet<Rule> = HashSet::new();
+        // Old rules of critical entries go last, each once its entry's new
+        // rules are verified in the firewall (see priority)
+        let critical: HashSet<String> =
+            self.entries.iter().filter(|entry| entry.priority == Priority::Critical).map(DdnsEntry::key).collect();
+        let critical_old: HashMap<Rule, String> = self.existing_rules
+            .iter()
+            .filter(|rule| !self.desired_rules.contains(*rule))
+            .filter_map(|rule| Some((rule.clone(), self.cache.owners.get(rule)?.iter().find(|o| critical.contains(*o))?.clone())))
+            .collect();
+        let mut ordered: Vec<&Rule> = self.existing_rules.iter().collect();
+        ordered.sort_by_key(|rule| critical_old.contains_key(*rule));
+        let mut verified: HashMap<String, bool> = HashMap::new();
+        let mut iteration = 0;
+        for rule in ordered {
+            iteration += 1;
+            if iteration > MAX_RULES {
+                eprintln!("[ddnsfw] WARN: Loop protection triggered in phase 3");
+                break;
+            }
+
+            if !self.desired_rules.contains(rule) {
+                // An entry moved: sessions from its old address (NAT) keep
+                // working until grace_seconds have passed, a later sync removes it
+                let now = now_secs();
+                if let Some(until) = self.cache.grace_until(rule, &labels, self.config.grace_seconds, now) {
+                    if self.verbose || !self.cache.retiring.contains_key(rule) {
+                        println!("[ddnsfw] Keeping old {} until {} UTC (grace_seconds)", rule, format_utc(until));
+                    }
+                    self.cache.retiring.entry(rule.clone()).or_insert(now);
+                    graced.insert(rule.clone());
+                    continue;
+                }
+                print!("[ddnsfw] Removing old {} ... ", rule);
+                let _ = io::stdout().flush();
+
+                match retry::gate(&self.cache.retries, Op::Delete, rule, now_secs()) {
+                    Gate::Go => {}
+                    Gate::Deferred(at) => {
+                        println!("DEFERRED (retry at {}, rule remains)", format_utc(at));
+                        self.held_back += 1;
+                        continue;
+                    }
+                    Gate::Exhausted(attempts) => {
+                        println!("PARKED after {} attempts (run 'ddnsfw retry', rule remains)", attempts);
+                        self.held_back += 1;
+                        continue;
+                    }
+                }
+
+                if let Some(key) = critical_old.get(rule) {
+                    let in_place = *verified.entry(key.clone()).or_insert_with(|| {
+                        self.owners
+                            .iter()
+                            .filter(|(new, new_owners)| new_owners.contains(key) && !self.existing_rules.contains(*new))
+                            .all(|(new, _)| self.backend.rule_exists(new))
+                    });
+                    if !in_place {
+                        println!("HELD (new rules of {} not verified, rule remains)", key);
+                        self.held_back += 1;
+                        continue;
+                    }
+                }
+
+                if let Some(batch) = batch.as_mut() {
+                    if batch.delete(rule) {
+                        println!("STAGED");
+                    } else {
+                        println!("FENCED (not found as ddnsfw wrote it, left alone)");
+                        self.held_back += 1;
+                    }
+                    continue;
+                }
 
-        if !desired_rules.contains(&(ip, port)) {
-            print!("[ddnsfw] Removing old {}:{} ... ", ip, port);
-            let _ = io::stdout().flush();
+                if !self.backend.owns(rule) {
+                    println!("FENCED (not found as ddnsfw wrote it, left alone)");
+                    self.held_back += 1;
+                    continue;
+                }
 
-            cache.set_deleting(ip, port);
+                self.cache.transition(Event::BeginDelete(rule.clone()));
 
-            if delete_rule(iptables_bin, ip, port) {
-                cache.remove_rule(ip, port);
-                println!("OK");
+                if self.backend.delete_rule(rule) {
+                    retry::clear(&mut self.cache.retries, Op::Delete, rule);
+                    self.cache.transition(Event::Succeeded);
+                    self.stats.removed += 1;
+                    println!("OK");
+                    emit_change("delete", rule, &self.cache.owners, &self.owners, &self.meta, &self.entries);
+                } else {
+                    let attempts = retry::record_failure(&mut self.cache.retries, Op::Delete, rule, now_secs());
+                    self.cache.transition(Event::Failed);
+                    self.stats.failed += 1;
+                    println!("FAILED (attempt {}/{}, rule remains)", attempts, retry::MAX_ATTEMPTS);
+                    emit_change("delete_failed", rule, &self.cache.owners, &self.owners, &self.meta, &self.entries);
+                }
+            }
+        }
+        // The staged deletes, in one go
+        for (rule, deleted) in batch.as_mut().map(restore::Batch::commit).unwrap_or_default() {
+            if deleted {
+                self.cache.transition(Event::BeginDelete(rule.clone()));
+                self.cache.transition(Event::Succeeded);
+                retry::clear(&mut self.cache.retries, Op::Delete, &rule);
+                self.stats.removed += 1;
+                emit_change("delete", &rule, &self.cache.owners, &self.owners, &self.meta, &self.entries);
             } else {
-                cache.set_idle();
-                println!("FAILED (rule remains)");
+                let attempts = retry::record_failure(&mut self.cache.retries, Op::Delete, &rule, now_secs());
+                self.stats.failed += 1;
+                println!("[ddnsfw] Removing old {} FAILED (attempt {}/{}, rule remains)", rule, attempts, retry::MAX_ATTEMPTS);
+                emit_change("delete_failed", &rule, &self.cache.owners, &self.owners, &self.meta, &self.entries);
+            }
+        }
+        // Turned off: the chain goes once its rules moved back to INPUT
+        if let Backend::Iptables(bin) = &self.backend {
+            if !dedicated::is_enabled() && scopes.contains(&(Table::Filter, dedicated::name().to_string())) {
+                dedicated::retire(bin);
+            }
+        }
+
+        // Graced rules stay desired (owners, companions); an address wanted
+        // again, or gone, leaves the list
+        self.desired_rules.extend(graced.iter().cloned());
+        let installed = &self.cache.rules;
+        self.cache.retiring.retain(|rule, _| graced.contains(rule) || (installed.contains(rule) && !self.desired_rules.contains(rule)));
+    }
+
+    /// Records the provenance of what is installed and prunes stale state
+    fn record_owners(&mut self) {
+        // Record provenance of what is installed; rules kept by fail-safe (DNS
+        // or add failure) keep their previous owners, and rules restored after a
+        // reboot keep their original install time
+        let previous_owners = std::mem::take(&mut self.cache.owners);
+        let previous_since = std::mem::take(&mut self.cache.since);
+        let now = now_secs();
+        for rule in self.cache.rules.iter().filter(|r| self.desired_rules.contains(r)) {
+            if let Some(rule_owners) = self.owners.remove(rule).or_else(|| previous_owners.get(rule).cloned()) {
+                self.cache.owners.insert(rule.clone(), rule_owners);
+            }
+            self.cache.since.insert(rule.clone(), previous_since.get(rule).copied().unwrap_or(now));
+        }
+        self.cache.meta = std::mem::take(&mut self.meta);
+        let live = self.cache.rules.clone();
+        for item in prune::prune(&mut self.cache, Some(&live), now, self.config.state_retention) {
+            println!("[ddnsfw] Pruned {}", item);
+        }
+        self.cache.save();
+    }
+
+    /// Phase 4: reconciles the companion rules (connlimit, knock, lockdown)
+    /// of the active allow rules, and the backends' own upkeep
+    fn companions(&self) {
+        if let Backend::Iptables(bin) = &self.backend {
+            let mut limits: HashMap<(Ipv4Addr, u16), u32> = HashMap::new();
+            let mut knocks: Vec<(Ipv4Addr, u16, Vec<u16>)> = Vec::new();
+            let mut knocked: HashSet<(Ipv4Addr, u16)> = HashSet::new();
+            let mut active_by_port: HashMap<u16, Vec<&Rule>> = HashMap::new();
+            for rule in self.desired_rules.iter().filter(|r| dedicated::is_inbound_allow(r) && self.cache.rules.contains(r)) {
+                active_by_port.entry(rule.port).or_default().push(rule);
+            }
+            for entry in &self.entries {
+                for rule in active_by_port.get(&entry.port).into_iter().flatten() {
+                    let (ip, port) = (rule.ip, rule.port);
+                    if let Some(limit) = entry.max_conns {
+                        let current = limits.entry((ip, port)).or_insert(limit);
+                        *current = (*current).min(limit);
+                    }
+                    if let Some(sequence) = &entry.knock {
+                        if knocked.insert((ip, port)) {
+                            knocks.push((ip, port, sequence.clone()));
+                        }
+                    }
+                }
+            }
+            sync_connlimits(bin, &limits);
+            knock::sync(bin, &knocks);
+
+            // Lockdowns of ports with an active allow; the first entry's mode wins
+            let open: HashSet<(rule::Proto, u16)> = self.cache
+                .rules
+                .iter()
+                .filter(|r| r.table == Table::Filter && (r.chain == "INPUT" || r.chain == dedicated::name()) && self.desired_rules.contains(*r))
+                .map(|r| (r.proto, r.port))
+                .collect();
+            let mut lockdowns: HashMap<(rule::Proto, u16), lockdown::Mode> = HashMap::new();
+            for entry in &self.entries {
+                let Some(mode) = entry.lockdown else {
+                    continue;
+                };
+                if open.contains(&(entry.allow_proto(), entry.port)) {
+                    lockdowns.entry((entry.allow_proto(), entry.port)).or_insert(mode);
+                }
             }
+            lockdown::sync(bin, &lockdowns);
+        } else if self.entries.iter().any(|e| e.max_conns.is_some() || e.knock.is_some() || e.lockdown.is_some()) {
+            eprintln!("[ddnsfw] WARN: max_conns, knock and lockdown are only supported by the iptables backend");
+        }
+        // Element timeouts restart from every sync that still wants the address
+        if let Backend::Nftables(nft) = &self.backend {
+            nft.refresh(self.cache.rules.iter().filter(|r| self.desired_rules.contains(r)), &self.cache.owners);
+        }
+        if let Backend::Ipset(ipset) = &self.backend {
+            ipset.tidy();
+        }
+        if let Backend::Firewalld(firewalld) = &self.backend {
+            firewalld.tidy();
+        }
+    }
+}
+
+/// Adds missing connlimit companions, then drops those whose limit changed
+/// or whose allow rule is gone.
+fn sync_connlimits(bin: &str, limits: &HashMap<(Ipv4Addr, u16), u32>) {
+    let existing = get_existing_connlimits(bin);
+
+    for (&(ip, port), &limit) in limits.iter().take(MAX_RULES) {
+        if existing.contains(&(ip, port, limit)) {
+            continue;
+        }
+        print!("[ddnsfw] Limiting {}:{} to {} connections ... ", ip, port, limit);
+        let _ = io::stdout().flush();
+        if connlimit_rule(bin, "-I", ip, port, limit) {
+            println!("OK");
+        } else {
+            println!("FAILED");
         }
     }
 
-    cache.set_idle();
-    println!("[ddnsfw] Sync complete");
+    for &(ip, port, limit) in existing.iter().take(MAX_RULES) {
+        if limits.get(&(ip, port)) == Some(&limit) {
+            continue;
+        }
+        print!("[ddnsfw] Removing connection limit {}:{} ... ", ip, port);
+        let _ = io::stdout().flush();
+        if connlimit_rule(bin, "-D", ip, port, limit) {
+            println!("OK");
+        } else {
+            println!("FAILED (rule remains)");
+        }
+    }
 }
 
 // ============================================================================
@@ -743,7 +3812,7 @@ fn interactive_setup() -> Vec<DdnsEntry> {
         };
 
         println!("Added: {}:{}", hostname, port);
-        entries.push(DdnsEntry { hostname, port });
+        entries.push(DdnsEntry::new(hostname, port));
 
         if !prompt_yn("\nAdd another entry?", false) {
             break;
@@ -766,25 +3835,105 @@ fn interactive_setup() -> Vec<DdnsEntry> {
     entries
 }
 
-fn install(entries: Vec<DdnsEntry>) {
+/// The default config of an interactive install
+fn entries_config(entries: &[DdnsEntry]) -> String {
+    let mut config = String::from(
+        "# DDNS Firewall Configuration\n\
+         # Format: hostname:port[/udp]\n\n\
+         schema = 1\n\n",
+    );
+    for e in entries {
+        config.push_str(&format!("{}:{}\n", e.hostname, e.port));
+    }
+    config
+}
+
+/// The sync service unit; `template` for the `ddnsfw@.service` of profiles
+fn service_unit(template: bool) -> &'static str {
+    if template {
+        r#"[Unit]
+Description=DDNS Firewall Synchronizer (profile %i)
+After=network-online.target
+Wants=network-online.target
+
+[Service]
+Type=oneshot
+ExecStart=/etc/ddnsfw/run --profile %i
+User=root
+RuntimeDirectory=ddnsfw
+RuntimeDirectoryPreserve=yes
+StateDirectory=ddnsfw
+StandardOutput=journal
+StandardError=journal
+SyslogIdentifier=ddnsfw-%i
+
+[Install]
+WantedBy=multi-user.target
+"#
+    } else {
+        r#"[Unit]
+Description=DDNS Firewall Synchronizer
+After=network-online.target
+Wants=network-online.target
+
+[Service]
+Type=oneshot
+ExecStart=/etc/ddnsfw/run
+User=root
+RuntimeDirectory=ddnsfw
+RuntimeDirectoryPreserve=yes
+StateDirectory=ddnsfw
+StandardOutput=journal
+StandardError=journal
+SyslogIdentifier=ddnsfw
+
+[Install]
+WantedBy=multi-user.target
+"#
+    }
+}
+
+fn timer_unit(template: bool) -> String {
+    format!(
+        r#"[Unit]
+Description=DDNS Firewall Synchronizer Timer{}
+
+[Timer]
+OnBootSec=30sec
+OnUnitActiveSec={}sec
+RandomizedDelaySec=10sec
+Persistent=true
+
+[Install]
+WantedBy=timers.target
+"#,
+        if template { " (profile %i)" } else { "" },
+        TIMER_INTERVAL_SECS
+    )
+}
+
+/// Installs the running binary with the given config and units
+fn install(config: &str, service: &str, timer: &str) {
     println!("\nInstalling...\n");
 
+    let profile = profile::current();
+
     print!("  [1/8] Creating directory... ");
-    if fs::create_dir_all(INSTALL_DIR).is_err() {
+    if fs::create_dir_all(&profile.dir).is_err() {
         exit_err("Failed to create directory");
     }
     // Set directory permissions to 700 (rwx------) - only root can access
-    if fs::set_permissions(INSTALL_DIR, fs::Permissions::from_mode(0o700)).is_err() {
-        exit_err("Failed to set directory permissions");
+    for dir in [INSTALL_DIR, profile.dir.as_str()] {
+        if fs::set_permissions(dir, fs::Permissions::from_mode(0o700)).is_err() {
+            exit_err("Failed to set directory permissions");
+        }
     }
     println!("OK");
 
     print!("  [2/8] Copying binary... ");
     let exe = env::current_exe().unwrap_or_else(|_| exit_err("Cannot get exe path"));
-    if exe.to_string_lossy() != BINARY_PATH {
-        if fs::copy(&exe, BINARY_PATH).is_err() {
-            exit_err("Failed to copy binary");
-        }
+    if exe.to_string_lossy() != BINARY_PATH && fs::copy(&exe, BINARY_PATH).is_err() {
+        exit_err("Failed to copy binary");
     }
     // Set binary permissions to 700 (rwx------) - only root can execute
     if fs::set_permissions(BINARY_PATH, fs::Permissions::from_mode(0o700)).is_err() {
@@ -793,19 +3942,12 @@ fn install(entries: Vec<DdnsEntry>) {
     println!("OK");
 
     print!("  [3/8] Creating config... ");
-    let mut config = String::from(
-        "# DDNS Firewall Configuration\n\
-         # Format: hostname:port\n\n",
-    );
-    for e in &entries {
-        config.push_str(&format!("{}:{}\n", e.hostname, e.port));
-    }
     let file = OpenOptions::new()
         .write(true)
         .create(true)
         .truncate(true)
         .mode(0o600)
-        .open(CONFIG_PATH);
+        .open(&profile::current().config_path);
     if file.is_err() || file.unwrap().write_all(config.as_bytes()).is_err() {
         exit_err("Failed to write config");
     }
@@ -821,58 +3963,40 @@ fn install(entries: Vec<DdnsEntry>) {
     if OpenOptions::new()
         .write(true)
         .create(true)
+        .truncate(false)
         .mode(0o600)
-        .open(LOCK_PATH)
+        .open(&profile::current().lock_path)
         .is_err()
     {
         exit_err("Failed to create lock file");
     }
     println!("OK");
 
-    print!("  [6/8] Creating systemd service... ");
-    let service = r#"[Unit]
-Description=DDNS Firewall Synchronizer
-After=network-online.target
-Wants=network-online.target
-
-[Service]
-Type=oneshot
-ExecStart=/etc/ddnsfw/run
-User=root
-StandardOutput=journal
-StandardError=journal
-SyslogIdentifier=ddnsfw
+    // A named profile runs as an instance of template units, each with its
+    // own unit name and journal identifier
+    let (service_path, timer_path) = match profile.name {
+        Some(_) => (TEMPLATE_SERVICE_PATH, TEMPLATE_TIMER_PATH),
+        None => (SERVICE_PATH, TIMER_PATH),
+    };
 
-[Install]
-WantedBy=multi-user.target
-"#;
-    if fs::write(SERVICE_PATH, service).is_err() {
+    print!("  [6/8] Creating systemd service... ");
+    if fs::write(service_path, service).is_err() {
         exit_err("Failed to write service file");
     }
     println!("OK");
 
     print!("  [7/8] Creating systemd timer... ");
-    let timer = r#"[Unit]
-Description=DDNS Firewall Synchronizer Timer
-
-[Timer]
-OnBootSec=30sec
-OnUnitActiveSec=2min
-RandomizedDelaySec=10sec
-Persistent=true
-
-[Install]
-WantedBy=timers.target
-"#;
-    if fs::write(TIMER_PATH, timer).is_err() {
+    if fs::write(timer_path, timer).is_err() {
         exit_err("Failed to write timer file");
     }
     println!("OK");
 
     print!("  [8/8] Enabling service... ");
     let _ = Command::new("systemctl").args(["daemon-reload"]).output();
-    let _ = Command::new("systemctl").args(["enable", "ddnsfw.timer"]).output();
-    let _ = Command::new("systemctl").args(["start", "ddnsfw.timer"]).output();
+    let unit = profile.unit();
+    let timer_unit = format!("{}.timer", unit);
+    let _ = Command::new("systemctl").args(["enable", &timer_unit]).output();
+    let _ = Command::new("systemctl").args(["start", &timer_unit]).output();
     println!("OK");
 
     println!("\n╔════════════════════════════════════════════════════════════╗");
@@ -880,35 +4004,226 @@ WantedBy=timers.target
     println!("╚════════════════════════════════════════════════════════════╝");
     println!("\nFiles:");
     println!("  Binary:  {}", BINARY_PATH);
-    println!("  Config:  {}", CONFIG_PATH);
-    println!("  Cache:   {}", CACHE_PATH);
-    println!("  Service: {}", SERVICE_PATH);
-    println!("  Timer:   {}", TIMER_PATH);
+    println!("  Config:  {}", profile::current().config_path);
+    println!("  Cache:   {}", mirror::cache_path());
+    println!("  Service: {}", service_path);
+    println!("  Timer:   {}", timer_path);
     println!("\nCommands:");
-    println!("  Status:  systemctl status ddnsfw.timer");
-    println!("  Logs:    journalctl -u ddnsfw -f");
-    println!("  Rules:   iptables -L INPUT -n | grep DDNS");
+    println!("  Status:  systemctl status {}", timer_unit);
+    println!("  Logs:    journalctl -t {} -f", profile.syslog_identifier());
+    println!("  Rules:   iptables -S INPUT | grep -w {}", profile.comment());
 
     println!("\nRunning initial sync...\n");
-    let _ = Command::new("systemctl").args(["start", "ddnsfw.service"]).output();
+    let _ = Command::new("systemctl").args(["start", &format!("{}.service", unit)]).output();
 }
 
 // ============================================================================
 // Main
 // ============================================================================
 
+const USAGE: &str = "Usage: ddnsfw [--profile NAME] [--strict] [--verbose] [--explain-execs] [COMMAND]
+
+Commands:
+  sync              Sync the firewall now (waits for a running sync)
+  plan              What a sync would add and remove, without changing
+                    anything (also sync --dry-run; exit 2 when changes are pending)
+  install           Interactive install of this binary, config and timer
+  uninstall         Remove this profile: rules, timer, config and state
+                    (--keep-config, --keep-rules)
+  status [--json]   Installed paths, timer, last sync, rules and entries
+  list              Installed rules with their entries
+  why IP [PORT[/udp]]
+                    Which rules let IP in, whose they are and until when
+  doctor            Health check with suggested fixes
+  verify-install [--sha256 HEX]
+                    Post-provisioning checks for CI, as JSON (exit 1 on failure)
+  fix-perms [--check]
+                    Repair owners and modes of the installed files
+  config validate   Check the config
+  migrate-config [--dry-run]
+                    Convert conf.conf to config.toml
+  add, remove, disable, enable, rename
+                    Edit entries, then sync (conf.conf only)
+  adopt --comment OLD | --port PORT[,PORT]
+                    Take over allow rules of a predecessor (--rewrite, --dry-run)
+  logs, events, report
+                    Sync history (--help on each for options)
+  export entries|rules|history [--format csv|json|terraform]
+                    Access data as CSV for spreadsheets and tickets
+  daemon            Long-running mode instead of the timer
+  backup create|list|restore
+                    Snapshots of config and state (before every edit too)
+  retry, state prune, simulate-change, grants, otp-allow, bundle, service-user
+  push SERVER:PORT HOSTNAME IP
+                    Send a signed push to a daemon (key in DDNSFW_PUSH_KEY)
+
+Without a command: the installed binary runs a timer sync, any other
+copy starts the interactive install.
+
+Options:
+  --explain-execs   Print every external command a sync runs, then the
+                    counts and the repeated ones
+  -h, --help        Show this help
+  -V, --version     Show the version";
+
+/// `ddnsfw sync`: an explicit sync; exit 1 if it failed. `--dry-run`
+/// only prints the plan.
+fn sync_command(args: &[String]) {
+    match args {
+        [] => {}
+        [flag] if flag == "--dry-run" => return plan::command(&[]),
+        _ => exit_err("Usage: ddnsfw sync [--dry-run]"),
+    }
+    if !sync_firewall() {
+        std::process::exit(1);
+    }
+}
+
+/// `ddnsfw install`: the interactive install, wherever the binary is
+fn install_command(args: &[String]) {
+    if !args.is_empty() {
+        exit_err("Usage: ddnsfw install");
+    }
+    if is_installed() {
+        exit_err(&format!("Already installed at {} (remove with: ddnsfw uninstall)", BINARY_PATH));
+    }
+    let entries = interactive_setup();
+    let template = profile::current().name.is_some();
+    install(&entries_config(&entries), service_unit(template), &timer_unit(template));
+}
+
 fn main() {
-    if !is_root() {
-        exit_err("Must run as root");
+    let mut args: Vec<String> = env::args().collect();
+
+    // Hidden soak test: the driver, or one of its sandboxed child syncs
+    if let Some(chaos) = chaos::active() {
+        profile::select_sandbox(chaos.dir());
+        match args.get(1).map(String::as_str) {
+            Some("retry") => retry::retry_command(),
+            _ => {
+                sync_firewall();
+            }
+        }
+        return;
+    }
+    if args.get(1).map(String::as_str) == Some("--chaos") {
+        return chaos::run(&args[2..]);
+    }
+    if args.get(1).map(String::as_str) == Some("--bench") {
+        return bench::run(&args[2..]);
+    }
+
+    let mut profile_name = env::var("DDNSFW_PROFILE").ok().filter(|name| !name.is_empty());
+    if args.get(1).map(String::as_str) == Some("--profile") {
+        let Some(name) = args.get(2).cloned() else {
+            exit_err("Usage: ddnsfw --profile <name> [command]");
+        };
+        profile_name = Some(name);
+        args.drain(1..3);
+    }
+    profile::select(profile_name);
+    while let Some(flag) = args.get(1).filter(|a| matches!(a.as_str(), "--strict" | "--verbose" | "--explain-execs")) {
+        match flag.as_str() {
+            "--strict" => STRICT_FLAG.store(true, Ordering::Relaxed),
+            "--verbose" => VERBOSE_FLAG.store(true, Ordering::Relaxed),
+            _ => execs::explain(),
+        }
+        args.remove(1);
+    }
+    // No root needed
+    match args.get(1).map(String::as_str) {
+        Some("-h" | "--help" | "help") => return println!("{}", USAGE),
+        Some("-V" | "--version") => return println!("ddnsfw {}", env!("CARGO_PKG_VERSION")),
+        Some("push") => return push::send(&args[2..]),
+        _ => {}
+    }
+    // Root, or the service user when that mode is enabled
+    privsep::enter(args.get(1).map(String::as_str));
+    if args.get(1).map(String::as_str) != Some(privsep::HELPER_COMMAND) {
+        profile::current().relocate();
+    }
+
+    match args.get(1).map(String::as_str) {
+        Some("sync") => return sync_command(&args[2..]),
+        Some("plan") => return plan::command(&args[2..]),
+        Some("why") => return why::command(&args[2..]),
+        Some("install") => return install_command(&args[2..]),
+        Some("uninstall") => return uninstall::command(&args[2..]),
+        Some("status") => return status::command(&args[2..]),
+        Some("otp-allow") => return otp::otp_allow(&args[2..]),
+        Some("add") => return edit::add(&args[2..]),
+        Some("remove") => return edit::remove(&args[2..]),
+        Some("disable") => return edit::disable(&args[2..]),
+        Some("enable") => return edit::enable(&args[2..]),
+        Some("rename") => return edit::rename(&args[2..]),
+        Some("adopt") => return adopt::command(&args[2..]),
+        Some("config") => return config_command(&args[2..]),
+        Some("migrate-config") => return migrate::command(&args[2..]),
+        Some("list") => return list(),
+        Some("logs") => return logs::logs(&args[2..]),
+        Some("events") => return events::events(&args[2..]),
+        Some("retry") => return retry::retry_command(),
+        Some("doctor") => return doctor::doctor(&args[2..]),
+        Some("verify-install") => return verify::command(&args[2..]),
+        Some("fix-perms") => return perms::command(&args[2..]),
+        Some("state") => return prune::command(&args[2..]),
+        Some("daemon") => return daemon::run(&args[2..]),
+        Some("simulate-change") => return simulate::command(&args[2..]),
+        Some("report") => return report::command(&args[2..]),
+        Some("export") => return export::command(&args[2..]),
+        Some("grants") => return grants::command(&args[2..]),
+        Some("bundle") => return bundle::create(&args[2..]),
+        Some("backup") => return backup::command(&args[2..]),
+        Some("service-user") => return privsep::command(&args[2..]),
+        Some(privsep::HELPER_COMMAND) => return privsep::helper(&args[2..]),
+        Some("--from-bundle") => return bundle::install_from(&args[2..]),
+        Some(other) => exit_err(&format!("Unknown command '{}' (see ddnsfw --help)", other)),
+        None => {}
     }
 
     if is_installed() && is_running_installed() {
-        sync_firewall();
+        // Strict mode: a non-zero exit fails the unit, so OnFailure= fires
+        if !scheduled_sync() {
+            std::process::exit(1);
+        }
     } else if is_installed() {
         println!("Already installed at {}", BINARY_PATH);
-        println!("To reinstall: sudo rm -rf {} {} {}", INSTALL_DIR, SERVICE_PATH, TIMER_PATH);
+        println!("To reinstall: sudo {} uninstall, then run this binary again", BINARY_PATH);
     } else {
         let entries = interactive_setup();
-        install(entries);
+        let template = profile::current().name.is_some();
+        install(&entries_config(&entries), service_unit(template), &timer_unit(template));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strip_comment_keeps_hashes_inside_values_and_quotes() {
+        assert_eq!(strip_comment("grace_seconds = 300   # keep old"), "grace_seconds = 300");
+        assert_eq!(strip_comment("host:22 history=3\t# or per entry"), "host:22 history=3");
+        assert_eq!(strip_comment("push_key = s3cr#t-key-value"), "push_key = s3cr#t-key-value");
+        assert_eq!(strip_comment("host:22 note=\"ticket #42\" # why"), "host:22 note=\"ticket #42\"");
+        assert_eq!(strip_comment("host:22"), "host:22");
+    }
+
+    #[test]
+    fn trailing_comments_do_not_reach_settings_or_entries() {
+        let path = std::env::temp_dir().join(format!("ddnsfw-comments-{}.conf", std::process::id()));
+        let content = "grace_seconds = 300   # keep old\n\
+                       push_key = 0123456789abcdef   # at least 16 characters\n\
+                       host.example.com:22 history=3   # or per entry\n";
+        fs::write(&path, content).unwrap();
+        let mut config = Config::new();
+        load_config_file(&path, &mut config, &mut HashMap::new(), 0);
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(config.grace_seconds, 300);
+        assert_eq!(config.push_key.as_deref(), Some("0123456789abcdef"));
+        assert_eq!(config.entries.len(), 1);
+        assert_eq!(config.entries[0].history, Some(3));
+        assert_eq!(config.entries[0].spec, "host.example.com:22 history=3");
     }
 }
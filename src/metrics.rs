@@ -0,0 +1,154 @@
+//! Prometheus metrics, for node_exporter's textfile collector or scraped
+//! from the daemon.
+//!
+//!   metrics_file = /var/lib/node_exporter/textfile_collector/ddnsfw.prom
+//!   metrics_listen = 127.0.0.1:9464    # HTTP /metrics, `ddnsfw daemon` only
+//!
+//! With `metrics_file`, every completed sync rewrites the file (through a
+//! `.tmp` sibling and a rename, so the collector never reads half of it;
+//! mode 644, node_exporter runs as its own user). With `metrics_listen`,
+//! the daemon answers `GET /metrics` with the same text, read from the
+//! state file and the cache at scrape time:
+//!
+//!   ddnsfw_syncs_total{profile="default"} 812
+//!   ddnsfw_dns_failures_total{profile="default"} 3
+//!   ddnsfw_rules_added_total{profile="default"} 41
+//!   ddnsfw_rules_removed_total{profile="default"} 38
+//!   ddnsfw_rule_failures_total{profile="default"} 0
+//!   ddnsfw_last_sync_timestamp_seconds{profile="default"} 1767225600
+//!   ddnsfw_last_sync_duration_seconds{profile="default"} 0.41
+//!   ddnsfw_rules{profile="default",family="ipv4"} 3
+//!
+//! The counters are running sums kept in the state file (see stats), so
+//! they survive the timer's short-lived processes; deleting the state file
+//! resets them, which Prometheus handles as any counter reset. A DNS
+//! failure is a hostname no entry could resolve in a sync. Syncs that stop
+//! early (lock, missing firewall tool) are not counted. The listener is
+//! plain HTTP without authentication and serves nothing but these numbers:
+//! bind it to loopback or the monitoring network.
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::fs::OpenOptionsExt;
+use std::thread;
+use std::time::Duration;
+
+use crate::webhook::read_request;
+use crate::{exit_err, profile, stats, Cache, Config};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// `metrics_file` paths: absolute, ending in `.prom` (what the collector reads)
+pub fn is_valid_file(path: &str) -> bool {
+    path.starts_with('/') && path.ends_with(".prom") && !path.contains("/../")
+}
+
+/// The exposition text for the last completed sync and `rules` per family
+fn render(rules: usize, rules6: usize) -> String {
+    let profile = profile::current();
+    let label = format!("profile=\"{}\"", profile.name.as_deref().unwrap_or("default"));
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, String)]| {
+        out.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind));
+        for (labels, value) in samples {
+            out.push_str(&format!("{}{{{}}} {}\n", name, labels, value));
+        }
+    };
+    let total = |key: &str| vec![(label.clone(), stats::last(key).unwrap_or(0).to_string())];
+
+    metric("ddnsfw_syncs_total", "counter", "Completed syncs.", &total("syncs_total"));
+    metric("ddnsfw_dns_failures_total", "counter", "Hostnames that failed to resolve, summed over syncs.", &total("dns_failures_total"));
+    metric("ddnsfw_rules_added_total", "counter", "Firewall rules added.", &total("added_total"));
+    metric("ddnsfw_rules_removed_total", "counter", "Firewall rules removed.", &total("removed_total"));
+    metric("ddnsfw_rule_failures_total", "counter", "Rule adds and deletes that failed.", &total("failed_total"));
+    // Before the first completed sync there is no last sync to describe
+    if let Some(finished) = stats::last("finished") {
+        let duration = stats::last("total_ms").unwrap_or(0) as f64 / 1000.0;
+        metric(
+            "ddnsfw_last_sync_timestamp_seconds",
+            "gauge",
+            "Unix time the last completed sync finished.",
+            &[(label.clone(), finished.to_string())],
+        );
+        metric(
+            "ddnsfw_last_sync_duration_seconds",
+            "gauge",
+            "Duration of the last completed sync.",
+            &[(label.clone(), duration.to_string())],
+        );
+    }
+    metric(
+        "ddnsfw_rules",
+        "gauge",
+        "Firewall rules currently managed.",
+        &[(format!("{},family=\"ipv4\"", label), rules.to_string()), (format!("{},family=\"ipv6\"", label), rules6.to_string())],
+    );
+    out
+}
+
+/// Rewrites `metrics_file` after a sync. Best effort: a collector directory
+/// that is missing or not writable only warns.
+pub fn write(config: &Config, rules: usize, rules6: usize) {
+    let Some(path) = &config.metrics_file else {
+        return;
+    };
+    let temp_path = format!("{}.tmp", path);
+    let written = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o644)
+        .open(&temp_path)
+        .and_then(|mut file| file.write_all(render(rules, rules6).as_bytes()))
+        .and_then(|_| fs::rename(&temp_path, path));
+    if let Err(e) = written {
+        let _ = fs::remove_file(&temp_path);
+        eprintln!("[ddnsfw] WARN: Cannot write metrics to {}: {}", path, e);
+    }
+}
+
+fn respond(stream: &mut TcpStream, status: &str, content_type: &str, text: &str) {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        text.len(),
+        text
+    );
+    let _ = stream.write_all(response.as_bytes());
+}
+
+fn handle(mut stream: TcpStream) {
+    let _ = stream.set_read_timeout(Some(REQUEST_TIMEOUT));
+    let _ = stream.set_write_timeout(Some(REQUEST_TIMEOUT));
+    let Some(request) = read_request(&mut stream) else {
+        return respond(&mut stream, "400 Bad Request", "text/plain", "bad request\n");
+    };
+    let path = request.target.split_once('?').map_or(request.target.as_str(), |(path, _)| path);
+    if path != "/metrics" {
+        return respond(&mut stream, "404 Not Found", "text/plain", "not found: use /metrics\n");
+    }
+    if request.method != "GET" {
+        return respond(&mut stream, "405 Method Not Allowed", "text/plain", "use GET\n");
+    }
+    let cache = Cache::load();
+    respond(&mut stream, "200 OK", CONTENT_TYPE, &render(cache.rules.len(), cache.rules6.len()));
+}
+
+/// Starts the metrics listener when `metrics_listen` is set. Call after the
+/// daemon signals are blocked.
+pub fn listen(config: &Config) {
+    let Some(addr) = config.metrics_listen else {
+        return;
+    };
+    let listener =
+        TcpListener::bind(addr).unwrap_or_else(|e| exit_err(&format!("Cannot serve metrics on {}: {}", addr, e)));
+    println!("[ddnsfw] Serving metrics on http {}/metrics", addr);
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            handle(stream);
+        }
+    });
+}
@@ -0,0 +1,496 @@
+//! `ddnsfw migrate-config [--dry-run]`: converts conf.conf to config.toml.
+//!
+//! Settings become top-level keys (repeated ones an array, otherwise the
+//! last wins, as when reading), includes an `include` array, and every
+//! entry line an `[[entry]]` table: `$VARIABLES` are expanded, bundle
+//! lines and `[bundle]` sections become `ports` arrays, `#disabled` lines
+//! `enabled = false`. A `[credentials NAME]` section becomes a
+//! `[[credentials]]` table. Integers and yes/no become TOML integers and
+//! booleans. Comments move with the setting or entry below them. A line
+//! that cannot be converted is kept as an `# UNCONVERTED:` comment, with a
+//! warning. Included files are left as they are: they are still read in
+//! the classic format.
+//!
+//! Before anything changes, both files are read into a config and their
+//! entries compared; a difference aborts the migration. Then config.toml
+//! is written (mode 600, never over an existing one), a snapshot is taken,
+//! and conf.conf is renamed to conf.conf.migrated, so the next sync reads
+//! the new file. `--dry-run` prints the TOML instead.
+
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use crate::tomlconfig::{self, ENTRY_KEYS, REPEATABLE};
+use crate::{
+    acquire_lock, backup, exit_err, expand_variables, is_valid_bundle_name, kube, load_config_file, parse_port_list,
+    parse_setting_line, parse_variable_line, profile, split_tokens, strip_comment, Config, DdnsEntry, MAX_CONFIG_LINES,
+};
+
+const USAGE: &str = "Usage: ddnsfw migrate-config [--dry-run]";
+const DISABLED_PREFIX: &str = "#disabled ";
+const INCLUDE_KEYS: &[&str] = &["include", "include_if_exists"];
+
+/// A TOML basic string
+fn quote(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\t' => out.push_str("\\t"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// A conf.conf value as TOML: integers, yes/no and all-integer comma
+/// lists typed, anything else a string. Only values that read back the
+/// same are typed (`022` stays a string).
+fn typed(value: &str) -> String {
+    let int = |s: &str| s.parse::<i64>().ok().filter(|n| n.to_string() == s);
+    match value {
+        "yes" => return String::from("true"),
+        "no" => return String::from("false"),
+        _ => {}
+    }
+    if let Some(n) = int(value) {
+        return n.to_string();
+    }
+    let parts: Vec<&str> = value.split(',').collect();
+    if parts.len() > 1 && parts.iter().all(|part| int(part).is_some()) {
+        return format!("[{}]", parts.join(", "));
+    }
+    quote(value)
+}
+
+fn is_bare_key(key: &str) -> bool {
+    !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// A top-level key with its values (TOML) and the comments above it
+struct Global {
+    comments: Vec<String>,
+    key: String,
+    values: Vec<String>,
+}
+
+#[derive(Default)]
+struct Migration {
+    comments: Vec<String>,  // Waiting for the next setting or entry
+    globals: Vec<Global>,
+    credentials: Vec<String>,  // Rendered [[credentials]] tables
+    entries: Vec<String>,  // Rendered [[entry]] tables
+    unconverted: usize,
+}
+
+impl Migration {
+    fn setting(&mut self, key: &str, value: String) {
+        let comments = std::mem::take(&mut self.comments);
+        match self.globals.iter_mut().find(|global| global.key == key) {
+            Some(global) => {
+                global.comments.extend(comments);
+                if !REPEATABLE.contains(&key) && !INCLUDE_KEYS.contains(&key) {
+                    global.values.clear();
+                }
+                global.values.push(value);
+            }
+            None => self.globals.push(Global { comments, key: key.to_string(), values: vec![value] }),
+        }
+    }
+
+    /// Converts an (expanded) entry line; false if it cannot be
+    fn entry(&mut self, line: &str, enabled: bool, bundles: &HashMap<String, Vec<u16>>) -> bool {
+        let tokens = split_tokens(line);
+        let Some(target) = tokens.first() else {
+            return false;
+        };
+        let (host, ports, proto) = match target.rsplit_once(':') {
+            Some((host, rest)) => match rest.strip_prefix('@') {
+                Some(name) => match bundles.get(name) {
+                    Some(ports) => (host, ports.clone(), None),
+                    None => return false,
+                },
+                None => {
+                    let (port, proto) = match rest.split_once('/') {
+                        Some((port, proto)) => (port, Some(proto)),
+                        None => (rest, None),
+                    };
+                    match port.parse::<u16>() {
+                        Ok(port) if port > 0 => (host, vec![port], proto),
+                        _ => return false,
+                    }
+                }
+            },
+            // An SRV name
+            None => (target.as_str(), Vec::new(), None),
+        };
+        if host.is_empty() {
+            return false;
+        }
+
+        // A repeated option wins, as when the line is read
+        let mut options: Vec<(&str, &str)> = Vec::new();
+        for token in &tokens[1..] {
+            let Some((key, value)) = token.split_once('=') else {
+                return false;
+            };
+            if !is_bare_key(key) || ENTRY_KEYS.contains(&key) {
+                return false;
+            }
+            options.retain(|(k, _)| *k != key);
+            options.push((key, value));
+        }
+
+        let mut table: Vec<String> = std::mem::take(&mut self.comments);
+        table.push(String::from("[[entry]]"));
+        table.push(format!("host = {}", quote(host)));
+        match ports[..] {
+            [] => {}
+            [port] => table.push(format!("port = {}", port)),
+            _ => table.push(format!("ports = [{}]", ports.iter().map(u16::to_string).collect::<Vec<_>>().join(", "))),
+        }
+        if let Some(proto) = proto {
+            table.push(format!("proto = {}", quote(proto)));
+        }
+        if !enabled {
+            table.push(String::from("enabled = false"));
+        }
+        for (key, value) in options {
+            table.push(format!("{} = {}", key, typed(value)));
+        }
+        self.entries.push(table.join("\n"));
+        true
+    }
+
+    fn unconverted(&mut self, origin: &str, line: &str) {
+        eprintln!("[ddnsfw] WARN: {}: cannot convert '{}', kept as a comment", origin, line);
+        self.comments.push(format!("# UNCONVERTED: {}", line));
+        self.unconverted += 1;
+    }
+
+    fn render(&self, source: &str) -> String {
+        let mut out = format!("# Converted from {} by ddnsfw migrate-config\n", source);
+        for global in &self.globals {
+            if !global.comments.is_empty() {
+                out.push('\n');
+            }
+            for comment in &global.comments {
+                out.push_str(&format!("{}\n", comment));
+            }
+            match &global.values[..] {
+                [value] => out.push_str(&format!("{} = {}\n", global.key, value)),
+                values => out.push_str(&format!("{} = [{}]\n", global.key, values.join(", "))),
+            }
+        }
+        for table in self.credentials.iter().chain(&self.entries) {
+            out.push_str(&format!("\n{}\n", table));
+        }
+        if !self.comments.is_empty() {
+            out.push_str(&format!("\n{}\n", self.comments.join("\n")));
+        }
+        out
+    }
+}
+
+/// Converts the classic config text
+fn convert(path: &Path, content: &str) -> Migration {
+    let mut migration = Migration::default();
+    let mut vars: HashMap<String, String> = HashMap::new();
+    let mut bundles: HashMap<String, Vec<u16>> = HashMap::new();
+    let mut bundle: Option<String> = None;  // Open [bundle NAME] section
+    let mut credentials = false;  // Whether a [credentials NAME] section is open (the last table)
+
+    for (index, raw) in content.lines().take(MAX_CONFIG_LINES).enumerate() {
+        let origin = format!("{}:{}", path.display(), index + 1);
+        let line = raw.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let expand = |text: &str| match expand_variables(text, &vars) {
+            Ok(text) => text,
+            Err(name) => exit_err(&format!("Undefined variable '${}' in {}", name, path.display())),
+        };
+
+        if let Some(disabled) = line.strip_prefix(DISABLED_PREFIX) {
+            let converted = expand_variables(strip_comment(disabled.trim()), &vars)
+                .is_ok_and(|disabled| migration.entry(&disabled, false, &bundles));
+            if !converted {
+                migration.comments.push(line.to_string());
+            }
+            continue;
+        }
+        if line.starts_with('#') {
+            migration.comments.push(line.to_string());
+            continue;
+        }
+        let line = strip_comment(line);
+
+        if let Some((directive, target)) = line.split_once(char::is_whitespace) {
+            let key = match directive {
+                "include" => Some("include"),
+                "include-if-exists" => Some("include_if_exists"),
+                _ => None,
+            };
+            if let Some(key) = key {
+                migration.setting(key, quote(target.trim()));
+                continue;
+            }
+        }
+
+        if let Some(header) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            (bundle, credentials) = match header.split_whitespace().collect::<Vec<_>>()[..] {
+                ["bundle", name] if is_valid_bundle_name(name) => {
+                    bundles.entry(name.to_string()).or_default();
+                    (Some(name.to_string()), false)
+                }
+                ["credentials", name] if kube::is_valid_name(name) => {
+                    let mut table = std::mem::take(&mut migration.comments);
+                    table.push(format!("[[credentials]]\nname = {}", quote(name)));
+                    migration.credentials.push(table.join("\n"));
+                    (None, true)
+                }
+                ["global"] => (None, false),
+                _ => exit_err(&format!("{}: invalid section '{}'", origin, line)),
+            };
+            continue;
+        }
+
+        if credentials {
+            match parse_setting_line(line) {
+                Some((key, value)) if kube::PROFILE_KEYS.contains(&key) => {
+                    if let Some(table) = migration.credentials.last_mut() {
+                        table.push_str(&format!("\n{} = {}", key, quote(&expand(value))));
+                    }
+                    continue;
+                }
+                _ => credentials = false,
+            }
+        }
+
+        if let Some(name) = bundle.clone() {
+            match parse_setting_line(line) {
+                Some(("ports", value)) => {
+                    let Some(ports) = parse_port_list(&expand(value)) else {
+                        exit_err(&format!("{}: invalid ports '{}' for bundle {}", origin, value, name));
+                    };
+                    bundles.insert(name, ports);
+                    continue;
+                }
+                Some(("hosts", value)) => {
+                    for host in expand(value).split(',').map(str::trim).filter(|h| !h.is_empty()) {
+                        let entry_line = format!("{}:@{}", host, name);
+                        if !migration.entry(&entry_line, true, &bundles) {
+                            migration.unconverted(&origin, &entry_line);
+                        }
+                    }
+                    continue;
+                }
+                _ => bundle = None,
+            }
+        }
+
+        if let Some((name, value)) = parse_variable_line(line) {
+            vars.insert(name.to_string(), value.to_string());
+            continue;
+        }
+
+        if let Some((key, value)) = parse_setting_line(line) {
+            let value = if REPEATABLE.contains(&key) { quote(value) } else { typed(value) };
+            migration.setting(key, value);
+            continue;
+        }
+
+        let line = expand(line);
+        if !migration.entry(&line, true, &bundles) {
+            migration.unconverted(&origin, &line);
+        }
+    }
+    migration
+}
+
+/// What an entry allows, however its line was written
+fn fingerprint(entry: &DdnsEntry) -> String {
+    let tokens = split_tokens(&entry.spec);
+    let mut options: Vec<(&str, &str)> = Vec::new();
+    for token in tokens.iter().skip(1) {
+        let (key, value) = token.split_once('=').unwrap_or((token, ""));
+        options.retain(|(k, _)| *k != key);
+        options.push((key, value));
+    }
+    options.sort_unstable();
+    format!("{} {:?}", entry.label(), options)
+}
+
+/// The entries (and credentials profiles) of a config, comparable across
+/// formats. Wildcards are not compared: a bundle port list becomes one
+/// wildcard line per port.
+fn summary(config: &Config) -> (Vec<String>, usize, Vec<(&String, &kube::Profile)>) {
+    let mut entries: Vec<String> = config.entries.iter().map(fingerprint).collect();
+    entries.sort_unstable();
+    (entries, config.srv_entries.len(), config.kube.profiles.iter().collect())
+}
+
+pub fn command(args: &[String]) {
+    let dry_run = match args {
+        [] => false,
+        [flag] if flag == "--dry-run" => true,
+        _ => exit_err(USAGE),
+    };
+    let profile = profile::current();
+    let target = format!("{}/{}", profile.dir, profile::TOML_CONFIG);
+    if tomlconfig::is_active() {
+        exit_err(&format!("{} exists already, nothing to migrate", target));
+    }
+    let source = Path::new(&profile.legacy_config_path);
+    let Ok(content) = fs::read_to_string(source) else {
+        exit_err(&format!("Cannot read {}", source.display()));
+    };
+    let migration = convert(source, &content);
+    let toml = migration.render(&profile.legacy_config_path);
+    if dry_run {
+        print!("{}", toml);
+        return;
+    }
+
+    let Some(_lock) = acquire_lock() else {
+        exit_err("Could not acquire lock");
+    };
+    let tmp = format!("{}.tmp", target);
+    let written = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp)
+        .and_then(|mut f| f.write_all(toml.as_bytes()).and_then(|_| f.sync_all()));
+    if written.is_err() {
+        let _ = fs::remove_file(&tmp);
+        exit_err(&format!("Cannot write {}", tmp));
+    }
+
+    // Both files must describe the same entries
+    let mut before = Config::new();
+    load_config_file(source, &mut before, &mut HashMap::new(), 0);
+    let mut after = Config::new();
+    tomlconfig::load(Path::new(&tmp), &mut after, 0);
+    if summary(&before) != summary(&after) {
+        let _ = fs::remove_file(&tmp);
+        exit_err(&format!(
+            "The converted config would not have the same entries ({} in {}, {} converted); nothing was changed. \
+             See the result with: ddnsfw migrate-config --dry-run",
+            before.entries.len(),
+            source.display(),
+            after.entries.len()
+        ));
+    }
+
+    backup::before_edit();
+    let migrated = format!("{}.migrated", profile.legacy_config_path);
+    if Path::new(&target).exists() || fs::rename(&tmp, &target).is_err() {
+        let _ = fs::remove_file(&tmp);
+        exit_err(&format!("Cannot create {}", target));
+    }
+    if fs::rename(source, &migrated).is_err() {
+        // config.toml wins anyway; the leftover is only reported
+        eprintln!("[ddnsfw] WARN: Cannot rename {} to {}, remove it by hand", source.display(), migrated);
+    }
+    println!(
+        "[ddnsfw] Migrated {} to {} ({} entry tables, {} settings)",
+        source.display(),
+        target,
+        migration.entries.len(),
+        migration.globals.len()
+    );
+    println!("  Old config kept as {}", migrated);
+    if migration.unconverted > 0 {
+        let plural = if migration.unconverted == 1 { "" } else { "s" };
+        println!("  {} line{} could not be converted, see the UNCONVERTED comments", migration.unconverted, plural);
+    }
+    println!("  Check with: ddnsfw config validate");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLASSIC: &str = "\
+# Ops fleet
+backend = iptables
+grace_seconds = 300   # keep old addresses for five minutes
+history = 2
+watchdog = 10.0.0.1:22
+watchdog = 10.0.0.2:22
+$TEAM = team.example.net
+
+[bundle admin]
+ports = 22,443
+hosts = alice.example.net, bob.example.net
+
+# Second cluster
+[credentials eu]
+kubeconfig = /etc/ddnsfw/eu.kubeconfig
+context = eu-west-1
+
+[global]
+# Alice's laptop
+$TEAM:22 note=\"on call\" max_conns=5 chain=ADMIN_IN
+carol.example.net:@admin priority=critical
+db.example.net:5432 knock=7000,8000,9000
+home.example.net:51820/udp
+ops.example.net:443 credentials=eu
+#disabled old.example.net:22
+";
+
+    #[test]
+    fn quoting_and_typing() {
+        assert_eq!(quote("say \"hi\"\t\\"), "\"say \\\"hi\\\"\\t\\\\\"");
+        assert_eq!(typed("yes"), "true");
+        assert_eq!(typed("300"), "300");
+        assert_eq!(typed("022"), "\"022\"");
+        assert_eq!(typed("22,443"), "[22, 443]");
+        assert_eq!(typed("a,b"), "\"a,b\"");
+    }
+
+    #[test]
+    fn migrated_config_reads_back_the_same() {
+        let dir = std::env::temp_dir().join(format!("ddnsfw-migrate-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (classic, toml) = (dir.join("conf.conf"), dir.join("config.toml"));
+        fs::write(&classic, CLASSIC).unwrap();
+        let migration = convert(&classic, CLASSIC);
+        assert_eq!(migration.unconverted, 0);
+        let rendered = migration.render("conf.conf");
+        fs::write(&toml, &rendered).unwrap();
+
+        let mut before = Config::new();
+        load_config_file(&classic, &mut before, &mut HashMap::new(), 0);
+        let mut after = Config::new();
+        tomlconfig::load(&toml, &mut after, 0);
+        let _ = fs::remove_dir_all(&dir);
+
+        assert!(rendered.contains("# Alice's laptop\n[[entry]]\nhost = \"team.example.net\""), "{}", rendered);
+        assert!(rendered.contains("ports = [22, 443]"), "{}", rendered);
+        assert!(rendered.contains("enabled = false"), "{}", rendered);
+        assert!(
+            rendered.contains("# Second cluster\n[[credentials]]\nname = \"eu\"\nkubeconfig = \"/etc/ddnsfw/eu.kubeconfig\"\ncontext = \"eu-west-1\"\n"),
+            "{}",
+            rendered
+        );
+        assert_eq!(before.entries.len(), 10);
+        assert_eq!(after.kube.profiles["eu"].context.as_deref(), Some("eu-west-1"));
+        assert!(summary(&before) == summary(&after));
+        assert_eq!(
+            before.entries.iter().map(|e| e.spec.as_str()).collect::<Vec<_>>(),
+            after.entries.iter().map(|e| e.spec.as_str()).collect::<Vec<_>>()
+        );
+        assert_eq!((before.grace_seconds, before.history), (after.grace_seconds, after.history));
+        assert_eq!((after.grace_seconds, after.history), (300, 2));
+        assert!(before.backend == after.backend);
+        assert!(before.watchdogs == after.watchdogs && after.watchdogs.len() == 2);
+    }
+}
@@ -0,0 +1,159 @@
+//! tmpfs cache with a persistent mirror, for flash-backed servers.
+//!
+//!   cache_store = tmpfs
+//!
+//! The cache is rewritten and fsynced on every state transition of a sync,
+//! which adds up to a lot of wear on the SD card of a Raspberry Pi gateway
+//! syncing every minute. With `cache_store = tmpfs` the cache lives in
+//! /run/ddnsfw instead, written without fsync, and after each sync a
+//! checkpoint is copied to /var/lib/ddnsfw, only when something durable
+//! changed (the clock mark alone does not count). A crashed sync recovers
+//! from /run as usual; after a reboot /run is empty, the last checkpoint is
+//! restored, and the sync reconciles it against the tagged rules like any
+//! other cache. Switching the setting either way carries the cache over.
+
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::Path;
+use std::sync::OnceLock;
+
+use crate::{exit_err, profile, INSTALL_DIR};
+
+pub const RUN_DIR: &str = "/run/ddnsfw";
+pub const MIRROR_DIR: &str = "/var/lib/ddnsfw";
+
+static TMPFS: OnceLock<bool> = OnceLock::new();
+static CACHE_PATH: OnceLock<String> = OnceLock::new();
+
+/// Applies `cache_store`. Fails if the cache was already used under the
+/// other store in this process.
+pub fn select(value: &str) -> bool {
+    let tmpfs = match value {
+        "disk" => false,
+        "tmpfs" => true,
+        _ => exit_err(&format!("Invalid cache_store '{}' (disk, tmpfs)", value)),
+    };
+    *TMPFS.get_or_init(|| tmpfs) == tmpfs
+}
+
+/// This profile's cache under /run/ddnsfw and /var/lib/ddnsfw; None outside
+/// the install dir (the --chaos sandbox keeps everything in one place)
+fn store_paths() -> Option<(String, String)> {
+    let relative = profile::current().dir.strip_prefix(INSTALL_DIR)?;
+    let file = Path::new(&profile::current().cache_path).file_name()?.to_str()?;
+    Some((format!("{}{}/{}", RUN_DIR, relative, file), format!("{}{}/{}", MIRROR_DIR, relative, file)))
+}
+
+fn create_parent(path: &str) -> bool {
+    Path::new(path)
+        .parent()
+        .is_some_and(|dir| DirBuilder::new().recursive(true).mode(0o700).create(dir).is_ok())
+}
+
+fn exists(path: &str) -> bool {
+    Path::new(path).exists()
+}
+
+/// Copies `from` over `to` (atomically), printing why
+fn carry(from: &str, to: &str, why: &str) -> bool {
+    let temp_path = format!("{}.tmp", to);
+    let copied = create_parent(to) && fs::copy(from, &temp_path).is_ok() && fs::rename(&temp_path, to).is_ok();
+    if copied {
+        println!("[ddnsfw] {} {} -> {}", why, from, to);
+    } else {
+        let _ = fs::remove_file(&temp_path);
+        eprintln!("[ddnsfw] WARN: Cannot copy {} to {}", from, to);
+    }
+    copied
+}
+
+/// Picks the cache file for this process, first carrying the cache over
+/// from the other store when the setting changed (or restoring the
+/// checkpoint after a reboot)
+fn resolve() -> String {
+    let disk = profile::current().cache_path.clone();
+    let Some((hot, mirror)) = store_paths() else {
+        return disk;
+    };
+    if !*TMPFS.get_or_init(|| false) {
+        if !exists(&disk) {
+            if let Some(from) = [&hot, &mirror].into_iter().find(|path| exists(path)) {
+                carry(from, &disk, "Moved cache");
+            }
+        }
+        // Left over from tmpfs mode: stale once the disk cache is in use
+        if exists(&disk) {
+            let _ = fs::remove_file(&hot);
+            let _ = fs::remove_file(&mirror);
+        }
+        return disk;
+    }
+
+    if !create_parent(&hot) {
+        eprintln!("[ddnsfw] WARN: Cannot create {}, keeping the cache on disk", RUN_DIR);
+        return disk;
+    }
+    if !exists(&hot) {
+        if exists(&mirror) {
+            carry(&mirror, &hot, "Restored checkpoint");
+        } else if exists(&disk) && carry(&disk, &hot, "Moved cache") && carry(&disk, &mirror, "Checkpointed") {
+            let _ = fs::remove_file(&disk);
+        }
+    }
+    hot
+}
+
+/// Where the cache is read and written
+pub fn cache_path() -> &'static str {
+    CACHE_PATH.get_or_init(resolve)
+}
+
+/// Whether cache writes need fsync (not on tmpfs: nothing survives a
+/// power loss there anyway)
+pub fn durable() -> bool {
+    cache_path() == profile::current().cache_path
+}
+
+/// Everything but the clock mark, which changes on every save
+fn durable_lines(content: &str) -> Vec<&str> {
+    content.lines().filter(|line| !line.starts_with("CLOCK:")).collect()
+}
+
+/// Copies the tmpfs cache to /var/lib/ddnsfw if it changed since the last
+/// checkpoint. Call with the sync lock held.
+pub fn checkpoint() {
+    if durable() {
+        return;
+    }
+    let Some((_, mirror)) = store_paths() else {
+        return;
+    };
+    let Ok(content) = fs::read_to_string(cache_path()) else {
+        return;
+    };
+    let previous = fs::read_to_string(&mirror).unwrap_or_default();
+    if durable_lines(&content) == durable_lines(&previous) {
+        return;
+    }
+    if !create_parent(&mirror) {
+        eprintln!("[ddnsfw] WARN: Cannot create {}, cache not checkpointed", MIRROR_DIR);
+        return;
+    }
+    let temp_path = format!("{}.tmp", mirror);
+    if let Ok(mut file) = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&temp_path) {
+        if file.write_all(content.as_bytes()).and_then(|_| file.sync_all()).is_ok() && fs::rename(&temp_path, &mirror).is_ok() {
+            return;
+        }
+    }
+    let _ = fs::remove_file(&temp_path);
+    eprintln!("[ddnsfw] WARN: Cannot write checkpoint {}", mirror);
+}
+
+/// Removes this profile's tmpfs cache and checkpoint (uninstall)
+pub fn remove() -> bool {
+    let Some((hot, mirror)) = store_paths() else {
+        return true;
+    };
+    [hot, mirror].iter().all(|path| fs::remove_file(path).is_ok() || !exists(path))
+}
@@ -0,0 +1,184 @@
+//! nftables named-set backend with kernel-side expiry.
+//!
+//! Writes the allowed IPs into sets named `allow_<port>` of the table
+//! `inet <nft_table>` (created on first use). Every element carries a
+//! timeout of the hostname's DNS TTL plus `nft_grace`, restarted by each sync
+//! that still wants the address, so the kernel drops stale addresses on its
+//! own if ddnsfw stops running. While ddnsfw runs, the usual rules hold:
+//! addresses of hostnames that fail to resolve are kept (and refreshed).
+//!
+//! Reference the sets from your own ruleset, e.g.:
+//!   tcp dport 22 ip saddr @allow_22 accept
+//!
+//! The table is dedicated to ddnsfw: only its `allow_<port>` sets are read.
+
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use crate::rule::Rule;
+use crate::{execs, resolver, MAX_LISTING_LINES, MAX_RULES};
+
+const NFT_PATHS: &[&str] = &["/usr/sbin/nft", "/sbin/nft", "/usr/bin/nft"];
+const SET_PREFIX: &str = "allow_";
+/// Assumed TTL when the record's TTL cannot be looked up
+const DEFAULT_TTL_SECS: u64 = 300;
+pub const MIN_GRACE_SECS: u64 = 300;
+pub const MAX_GRACE_SECS: u64 = 30 * 86400;
+
+pub fn find_nft() -> Option<&'static str> {
+    NFT_PATHS.iter().find(|p| Path::new(p).exists()).copied()
+}
+
+/// nft identifiers: `[A-Za-z][A-Za-z0-9_]*`
+pub fn is_valid_table_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && name.len() <= 32
+}
+
+/// Backend settings from the config file
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub table: String,
+    pub grace: u64,
+}
+
+impl Settings {
+    pub fn new() -> Self {
+        Settings { table: String::from("ddnsfw"), grace: 3600 }
+    }
+}
+
+pub struct Nft {
+    bin: &'static str,
+    settings: Settings,
+}
+
+impl Nft {
+    pub fn open(bin: &'static str, settings: Settings) -> Self {
+        Nft { bin, settings }
+    }
+
+    /// Runs an nft script as one transaction (all or nothing)
+    fn apply(&self, script: &str) -> bool {
+        let Ok(mut child) = execs::spawn(
+            Command::new(self.bin)
+                .args(["-f", "-"])
+                .stdin(Stdio::piped())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null()),
+        )
+        else {
+            return false;
+        };
+        if let Some(mut stdin) = child.stdin.take() {
+            let _ = stdin.write_all(script.as_bytes());
+        }
+        child.wait().map(|s| s.success()).unwrap_or(false)
+    }
+
+    fn set(&self, port: u16) -> String {
+        format!("inet {} {}{}", self.settings.table, SET_PREFIX, port)
+    }
+
+    /// `set allow_22 { ... elements = { 192.0.2.1 timeout 1h expires 59m, ... } }`
+    pub fn existing_rules(&self) -> HashSet<(Ipv4Addr, u16)> {
+        let mut rules = HashSet::new();
+        let Ok(output) = execs::output(
+            Command::new(self.bin)
+                .args(["list", "table", "inet", &self.settings.table])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null()),
+        )
+        else {
+            return rules;
+        };
+        if !output.status.success() {
+            return rules;
+        }
+
+        let listing = String::from_utf8_lossy(&output.stdout);
+        let mut port: Option<u16> = None;
+        let mut in_elements = false;
+        for line in listing.lines().take(MAX_LISTING_LINES) {
+            let line = line.trim();
+            if let Some(name) = line.strip_prefix("set ").and_then(|l| l.strip_suffix(" {")) {
+                port = name.strip_prefix(SET_PREFIX).and_then(|p| p.parse().ok());
+                continue;
+            }
+            let items = match line.strip_prefix("elements = {") {
+                Some(rest) => {
+                    in_elements = true;
+                    rest
+                }
+                None if in_elements => line,
+                None => continue,
+            };
+            if items.ends_with('}') {
+                in_elements = false;
+            }
+            let Some(port) = port else {
+                continue;
+            };
+            for item in items.trim_end_matches('}').split(',') {
+                let Some(ip) = item.split_whitespace().next().and_then(|ip| ip.parse().ok()) else {
+                    continue;
+                };
+                if rules.len() >= MAX_RULES {
+                    eprintln!("[ddnsfw] WARN: Too many set elements, truncating");
+                    return rules;
+                }
+                rules.insert((ip, port));
+            }
+        }
+        rules
+    }
+
+    pub fn rule_exists(&self, ip: Ipv4Addr, port: u16) -> bool {
+        self.existing_rules().contains(&(ip, port))
+    }
+
+    /// Creates the table and set on first use (`add` is a no-op when they exist)
+    pub fn add_rule(&self, ip: Ipv4Addr, port: u16) -> bool {
+        let script = format!(
+            "add table inet {table}\n\
+             add set {set} {{ type ipv4_addr; flags timeout; }}\n\
+             add element {set} {{ {ip} timeout {timeout}s }}\n",
+            table = self.settings.table,
+            set = self.set(port),
+            ip = ip,
+            timeout = DEFAULT_TTL_SECS + self.settings.grace,
+        );
+        self.apply(&script)
+    }
+
+    pub fn delete_rule(&self, ip: Ipv4Addr, port: u16) -> bool {
+        self.apply(&format!("delete element {} {{ {} }}\n", self.set(port), ip))
+    }
+
+    /// Restarts the timeout of every installed element the sync still wants,
+    /// at the owning hostnames' TTL plus grace. Each element is deleted and
+    /// re-added in the same transaction, so it never disappears.
+    pub fn refresh<'a>(&self, rules: impl Iterator<Item = &'a Rule>, owners: &HashMap<Rule, Vec<String>>) {
+        let mut ttls: HashMap<String, u64> = HashMap::new();
+        let mut script = String::new();
+        for rule in rules.filter(|r| r.is_plain()).take(MAX_RULES) {
+            let mut ttl = None;
+            for hostname in owners.get(rule).into_iter().flatten().filter_map(|label| Some(label.rsplit_once(':')?.0)) {
+                let secs = *ttls.entry(hostname.to_string()).or_insert_with(|| resolver::lookup_ttl(hostname).unwrap_or(DEFAULT_TTL_SECS));
+                ttl = Some(ttl.map_or(secs, |t: u64| t.max(secs)));
+            }
+            let timeout = ttl.unwrap_or(DEFAULT_TTL_SECS) + self.settings.grace;
+            let set = self.set(rule.port);
+            script.push_str(&format!("delete element {} {{ {} }}\n", set, rule.ip));
+            script.push_str(&format!("add element {} {{ {} timeout {}s }}\n", set, rule.ip, timeout));
+        }
+        if !script.is_empty() && !self.apply(&script) {
+            eprintln!("[ddnsfw] WARN: Could not refresh nftables element timeouts");
+        }
+    }
+}
@@ -0,0 +1,103 @@
+//! TOTP-gated temporary access (emergency path when DDNS is broken).
+//!
+//! `ddnsfw otp-allow <port> <code> [ip]` verifies an RFC 6238 code against
+//! `otp_secret` from the config and records a time-limited grant in the
+//! grants ledger (see grants). The next sync (triggered immediately) adds the rule; once the grant
+//! expires, the rule is removed like any other stale rule.
+//!
+//! Without an explicit IP the caller's address is taken from SSH_CONNECTION,
+//! which allows exposing this through a restricted SSH forced command.
+
+use std::env;
+use std::net::Ipv4Addr;
+
+use crate::crypto::{base32_decode, ct_eq, hmac_sha1};
+use crate::grants::{Kind, Ledger, TemporaryGrant};
+use crate::{acquire_lock, exit_err, now_secs, parse_config, sync_firewall, Cache};
+
+const TOTP_STEP_SECS: u64 = 30;
+const TOTP_DIGITS: u32 = 6;
+const TOTP_SKEW_STEPS: u64 = 1;  // Accept one step of clock drift either way
+
+/// RFC 4226 HOTP value for a counter.
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mac = hmac_sha1(secret, &counter.to_be_bytes());
+    let offset = (mac[19] & 0x0f) as usize;
+    let value = u32::from_be_bytes([mac[offset] & 0x7f, mac[offset + 1], mac[offset + 2], mac[offset + 3]]);
+    value % 10u32.pow(TOTP_DIGITS)
+}
+
+/// Returns the matching time step, rejecting steps at or before `last_used`.
+fn verify(secret: &[u8], code: &str, now: u64, last_used: u64) -> Option<u64> {
+    if code.len() != TOTP_DIGITS as usize || !code.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    let current = now / TOTP_STEP_SECS;
+    let mut matched = None;
+    for step in current.saturating_sub(TOTP_SKEW_STEPS)..=current + TOTP_SKEW_STEPS {
+        let expected = format!("{:0width$}", hotp(secret, step), width = TOTP_DIGITS as usize);
+        // Evaluate every window so timing does not reveal which one matched
+        if ct_eq(expected.as_bytes(), code.as_bytes()) && step > last_used {
+            matched = Some(step);
+        }
+    }
+    matched
+}
+
+pub fn caller_ip() -> Option<Ipv4Addr> {
+    env::var("SSH_CONNECTION")
+        .ok()?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+pub fn otp_allow(args: &[String]) {
+    let (Some(port), Some(code)) = (args.first(), args.get(1)) else {
+        exit_err("Usage: ddnsfw otp-allow <port> <code> [ip]");
+    };
+    let port: u16 = match port.parse() {
+        Ok(p) if p > 0 => p,
+        _ => exit_err("Invalid port"),
+    };
+    let ip = match args.get(2) {
+        Some(ip) => ip.parse().unwrap_or_else(|_| exit_err("Invalid IPv4 address")),
+        None => caller_ip().unwrap_or_else(|| exit_err("Cannot determine caller IP, pass it explicitly")),
+    };
+
+    let config = parse_config();
+    let Some(secret) = config.otp_secret.as_deref().and_then(base32_decode) else {
+        exit_err("otp_secret is not configured (base32)");
+    };
+    if secret.len() < 10 {
+        exit_err("otp_secret is too short (need at least 80 bits)");
+    }
+
+    {
+        let Some(_lock) = acquire_lock() else {
+            exit_err("Could not acquire lock");
+        };
+
+        let mut cache = Cache::load();
+        let now = now_secs();
+        let Some(step) = verify(&secret, code, now, cache.otp_last_step) else {
+            eprintln!("[ddnsfw] OTP rejected for {}:{}", ip, port);
+            exit_err("Invalid or already used code");
+        };
+
+        cache.otp_last_step = step;
+        cache.save();
+        let mut ledger = Ledger::load();
+        ledger.grant(TemporaryGrant::new(Kind::Otp, ip, port, config.otp_minutes, "TOTP code accepted"));
+        ledger.save();
+
+        println!(
+            "[ddnsfw] OTP accepted: granting {}:{} for {} minutes",
+            ip, port, config.otp_minutes
+        );
+    }
+
+    sync_firewall();
+}
@@ -0,0 +1,290 @@
+//! Permissions audit: the installed files' owners and modes, repaired when
+//! they drift.
+//!
+//!   ddnsfw fix-perms [--check]
+//!   perms_check = repair | warn | off      # after timer syncs (default repair)
+//!
+//! The security model rests on a handful of modes: only root (or the
+//! service user) can read the config and state, change the binary the
+//! units and sudo run, or take the lock. A careless `chmod -R 755
+//! /etc/ddnsfw` or a restore from a backup made as another user quietly
+//! breaks that. Checked, with what each must be:
+//!
+//! - /etc/ddnsfw and the profile directory: root 700
+//! - the binary /etc/ddnsfw/run: root 700
+//! - config, cache, lock and the other state files: root 600
+//! - the service and timer units: root 644
+//!
+//! In service user mode (see privsep) the layout that mode sets up is the
+//! reference instead: /etc/ddnsfw root:ddnsfw 1770, the binary root:ddnsfw
+//! 750, the profile's files owned by ddnsfw, plus the root-owned mode
+//! marker (644) and sudoers rule (440).
+//!
+//! `fix-perms` sets every drifted owner and mode back and logs each change;
+//! with `--check` it only lists them (exit 1 when any). After each timer
+//! sync the same audit runs, repairing with a warning and a `perms_repaired`
+//! event (`warn` only reports). The service user cannot repair root's
+//! files: timer syncs in that mode only report, for `sudo ddnsfw
+//! fix-perms`. Missing files are left to `doctor`, symlinks are reported
+//! and never followed, and paths on a read-only filesystem are skipped.
+//!
+//! The service user can rename and replace files in its own directories,
+//! so a repair never goes by path: every component is opened without
+//! following symlinks, the file must still be the one the audit saw, and
+//! the owner and mode are set on that open descriptor.
+
+use std::fs;
+use std::io::{self, Write};
+use std::os::fd::OwnedFd;
+use std::os::unix::fs::{fchown, MetadataExt, PermissionsExt};
+
+use rustix::fs::{Mode, OFlags};
+
+use crate::events::{self, Value};
+use crate::privsep::{self, MARKER_PATH, SUDOERS_PATH};
+use crate::{exit_err, mirror, profile, sys, Config, BINARY_PATH, INSTALL_DIR};
+use crate::{SERVICE_PATH, TEMPLATE_SERVICE_PATH, TEMPLATE_TIMER_PATH, TIMER_PATH};
+
+const USAGE: &str = "Usage: ddnsfw fix-perms [--check]";
+
+/// What the timer syncs do about drift
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Check {
+    Repair,
+    Warn,
+    Off,
+}
+
+impl Check {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "repair" => Some(Check::Repair),
+            "warn" => Some(Check::Warn),
+            "off" => Some(Check::Off),
+            _ => None,
+        }
+    }
+}
+
+/// A path with the owner and mode it must have
+struct Expected {
+    path: String,
+    uid: u32,
+    gid: u32,
+    mode: u32,
+}
+
+/// How a path differs from what it must be
+struct Drift {
+    expected: Expected,
+    uid: u32,
+    gid: u32,
+    mode: u32,
+    /// The audited file, so a swapped-in one is never repaired
+    dev: u64,
+    ino: u64,
+}
+
+impl Drift {
+    fn describe(&self) -> String {
+        let mut parts = Vec::new();
+        if (self.uid, self.gid) != (self.expected.uid, self.expected.gid) {
+            parts.push(format!("owner {}:{} -> {}:{}", self.uid, self.gid, self.expected.uid, self.expected.gid));
+        }
+        if self.mode != self.expected.mode {
+            parts.push(format!("mode {:o} -> {:o}", self.mode, self.expected.mode));
+        }
+        parts.join(", ")
+    }
+
+    fn repair(&self) -> bool {
+        let expected = &self.expected;
+        let Ok(file) = open_nofollow(&expected.path).map(fs::File::from) else {
+            return false;
+        };
+        match file.metadata() {
+            Ok(meta) if (meta.dev(), meta.ino()) == (self.dev, self.ino) => {}
+            _ => return false,
+        }
+        let owned = (self.uid, self.gid) == (expected.uid, expected.gid)
+            || fchown(&file, Some(expected.uid), Some(expected.gid)).is_ok();
+        // chown clears setuid/setgid bits, so the mode goes last
+        owned && file.set_permissions(fs::Permissions::from_mode(expected.mode)).is_ok()
+    }
+}
+
+/// Opens an absolute `path` one component at a time, refusing a symlink
+/// anywhere along it
+fn open_nofollow(path: &str) -> io::Result<OwnedFd> {
+    let nofollow = OFlags::RDONLY | OFlags::NOFOLLOW | OFlags::CLOEXEC;
+    let mut fd = rustix::fs::open("/", nofollow | OFlags::DIRECTORY, Mode::empty())?;
+    let mut components = path.split('/').filter(|c| !c.is_empty()).peekable();
+    while let Some(component) = components.next() {
+        if component == ".." {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        // Directories on the way; the file itself may be anything but a
+        // symlink, and a FIFO must not block the open
+        let flags = match components.peek() {
+            Some(_) => nofollow | OFlags::DIRECTORY,
+            None => nofollow | OFlags::NONBLOCK,
+        };
+        fd = rustix::fs::openat(&fd, component, flags, Mode::empty())?;
+    }
+    Ok(fd)
+}
+
+/// Every checked path of this profile with its owner and mode
+fn expected() -> Vec<Expected> {
+    let profile = profile::current();
+    let service = privsep::configured();
+    // The profile's own files: root's, or the service user's
+    let (uid, gid) = service.unwrap_or((0, 0));
+    let mut paths = Vec::new();
+    let mut add = |path: &str, uid: u32, gid: u32, mode: u32| {
+        if !paths.iter().any(|e: &Expected| e.path == path) {
+            paths.push(Expected { path: path.to_string(), uid, gid, mode });
+        }
+    };
+
+    match service {
+        Some((_, group)) => {
+            add(INSTALL_DIR, 0, group, 0o1770);
+            add(BINARY_PATH, 0, group, 0o750);
+            add(MARKER_PATH, 0, 0, 0o644);
+            add(SUDOERS_PATH, 0, 0, 0o440);
+        }
+        None => {
+            add(INSTALL_DIR, 0, 0, 0o700);
+            add(BINARY_PATH, 0, 0, 0o700);
+        }
+    }
+    add(&profile.dir, uid, gid, 0o700);
+    add(&profile.state_dir, uid, gid, 0o700);
+    add(&profile.config_path, uid, gid, 0o600);
+    add(mirror::cache_path(), uid, gid, 0o600);
+    add(&profile.lock_path, uid, gid, 0o600);
+    for file in profile::STATE_FILES {
+        add(&format!("{}/{}", profile.state_dir, file), uid, gid, 0o600);
+    }
+    let (service_path, timer_path) = match profile.name {
+        Some(_) => (TEMPLATE_SERVICE_PATH, TEMPLATE_TIMER_PATH),
+        None => (SERVICE_PATH, TIMER_PATH),
+    };
+    add(service_path, 0, 0, 0o644);
+    add(timer_path, 0, 0, 0o644);
+    paths
+}
+
+/// The drifted paths; symlinks come back as errors
+fn audit() -> (Vec<Drift>, Vec<String>) {
+    let (mut drifts, mut errors) = (Vec::new(), Vec::new());
+    for expected in expected() {
+        // Missing files are doctor's business
+        let Ok(meta) = fs::symlink_metadata(&expected.path) else {
+            continue;
+        };
+        if meta.file_type().is_symlink() {
+            errors.push(format!("{} is a symbolic link, left alone", expected.path));
+            continue;
+        }
+        let mode = meta.permissions().mode() & 0o7777;
+        if (meta.uid(), meta.gid(), mode) == (expected.uid, expected.gid, expected.mode) || sys::is_read_only(&expected.path) {
+            continue;
+        }
+        drifts.push(Drift { uid: meta.uid(), gid: meta.gid(), mode, dev: meta.dev(), ino: meta.ino(), expected });
+    }
+    (drifts, errors)
+}
+
+/// After a timer sync
+pub fn scheduled(config: &Config) {
+    if config.perms_check == Check::Off {
+        return;
+    }
+    let (drifts, errors) = audit();
+    for error in &errors {
+        eprintln!("[ddnsfw] WARN: {}", error);
+    }
+    // The service user cannot take root's files back
+    let repair = config.perms_check == Check::Repair && sys::is_root();
+    for drift in &drifts {
+        if !repair {
+            eprintln!("[ddnsfw] WARN: {} has drifted ({}), run: sudo {} fix-perms", drift.expected.path, drift.describe(), BINARY_PATH);
+        } else if drift.repair() {
+            eprintln!("[ddnsfw] WARN: Repaired {} ({})", drift.expected.path, drift.describe());
+            let (path, change) = (drift.expected.path.as_str(), drift.describe());
+            events::emit("perms_repaired", &[("path", Value::Str(path)), ("change", Value::Str(&change))]);
+        } else {
+            eprintln!("[ddnsfw] WARN: Could not repair {} ({})", drift.expected.path, drift.describe());
+        }
+    }
+}
+
+/// `ddnsfw fix-perms [--check]`
+pub fn command(args: &[String]) {
+    let check_only = match args {
+        [] => false,
+        [flag] if flag == "--check" => true,
+        _ => exit_err(USAGE),
+    };
+    if !check_only && !sys::is_root() {
+        exit_err("Must run as root (sudo ddnsfw fix-perms)");
+    }
+    let (drifts, errors) = audit();
+    let mut failed = errors.len();
+    for error in &errors {
+        println!("[ddnsfw] {}", error);
+    }
+    for drift in &drifts {
+        if check_only {
+            println!("[ddnsfw] {}: {}", drift.expected.path, drift.describe());
+            continue;
+        }
+        print!("[ddnsfw] Fixing {} ({}) ... ", drift.expected.path, drift.describe());
+        let _ = io::stdout().flush();
+        if drift.repair() {
+            println!("OK");
+            let (path, change) = (drift.expected.path.as_str(), drift.describe());
+            events::emit("perms_repaired", &[("path", Value::Str(path)), ("change", Value::Str(&change))]);
+        } else {
+            println!("FAILED");
+            failed += 1;
+        }
+    }
+    let checked = expected().len();
+    if check_only {
+        println!("[ddnsfw] {} paths checked, {} drifted", checked, drifts.len() + errors.len());
+        if !drifts.is_empty() || !errors.is_empty() {
+            std::process::exit(1);
+        }
+    } else {
+        println!("[ddnsfw] {} paths checked, {} repaired, {} failed", checked, drifts.len() + errors.len() - failed, failed);
+        if failed > 0 {
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn open_refuses_symlinks_anywhere_on_the_path() {
+        let dir = std::env::temp_dir().join(format!("ddnsfw-perms-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("real")).unwrap();
+        fs::write(dir.join("real/config"), "").unwrap();
+        symlink(dir.join("real"), dir.join("link")).unwrap();
+        symlink(dir.join("real/config"), dir.join("real/alias")).unwrap();
+        let path = |p: &str| dir.join(p).to_string_lossy().into_owned();
+
+        assert!(open_nofollow(&path("real/config")).is_ok());
+        assert!(open_nofollow(&path("real/alias")).is_err());
+        assert!(open_nofollow(&path("link/config")).is_err());
+        assert!(open_nofollow(&path("real/../real/config")).is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,161 @@
+//! `ddnsfw plan` (or `ddnsfw sync --dry-run`): what a sync would change.
+//!
+//! Resolves every entry the way the sync does (pushes, hosts.override,
+//! throttled answers, then the network, picked by dns_select), applies the
+//! checks that keep an entry's existing rules (DNS failure, this server's
+//! own address, a change-rate freeze), leaves out the CGNAT answers of
+//! entries with an IPv6 side (see cgnat), keeps the recent addresses of
+//! `history`, adds the live temporary grants, and diffs the result against
+//! the firewall listing. The plan is printed terraform-style, adds first as
+//! the sync would run them:
+//!
+//!     + 203.0.113.9:22                       home.example.net:22
+//!     - 198.51.100.4:22                      home.example.net:22
+//!     = port 443 kept: office.example.net did not resolve
+//!
+//! Allow rules only: companions (connlimit, knock, lockdown), watchdogs and
+//! the IPv6 side are reconciled by the sync itself. Beacons are not
+//! fetched. The firewall is only listed, and the cache, throttle and
+//! change-rate state are only read; wildcard and SRV entries refresh their
+//! last answers as any lookup does. Exit status: 0 when the firewall
+//! matches, 2 when changes are pending, 1 on errors.
+
+use std::collections::{HashMap, HashSet};
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+use crate::rule::Rule;
+use crate::{
+    cgnat, churn, exit_err, format_utc, grants, managed_scopes, now_secs, open_backend, parse_config, provider, push, srv, sys,
+    throttle, Backend, Cache, PortIndex, DNS_TIMEOUT_SECS,
+};
+
+const EXIT_DRIFT: i32 = 2;
+
+pub fn command(args: &[String]) {
+    if !args.is_empty() {
+        exit_err("Usage: ddnsfw plan");
+    }
+    let mut config = parse_config();
+    for diagnostic in &config.diagnostics {
+        eprintln!("[ddnsfw] WARN: {}", diagnostic);
+    }
+    provider::expand(&mut config);
+    srv::expand(&mut config);
+    let Some(backend) = open_backend(&config) else {
+        exit_err(&format!("{} not found", config.backend.tool()));
+    };
+    // As the sync would: entries just given an id= keep what they had
+    let mut cache = Cache::load();
+    let rekeyed = cache.rekey(&config.entries);
+    let existing = backend.existing_rules(&managed_scopes(&config.entries, &cache));
+    let index = PortIndex::new(&existing);
+
+    let now = now_secs();
+    let throttled = throttle::State::load();
+    let mut churn = churn::State::load();
+    for (label, id) in &rekeyed {
+        churn.rename(label, id);
+    }
+    let pushes = config.push_listen.is_some().then(push::Store::load);
+    let mut own_addrs = sys::local_ipv4_addrs();
+    own_addrs.extend(&config.self_ips);
+    let shared_space = cgnat::Check::new(config.cgnat, &own_addrs);
+    let iptables = matches!(backend, Backend::Iptables(_));
+
+    let mut previous_ips: HashMap<&str, Vec<Ipv4Addr>> = HashMap::new();
+    for (rule, rule_owners) in &cache.owners {
+        for owner in rule_owners {
+            previous_ips.entry(owner.as_str()).or_default().push(rule.ip);
+        }
+    }
+
+    println!("[ddnsfw] Planning {} entries against the live firewall (nothing is changed)", config.entries.len());
+    let mut desired: HashSet<Rule> = HashSet::new();
+    let mut owners: HashMap<Rule, Vec<String>> = HashMap::new();
+    let mut kept: Vec<String> = Vec::new();
+    for entry in &config.entries {
+        let key = entry.key();
+        let pushed = pushes.as_ref().and_then(|store| store.answer(&entry.hostname, config.push_max_age, now));
+        let chain = entry.resolver_chain(&config);
+        let addrs = match pushed.or_else(|| chain.resolve_local(&entry.hostname)) {
+            Some(answer) => answer.addrs,
+            None => throttle::interval_for(&config.provider_intervals, &entry.hostname)
+                .and_then(|secs| throttled.fresh(&entry.hostname, secs, now))
+                .unwrap_or_else(|| chain.resolve(&entry.hostname, Duration::from_secs(DNS_TIMEOUT_SECS))),
+        };
+        let current = previous_ips.get(key.as_str()).map(Vec::as_slice).unwrap_or_default();
+
+        let picked = config.dns_select.pick(&addrs, current, config.dns_max_addrs);
+        let ips: Vec<Ipv4Addr> = picked.iter().copied().filter(|ip| !own_addrs.contains(ip)).collect();
+        let shared = if shared_space.prefers_v6(&config, entry, iptables) { shared_space.shared(&ips) } else { Vec::new() };
+        if !shared.is_empty() && shared.len() == ips.len() {
+            // IPv6 only: the entry wants no IPv4 rule
+            continue;
+        }
+        let reason = match picked.first() {
+            None => Err(format!("{} did not resolve", entry.hostname)),
+            Some(ip) if ips.is_empty() => Err(format!("{} resolves to this server ({})", entry.hostname, ip)),
+            Some(_) => {
+                let ips: Vec<Ipv4Addr> = ips.into_iter().filter(|ip| !shared.contains(ip)).collect();
+                let limit = entry.max_changes.unwrap_or(config.max_changes_per_day);
+                let changes = churn.peek(&key, &ips, now);
+                if limit > 0 && changes > limit {
+                    Err(format!("{} frozen ({} changes in 24h)", entry.label(), changes))
+                } else {
+                    Ok(ips)
+                }
+            }
+        };
+        let ips = match reason {
+            Ok(ips) => ips,
+            Err(reason) => {
+                // Fail-safe, as in the sync: the port's rules stay
+                for rule in index.by_port.get(&entry.port).into_iter().flatten() {
+                    desired.insert(rule.clone());
+                }
+                kept.push(format!("port {} kept: {}", entry.port, reason));
+                continue;
+            }
+        };
+        let keep = entry.history.unwrap_or(config.history);
+        let recent: Vec<Ipv4Addr> = cache.recent(&key, &ips, keep).into_iter().filter(|ip| !own_addrs.contains(ip)).collect();
+        for rule in ips.iter().chain(&recent).flat_map(|&ip| entry.rules_for(ip)).filter(|rule| backend.supports(rule)) {
+            owners.entry(rule.clone()).or_default().push(key.clone());
+            desired.insert(rule);
+        }
+    }
+    for grant in grants::Ledger::load().grants.iter().filter(|grant| grant.expires > now) {
+        owners.entry(grant.rule()).or_default().push(grant.kind.owner_label());
+        desired.insert(grant.rule());
+    }
+
+    let mut adds: Vec<&Rule> = desired.iter().filter(|rule| !existing.contains(rule)).collect();
+    let labels: HashSet<String> = config.entries.iter().map(|entry| entry.key()).collect();
+    let mut removes: Vec<&Rule> = Vec::new();
+    for rule in existing.iter().filter(|rule| !desired.contains(rule)) {
+        match cache.grace_until(rule, &labels, config.grace_seconds, now) {
+            Some(until) => kept.push(format!("{} kept until {} UTC (grace_seconds)", rule, format_utc(until))),
+            None => removes.push(rule),
+        }
+    }
+    adds.sort_by_key(|rule| rule.to_string());
+    removes.sort_by_key(|rule| rule.to_string());
+    let unknown = vec![String::from("unknown")];
+    for rule in &adds {
+        println!("  + {:<36} {}", rule.to_string(), owners[*rule].join(", "));
+    }
+    for rule in &removes {
+        println!("  - {:<36} {}", rule.to_string(), cache.owners.get(*rule).unwrap_or(&unknown).join(", "));
+    }
+    for line in &kept {
+        println!("  = {}", line);
+    }
+
+    if adds.is_empty() && removes.is_empty() {
+        println!("[ddnsfw] No changes: the firewall matches the config ({} rules)", existing.len());
+        return;
+    }
+    println!("[ddnsfw] Plan: {} to add, {} to remove", adds.len(), removes.len());
+    std::process::exit(EXIT_DRIFT);
+}
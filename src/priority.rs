@@ -0,0 +1,49 @@
+//! Priority classes: which entries a sync handles first.
+//!
+//!   admin.example.net:22 priority=critical
+//!   game.example.net:27015/udp priority=best-effort
+//!
+//! Entries are `normal` unless they say otherwise. Each sync resolves the
+//! entries class by class (critical, normal, best-effort; config order
+//! within a class), adds their rules in the same order, and with
+//! iptables-restore commits each class's adds on their own, so a slow
+//! best-effort lookup or a failed game-server restore never stands between
+//! an SSH address change and its rule.
+//!
+//! The old rules of a critical entry are deleted last, and only once every
+//! rule the entry gained this sync is verified in the firewall (an
+//! `iptables -C`, not the exit status of the add). Until then they stay
+//! (`HELD`) and the next sync tries again, so a lost add can never leave
+//! the entry with no allow at all. Grants rank as normal. The order does
+//! not change across backends; the per-class commits need the iptables
+//! backend's batching.
+
+/// An entry's class; the derived order is the processing order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Priority {
+    Critical,
+    #[default]
+    Normal,
+    BestEffort,
+}
+
+impl Priority {
+    pub const ALL: [Priority; 3] = [Priority::Critical, Priority::Normal, Priority::BestEffort];
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "critical" => Some(Priority::Critical),
+            "normal" => Some(Priority::Normal),
+            "best-effort" => Some(Priority::BestEffort),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Priority::Critical => "critical",
+            Priority::Normal => "normal",
+            Priority::BestEffort => "best-effort",
+        }
+    }
+}
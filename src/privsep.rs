@@ -0,0 +1,513 @@
+//! Service user mode: everything but the firewall changes runs unprivileged.
+//!
+//!   ddnsfw service-user enable | disable | status
+//!
+//! When enabled, every ddnsfw process (the timer's, the daemon, and admin
+//! commands started as root, which switch over at once) runs as the system
+//! user `ddnsfw`, so the code that parses DNS answers, provider responses
+//! and the config holds no root rights. iptables is reached through
+//!
+//!   sudo -n /etc/ddnsfw/run iptables-helper [-6] <iptables args>
+//!
+//! a root entry point that reads neither config nor state: it accepts only
+//! a chain listing, or the check, insert, append or delete of one allow
+//! (`-s` of at least a /8, `-p tcp|udp --dport N`, `-j ACCEPT`, tagged with
+//! a ddnsfw profile's default comment), runs it with the system iptables
+//! (or ip6tables after `-6`) and refuses everything else: flushes,
+//! policies, chain changes, negations, blocking targets, untagged or
+//! foreign-tagged rules. A compromised service user can therefore only add
+//! or remove ddnsfw's own allows, never open a port to everyone, lock the
+//! host out or touch another tool's rules. Entry options needing other
+//! rules (companions, marks, jumps, lockdowns; see `unsupported_options`)
+//! and `comment_prefix` are refused in this mode, as are other backends.
+//!
+//! Layout while enabled: /etc/ddnsfw is root:ddnsfw 1770 (sticky, so the
+//! user can replace its own state files but not the root-owned binary that
+//! sudo runs), the binary is root:ddnsfw 0750, the state and config files
+//! belong to ddnsfw, the units run `User=ddnsfw`, and the root-owned marker
+//! /etc/ddnsfw/service-user switches the mode on.
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::{chown, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::rule::{is_valid_chain_name, Table};
+use crate::{
+    exit_err, find_iptables, is_installed, parse_config, profile, sys, v6, Config, DdnsEntry, BINARY_PATH, INSTALL_DIR,
+    MAX_LOOP_ITERATIONS,
+};
+use crate::{SERVICE_PATH, TEMPLATE_SERVICE_PATH};
+
+const USER: &str = "ddnsfw";
+pub const MARKER_PATH: &str = "/etc/ddnsfw/service-user";
+pub const SUDOERS_PATH: &str = "/etc/sudoers.d/ddnsfw";
+pub const HELPER_COMMAND: &str = "iptables-helper";
+const MAX_HELPER_ARGS: usize = 64;
+const MAX_WALK_DEPTH: usize = 4;
+const USAGE: &str = "Usage: ddnsfw service-user [enable|disable|status]";
+
+/// Set once this process runs as the service user
+static ROUTED: AtomicBool = AtomicBool::new(false);
+
+/// The service user's uid and gid while the mode is enabled
+pub fn configured() -> Option<(u32, u32)> {
+    // Only root can have created a root-owned marker in the sticky directory
+    let meta = fs::symlink_metadata(MARKER_PATH).ok()?;
+    if !meta.is_file() || meta.uid() != 0 {
+        return None;
+    }
+    sys::lookup_user(USER)
+}
+
+/// Whether iptables must be reached through the helper
+pub fn routed() -> bool {
+    ROUTED.load(Ordering::Relaxed)
+}
+
+/// Settles who this process runs as, before anything else happens: root
+/// switches to the service user when the mode is enabled (except for the
+/// root-only entry points), the service user goes through the helper, and
+/// anyone else is refused.
+pub fn enter(command: Option<&str>) {
+    let service = configured();
+    if service.is_some_and(|(uid, _)| uid != 0 && sys::euid() == uid) {
+        ROUTED.store(true, Ordering::Relaxed);
+        return;
+    }
+    if !sys::is_root() {
+        exit_err("Must run as root");
+    }
+    let Some((uid, gid)) = service else {
+        return;
+    };
+    if matches!(command, Some("service-user") | Some("uninstall") | Some("fix-perms") | Some(HELPER_COMMAND)) {
+        return;
+    }
+    if !sys::drop_privileges(uid, gid) {
+        exit_err(&format!("Cannot switch to the {} user", USER));
+    }
+    ROUTED.store(true, Ordering::Relaxed);
+}
+
+/// The command running `iptables <args>` (or ip6tables): directly, or
+/// through the helper
+pub fn iptables_command(bin: &str, args: &[&str]) -> Command {
+    if routed() {
+        let mut command = Command::new("sudo");
+        command.args(["-n", BINARY_PATH, HELPER_COMMAND]);
+        if Path::new(bin).file_name().is_some_and(|name| name == "ip6tables") {
+            command.arg("-6");
+        }
+        command.args(args);
+        command
+    } else {
+        let mut command = Command::new(bin);
+        command.args(args);
+        command
+    }
+}
+
+// ============================================================================
+// Helper (root)
+// ============================================================================
+
+const BUILTIN_CHAINS: &[&str] = &["INPUT", "FORWARD", "OUTPUT", "PREROUTING", "POSTROUTING"];
+const MODULES: &[&str] = &["tcp", "udp", "comment"];
+const PROTOCOLS: &[&str] = &["tcp", "udp"];
+/// The only target: the helper adds allows, never blocks
+const TARGET: &str = "ACCEPT";
+/// Shortest source prefix: anything wider opens the port to the world
+const MIN_PREFIX: u8 = 8;
+
+fn is_chain(name: &str) -> bool {
+    BUILTIN_CHAINS.contains(&name) || is_valid_chain_name(name)
+}
+
+fn is_source(value: &str, ipv6: bool) -> bool {
+    let (ip, prefix) = value.split_once('/').unwrap_or((value, if ipv6 { "128" } else { "32" }));
+    let (parsed, max) = if ipv6 {
+        (ip.parse::<std::net::Ipv6Addr>().is_ok(), 128)
+    } else {
+        (ip.parse::<std::net::Ipv4Addr>().is_ok(), 32)
+    };
+    parsed && prefix.parse::<u8>().is_ok_and(|p| (MIN_PREFIX..=max).contains(&p))
+}
+
+/// Entry options whose rules the helper refuses (anything but an allow)
+pub fn unsupported_options(entry: &DdnsEntry) -> Vec<&'static str> {
+    let options = [
+        ("mark", entry.mark.is_some()),
+        ("notrack", entry.notrack),
+        ("vpn", entry.vpn.is_some()),
+        ("jump", entry.jump.is_some()),
+        ("action", entry.returns),
+        ("max_conns", entry.max_conns.is_some()),
+        ("knock", entry.knock.is_some()),
+        ("lockdown", entry.lockdown.is_some()),
+    ];
+    options.into_iter().filter(|(_, set)| *set).map(|(name, _)| name).collect()
+}
+
+/// Whether the config only needs rules the helper accepts
+pub fn check_config(config: &Config) -> Result<(), String> {
+    let profile = profile::current();
+    if profile.comment() != profile.default_comment() {
+        return Err(String::from("comment_prefix is not supported in service user mode"));
+    }
+    for entry in &config.entries {
+        let options = unsupported_options(entry);
+        if !options.is_empty() {
+            return Err(format!(
+                "{}: {} not supported in service user mode (the helper only adds allows)",
+                entry.origin,
+                options.join(", ")
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Checks one iptables (`ipv6`: ip6tables) invocation against the
+/// operations the sync issues
+fn validate(args: &[String], ipv6: bool) -> Result<(), String> {
+    let mut args = args.iter().map(String::as_str).peekable();
+    if args.peek() == Some(&"-t") {
+        args.next();
+        let table = args.next().unwrap_or_default();
+        Table::parse(table).ok_or_else(|| format!("table '{}' not allowed", table))?;
+    }
+    let action = args.next().unwrap_or_default();
+    let chain = args.next().unwrap_or_default();
+    if !is_chain(chain) {
+        return Err(format!("chain '{}' not allowed", chain));
+    }
+    match action {
+        "-S" => {
+            return match args.next() {
+                None | Some("-v") if args.next().is_none() => Ok(()),
+                _ => Err(String::from("listing takes no further arguments")),
+            };
+        }
+        "-I" => {
+            if args.peek().is_some_and(|a| a.chars().all(|c| c.is_ascii_digit())) {
+                args.next();
+            }
+        }
+        "-C" | "-A" | "-D" => {}
+        _ => return Err(format!("operation '{}' not allowed", action)),
+    }
+
+    // Each of these exactly once; `-m` may repeat
+    let mut seen: Vec<&str> = Vec::new();
+    while let Some(arg) = args.next() {
+        if arg == "!" {
+            return Err(String::from("negation not allowed"));
+        }
+        let value = args.next().ok_or_else(|| format!("'{}' needs a value", arg))?;
+        let ok = match arg {
+            "-m" => MODULES.contains(&value),
+            "-s" => is_source(value, ipv6),
+            "-p" => PROTOCOLS.contains(&value),
+            "--dport" => value.parse::<u16>().is_ok_and(|port| port > 0),
+            "--comment" => profile::is_default_comment(value.trim_matches('"')),
+            "-j" => value == TARGET,
+            _ => return Err(format!("option '{}' not allowed", arg)),
+        };
+        if !ok {
+            return Err(format!("value '{}' not allowed for {}", value, arg));
+        }
+        if arg != "-m" {
+            if seen.contains(&arg) {
+                return Err(format!("{} given twice", arg));
+            }
+            seen.push(arg);
+        }
+    }
+    // Untagged rules belong to someone else; a rule without a source or
+    // port would open more than one allow
+    for required in ["-s", "-p", "--dport", "--comment", "-j"] {
+        if !seen.contains(&required) {
+            return Err(format!("rule without {}", required));
+        }
+    }
+    Ok(())
+}
+
+/// `ddnsfw iptables-helper <iptables args>` (root, through sudo)
+pub fn helper(args: &[String]) {
+    if !sys::is_root() {
+        exit_err("The iptables helper must run as root");
+    }
+    if args.len() > MAX_HELPER_ARGS {
+        exit_err("Too many arguments");
+    }
+    let (ipv6, args) = match args.split_first() {
+        Some((flag, rest)) if flag == "-6" => (true, rest),
+        _ => (false, args),
+    };
+    if let Err(reason) = validate(args, ipv6) {
+        exit_err(&format!("iptables helper refused: {}", reason));
+    }
+    let bin = if ipv6 { v6::find_ip6tables() } else { find_iptables() };
+    let Some(bin) = bin else {
+        exit_err(if ipv6 { "ip6tables not found" } else { "iptables not found" });
+    };
+    let Ok(output) = Command::new(bin).args(args).output() else {
+        exit_err("Cannot run iptables");
+    };
+    let _ = io::stdout().write_all(&output.stdout);
+    let _ = io::stderr().write_all(&output.stderr);
+    std::process::exit(output.status.code().unwrap_or(1));
+}
+
+// ============================================================================
+// service-user command
+// ============================================================================
+
+/// `ddnsfw service-user ...`
+pub fn command(args: &[String]) {
+    match args.first().map(String::as_str) {
+        None | Some("status") => status(),
+        Some("enable") => enable(),
+        Some("disable") => disable(),
+        Some(_) => exit_err(USAGE),
+    }
+}
+
+fn status() {
+    match configured() {
+        Some((uid, gid)) => println!("[ddnsfw] Service user mode: enabled ({} uid {}, gid {})", USER, uid, gid),
+        None => println!("[ddnsfw] Service user mode: disabled (everything runs as root)"),
+    }
+    let sudoers = if Path::new(SUDOERS_PATH).exists() { "present" } else { "missing" };
+    println!("  sudoers rule: {} ({})", SUDOERS_PATH, sudoers);
+    for path in [SERVICE_PATH, TEMPLATE_SERVICE_PATH] {
+        if let Ok(unit) = fs::read_to_string(path) {
+            let user = unit.lines().find_map(|l| l.strip_prefix("User=")).unwrap_or("root");
+            println!("  {}: User={}", path, user);
+        }
+    }
+}
+
+fn run(program: &str, args: &[&str]) -> bool {
+    Command::new(program).args(args).output().is_ok_and(|o| o.status.success())
+}
+
+/// Rewrites the `User=` line of the installed service units
+fn set_unit_user(user: &str) {
+    for path in [SERVICE_PATH, TEMPLATE_SERVICE_PATH] {
+        let Ok(unit) = fs::read_to_string(path) else {
+            continue;
+        };
+        let unit: String = unit
+            .lines()
+            .map(|line| if line.starts_with("User=") { format!("User={}\n", user) } else { format!("{}\n", line) })
+            .collect();
+        if fs::write(path, unit).is_err() {
+            eprintln!("[ddnsfw] WARN: Could not update {}", path);
+        }
+    }
+    let _ = Command::new("systemctl").arg("daemon-reload").output();
+}
+
+/// Hands everything below `dir` (except the binary and the marker) to
+/// `uid`/`gid`
+fn chown_tree(dir: &Path, uid: u32, gid: u32, depth: usize) -> bool {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return false;
+    };
+    let mut ok = true;
+    for entry in entries.flatten().take(MAX_LOOP_ITERATIONS) {
+        let path = entry.path();
+        if path == Path::new(BINARY_PATH) || path == Path::new(MARKER_PATH) {
+            continue;
+        }
+        // Never follow links planted in the tree
+        let Ok(meta) = fs::symlink_metadata(&path) else {
+            continue;
+        };
+        if meta.file_type().is_symlink() {
+            continue;
+        }
+        ok &= chown(&path, Some(uid), Some(gid)).is_ok();
+        if meta.is_dir() && depth < MAX_WALK_DEPTH {
+            ok &= chown_tree(&path, uid, gid, depth + 1);
+        }
+    }
+    ok
+}
+
+fn enable() {
+    if !sys::is_root() || !is_installed() {
+        exit_err("Run as root on an installed system");
+    }
+    if let Err(reason) = check_config(&parse_config()) {
+        exit_err(&reason);
+    }
+    if !run("sudo", &["-V"]) {
+        exit_err("sudo is required for the service user mode");
+    }
+    if sys::lookup_user(USER).is_none() {
+        print!("[ddnsfw] Creating system user {} ... ", USER);
+        let created = run(
+            "useradd",
+            &["--system", "--no-create-home", "--home-dir", "/nonexistent", "--shell", "/usr/sbin/nologin", USER],
+        );
+        println!("{}", if created { "OK" } else { "FAILED" });
+    }
+    let Some((uid, gid)) = sys::lookup_user(USER) else {
+        exit_err(&format!("User {} does not exist", USER));
+    };
+
+    // The only root right the user gets: the validating helper
+    let rule = format!(
+        "# ddnsfw service user: firewall changes only, through the validating helper\n\
+         Defaults:{user} !requiretty\n\
+         {user} ALL=(root) NOPASSWD: {binary} {helper} *\n",
+        user = USER,
+        binary = BINARY_PATH,
+        helper = HELPER_COMMAND
+    );
+    let temp_path = format!("{}.tmp", SUDOERS_PATH);
+    let written = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o440)
+        .open(&temp_path)
+        .and_then(|mut file| file.write_all(rule.as_bytes()));
+    if written.is_err() || !run("visudo", &["-cqf", &temp_path]) || fs::rename(&temp_path, SUDOERS_PATH).is_err() {
+        let _ = fs::remove_file(&temp_path);
+        exit_err(&format!("Could not install {}", SUDOERS_PATH));
+    }
+
+    let set_mode = |path: &str, mode: u32| fs::set_permissions(path, fs::Permissions::from_mode(mode)).is_ok();
+    let owned = chown(INSTALL_DIR, Some(0), Some(gid)).is_ok()
+        && set_mode(INSTALL_DIR, 0o1770)
+        && chown(BINARY_PATH, Some(0), Some(gid)).is_ok()
+        && set_mode(BINARY_PATH, 0o750)
+        && chown_tree(Path::new(INSTALL_DIR), uid, gid, 0);
+    if !owned {
+        exit_err(&format!("Could not hand {} to {}", INSTALL_DIR, USER));
+    }
+    set_unit_user(USER);
+
+    // Last: the mode only starts once everything is in place
+    let marker = OpenOptions::new().write(true).create(true).truncate(true).mode(0o644).open(MARKER_PATH);
+    if marker.and_then(|mut file| file.write_all(b"ddnsfw\n")).is_err() {
+        exit_err(&format!("Could not write {}", MARKER_PATH));
+    }
+    println!("[ddnsfw] Service user mode enabled: syncs run as {} (uid {}), iptables through {} {}", USER, uid, BINARY_PATH, HELPER_COMMAND);
+    println!("Check it with: sudo {} doctor", BINARY_PATH);
+}
+
+/// Removes the sudoers rule (uninstall); the user is kept, as by `disable`
+pub fn remove_sudoers() -> bool {
+    fs::remove_file(SUDOERS_PATH).is_ok() || !Path::new(SUDOERS_PATH).exists()
+}
+
+fn disable() {
+    if !sys::is_root() {
+        exit_err("Must run as root");
+    }
+    // First: from now on every process runs as root again
+    if fs::remove_file(MARKER_PATH).is_err() && Path::new(MARKER_PATH).exists() {
+        exit_err(&format!("Could not remove {}", MARKER_PATH));
+    }
+    let _ = fs::remove_file(SUDOERS_PATH);
+    let set_mode = |path: &str, mode: u32| fs::set_permissions(path, fs::Permissions::from_mode(mode)).is_ok();
+    let owned = chown(INSTALL_DIR, Some(0), Some(0)).is_ok()
+        && set_mode(INSTALL_DIR, 0o700)
+        && chown(BINARY_PATH, Some(0), Some(0)).is_ok()
+        && set_mode(BINARY_PATH, 0o700)
+        && chown_tree(Path::new(INSTALL_DIR), 0, 0, 0);
+    if !owned {
+        eprintln!("[ddnsfw] WARN: Could not return every file in {} to root", INSTALL_DIR);
+    }
+    set_unit_user("root");
+    println!("[ddnsfw] Service user mode disabled: everything runs as root again");
+    println!("The {} user was kept (remove it with: userdel {})", USER, USER);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rule::Rule;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    fn refused(line: &str) -> bool {
+        validate(&args(line), false).is_err()
+    }
+
+    const ALLOW: &str = "-s 203.0.113.7/32 -p tcp -m tcp --dport 22 -m comment --comment DDNS-ACCESS -j ACCEPT";
+
+    #[test]
+    fn accepts_what_the_sync_issues() {
+        let rule = Rule::allow("203.0.113.7".parse().unwrap(), 22);
+        for action in ["-I", "-A", "-C", "-D"] {
+            assert_eq!(validate(&rule.iptables_args(action), false), Ok(()), "{}", action);
+        }
+        assert_eq!(validate(&args(&format!("-I INPUT 3 {}", ALLOW)), false), Ok(()));
+        assert_eq!(validate(&args("-t filter -S INPUT"), false), Ok(()));
+        let watchdog = "-I INPUT -s 10.0.0.1/32 -p tcp --dport 22 -m comment --comment DDNS-ACCESS-lab-WATCHDOG -j ACCEPT";
+        assert_eq!(validate(&args(watchdog), false), Ok(()));
+        let v6 = "-I INPUT -s 2001:db8::/64 -p udp -m udp --dport 51820 -m comment --comment DDNS-ACCESS-lab -j ACCEPT";
+        assert_eq!(validate(&args(v6), true), Ok(()));
+    }
+
+    #[test]
+    fn refuses_world_and_wide_sources() {
+        assert!(refused("-I INPUT -s 0.0.0.0/0 -p tcp --dport 22 -m comment --comment DDNS-ACCESS -j ACCEPT"));
+        assert!(refused("-I INPUT -s 10.0.0.0/7 -p tcp --dport 22 -m comment --comment DDNS-ACCESS -j ACCEPT"));
+        assert!(refused("-I INPUT -p tcp --dport 22 -m comment --comment DDNS-ACCESS -j ACCEPT"));
+        assert!(validate(&args("-I INPUT -s ::/0 -p tcp --dport 22 -m comment --comment DDNS-ACCESS -j ACCEPT"), true).is_err());
+    }
+
+    #[test]
+    fn refuses_negation() {
+        assert!(refused("-I INPUT ! -s 203.0.113.7/32 -p tcp --dport 22 -m comment --comment DDNS-ACCESS -j ACCEPT"));
+        assert!(refused("-I INPUT -s 203.0.113.7/32 -p tcp ! --dport 22 -m comment --comment DDNS-ACCESS -j ACCEPT"));
+    }
+
+    #[test]
+    fn refuses_rules_without_a_port() {
+        assert!(refused("-I INPUT -s 203.0.113.7/32 -m comment --comment DDNS-ACCESS -j ACCEPT"));
+        assert!(refused("-I INPUT -s 203.0.113.7/32 -p tcp -m comment --comment DDNS-ACCESS -j ACCEPT"));
+        assert!(refused("-I INPUT -s 203.0.113.7/32 -p tcp --dport 0 -m comment --comment DDNS-ACCESS -j ACCEPT"));
+        assert!(refused("-I INPUT -s 203.0.113.7/32 -p icmp --dport 22 -m comment --comment DDNS-ACCESS -j ACCEPT"));
+    }
+
+    #[test]
+    fn refuses_blocking_targets() {
+        for target in ["DROP", "REJECT", "RETURN", "MARK", "CT", "DDNSFW"] {
+            let line = ALLOW.replace("ACCEPT", target);
+            assert!(refused(&format!("-I INPUT 1 {}", line)), "{}", target);
+        }
+        assert!(refused("-I INPUT 1 -s 203.0.113.7/32 -p tcp --dport 22 -m comment --comment DDNS-ACCESS"));
+    }
+
+    #[test]
+    fn refuses_foreign_comments() {
+        for comment in ["fail2ban", "DDNS-ACCESSX", "ACME-DDNS", "DDNS-ACCESS-Lab", "DDNS-ACCESS-"] {
+            let line = ALLOW.replace("DDNS-ACCESS", comment);
+            assert!(refused(&format!("-D INPUT {}", line)), "{}", comment);
+        }
+        assert!(refused("-D INPUT -s 203.0.113.7/32 -p tcp --dport 22 -j ACCEPT"));
+    }
+
+    #[test]
+    fn refuses_other_operations_and_options() {
+        assert!(refused("-F INPUT"));
+        assert!(refused("-P INPUT ACCEPT"));
+        assert!(refused("-X DDNSFW"));
+        assert!(refused("-S INPUT -v extra"));
+        assert!(refused(&format!("-I INPUT {} -m recent --set --name x", ALLOW)));
+        assert!(refused(&format!("-I INPUT {} -s 198.51.100.1/32", ALLOW)));
+    }
+}
@@ -0,0 +1,245 @@
+//! Profiles: independent instances on one host (e.g. `prod`, `lab`).
+//!
+//! The default profile uses the classic layout (`/etc/ddnsfw/conf.conf`,
+//! unit `ddnsfw`, comment `DDNS-ACCESS`). A named profile keeps its config
+//! and state under `/etc/ddnsfw/profiles/<name>/`, runs as the template unit
+//! instance `ddnsfw@<name>` with SyslogIdentifier `ddnsfw-<name>`, and tags
+//! its rules `DDNS-ACCESS-<name>` so profiles never touch each other's rules.
+//!
+//! The `DDNS-ACCESS` part of the comment can be replaced per profile with
+//! the `comment_prefix` setting (e.g. `ACME-DDNS` -> `ACME-DDNS-<name>`) so
+//! unrelated tools sharing a host never claim each other's rules.
+//!
+//! The profile is chosen once at startup by `--profile <name>` (as written
+//! into the template unit) or the DDNSFW_PROFILE environment variable.
+//!
+//! The config is `config.toml` when the profile dir has one, else the
+//! classic `conf.conf` (see tomlconfig). Config files are always read from
+//! the profile dir. The state the sync
+//! writes (cache, lock, skips, ...) normally lives there too, but on
+//! immutable systems (ostree, read-only /etc) it moves to the same layout
+//! under /var/lib/ddnsfw/state, carrying the existing files over once.
+
+use std::fs::{self, DirBuilder};
+use std::os::unix::fs::DirBuilderExt;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use crate::{exit_err, sys, INSTALL_DIR};
+
+pub struct Profile {
+    pub name: Option<String>,
+    pub dir: String,
+    /// Where the state files below live: `dir`, or its relocated twin
+    pub state_dir: String,
+    pub config_path: String,
+    /// The classic config, `config_path` unless config.toml took over
+    pub legacy_config_path: String,
+    pub cache_path: String,
+    pub lock_path: String,
+    pub skips_path: String,
+    pub state_path: String,
+    pub throttle_path: String,
+    pub wildcards_path: String,
+    pub srv_path: String,
+    pub hosts_override_path: String,
+    pub grants_path: String,
+    pub push_path: String,
+    pub churn_path: String,
+    comment: OnceLock<String>,
+}
+
+/// Config file names in a profile dir
+pub const LEGACY_CONFIG: &str = "conf.conf";
+pub const TOML_CONFIG: &str = "config.toml";
+
+const DEFAULT_COMMENT_PREFIX: &str = "DDNS-ACCESS";
+/// Root of the relocated state (the units' StateDirectory)
+const RELOCATED_ROOT: &str = "/var/lib/ddnsfw/state";
+/// State files carried over by `relocate` (the cache store handles its own)
+/// and removed by `uninstall --keep-config`
+pub const STATE_FILES: &[&str] = &[
+    "service.cache", "service.skips", "service.state", "service.throttle",
+    "service.wildcards", "service.srv", "service.grants", "service.push", "service.churn", "events.jsonl",
+];
+
+static PROFILE: OnceLock<Profile> = OnceLock::new();
+
+/// Comment prefixes: letters, digits, '-', '_' (no spaces or quotes in -S output)
+pub fn is_valid_comment_prefix(prefix: &str) -> bool {
+    !prefix.is_empty()
+        && prefix.len() <= 64
+        && prefix.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 32
+        && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+/// Whether `comment` is the default comment of some profile (`DDNS-ACCESS`,
+/// `DDNS-ACCESS-<name>`), or its watchdog twin (`...-WATCHDOG`)
+pub fn is_default_comment(comment: &str) -> bool {
+    let comment = comment.strip_suffix("-WATCHDOG").unwrap_or(comment);
+    match comment.strip_prefix(DEFAULT_COMMENT_PREFIX) {
+        Some("") => true,
+        Some(rest) => rest.strip_prefix('-').is_some_and(is_valid_name),
+        None => false,
+    }
+}
+
+/// Selects the profile for this process. Must run before any path is used.
+pub fn select(name: Option<String>) {
+    if let Some(name) = &name {
+        if !is_valid_name(name) {
+            exit_err(&format!("Invalid profile name '{}' (use a-z, 0-9, '-', '_')", name));
+        }
+    }
+    let _ = PROFILE.set(build(name));
+}
+
+/// Selects a throwaway profile rooted at `dir` (the --chaos sandbox)
+pub fn select_sandbox(dir: &str) {
+    let _ = PROFILE.set(build_in(dir.to_string(), dir.to_string(), Some(String::from("chaos"))));
+}
+
+/// `dir`'s twin under the relocated state root
+fn relocated(dir: &str) -> String {
+    format!("{}{}", RELOCATED_ROOT, dir.strip_prefix(INSTALL_DIR).unwrap_or_default())
+}
+
+/// Where state shared by all profiles (the DNS cache) lives
+pub fn shared_state_dir() -> String {
+    if sys::is_read_only(INSTALL_DIR) {
+        relocated(INSTALL_DIR)
+    } else {
+        INSTALL_DIR.to_string()
+    }
+}
+
+fn build(name: Option<String>) -> Profile {
+    let dir = match &name {
+        Some(name) => format!("{}/profiles/{}", INSTALL_DIR, name),
+        None => INSTALL_DIR.to_string(),
+    };
+    let state_dir = if sys::is_read_only(&dir) { relocated(&dir) } else { dir.clone() };
+    build_in(dir, state_dir, name)
+}
+
+fn build_in(dir: String, state_dir: String, name: Option<String>) -> Profile {
+    let legacy_config_path = format!("{}/{}", dir, LEGACY_CONFIG);
+    let toml_config_path = format!("{}/{}", dir, TOML_CONFIG);
+    Profile {
+        config_path: if Path::new(&toml_config_path).exists() { toml_config_path } else { legacy_config_path.clone() },
+        legacy_config_path,
+        cache_path: format!("{}/service.cache", state_dir),
+        lock_path: format!("{}/.lock", state_dir),
+        skips_path: format!("{}/service.skips", state_dir),
+        state_path: format!("{}/service.state", state_dir),
+        throttle_path: format!("{}/service.throttle", state_dir),
+        wildcards_path: format!("{}/service.wildcards", state_dir),
+        srv_path: format!("{}/service.srv", state_dir),
+        hosts_override_path: format!("{}/hosts.override", dir),
+        grants_path: format!("{}/service.grants", state_dir),
+        push_path: format!("{}/service.push", state_dir),
+        churn_path: format!("{}/service.churn", state_dir),
+        comment: OnceLock::new(),
+        dir,
+        state_dir,
+        name,
+    }
+}
+
+pub fn current() -> &'static Profile {
+    PROFILE.get_or_init(|| build(None))
+}
+
+impl Profile {
+    /// systemd unit (without suffix): `ddnsfw` or `ddnsfw@<name>`
+    pub fn unit(&self) -> String {
+        match &self.name {
+            Some(name) => format!("ddnsfw@{}", name),
+            None => String::from("ddnsfw"),
+        }
+    }
+
+    /// Comment tagging this profile's rules: `<prefix>` or `<prefix>-<name>`
+    pub fn comment(&self) -> &str {
+        self.comment.get_or_init(|| self.comment_with(DEFAULT_COMMENT_PREFIX))
+    }
+
+    /// The comment before any `comment_prefix` (what older versions used)
+    pub fn default_comment(&self) -> String {
+        self.comment_with(DEFAULT_COMMENT_PREFIX)
+    }
+
+    fn comment_with(&self, prefix: &str) -> String {
+        match &self.name {
+            Some(name) => format!("{}-{}", prefix, name),
+            None => prefix.to_string(),
+        }
+    }
+
+    /// Applies `comment_prefix`. Fails if rules were already handled under
+    /// a different comment in this process.
+    pub fn set_comment_prefix(&self, prefix: &str) -> bool {
+        let comment = self.comment_with(prefix);
+        self.comment.get_or_init(|| comment.clone()) == &comment
+    }
+
+    /// Whether the state moved off a read-only profile dir
+    pub fn is_relocated(&self) -> bool {
+        self.state_dir != self.dir
+    }
+
+    /// Moves the state files to where this process keeps them: into the
+    /// relocated dir when the profile dir turned read-only (copies, the
+    /// originals cannot be removed; files already there are newer and
+    /// win), back over the stale originals when it is writable again. Call
+    /// once the process runs as the user owning the state.
+    pub fn relocate(&self) {
+        let twin = relocated(&self.dir);
+        let (from, to) = if self.is_relocated() { (&self.dir, &self.state_dir) } else { (&twin, &self.dir) };
+        if self.is_relocated() && DirBuilder::new().recursive(true).mode(0o700).create(to).is_err() {
+            eprintln!("[ddnsfw] WARN: Cannot create state directory {}", to);
+            return;
+        }
+        if twin == self.dir || !Path::new(from).is_dir() {
+            return;
+        }
+        let pending: Vec<&str> = STATE_FILES
+            .iter()
+            .filter(|file| Path::new(&format!("{}/{}", from, file)).exists())
+            .filter(|file| !self.is_relocated() || !Path::new(&format!("{}/{}", to, file)).exists())
+            .copied()
+            .collect();
+        if pending.is_empty() {
+            return;
+        }
+        if self.is_relocated() {
+            println!("[ddnsfw] {} is read-only, keeping state in {}", self.dir, to);
+        }
+        for file in pending {
+            let (source, target) = (format!("{}/{}", from, file), format!("{}/{}", to, file));
+            let temp_path = format!("{}.tmp", target);
+            if fs::copy(&source, &temp_path).is_ok() && fs::rename(&temp_path, &target).is_ok() {
+                println!("[ddnsfw] Moved {} -> {}", source, target);
+                if !self.is_relocated() {
+                    let _ = fs::remove_file(&source);
+                }
+            } else {
+                let _ = fs::remove_file(&temp_path);
+                eprintln!("[ddnsfw] WARN: Cannot copy {} to {}", source, target);
+            }
+        }
+    }
+
+    /// Journal identifier: `ddnsfw` or `ddnsfw-<name>`
+    pub fn syslog_identifier(&self) -> String {
+        match &self.name {
+            Some(name) => format!("ddnsfw-{}", name),
+            None => String::from("ddnsfw"),
+        }
+    }
+}
@@ -0,0 +1,293 @@
+//! Wildcard entries enumerated through DDNS provider APIs.
+//!
+//!   provider_account = home dynu <api-key>
+//!   provider_account = prod cloudflare <zone-id> <api-token>
+//!
+//!   dynu:home/*:22             # every hostname of the Dynu account
+//!   cloudflare:prod/*:@admin   # every A record of the zone, bundle ports
+//!
+//! Each sync lists the account's hostnames and expands the wildcard into one
+//! ordinary entry per hostname (same port/bundle and options), so a hostname
+//! added at the provider is allowed on the next sync and one removed there
+//! loses its rules. The last successful listing is kept in the profile's
+//! `service.wildcards`; when the API is unreachable that list is used, so a
+//! provider outage never removes rules. DuckDNS has no listing API and
+//! cannot be enumerated.
+//!
+//! Credentials are passed to curl on stdin, never on its command line.
+
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::net::Ipv4Addr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::process::{Command, Stdio};
+
+use crate::proxmox::{json_field, json_objects};
+use crate::{execs, exit_err, profile, push_entries, Config, DNS_TIMEOUT_SECS, MAX_ENTRIES};
+
+const MAX_RESPONSE_BYTES: usize = 1 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Provider {
+    Dynu,
+    Cloudflare,
+}
+
+impl Provider {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "dynu" => Some(Provider::Dynu),
+            "cloudflare" => Some(Provider::Cloudflare),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Provider::Dynu => "dynu",
+            Provider::Cloudflare => "cloudflare",
+        }
+    }
+}
+
+/// `provider_account = NAME PROVIDER CREDENTIALS...`
+#[derive(Clone)]
+pub struct Account {
+    name: String,
+    provider: Provider,
+    zone: String,  // Cloudflare zone id (empty for Dynu)
+    key: String,
+}
+
+impl Account {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let fields: Vec<&str> = value.split_whitespace().collect();
+        let (name, provider, zone, key) = match fields[..] {
+            [name, "dynu", key] => (name, Provider::Dynu, "", key),
+            [name, "cloudflare", zone, key] => (name, Provider::Cloudflare, zone, key),
+            [_, "duckdns", ..] => return Err(String::from("duckdns has no API to list hostnames")),
+            [_, provider, ..] if Provider::parse(provider).is_none() => {
+                return Err(format!("unknown provider '{}' (dynu, cloudflare)", provider));
+            }
+            _ => return Err(String::from("use NAME dynu KEY or NAME cloudflare ZONE TOKEN")),
+        };
+        let token_ok = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_graphic());
+        if !is_valid_account_name(name) || !token_ok(key) || !(zone.is_empty() || zone.chars().all(|c| c.is_ascii_alphanumeric())) {
+            return Err(String::from("invalid account name or credentials"));
+        }
+        Ok(Account { name: name.to_string(), provider, zone: zone.to_string(), key: key.to_string() })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// (url, request headers)
+    fn request(&self) -> (String, String) {
+        match self.provider {
+            Provider::Dynu => (
+                String::from("https://api.dynu.com/v2/dns"),
+                format!("API-Key: {}\nAccept: application/json\n", self.key),
+            ),
+            Provider::Cloudflare => (
+                format!("https://api.cloudflare.com/client/v4/zones/{}/dns_records?type=A&per_page=500", self.zone),
+                format!("Authorization: Bearer {}\nAccept: application/json\n", self.key),
+            ),
+        }
+    }
+
+    /// The account's records (JSON), or None when the API cannot be queried
+    fn fetch(&self) -> Option<String> {
+        let (url, headers) = self.request();
+        let mut child = execs::spawn(
+            Command::new("curl")
+                .args([
+                    "-fsS",
+                    "--proto", "=https",
+                    "--max-time", &DNS_TIMEOUT_SECS.to_string(),
+                    "--max-filesize", &MAX_RESPONSE_BYTES.to_string(),
+                    "-H", "@-",
+                    &url,
+                ])
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null()),
+        )
+        .ok()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            let _ = stdin.write_all(headers.as_bytes());
+        }
+        let output = child.wait_with_output().ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        let body = String::from_utf8_lossy(&output.stdout).into_owned();
+        // Cloudflare reports errors in the body too
+        if self.provider == Provider::Cloudflare && !body.contains("\"success\":true") {
+            return None;
+        }
+        Some(body)
+    }
+
+    /// Hostnames in the account, or None when the API cannot be queried
+    fn hostnames(&self) -> Option<Vec<String>> {
+        let body = self.fetch()?;
+        let mut hostnames: Vec<String> = Vec::new();
+        for object in json_objects(&body) {
+            let Some(name) = json_field(object, "name").map(|n| n.to_ascii_lowercase()) else {
+                continue;
+            };
+            if is_valid_hostname(&name) && !hostnames.contains(&name) && hostnames.len() < MAX_ENTRIES {
+                hostnames.push(name);
+            }
+        }
+        Some(hostnames)
+    }
+
+    /// The IPv4 address the provider holds for `hostname` (the `api:`
+    /// resolver): what the DDNS client last pushed, free of DNS caching
+    pub fn address_of(&self, hostname: &str) -> Option<Ipv4Addr> {
+        let field = match self.provider {
+            Provider::Dynu => "ipv4Address",
+            Provider::Cloudflare => "content",
+        };
+        let body = self.fetch()?;
+        let record = json_objects(&body).find(|object| json_field(object, "name").is_some_and(|n| n.eq_ignore_ascii_case(hostname)))?;
+        json_field(record, field)?.parse().ok()
+    }
+}
+
+fn is_valid_account_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= 32 && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Plain hostnames only (wildcard records such as `*.example.com` are skipped)
+pub fn is_valid_hostname(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 253
+        && name.split('.').all(|label| {
+            !label.is_empty() && label.len() <= 63 && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+/// `provider:account/*:PORT|@bundle [options]`
+pub struct Wildcard {
+    provider: Provider,
+    account: String,
+    tail: String,  // `22 max_conns=3`, `@admin`, ...
+    origin: String,
+}
+
+impl Wildcard {
+    /// None if `line` is not a wildcard entry; a malformed one is fatal
+    /// (dropping it would silently delete its rules)
+    pub fn parse(line: &str, origin: &str) -> Option<Self> {
+        let (target, options) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let (source, service) = target.split_once("/*")?;
+        let service = service.strip_prefix(':').unwrap_or_default();
+        let parsed = source.split_once(':').and_then(|(provider, account)| {
+            let provider = Provider::parse(provider)?;
+            is_valid_account_name(account).then_some((provider, account))
+        });
+        let Some((provider, account)) = parsed.filter(|_| !service.is_empty()) else {
+            exit_err(&format!("{}: invalid wildcard '{}' (use dynu:ACCOUNT/*:PORT)", origin, target));
+        };
+        Some(Wildcard {
+            provider,
+            account: account.to_string(),
+            tail: format!("{} {}", service, options.trim()).trim_end().to_string(),
+            origin: origin.to_string(),
+        })
+    }
+
+    fn key(&self) -> String {
+        format!("{}:{}", self.provider.name(), self.account)
+    }
+}
+
+/// Fatal if a wildcard names an unknown account or the wrong provider
+pub fn check(config: &Config) {
+    for wildcard in &config.wildcards {
+        match config.provider_accounts.iter().find(|a| a.name == wildcard.account) {
+            Some(account) if account.provider == wildcard.provider => {}
+            Some(account) => exit_err(&format!(
+                "{}: account '{}' is a {} account, not {}",
+                wildcard.origin,
+                account.name,
+                account.provider.name(),
+                wildcard.provider.name()
+            )),
+            None => exit_err(&format!("{}: unknown provider_account '{}'", wildcard.origin, wildcard.account)),
+        }
+    }
+}
+
+/// `provider:account host,host,...` per line
+fn load_known() -> HashMap<String, Vec<String>> {
+    fs::read_to_string(&profile::current().wildcards_path)
+        .unwrap_or_default()
+        .lines()
+        .take(MAX_ENTRIES)
+        .filter_map(|line| {
+            let (key, hosts) = line.split_once(' ')?;
+            let hosts = hosts.split(',').filter(|h| is_valid_hostname(h)).take(MAX_ENTRIES).map(String::from).collect();
+            Some((key.to_string(), hosts))
+        })
+        .collect()
+}
+
+fn save_known(known: &HashMap<String, Vec<String>>) {
+    let content: String = known.iter().map(|(key, hosts)| format!("{} {}\n", key, hosts.join(","))).collect();
+    let path = &profile::current().wildcards_path;
+    let temp_path = format!("{}.tmp", path);
+    if let Ok(mut file) = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&temp_path) {
+        if file.write_all(content.as_bytes()).is_ok() {
+            let _ = fs::rename(&temp_path, path);
+        }
+    }
+}
+
+/// Lists every wildcard's account and adds one entry per hostname
+pub fn expand(config: &mut Config) {
+    if config.wildcards.is_empty() {
+        return;
+    }
+    let previous = load_known();
+    let mut known: HashMap<String, Vec<String>> = HashMap::new();
+    let mut lines: Vec<(String, String)> = Vec::new();
+
+    for wildcard in &config.wildcards {
+        let key = wildcard.key();
+        if !known.contains_key(&key) {
+            let Some(account) = config.provider_accounts.iter().find(|a| a.name == wildcard.account) else {
+                continue;
+            };
+            let hostnames = match account.hostnames() {
+                Some(hostnames) => {
+                    println!("[ddnsfw] {}/*: {} hostnames", key, hostnames.len());
+                    hostnames
+                }
+                None => {
+                    let hostnames = previous.get(&key).cloned().unwrap_or_default();
+                    eprintln!(
+                        "[ddnsfw] WARN: {} API unreachable, using the last {} known hostnames of {}",
+                        account.provider.name(),
+                        hostnames.len(),
+                        key
+                    );
+                    hostnames
+                }
+            };
+            known.insert(key.clone(), hostnames);
+        }
+        for host in &known[&key] {
+            lines.push((format!("{}:{}", host, wildcard.tail), wildcard.origin.clone()));
+        }
+    }
+
+    save_known(&known);
+    for (line, origin) in lines {
+        push_entries(config, &line, &origin);
+    }
+}
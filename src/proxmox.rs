@@ -0,0 +1,176 @@
+//! Proxmox VE cluster firewall backend.
+//!
+//! Writes the allowed IPs into cluster-wide ipsets named `<prefix>-<port>`
+//! through pvesh. The cluster filesystem replicates cluster.fw, so every
+//! node receives the update from a single ddnsfw instance.
+//!
+//! Reference the sets from PVE firewall rules, e.g.:
+//!   IN ACCEPT -source +ddnsfw-22 -p tcp -dport 22
+//!
+//! Only members tagged with the managed comment are ever removed.
+
+use std::collections::HashSet;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use crate::{execs, profile, MAX_LISTING_LINES, MAX_RULES};
+
+const PVESH_PATHS: &[&str] = &["/usr/bin/pvesh", "/usr/sbin/pvesh"];
+const IPSET_API: &str = "/cluster/firewall/ipset";
+
+pub fn find_pvesh() -> Option<&'static str> {
+    PVESH_PATHS.iter().find(|p| Path::new(p).exists()).copied()
+}
+
+/// PVE accepts `[A-Za-z][A-Za-z0-9_-]+`; the port suffix is appended later.
+pub fn is_valid_ipset_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        && name.len() <= 58
+}
+
+pub fn ipset_name(prefix: &str, port: u16) -> String {
+    format!("{}-{}", prefix, port)
+}
+
+fn ipset_port(prefix: &str, name: &str) -> Option<u16> {
+    name.strip_prefix(prefix)?.strip_prefix('-')?.parse().ok()
+}
+
+// ============================================================================
+// pvesh Invocation
+// ============================================================================
+
+fn pvesh(bin: &str, args: &[&str]) -> Option<String> {
+    let output = execs::output(
+        Command::new(bin)
+            .args(args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null()),
+    )
+    .ok()?;
+
+    if output.status.success() {
+        Some(String::from_utf8_lossy(&output.stdout).into_owned())
+    } else {
+        None
+    }
+}
+
+fn pvesh_run(bin: &str, args: &[&str]) -> bool {
+    execs::status(
+        Command::new(bin)
+            .args(args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null()),
+    )
+    .map(|s| s.success())
+    .unwrap_or(false)
+}
+
+/// Extracts a string field from a flat JSON object as printed by pvesh.
+pub fn json_field(object: &str, key: &str) -> Option<String> {
+    let pattern = format!("\"{}\"", key);
+    let rest = &object[object.find(&pattern)? + pattern.len()..];
+    let rest = rest.trim_start().strip_prefix(':')?.trim_start();
+    let rest = rest.strip_prefix('"')?;
+    let mut value = String::new();
+    let mut escaped = false;
+    for c in rest.chars() {
+        match (escaped, c) {
+            (true, _) => {
+                value.push(c);
+                escaped = false;
+            }
+            (false, '\\') => escaped = true,
+            (false, '"') => return Some(value),
+            (false, _) => value.push(c),
+        }
+    }
+    None
+}
+
+/// pvesh lists are arrays of flat objects, so splitting on '}' isolates them.
+pub fn json_objects(json: &str) -> impl Iterator<Item = &str> {
+    json.split('}').take(MAX_LISTING_LINES)
+}
+
+// ============================================================================
+// Rule Operations
+// ============================================================================
+
+fn ipset_members(bin: &str, name: &str) -> Option<HashSet<Ipv4Addr>> {
+    let path = format!("{}/{}", IPSET_API, name);
+    let output = pvesh(bin, &["get", &path, "--output-format", "json"])?;
+
+    let mut members = HashSet::new();
+    for object in json_objects(&output) {
+        if json_field(object, "comment").as_deref() != Some(profile::current().comment()) {
+            continue;
+        }
+        let Some(cidr) = json_field(object, "cidr") else {
+            continue;
+        };
+        if let Ok(ip) = cidr.trim_end_matches("/32").parse() {
+            members.insert(ip);
+        }
+    }
+    Some(members)
+}
+
+pub fn get_existing_rules(bin: &str, prefix: &str) -> HashSet<(Ipv4Addr, u16)> {
+    let mut rules = HashSet::new();
+
+    let Some(output) = pvesh(bin, &["get", IPSET_API, "--output-format", "json"]) else {
+        return rules;
+    };
+
+    for object in json_objects(&output) {
+        let Some(port) = json_field(object, "name").and_then(|n| ipset_port(prefix, &n)) else {
+            continue;
+        };
+        for ip in ipset_members(bin, &ipset_name(prefix, port)).unwrap_or_default() {
+            if rules.len() >= MAX_RULES {
+                eprintln!("[ddnsfw] WARN: Too many ipset members, truncating");
+                return rules;
+            }
+            rules.insert((ip, port));
+        }
+    }
+
+    rules
+}
+
+pub fn rule_exists(bin: &str, prefix: &str, ip: Ipv4Addr, port: u16) -> bool {
+    ipset_members(bin, &ipset_name(prefix, port))
+        .map(|members| members.contains(&ip))
+        .unwrap_or(false)
+}
+
+pub fn add_rule(bin: &str, prefix: &str, ip: Ipv4Addr, port: u16) -> bool {
+    let name = ipset_name(prefix, port);
+
+    // Create the set on first use; failure here surfaces on the member add
+    if ipset_members(bin, &name).is_none() {
+        pvesh_run(
+            bin,
+            &["create", IPSET_API, "--name", &name, "--comment", profile::current().comment()],
+        );
+    }
+
+    pvesh_run(
+        bin,
+        &[
+            "create", &format!("{}/{}", IPSET_API, name),
+            "--cidr", &ip.to_string(),
+            "--comment", profile::current().comment(),
+        ],
+    )
+}
+
+pub fn delete_rule(bin: &str, prefix: &str, ip: Ipv4Addr, port: u16) -> bool {
+    let path = format!("{}/{}/{}", IPSET_API, ipset_name(prefix, port), ip);
+    pvesh_run(bin, &["delete", &path])
+}
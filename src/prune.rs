@@ -0,0 +1,131 @@
+//! Pruning of cache records that no longer describe anything.
+//!
+//!   state_retention = 7   # days a queued retry may sit past its due time
+//!
+//! Rules themselves are re-listed from the firewall on every sync, but the
+//! records kept next to them (owners, install times, entry metadata, queued
+//! retries) are only cleaned up as a side effect of a sync that gets far
+//! enough. The pruning pass drops:
+//!
+//! - owners, install times and grace starts of rules that are no longer in
+//!   the firewall
+//! - owner/description metadata and address histories no installed rule
+//!   refers to
+//! - retries that are moot (deleting a rule that is gone, adding one that
+//!   is there) or that have been due for longer than the retention, so a
+//!   parked operation is eventually attempted afresh
+//!
+//! It runs at the end of every sync and on demand via `ddnsfw state prune`.
+
+use std::collections::HashSet;
+
+use crate::retry::Op;
+use crate::rule::Rule;
+use crate::{acquire_lock, exit_err, managed_scopes, now_secs, open_backend, parse_config, Cache};
+
+pub const DEFAULT_RETENTION_DAYS: u64 = 7;
+pub const MAX_RETENTION_DAYS: u64 = 365;
+
+/// Drops stale records from `cache` (not saved); returns what was dropped.
+/// `live` is the firewall listing, or None when it cannot be trusted, in
+/// which case only time-based pruning happens.
+pub fn prune(cache: &mut Cache, live: Option<&HashSet<Rule>>, now: u64, retention_days: u64) -> Vec<String> {
+    let mut pruned = Vec::new();
+
+    if let Some(live) = live {
+        cache.owners.retain(|rule, _| {
+            let keep = live.contains(rule);
+            if !keep {
+                pruned.push(format!("owners of {}", rule));
+            }
+            keep
+        });
+        cache.since.retain(|rule, _| {
+            let keep = live.contains(rule);
+            if !keep {
+                pruned.push(format!("install time of {}", rule));
+            }
+            keep
+        });
+        cache.retiring.retain(|rule, _| live.contains(rule));
+        cache.retries.retain(|r| {
+            let moot = match r.op {
+                Op::Add => live.contains(&r.rule),
+                Op::Delete => !live.contains(&r.rule),
+            };
+            if moot {
+                pruned.push(format!("moot retry of {}", r.rule));
+            }
+            !moot
+        });
+    }
+
+    let owners = &cache.owners;
+    cache.meta.retain(|label, _| {
+        let keep = owners.values().any(|o| o.contains(label));
+        if !keep {
+            pruned.push(format!("metadata of {}", label));
+        }
+        keep
+    });
+    cache.history.retain(|label, _| {
+        let keep = owners.values().any(|o| o.contains(label));
+        if !keep {
+            pruned.push(format!("address history of {}", label));
+        }
+        keep
+    });
+
+    let retention = retention_days.saturating_mul(86400);
+    cache.retries.retain(|r| {
+        let keep = r.next_at.saturating_add(retention) > now;
+        if !keep {
+            pruned.push(format!("retry of {} ({} attempts, past retention)", r.rule, r.attempts));
+        }
+        keep
+    });
+
+    pruned.sort();
+    pruned
+}
+
+/// `ddnsfw state prune [--dry-run]`
+pub fn command(args: &[String]) {
+    let dry_run = match args {
+        [cmd] if cmd == "prune" => false,
+        [cmd, flag] if cmd == "prune" && flag == "--dry-run" => true,
+        _ => exit_err("Usage: ddnsfw state prune [--dry-run]"),
+    };
+
+    let config = parse_config();
+    let Some(backend) = open_backend(&config) else {
+        exit_err(&format!("{} not found", config.backend.tool()));
+    };
+    let Some(_lock) = acquire_lock() else {
+        exit_err("Could not acquire lock");
+    };
+
+    let mut cache = Cache::load();
+    if !cache.state.is_idle() {
+        exit_err("An operation was interrupted; run a sync (ddnsfw sync) first");
+    }
+
+    // An empty listing next to cached rules is more likely a listing failure
+    let live = backend.existing_rules(&managed_scopes(&config.entries, &cache));
+    let trusted = !live.is_empty() || cache.rules.is_empty();
+    if !trusted {
+        eprintln!("[ddnsfw] WARN: Firewall listing is empty but the cache holds rules, keeping rule records");
+    }
+
+    let pruned = prune(&mut cache, trusted.then_some(&live), now_secs(), config.state_retention);
+    for item in &pruned {
+        println!("[ddnsfw] {} {}", if dry_run { "Would prune" } else { "Pruned" }, item);
+    }
+    if !dry_run {
+        if trusted {
+            cache.rules = live;
+        }
+        cache.save();
+    }
+    println!("[ddnsfw] {} records {}", pruned.len(), if dry_run { "to prune" } else { "pruned" });
+}
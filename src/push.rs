@@ -0,0 +1,290 @@
+//! Signed push: the client announces its new IP instead of waiting for DNS.
+//!
+//!   push_listen = 0.0.0.0:8853     # UDP, `ddnsfw daemon` only
+//!   push_key = <shared secret>     # at least 16 characters
+//!   push_max_age = 900             # how long a push outranks the resolvers
+//!
+//! One datagram per push:
+//!
+//!   ddnsfw-push <hostname> <ip> <unix-timestamp> <hex hmac-sha256(push_key, "push|<hostname>|<ip>|<timestamp>")>
+//!
+//! `ddnsfw push SERVER:PORT HOSTNAME IP` sends one (key in DDNSFW_PUSH_KEY).
+//! The daemon accepts a push for a configured hostname when the signature
+//! matches, the timestamp is recent, and it is newer than the hostname's
+//! last accepted push (replays and reordered datagrams are dropped). The
+//! address is recorded in service.push, cross-checked against the entry's
+//! resolvers (logged, not required: DNS lags behind a push by design), and
+//! a sync runs at once. For push_max_age after the push, the pushed address
+//! is the entry's answer ahead of hosts.override and DNS; then the
+//! resolvers take over again, so a client keeps it fresh by re-pushing
+//! periodically. The "push|" prefix keeps beacon signatures from being
+//! replayed as pushes when both share a key. The key and the entries are
+//! those the daemon last read (see daemon.rs), so a key rotated with SIGHUP
+//! replaces the old one at once; a push arriving before that is dropped.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::os::unix::fs::OpenOptionsExt;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::crypto::{ct_eq, hex, hmac_sha256};
+use crate::events::{self, Value};
+use crate::resolver::Answer;
+use crate::{daemon, exit_err, is_usable_ipv4, now_secs, profile, sys, Config, DNS_TIMEOUT_SECS, MAX_ENTRIES};
+
+const MAGIC: &str = "ddnsfw-push";
+const MAX_DATAGRAM_BYTES: usize = 512;
+/// Oldest timestamp accepted (the datagram's age), and how far the
+/// sender's clock may run ahead
+const MAX_AGE_SECS: u64 = 300;
+const MAX_AHEAD_SECS: u64 = 60;
+pub const MIN_KEY_LEN: usize = 16;
+/// Copies sent by `ddnsfw push` (UDP may drop one; duplicates are ignored)
+const SEND_COPIES: usize = 3;
+const SEND_GAP: Duration = Duration::from_millis(200);
+/// Rejected requests are logged at most this often
+const REJECT_LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+fn message(hostname: &str, ip: &str, timestamp: &str) -> String {
+    format!("push|{}|{}|{}", hostname, ip, timestamp)
+}
+
+/// A verified push
+struct Push {
+    hostname: String,
+    ip: Ipv4Addr,
+    timestamp: u64,
+}
+
+fn verify(datagram: &[u8], key: &[u8], now: u64) -> Result<Push, &'static str> {
+    let text = std::str::from_utf8(datagram).map_err(|_| "malformed")?;
+    let fields: Vec<&str> = text.split_whitespace().collect();
+    let [MAGIC, hostname, ip_str, ts_str, mac] = fields[..] else {
+        return Err("malformed");
+    };
+    let expected = hex(&hmac_sha256(key, message(hostname, ip_str, ts_str).as_bytes()));
+    if !ct_eq(expected.as_bytes(), mac.to_ascii_lowercase().as_bytes()) {
+        return Err("bad signature");
+    }
+    let ip: Ipv4Addr = ip_str.parse().map_err(|_| "malformed")?;
+    let timestamp: u64 = ts_str.parse().map_err(|_| "malformed")?;
+    if !is_usable_ipv4(&ip) {
+        return Err("unusable address");
+    }
+    if timestamp > now + MAX_AHEAD_SECS || now.saturating_sub(timestamp) > MAX_AGE_SECS {
+        return Err("stale");
+    }
+    Ok(Push { hostname: hostname.to_string(), ip, timestamp })
+}
+
+/// Verifies a datagram against the push_key of `config`
+fn authenticate(datagram: &[u8], config: &Config, now: u64) -> Result<Push, &'static str> {
+    let Some(key) = config.push_key.as_deref() else {
+        return Err("push_key removed");
+    };
+    verify(datagram, key.as_bytes(), now)
+}
+
+// ============================================================================
+// Store (service.push)
+// ============================================================================
+
+/// Last accepted push per hostname: `hostname ip timestamp` lines
+pub struct Store {
+    pushes: HashMap<String, (Ipv4Addr, u64)>,
+}
+
+impl Store {
+    pub fn load() -> Self {
+        let content = fs::read_to_string(&profile::current().push_path).unwrap_or_default();
+        let pushes = content
+            .lines()
+            .take(MAX_ENTRIES)
+            .filter_map(|line| match line.split_whitespace().collect::<Vec<_>>()[..] {
+                [hostname, ip, timestamp] => Some((hostname.to_string(), (ip.parse().ok()?, timestamp.parse().ok()?))),
+                _ => None,
+            })
+            .collect();
+        Store { pushes }
+    }
+
+    fn save(&self) {
+        let mut content = String::new();
+        for (hostname, (ip, timestamp)) in &self.pushes {
+            content.push_str(&format!("{} {} {}\n", hostname, ip, timestamp));
+        }
+        let path = &profile::current().push_path;
+        let temp_path = format!("{}.tmp", path);
+        if let Ok(mut file) = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&temp_path) {
+            if file.write_all(content.as_bytes()).is_ok() {
+                let _ = fs::rename(&temp_path, path);
+            }
+        }
+    }
+
+    /// The pushed address of `hostname`, while younger than `max_age`
+    pub fn answer(&self, hostname: &str, max_age: u64, now: u64) -> Option<Answer> {
+        let (ip, timestamp) = self.pushes.get(hostname)?;
+        (now.saturating_sub(*timestamp) <= max_age).then(|| Answer { addrs: vec![*ip], via: String::from("push"), fallback: false })
+    }
+}
+
+// ============================================================================
+// Listener (daemon)
+// ============================================================================
+
+/// Rate-limited log of rejected requests (anyone can send them)
+pub struct Rejections {
+    what: &'static str,
+    last_log: Option<Instant>,
+    suppressed: u64,
+}
+
+impl Rejections {
+    /// `what` names the request in the log ("Push", "Webhook")
+    pub fn new(what: &'static str) -> Self {
+        Rejections { what, last_log: None, suppressed: 0 }
+    }
+
+    pub fn note(&mut self, reason: &str, from: SocketAddr) {
+        if self.last_log.is_some_and(|t| t.elapsed() < REJECT_LOG_INTERVAL) {
+            self.suppressed += 1;
+            return;
+        }
+        let more = if self.suppressed > 0 { format!(" ({} more since the last report)", self.suppressed) } else { String::new() };
+        eprintln!("[ddnsfw] WARN: {} from {} rejected: {}{}", self.what, from, reason, more);
+        self.last_log = Some(Instant::now());
+        self.suppressed = 0;
+    }
+}
+
+/// Records an accepted push, cross-checks DNS and asks for a sync; Err with
+/// the reason otherwise
+fn accept(push: Push, config: &Config, from: SocketAddr) -> Result<(), &'static str> {
+    let Some(entry) = config.entries.iter().find(|e| e.hostname == push.hostname) else {
+        return Err("hostname not configured");
+    };
+    let mut store = Store::load();
+    match store.pushes.get(&push.hostname) {
+        // A duplicate copy of the last push
+        Some(&(ip, timestamp)) if timestamp == push.timestamp && ip == push.ip => return Ok(()),
+        Some(&(_, timestamp)) if timestamp >= push.timestamp => return Err("replayed or out of order"),
+        _ => {}
+    }
+    store.pushes.retain(|hostname, _| config.entries.iter().any(|e| &e.hostname == hostname));
+    store.pushes.insert(push.hostname.clone(), (push.ip, push.timestamp));
+    store.save();
+
+    let answer = entry.resolver_chain(config).resolve_network(&push.hostname, Duration::from_secs(DNS_TIMEOUT_SECS));
+    let dns = match answer {
+        Some(answer) if answer.addrs.contains(&push.ip) => String::from("agrees"),
+        Some(answer) => format!("still reports {}", answer.addrs.iter().map(Ipv4Addr::to_string).collect::<Vec<_>>().join(", ")),
+        None => String::from("lookup failed"),
+    };
+    println!("[ddnsfw] Push from {}: {} -> {} (DNS {}), syncing now", from, push.hostname, push.ip, dns);
+    let ip = push.ip.to_string();
+    events::emit("push", &[("host", Value::Str(&push.hostname)), ("ip", Value::Str(&ip)), ("dns", Value::Str(&dns))]);
+    sys::request_sync();
+    Ok(())
+}
+
+/// Starts the push listener when `push_listen` is set. Call after the
+/// daemon signals are blocked.
+pub fn listen(config: &Config) {
+    let Some(addr) = config.push_listen else {
+        return;
+    };
+    if config.push_key.is_none() {
+        exit_err("push_listen requires push_key");
+    }
+    let socket = UdpSocket::bind(addr).unwrap_or_else(|e| exit_err(&format!("Cannot listen for pushes on {}: {}", addr, e)));
+    println!("[ddnsfw] Listening for signed pushes on udp {}", addr);
+    thread::spawn(move || {
+        let mut rejections = Rejections::new("Push");
+        let mut buf = [0u8; MAX_DATAGRAM_BYTES];
+        loop {
+            let Ok((len, from)) = socket.recv_from(&mut buf) else {
+                continue;
+            };
+            // The config the daemon last read, for the key too: never
+            // re-read here, a broken file would end the daemon
+            let accepted = daemon::current_config().ok_or("daemon starting").and_then(|config| {
+                let push = authenticate(&buf[..len], &config, now_secs())?;
+                accept(push, &config, from)
+            });
+            if let Err(reason) = accepted {
+                rejections.note(reason, from);
+            }
+        }
+    });
+}
+
+// ============================================================================
+// Sender
+// ============================================================================
+
+/// `ddnsfw push SERVER:PORT HOSTNAME IP` (key in DDNSFW_PUSH_KEY)
+pub fn send(args: &[String]) {
+    let [server, hostname, ip] = args else {
+        exit_err("Usage: ddnsfw push SERVER:PORT HOSTNAME IP (key in DDNSFW_PUSH_KEY)");
+    };
+    let Ok(key) = env::var("DDNSFW_PUSH_KEY") else {
+        exit_err("DDNSFW_PUSH_KEY is not set");
+    };
+    if key.len() < MIN_KEY_LEN {
+        exit_err(&format!("DDNSFW_PUSH_KEY is too short (at least {} characters)", MIN_KEY_LEN));
+    }
+    if hostname.is_empty() || hostname.len() >= 256 || hostname.contains(char::is_whitespace) {
+        exit_err(&format!("Invalid hostname '{}'", hostname));
+    }
+    if ip.parse::<Ipv4Addr>().is_err() {
+        exit_err(&format!("Invalid IP '{}'", ip));
+    }
+    let timestamp = now_secs().to_string();
+    let mac = hex(&hmac_sha256(key.as_bytes(), message(hostname, ip, &timestamp).as_bytes()));
+    let datagram = format!("{} {} {} {} {}", MAGIC, hostname, ip, timestamp, mac);
+
+    let socket = UdpSocket::bind("0.0.0.0:0").unwrap_or_else(|e| exit_err(&format!("Cannot open a UDP socket: {}", e)));
+    for copy in 0..SEND_COPIES {
+        if copy > 0 {
+            thread::sleep(SEND_GAP);
+        }
+        if let Err(e) = socket.send_to(datagram.as_bytes(), server.as_str()) {
+            exit_err(&format!("Cannot send to {}: {}", server, e));
+        }
+    }
+    println!("[ddnsfw] Pushed {} -> {} to {}", hostname, ip, server);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn datagram(key: &str, hostname: &str, ip: &str, timestamp: u64) -> String {
+        let mac = hex(&hmac_sha256(key.as_bytes(), message(hostname, ip, &timestamp.to_string()).as_bytes()));
+        format!("{} {} {} {} {}", MAGIC, hostname, ip, timestamp, mac)
+    }
+
+    #[test]
+    fn a_rotated_key_replaces_the_old_one() {
+        let (old, new) = ("old-shared-secret-0123", "new-shared-secret-4567");
+        let now = 1_700_000_000;
+        let mut config = Config::new();
+        config.push_key = Some(old.to_string());
+        let pushed = datagram(old, "home.example.net", "203.0.113.7", now);
+        assert!(authenticate(pushed.as_bytes(), &config, now).is_ok_and(|push| push.ip == Ipv4Addr::new(203, 0, 113, 7)));
+
+        // As after a SIGHUP with the new key in the config
+        config.push_key = Some(new.to_string());
+        assert_eq!(authenticate(pushed.as_bytes(), &config, now).err(), Some("bad signature"));
+        let pushed = datagram(new, "home.example.net", "203.0.113.7", now + 1);
+        assert!(authenticate(pushed.as_bytes(), &config, now + 1).is_ok());
+
+        config.push_key = None;
+        assert_eq!(authenticate(pushed.as_bytes(), &config, now + 1).err(), Some("push_key removed"));
+    }
+}
@@ -0,0 +1,287 @@
+//! `ddnsfw report [--period 30d] [--format text|json|csv]`: access review.
+//!
+//! One row per configured entry, for periodic security reviews of who can
+//! reach what:
+//!
+//! - owner (entry metadata)
+//! - current IP(s) allowed for it
+//! - days since the current rule was installed, i.e. since the last change
+//! - IP changes within the period (add events that replaced an old IP)
+//! - packets matched by its current rules (iptables counters, since the
+//!   rule was installed or the counters were last zeroed)
+//! - failed resolutions within the period
+//!
+//! Changes and failures come from the event stream (`events.jsonl` and its
+//! rotated predecessor); when the stream does not reach back to the start of
+//! the period the report says so instead of undercounting silently.
+
+use std::collections::HashMap;
+use std::fs;
+use std::net::Ipv4Addr;
+
+use crate::events::json_escape;
+use crate::proxmox::json_field;
+use crate::rule::{Proto, Table};
+use crate::{
+    exit_err, format_utc, has_comment, iptables, managed_scopes, now_secs, open_backend, parse_config, parse_rule_line,
+    profile, provider, srv, Backend, Cache, MAX_LISTING_LINES, MAX_LOOP_ITERATIONS,
+};
+
+pub const DEFAULT_PERIOD_SECS: u64 = 30 * 86400;
+const MAX_PERIOD_SECS: u64 = 3650 * 86400;
+const USAGE: &str = "Usage: ddnsfw report [--period <N>d|<N>h|<N>w] [--format text|json|csv]";
+
+enum Format {
+    Text,
+    Json,
+    Csv,
+}
+
+/// `30d`, `12h`, `2w`; a bare number is days
+pub fn parse_period(s: &str) -> Option<u64> {
+    let (number, unit) = match s.char_indices().last()? {
+        (i, 'h') => (&s[..i], 3600),
+        (i, 'd') => (&s[..i], 86400),
+        (i, 'w') => (&s[..i], 7 * 86400),
+        _ => (s, 86400),
+    };
+    let secs = number.parse::<u64>().ok()?.checked_mul(unit)?;
+    (secs > 0 && secs <= MAX_PERIOD_SECS).then_some(secs)
+}
+
+/// A numeric field of one of our own event lines
+pub fn json_num(object: &str, key: &str) -> Option<u64> {
+    let pattern = format!("\"{}\":", key);
+    let rest = &object[object.find(&pattern)? + pattern.len()..];
+    let end = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+    rest[..end].parse().ok()
+}
+
+struct Row {
+    label: String,
+    owner: String,
+    current: Vec<Ipv4Addr>,
+    days_since_change: Option<u64>,
+    changes: u64,
+    hits: Option<u64>,
+    failures: u64,
+}
+
+/// Packet counters of the managed rules, keyed like the rules they belong to
+fn rule_counters(bin: &str, scopes: &[(Table, String)]) -> HashMap<(Table, String, Ipv4Addr, u16, Proto), u64> {
+    let mut counters = HashMap::new();
+    let comment = profile::current().comment();
+    for (table, chain) in scopes.iter().take(MAX_LOOP_ITERATIONS) {
+        // -v adds `-c <packets> <bytes>` to every rule
+        let Some(output) = iptables(bin, &["-t", table.name(), "-S", chain, "-v"]) else {
+            continue;
+        };
+        for line in output.lines().take(MAX_LISTING_LINES).filter(|l| has_comment(l, comment)) {
+            let Some(parsed) = parse_rule_line(line) else {
+                continue;
+            };
+            if parsed.connlimit.is_some() {
+                continue;
+            }
+            let mut tokens = line.split_whitespace();
+            let Some(packets) = tokens.by_ref().find(|t| *t == "-c").and_then(|_| tokens.next()?.parse::<u64>().ok()) else {
+                continue;
+            };
+            *counters.entry((*table, chain.clone(), parsed.ip, parsed.port, parsed.proto)).or_insert(0) += packets;
+        }
+    }
+    counters
+}
+
+pub fn command(args: &[String]) {
+    let mut period = DEFAULT_PERIOD_SECS;
+    let mut period_label = String::from("30d");
+    let mut format = Format::Text;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--period" => {
+                let value = iter.next().unwrap_or_else(|| exit_err(USAGE));
+                period = parse_period(value).unwrap_or_else(|| exit_err(&format!("Invalid period '{}'", value)));
+                period_label = value.clone();
+            }
+            "--format" => {
+                format = match iter.next().map(String::as_str) {
+                    Some("text") => Format::Text,
+                    Some("json") => Format::Json,
+                    Some("csv") => Format::Csv,
+                    _ => exit_err(USAGE),
+                }
+            }
+            _ => exit_err(USAGE),
+        }
+    }
+
+    let mut config = parse_config();
+    provider::expand(&mut config);
+    srv::expand(&mut config);
+    let Some(backend) = open_backend(&config) else {
+        exit_err(&format!("{} not found", config.backend.tool()));
+    };
+    let cache = Cache::load();
+    let scopes = managed_scopes(&config.entries, &cache);
+    let existing = backend.existing_rules(&scopes);
+    let counters = match &backend {
+        Backend::Iptables(bin) => {
+            let mut scopes: Vec<(Table, String)> = scopes.into_iter().collect();
+            scopes.sort_by_key(|(table, chain)| (table.name(), chain.clone()));
+            Some(rule_counters(bin, &scopes))
+        }
+        _ => None,
+    };
+
+    let now = now_secs();
+    let start = now.saturating_sub(period);
+
+    // Changes and failures per host:port label
+    let mut changes: HashMap<String, u64> = HashMap::new();
+    let mut failures: HashMap<String, u64> = HashMap::new();
+    let mut oldest: Option<u64> = None;
+    let path = format!("{}/events.jsonl", profile::current().state_dir);
+    for file in [format!("{}.1", path), path] {
+        let Ok(content) = fs::read_to_string(&file) else {
+            continue;
+        };
+        for line in content.lines() {
+            let Some(ts) = json_num(line, "ts") else {
+                continue;
+            };
+            oldest = Some(oldest.map_or(ts, |o| o.min(ts)));
+            if ts < start {
+                continue;
+            }
+            let port = json_num(line, "port").unwrap_or(0);
+            match json_field(line, "event").as_deref() {
+                Some("add") if json_field(line, "old_ip").is_some() => {
+                    for host in json_field(line, "hostname").unwrap_or_default().split(',') {
+                        *changes.entry(format!("{}:{}", host, port)).or_insert(0) += 1;
+                    }
+                }
+                Some("resolve_failed") => {
+                    let host = json_field(line, "host").unwrap_or_default();
+                    *failures.entry(format!("{}:{}", host, port)).or_insert(0) += 1;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let mut rows = Vec::new();
+    let mut seen = Vec::new();
+    for entry in &config.entries {
+        let label = entry.label();
+        if seen.contains(&label) {
+            continue;
+        }
+        seen.push(label.clone());
+
+        let key = entry.key();
+        let mut owned: Vec<_> = existing.iter().filter(|r| cache.owners.get(*r).is_some_and(|o| o.contains(&key))).collect();
+        owned.sort_by_key(|rule| rule.to_string());
+        let mut current: Vec<Ipv4Addr> = owned.iter().map(|r| r.ip).collect();
+        current.dedup();
+        let installed = owned.iter().filter_map(|r| cache.since.get(*r)).max();
+        let hits = counters.as_ref().and_then(|counters| {
+            owned
+                .iter()
+                .map(|r| counters.get(&(r.table, r.chain.clone(), r.ip, r.port, r.proto)))
+                .sum::<Option<u64>>()
+        });
+        rows.push(Row {
+            owner: entry.meta.owner.clone().unwrap_or_default(),
+            current,
+            days_since_change: installed.map(|&ts| now.saturating_sub(ts) / 86400),
+            changes: changes.get(&label).copied().unwrap_or(0),
+            hits,
+            failures: failures.get(&label).copied().unwrap_or(0),
+            label,
+        });
+    }
+
+    // Events rotate by size: say when they do not cover the whole period
+    let truncated = oldest.filter(|&ts| ts > start);
+    let ips = |row: &Row| row.current.iter().map(Ipv4Addr::to_string).collect::<Vec<_>>().join(" ");
+    let opt = |value: Option<u64>| value.map(|v| v.to_string());
+
+    match format {
+        Format::Text => {
+            println!("[ddnsfw] Access report, last {} (until {} UTC)", period_label, format_utc(now));
+            if let Some(ts) = truncated {
+                println!("[ddnsfw] Note: events only reach back to {} UTC; counts cover less than the period", format_utc(ts));
+            }
+            println!(
+                "{:<32} {:<16} {:<16} {:>6} {:>8} {:>12} {:>9}",
+                "ENTRY", "OWNER", "CURRENT IP", "DAYS", "CHANGES", "HITS", "FAILURES"
+            );
+            for row in &rows {
+                let current = if row.current.is_empty() { String::from("-") } else { ips(row) };
+                println!(
+                    "{:<32} {:<16} {:<16} {:>6} {:>8} {:>12} {:>9}",
+                    row.label,
+                    if row.owner.is_empty() { "-" } else { &row.owner },
+                    current,
+                    opt(row.days_since_change).unwrap_or_else(|| String::from("-")),
+                    row.changes,
+                    opt(row.hits).unwrap_or_else(|| String::from("n/a")),
+                    row.failures
+                );
+            }
+        }
+        Format::Json => {
+            let rows: Vec<String> = rows
+                .iter()
+                .map(|row| {
+                    let current: Vec<String> = row.current.iter().map(|ip| format!("\"{}\"", ip)).collect();
+                    format!(
+                        "{{\"entry\":\"{}\",\"owner\":\"{}\",\"current_ips\":[{}],\"days_since_change\":{},\"changes\":{},\"hits\":{},\"failures\":{}}}",
+                        json_escape(&row.label),
+                        json_escape(&row.owner),
+                        current.join(","),
+                        opt(row.days_since_change).unwrap_or_else(|| String::from("null")),
+                        row.changes,
+                        opt(row.hits).unwrap_or_else(|| String::from("null")),
+                        row.failures
+                    )
+                })
+                .collect();
+            println!(
+                "{{\"generated\":{},\"period_secs\":{},\"events_since\":{},\"entries\":[{}]}}",
+                now,
+                period,
+                truncated.map_or(start, |ts| ts),
+                rows.join(",")
+            );
+        }
+        Format::Csv => {
+            println!("entry,owner,current_ips,days_since_change,changes,hits,failures");
+            for row in &rows {
+                println!(
+                    "{},{},{},{},{},{},{}",
+                    csv_field(&row.label),
+                    csv_field(&row.owner),
+                    ips(row),
+                    opt(row.days_since_change).unwrap_or_default(),
+                    row.changes,
+                    opt(row.hits).unwrap_or_default(),
+                    row.failures
+                );
+            }
+        }
+    }
+}
+
+/// Quotes a CSV field when it needs it; a leading `=`, `+`, `-` or `@`
+/// gets a `'` so spreadsheets show the text instead of evaluating it
+pub fn csv_field(s: &str) -> String {
+    let s = if s.starts_with(['=', '+', '-', '@']) { format!("'{}", s) } else { s.to_string() };
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s
+    }
+}
@@ -0,0 +1,451 @@
+//! Resolver chain: where an entry's addresses come from.
+//!
+//!   resolver = override, dns:1.1.1.1, native             # global chain
+//!   home.example.net:22 resolver=doh:https://cloudflare-dns.com/dns-query,getent
+//!
+//! The resolvers of a chain are asked in order until one returns a usable
+//! address; an empty answer (failure, timeout, NXDOMAIN) moves on to the
+//! next. Only when every resolver comes back empty is the entry a DNS
+//! failure (existing rules kept). Available resolvers:
+//!
+//! - `override`: the profile's hosts.override file
+//! - `native`: in-process DNS to the `nameservers` (default: those of
+//!   /etc/resolv.conf), see `dns`
+//! - `getent`: `getent ahostsv4` (NSS: /etc/hosts, nscd, systemd-resolved, ...)
+//! - `system`: the libc resolver in-process (getaddrinfo)
+//! - `resolved`: systemd-resolved over D-Bus (ResolveHostname, via `busctl`);
+//!   `resolved:nocache` bypasses its cache for the lookup, so a stale cached
+//!   answer cannot hold back a firewall update
+//! - `dns:IP`: one explicit DNS server, queried in-process like `native`
+//! - `doh:https://...`: a DNS-over-HTTPS JSON endpoint, queried with `curl`
+//! - `api:ACCOUNT`: the record as stored at the DDNS provider (`provider_account`)
+//!
+//! The default chain is `override, native`.
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, ToSocketAddrs};
+use std::process::{Command, Stdio};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+use crate::provider::Account;
+use crate::proxmox::{json_field, json_objects};
+use crate::{chaos, dns, execs, is_usable_ipv4, lookup_override, resolve_dns, DNS_TIMEOUT_SECS, MAX_LOOP_ITERATIONS};
+
+const MAX_CHAIN_LEN: usize = 8;
+const MAX_DOH_BYTES: usize = 65536;
+
+pub trait Resolver: Send + Sync {
+    fn name(&self) -> String;
+
+    /// Usable IPv4 answers, deduplicated in answer order; empty when this
+    /// resolver cannot answer
+    fn resolve(&self, hostname: &str) -> Vec<Ipv4Addr>;
+
+    /// The same, for resolvers whose lookups end by themselves within
+    /// `timeout`; None for those that need a watchdog thread
+    fn resolve_bounded(&self, _hostname: &str, _timeout: Duration) -> Option<Vec<Ipv4Addr>> {
+        None
+    }
+
+    /// Whether answers come from a local file (never rate limited)
+    fn is_local(&self) -> bool {
+        false
+    }
+}
+
+/// One element of a configured chain
+#[derive(Debug, Clone, PartialEq)]
+pub enum Spec {
+    Override,
+    Native,
+    Getent,
+    System,
+    Resolved { no_cache: bool },
+    Dns(Ipv4Addr),
+    Doh(String),
+    Api(String),
+}
+
+impl Spec {
+    fn parse(s: &str) -> Option<Self> {
+        let s = s.trim();
+        match s.split_once(':') {
+            None => match s {
+                "override" => Some(Spec::Override),
+                "native" => Some(Spec::Native),
+                "getent" => Some(Spec::Getent),
+                "system" => Some(Spec::System),
+                "resolved" => Some(Spec::Resolved { no_cache: false }),
+                _ => None,
+            },
+            Some(("resolved", "nocache")) => Some(Spec::Resolved { no_cache: true }),
+            Some(("dns", ip)) => ip.parse().ok().map(Spec::Dns),
+            Some(("doh", _)) => {
+                let url = &s[4..];
+                (url.starts_with("https://") && url.len() < 2048 && !url.chars().any(char::is_whitespace))
+                    .then(|| Spec::Doh(url.to_string()))
+            }
+            Some(("api", account)) if !account.is_empty() => Some(Spec::Api(account.to_string())),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Spec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Spec::Override => write!(f, "override"),
+            Spec::Native => write!(f, "native"),
+            Spec::Getent => write!(f, "getent"),
+            Spec::System => write!(f, "system"),
+            Spec::Resolved { no_cache: false } => write!(f, "resolved"),
+            Spec::Resolved { no_cache: true } => write!(f, "resolved:nocache"),
+            Spec::Dns(ip) => write!(f, "dns:{}", ip),
+            Spec::Doh(url) => write!(f, "doh:{}", url),
+            Spec::Api(account) => write!(f, "api:{}", account),
+        }
+    }
+}
+
+/// `override, dns:1.1.1.1, native` (non-empty, no duplicates)
+pub fn parse_chain(s: &str) -> Option<Vec<Spec>> {
+    let mut chain = Vec::new();
+    for item in s.split(',').filter(|i| !i.trim().is_empty()) {
+        let spec = Spec::parse(item)?;
+        if !chain.contains(&spec) {
+            chain.push(spec);
+        }
+    }
+    (!chain.is_empty() && chain.len() <= MAX_CHAIN_LEN).then_some(chain)
+}
+
+pub fn default_chain() -> Vec<Spec> {
+    vec![Spec::Override, Spec::Native]
+}
+
+/// Accounts named by `api:` resolvers that are not configured
+pub fn unknown_accounts<'a>(chain: &'a [Spec], accounts: &[Account]) -> Vec<&'a str> {
+    chain
+        .iter()
+        .filter_map(|spec| match spec {
+            Spec::Api(name) if !accounts.iter().any(|a| a.name() == name) => Some(name.as_str()),
+            _ => None,
+        })
+        .collect()
+}
+
+// ============================================================================
+// Implementations
+// ============================================================================
+
+struct HostsOverride;
+
+impl Resolver for HostsOverride {
+    fn name(&self) -> String {
+        String::from("override")
+    }
+    fn resolve(&self, hostname: &str) -> Vec<Ipv4Addr> {
+        lookup_override(hostname).into_iter().collect()
+    }
+    fn is_local(&self) -> bool {
+        true
+    }
+}
+
+/// In-process DNS to a list of nameservers
+struct Native {
+    name: String,
+    servers: Vec<IpAddr>,
+}
+
+impl Resolver for Native {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+    fn resolve(&self, hostname: &str) -> Vec<Ipv4Addr> {
+        self.resolve_bounded(hostname, Duration::from_secs(DNS_TIMEOUT_SECS)).unwrap_or_default()
+    }
+    fn resolve_bounded(&self, hostname: &str, timeout: Duration) -> Option<Vec<Ipv4Addr>> {
+        if let Some(chaos) = chaos::active() {
+            return Some(chaos.resolve(hostname));
+        }
+        let records = dns::lookup_a(hostname, &self.servers, timeout).unwrap_or_default();
+        Some(usable(records.into_iter().map(|(ip, _)| IpAddr::V4(ip))))
+    }
+}
+
+struct Getent;
+
+impl Resolver for Getent {
+    fn name(&self) -> String {
+        String::from("getent")
+    }
+    fn resolve(&self, hostname: &str) -> Vec<Ipv4Addr> {
+        resolve_dns(hostname)
+    }
+}
+
+struct System;
+
+impl Resolver for System {
+    fn name(&self) -> String {
+        String::from("system")
+    }
+    fn resolve(&self, hostname: &str) -> Vec<Ipv4Addr> {
+        let Ok(addrs) = (hostname, 0).to_socket_addrs() else {
+            return Vec::new();
+        };
+        usable(addrs.map(|a| a.ip()))
+    }
+}
+
+/// systemd-resolved's org.freedesktop.resolve1.Manager.ResolveHostname
+struct Resolved {
+    no_cache: bool,
+}
+
+const AF_INET: u8 = 2;
+const SD_RESOLVED_NO_CACHE: u64 = 1 << 12;
+
+impl Resolver for Resolved {
+    fn name(&self) -> String {
+        Spec::Resolved { no_cache: self.no_cache }.to_string()
+    }
+    fn resolve(&self, hostname: &str) -> Vec<Ipv4Addr> {
+        let flags = if self.no_cache { SD_RESOLVED_NO_CACHE } else { 0 };
+        let Ok(output) = execs::output(
+            Command::new("busctl")
+                .args([
+                    "call",
+                    &format!("--timeout={}", DNS_TIMEOUT_SECS),
+                    "org.freedesktop.resolve1",
+                    "/org/freedesktop/resolve1",
+                    "org.freedesktop.resolve1.Manager",
+                    "ResolveHostname",
+                    "isit",
+                    "0",
+                    hostname,
+                    &AF_INET.to_string(),
+                    &flags.to_string(),
+                ])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null()),
+        )
+        else {
+            return Vec::new();
+        };
+        if !output.status.success() {
+            return Vec::new();
+        }
+        usable(parse_resolve_reply(&String::from_utf8_lossy(&output.stdout)).into_iter().map(IpAddr::V4))
+    }
+}
+
+/// `a(iiay)st 2 3 2 4 192 0 2 1 3 2 4 192 0 2 7 "name" 1`: a count, then per
+/// address ifindex, family, byte count and the bytes
+fn parse_resolve_reply(reply: &str) -> Vec<Ipv4Addr> {
+    let mut tokens = reply.split_whitespace();
+    if tokens.next() != Some("a(iiay)st") {
+        return Vec::new();
+    }
+    let mut next = || tokens.next()?.parse::<u32>().ok();
+    let Some(count) = next() else {
+        return Vec::new();
+    };
+    let mut addrs = Vec::new();
+    for _ in 0..count.min(MAX_LOOP_ITERATIONS as u32) {
+        let (Some(_ifindex), Some(family), Some(len)) = (next(), next(), next()) else {
+            break;
+        };
+        if len > 16 {
+            break;
+        }
+        let bytes: Vec<u8> = (0..len).filter_map(|_| next()?.try_into().ok()).collect();
+        if family == u32::from(AF_INET) && bytes.len() == 4 {
+            addrs.push(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]));
+        }
+    }
+    addrs
+}
+
+struct Doh(String);
+
+impl Resolver for Doh {
+    fn name(&self) -> String {
+        format!("doh:{}", self.0)
+    }
+    fn resolve(&self, hostname: &str) -> Vec<Ipv4Addr> {
+        let separator = if self.0.contains('?') { '&' } else { '?' };
+        let url = format!("{}{}name={}&type=A", self.0, separator, hostname);
+        let Ok(output) = execs::output(
+            Command::new("curl")
+                .args([
+                    "-fsS",
+                    "--proto", "=https",
+                    "--max-time", &DNS_TIMEOUT_SECS.to_string(),
+                    "--max-filesize", &MAX_DOH_BYTES.to_string(),
+                    "-H", "accept: application/dns-json",
+                    &url,
+                ])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null()),
+        )
+        else {
+            return Vec::new();
+        };
+        if !output.status.success() {
+            return Vec::new();
+        }
+        // `"Answer":[{"name":..,"type":1,"TTL":..,"data":"192.0.2.1"}, ...]`
+        let body = String::from_utf8_lossy(&output.stdout);
+        let Some(answer) = body.split_once("\"Answer\"").map(|(_, rest)| rest) else {
+            return Vec::new();
+        };
+        usable(json_objects(answer).filter_map(|object| json_field(object, "data")?.parse().ok()))
+    }
+}
+
+struct Api(Account);
+
+impl Resolver for Api {
+    fn name(&self) -> String {
+        format!("api:{}", self.0.name())
+    }
+    fn resolve(&self, hostname: &str) -> Vec<Ipv4Addr> {
+        usable(self.0.address_of(hostname).map(IpAddr::V4))
+    }
+}
+
+fn usable(addrs: impl IntoIterator<Item = IpAddr>) -> Vec<Ipv4Addr> {
+    let mut result = Vec::new();
+    for addr in addrs.into_iter().take(MAX_LOOP_ITERATIONS) {
+        let ip = match addr {
+            IpAddr::V4(ip) => ip,
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(ip) => ip,
+                None => continue,
+            },
+        };
+        if is_usable_ipv4(&ip) && !result.contains(&ip) {
+            result.push(ip);
+        }
+    }
+    result
+}
+
+// ============================================================================
+// Chain
+// ============================================================================
+
+pub struct Chain {
+    resolvers: Vec<Arc<dyn Resolver>>,
+}
+
+/// A chain's answer and the resolver that gave it
+pub struct Answer {
+    pub addrs: Vec<Ipv4Addr>,
+    pub via: String,
+    pub fallback: bool,  // An earlier network resolver came back empty
+}
+
+impl Chain {
+    /// `api:` resolvers for unknown accounts are skipped (rejected when the
+    /// config is parsed); `native` asks `nameservers`, or when empty those
+    /// of /etc/resolv.conf
+    pub fn build(specs: &[Spec], accounts: &[Account], nameservers: &[IpAddr]) -> Self {
+        let resolvers = specs
+            .iter()
+            .filter_map(|spec| -> Option<Arc<dyn Resolver>> {
+                Some(match spec {
+                    Spec::Override => Arc::new(HostsOverride),
+                    Spec::Native => Arc::new(Native {
+                        name: String::from("native"),
+                        servers: if nameservers.is_empty() { dns::system_nameservers() } else { nameservers.to_vec() },
+                    }),
+                    Spec::Getent => Arc::new(Getent),
+                    Spec::System => Arc::new(System),
+                    Spec::Resolved { no_cache } => Arc::new(Resolved { no_cache: *no_cache }),
+                    Spec::Dns(ip) => Arc::new(Native { name: format!("dns:{}", ip), servers: vec![IpAddr::V4(*ip)] }),
+                    Spec::Doh(url) => Arc::new(Doh(url.clone())),
+                    Spec::Api(name) => Arc::new(Api(accounts.iter().find(|a| a.name() == name)?.clone())),
+                })
+            })
+            .collect();
+        Chain { resolvers }
+    }
+
+    /// Asks the local resolvers (hosts.override), in chain order
+    pub fn resolve_local(&self, hostname: &str) -> Option<Answer> {
+        self.resolvers.iter().filter(|r| r.is_local()).find_map(|resolver| {
+            let addrs = resolver.resolve(hostname);
+            (!addrs.is_empty()).then(|| Answer { addrs, via: resolver.name(), fallback: false })
+        })
+    }
+
+    /// Asks the network resolvers in order, each bounded by `timeout`
+    pub fn resolve_network(&self, hostname: &str, timeout: Duration) -> Option<Answer> {
+        let mut fallback = false;
+        for resolver in self.resolvers.iter().filter(|r| !r.is_local()) {
+            let addrs = resolver
+                .resolve_bounded(hostname, timeout)
+                .unwrap_or_else(|| with_timeout(Arc::clone(resolver), hostname, timeout));
+            if !addrs.is_empty() {
+                return Some(Answer { addrs, via: resolver.name(), fallback });
+            }
+            fallback = true;
+        }
+        None
+    }
+
+    /// Local first, then network; empty when nothing answered
+    pub fn resolve(&self, hostname: &str, timeout: Duration) -> Vec<Ipv4Addr> {
+        self.resolve_local(hostname)
+            .or_else(|| self.resolve_network(hostname, timeout))
+            .map(|answer| answer.addrs)
+            .unwrap_or_default()
+    }
+}
+
+/// Lowest TTL of the hostname's A records, asked of the system nameservers
+pub fn lookup_ttl(hostname: &str) -> Option<u64> {
+    let records = dns::lookup_a(hostname, &dns::system_nameservers(), Duration::from_secs(DNS_TIMEOUT_SECS))?;
+    records.iter().map(|&(_, ttl)| u64::from(ttl)).min()
+}
+
+/// Runs a lookup in a thread so a hung resolver cannot stall the sync
+fn with_timeout(resolver: Arc<dyn Resolver>, hostname: &str, timeout: Duration) -> Vec<Ipv4Addr> {
+    let hostname = hostname.to_string();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = tx.send(resolver.resolve(&hostname));
+    });
+    rx.recv_timeout(timeout).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolved_replies() {
+        let two = "a(iiay)st 2 3 2 4 192 0 2 1 3 2 4 192 0 2 7 \"host.example.net\" 1";
+        assert_eq!(parse_resolve_reply(two), [Ipv4Addr::new(192, 0, 2, 1), Ipv4Addr::new(192, 0, 2, 7)]);
+        // An IPv6 address is skipped, the count does not run past the end
+        let mixed = "a(iiay)st 9 3 10 16 32 1 13 184 0 0 0 0 0 0 0 0 0 0 0 1 3 2 4 192 0 2 1";
+        assert_eq!(parse_resolve_reply(mixed), [Ipv4Addr::new(192, 0, 2, 1)]);
+        // Truncated, oversized and foreign replies
+        assert!(parse_resolve_reply("a(iiay)st 1 3 2 4 192 0").is_empty());
+        assert!(parse_resolve_reply("a(iiay)st 1 3 2 200 192 0 2 1").is_empty());
+        assert!(parse_resolve_reply("a(iiay)st 1 3 2 4 192 0 2 999").is_empty());
+        assert!(parse_resolve_reply("s \"no such host\"").is_empty());
+    }
+
+    #[test]
+    fn unusable_addresses_are_dropped() {
+        let addrs = ["192.0.2.1", "0.0.0.0", "127.0.0.1", "::ffff:192.0.2.9", "2001:db8::1", "192.0.2.1"];
+        let usable = usable(addrs.iter().map(|a| a.parse::<IpAddr>().unwrap()));
+        assert_eq!(usable, [Ipv4Addr::new(192, 0, 2, 1), Ipv4Addr::new(192, 0, 2, 9)]);
+    }
+}
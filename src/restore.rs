@@ -0,0 +1,319 @@
+//! Atomic rule changes: each phase of a sync in one `iptables-restore`.
+//!
+//! With the iptables backend, phase 2 and phase 3 stage their changes
+//! instead of running one `iptables` per rule. At the end of each phase
+//! (in phase 2, of each priority class) the staged changes are rendered as
+//! one fragment and applied with `iptables-restore --noflush`, which
+//! commits each table at once:
+//!
+//!   *filter
+//!   -I INPUT 1 -s 198.51.100.7/32 -p tcp -m tcp --dport 22 -m comment --comment DDNS-ACCESS -j ACCEPT
+//!   -D INPUT -s 203.0.113.5/32 -p tcp -m tcp --dport 22 -m comment --comment DDNS-ACCESS -j ACCEPT
+//!   COMMIT
+//!
+//! `--noflush` leaves every chain alone but the ones declared; only the
+//! dedicated chain is, and is written whole (see `dedicated`). A crash
+//! leaves either none or all of a phase applied, and the adds still land
+//! before any delete. Deletes go through the ownership fence against one
+//! listing per chain, read when the first delete is staged. When the
+//! restore fails (a rule number past the end of a chain, a rule gone
+//! since the listing) the changes are applied one rule at a time. Without
+//! `iptables-restore`, or in service user mode (its helper runs iptables
+//! only), the sync works rule by rule from the start.
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use crate::rule::{Position, Rule, Table};
+use crate::{add_rule_at, dedicated, delete_rule, execs, iptables, is_own_rule, lists_own_rule, privsep, MAX_LISTING_LINES};
+
+/// A staged change
+enum Change {
+    Add(Rule, Position),
+    Delete(Rule),
+}
+
+impl Change {
+    fn rule(&self) -> &Rule {
+        match self {
+            Change::Add(rule, _) | Change::Delete(rule) => rule,
+        }
+    }
+}
+
+/// `iptables-restore` next to `bin`, when there is one
+pub fn find(bin: &str) -> Option<String> {
+    let restore = format!("{}-restore", bin);
+    Path::new(&restore).exists().then_some(restore)
+}
+
+/// One `iptables-restore --noflush` of `input`
+pub fn apply(restore: &str, input: &str) -> bool {
+    let Ok(mut child) = execs::spawn(
+        Command::new(restore)
+            .arg("--noflush")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null()),
+    ) else {
+        return false;
+    };
+    let written = child.stdin.take().is_some_and(|mut stdin| stdin.write_all(input.as_bytes()).is_ok());
+    child.wait().is_ok_and(|status| status.success()) && written
+}
+
+/// `-A` lines of a chain, in order, as `iptables -S` prints them
+fn rule_lines(bin: &str, table: Table, chain: &str) -> Option<Vec<String>> {
+    let listing = iptables(bin, &["-t", table.name(), "-S", chain])?;
+    Some(listing.lines().take(MAX_LISTING_LINES).filter(|line| line.starts_with("-A ")).map(String::from).collect())
+}
+
+/// The changes of a phase, written at once by `commit`
+pub struct Batch {
+    bin: &'static str,
+    restore: String,
+    rebuilt: Option<Vec<String>>,  // The dedicated chain as the sync changes it
+    listings: HashMap<(Table, String), Vec<String>>,
+    changes: Vec<Change>,
+}
+
+impl Batch {
+    /// None when the sync has to go rule by rule: no iptables-restore,
+    /// service user mode, or a dedicated chain that cannot be listed
+    pub fn open(bin: &'static str) -> Option<Self> {
+        if privsep::routed() {
+            return None;
+        }
+        let restore = find(bin)?;
+        let rebuilt = if dedicated::is_enabled() { Some(rule_lines(bin, Table::Filter, dedicated::name())?) } else { None };
+        Some(Batch { bin, restore, rebuilt, listings: HashMap::new(), changes: Vec::new() })
+    }
+
+    /// Whether the rule lives in the dedicated chain
+    fn in_chain(rule: &Rule) -> bool {
+        dedicated::is_enabled() && rule.table == Table::Filter && rule.chain == dedicated::name()
+    }
+
+    /// Changes staged since the last commit
+    pub fn staged(&self) -> usize {
+        self.changes.len()
+    }
+
+    pub fn add(&mut self, rule: &Rule, position: Position) {
+        if let Some(lines) = self.rebuilt.as_mut().filter(|_| Self::in_chain(rule)) {
+            // `-t filter -A CHAIN ...` as `-A CHAIN ...`
+            let line = rule.iptables_args("-A")[2..].join(" ");
+            let index = match position {
+                Position::Top => 0,
+                Position::At(n) => (n as usize - 1).min(lines.len()),
+                Position::Bottom => lines.len(),
+            };
+            lines.insert(index, line);
+        }
+        self.changes.push(Change::Add(rule.clone(), position));
+    }
+
+    /// Stages the delete of the rule as ddnsfw wrote it; false when its
+    /// chain holds no such rule (the ownership fence of a delete)
+    pub fn delete(&mut self, rule: &Rule) -> bool {
+        if let Some(lines) = self.rebuilt.as_mut().filter(|_| Self::in_chain(rule)) {
+            let Some(index) = lines.iter().position(|line| lists_own_rule(std::iter::once(line.as_str()), rule)) else {
+                return false;
+            };
+            lines.remove(index);
+        } else {
+            let bin = self.bin;
+            let lines = self
+                .listings
+                .entry((rule.table, rule.chain.clone()))
+                .or_insert_with(|| rule_lines(bin, rule.table, &rule.chain).unwrap_or_default());
+            if !lists_own_rule(lines.iter().map(String::as_str), rule) {
+                return false;
+            }
+        }
+        self.changes.push(Change::Delete(rule.clone()));
+        true
+    }
+
+    fn render(&self) -> String {
+        let mut tables: Vec<Table> = Vec::new();
+        for change in &self.changes {
+            if !tables.contains(&change.rule().table) {
+                tables.push(change.rule().table);
+            }
+        }
+        let rebuild = self.rebuilt.as_ref().filter(|_| self.changes.iter().any(|c| Self::in_chain(c.rule())));
+        let mut input = String::new();
+        for table in tables {
+            input.push_str(&format!("*{}\n", table.name()));
+            // Declared, so flushed and refilled whole
+            if let Some(lines) = rebuild.filter(|_| table == Table::Filter) {
+                input.push_str(&format!(":{} - [0:0]\n", dedicated::name()));
+                for line in lines {
+                    input.push_str(line);
+                    input.push('\n');
+                }
+            }
+            for change in self.changes.iter().filter(|c| c.rule().table == table && !Self::in_chain(c.rule())) {
+                // Without the leading `-t TABLE`
+                let args = match change {
+                    Change::Add(rule, position) => rule.add_args(*position),
+                    Change::Delete(rule) => rule.iptables_args("-D"),
+                };
+                input.push_str(&args[2..].join(" "));
+                input.push('\n');
+            }
+            input.push_str("COMMIT\n");
+        }
+        input
+    }
+
+    /// Writes the changes staged so far, or else applies them one by one.
+    /// Each staged rule with whether its change went through.
+    pub fn commit(&mut self) -> Vec<(Rule, bool)> {
+        if self.changes.is_empty() {
+            return Vec::new();
+        }
+        print!("[ddnsfw] Applying {} staged changes with {} ... ", self.changes.len(), self.restore);
+        let _ = io::stdout().flush();
+        let applied = apply(&self.restore, &self.render());
+        let changes = std::mem::take(&mut self.changes);
+        self.listings.clear();
+        if applied {
+            println!("OK");
+            return changes.into_iter().map(|(Change::Add(rule, _) | Change::Delete(rule))| (rule, true)).collect();
+        }
+        println!("FAILED (applying them one by one)");
+
+        let outcomes = changes
+            .into_iter()
+            .map(|change| match change {
+                Change::Add(rule, position) => {
+                    let added = add_rule_at(self.bin, &rule, position);
+                    (rule, added)
+                }
+                Change::Delete(rule) => {
+                    let deleted = is_own_rule(self.bin, &rule) && delete_rule(self.bin, &rule);
+                    (rule, deleted)
+                }
+            })
+            .collect();
+        // What the staging assumed may not have happened
+        if let Some(lines) = self.rebuilt.as_mut() {
+            *lines = rule_lines(self.bin, Table::Filter, dedicated::name()).unwrap_or_default();
+        }
+        outcomes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+
+    const OLD: &str = "-A INPUT -s 203.0.113.5/32 -p tcp -m tcp --dport 22 -m comment --comment DDNS-ACCESS -j ACCEPT";
+
+    /// A scratch dir with a fake iptables, logging its calls and listing
+    /// OLD, and two fake iptables-restore, keeping their input
+    fn sandbox(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ddnsfw-restore-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let d = dir.display();
+        let scripts = [
+            ("iptables", format!("#!/bin/sh\necho \"$*\" >> {d}/calls\ncase \"$*\" in *-S*) echo '{OLD}' ;; esac\n")),
+            ("restore-ok", format!("#!/bin/sh\ncat > {d}/input\n")),
+            ("restore-fail", format!("#!/bin/sh\ncat > {d}/input\nexit 1\n")),
+        ];
+        for (name, script) in scripts {
+            fs::write(dir.join(name), script).unwrap();
+            fs::set_permissions(dir.join(name), fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        dir
+    }
+
+    fn batch(dir: &Path, restore: &str) -> Batch {
+        let bin: &'static str = Box::leak(dir.join("iptables").display().to_string().into_boxed_str());
+        let restore = dir.join(restore).display().to_string();
+        Batch { bin, restore, rebuilt: None, listings: HashMap::new(), changes: Vec::new() }
+    }
+
+    /// Stages an add at the top, one at rule 3 of another chain, one at
+    /// the bottom of the raw table, and the delete of OLD
+    fn stage(batch: &mut Batch) {
+        batch.add(&Rule::allow("198.51.100.7".parse().unwrap(), 22), Position::Top);
+        batch.add(&Rule::parse("filter.ADMIN_IN:198.51.100.7:443/udp").unwrap(), Position::At(3));
+        batch.add(&Rule::parse("raw.PREROUTING:198.51.100.7:53/udp").unwrap(), Position::Bottom);
+        assert!(batch.delete(&Rule::allow("203.0.113.5".parse().unwrap(), 22)));
+        // Not in the listing: the fence refuses it
+        assert!(!batch.delete(&Rule::allow("203.0.113.6".parse().unwrap(), 22)));
+    }
+
+    const RENDERED: &str = "\
+*filter
+-I INPUT 1 -s 198.51.100.7/32 -p tcp -m tcp --dport 22 -m comment --comment DDNS-ACCESS -j ACCEPT
+-I ADMIN_IN 3 -s 198.51.100.7/32 -p udp -m udp --dport 443 -m comment --comment DDNS-ACCESS -j ACCEPT
+-D INPUT -s 203.0.113.5/32 -p tcp -m tcp --dport 22 -m comment --comment DDNS-ACCESS -j ACCEPT
+COMMIT
+*raw
+-A PREROUTING -s 198.51.100.7/32 -p udp -m udp --dport 53 -m comment --comment DDNS-ACCESS -j ACCEPT
+COMMIT
+";
+
+    #[test]
+    fn renders_one_fragment_per_table() {
+        let dir = sandbox("render");
+        let mut batch = batch(&dir, "restore-ok");
+        stage(&mut batch);
+        assert_eq!(batch.staged(), 4);
+        assert_eq!(batch.render(), RENDERED);
+        // One listing for the fence, read once per chain
+        assert_eq!(fs::read_to_string(dir.join("calls")).unwrap(), "-t filter -S INPUT\n");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn commits_in_one_restore() {
+        let dir = sandbox("commit");
+        let mut batch = batch(&dir, "restore-ok");
+        stage(&mut batch);
+        let outcomes = batch.commit();
+        assert_eq!(fs::read_to_string(dir.join("input")).unwrap(), RENDERED);
+        assert_eq!(outcomes.len(), 4);
+        assert!(outcomes.iter().all(|(_, ok)| *ok));
+        assert_eq!(batch.staged(), 0);
+        assert!(batch.listings.is_empty());
+        assert_eq!(fs::read_to_string(dir.join("calls")).unwrap(), "-t filter -S INPUT\n");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn failed_restore_falls_back_rule_by_rule() {
+        let dir = sandbox("fallback");
+        let mut batch = batch(&dir, "restore-fail");
+        stage(&mut batch);
+        let outcomes = batch.commit();
+        assert_eq!(fs::read_to_string(dir.join("input")).unwrap(), RENDERED);
+        assert_eq!(outcomes.iter().filter(|(_, ok)| *ok).count(), 4);
+        assert_eq!(batch.staged(), 0);
+        // Each change again, in order; the delete re-checks ownership first
+        let calls = fs::read_to_string(dir.join("calls")).unwrap();
+        let calls: Vec<&str> = calls.lines().collect();
+        assert_eq!(
+            calls,
+            [
+                "-t filter -S INPUT",
+                "-t filter -I INPUT 1 -s 198.51.100.7/32 -p tcp -m tcp --dport 22 -m comment --comment DDNS-ACCESS -j ACCEPT",
+                "-t filter -I ADMIN_IN 3 -s 198.51.100.7/32 -p udp -m udp --dport 443 -m comment --comment DDNS-ACCESS -j ACCEPT",
+                "-t raw -A PREROUTING -s 198.51.100.7/32 -p udp -m udp --dport 53 -m comment --comment DDNS-ACCESS -j ACCEPT",
+                "-t filter -S INPUT",
+                "-t filter -D INPUT -s 203.0.113.5/32 -p tcp -m tcp --dport 22 -m comment --comment DDNS-ACCESS -j ACCEPT",
+            ]
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
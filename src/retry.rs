@@ -0,0 +1,121 @@
+//! Durable retry queue for failed backend operations.
+//!
+//! A failed add or delete is recorded in the cache with its attempt count
+//! and the earliest time to try again. Later syncs skip the operation until
+//! then (keeping existing rules, as for any failure), with the delay
+//! doubling per attempt up to MAX_BACKOFF_SECS. After MAX_ATTEMPTS the
+//! operation is parked until the rule stops being wanted (or unwanted) or
+//! `ddnsfw retry` clears the queue, instead of hammering a broken backend.
+//!
+//! Cache form: `RETRY:+rule@attempts@next_at,-rule@attempts@next_at`.
+
+use std::fmt;
+
+use crate::rule::Rule;
+use crate::{acquire_lock, exit_err, format_utc, parse_config, sync_firewall, Cache};
+
+pub const MAX_ATTEMPTS: u32 = 8;
+const BASE_BACKOFF_SECS: u64 = 120;
+const MAX_BACKOFF_SECS: u64 = 3600;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Add,
+    Delete,
+}
+
+#[derive(Debug, Clone)]
+pub struct Retry {
+    pub op: Op,
+    pub rule: Rule,
+    pub attempts: u32,
+    pub next_at: u64,
+}
+
+/// Whether a queued operation may run now
+pub enum Gate {
+    Go,
+    Deferred(u64),
+    Exhausted(u32),
+}
+
+impl Retry {
+    pub fn parse(s: &str) -> Option<Self> {
+        let op = match s.get(..1)? {
+            "+" => Op::Add,
+            "-" => Op::Delete,
+            _ => return None,
+        };
+        let mut fields = s[1..].rsplitn(3, '@');
+        let next_at = fields.next()?.parse().ok()?;
+        let attempts = fields.next()?.parse().ok()?;
+        let rule = Rule::parse(fields.next()?)?;
+        Some(Retry { op, rule, attempts, next_at })
+    }
+}
+
+impl fmt::Display for Retry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let op = if self.op == Op::Add { '+' } else { '-' };
+        write!(f, "{}{}@{}@{}", op, self.rule, self.attempts, self.next_at)
+    }
+}
+
+fn backoff(attempts: u32) -> u64 {
+    BASE_BACKOFF_SECS
+        .saturating_mul(1 << attempts.saturating_sub(1).min(16))
+        .min(MAX_BACKOFF_SECS)
+}
+
+pub fn gate(queue: &[Retry], op: Op, rule: &Rule, now: u64) -> Gate {
+    match queue.iter().find(|r| r.op == op && r.rule == *rule) {
+        Some(r) if r.attempts >= MAX_ATTEMPTS => Gate::Exhausted(r.attempts),
+        Some(r) if now < r.next_at => Gate::Deferred(r.next_at),
+        _ => Gate::Go,
+    }
+}
+
+/// Counts a failed attempt and schedules the next one; returns the attempt count
+pub fn record_failure(queue: &mut Vec<Retry>, op: Op, rule: &Rule, now: u64) -> u32 {
+    let index = match queue.iter().position(|r| r.op == op && r.rule == *rule) {
+        Some(index) => index,
+        None => {
+            queue.push(Retry { op, rule: rule.clone(), attempts: 0, next_at: now });
+            queue.len() - 1
+        }
+    };
+    let entry = &mut queue[index];
+    entry.attempts += 1;
+    entry.next_at = now + backoff(entry.attempts);
+    entry.attempts
+}
+
+pub fn clear(queue: &mut Vec<Retry>, op: Op, rule: &Rule) {
+    queue.retain(|r| !(r.op == op && r.rule == *rule));
+}
+
+/// `ddnsfw retry`: shows the queue, clears it and syncs right away
+pub fn retry_command() {
+    // Settings first: cache_store decides where the queue lives
+    parse_config();
+    {
+        let Some(_lock) = acquire_lock() else {
+            exit_err("Could not acquire lock");
+        };
+        let mut cache = Cache::load();
+        if cache.retries.is_empty() {
+            println!("[ddnsfw] Retry queue is empty");
+        }
+        for r in &cache.retries {
+            let op = if r.op == Op::Add { "add" } else { "delete" };
+            println!(
+                "[ddnsfw] Clearing {} {} ({} attempts, next {})",
+                op, r.rule, r.attempts, format_utc(r.next_at)
+            );
+        }
+        cache.retries.clear();
+        cache.save();
+    }
+
+    sync_firewall();
+}
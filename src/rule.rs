@@ -0,0 +1,338 @@
+//! Managed rule model shared by the cache, the backends and the sync.
+//!
+//! A rule is identified by where it lives (table, chain), what it matches
+//! (source IP, destination port, protocol) and what it does (target), so the
+//! exact iptables spec for -I/-C/-D can always be rebuilt from the cache.
+//!
+//! Text form (cache and logs), with defaults omitted:
+//!   [table.chain:]ip:port[/proto][=TARGET][@credentials]
+//!   1.2.3.4:22                          filter/INPUT, tcp, ACCEPT
+//!   mangle.PREROUTING:1.2.3.4:22=MARK:0x10
+//!   raw.PREROUTING:1.2.3.4:873=NOTRACK
+//!   1.2.3.4:0/esp                       port 0: any port (ESP has none)
+//!   mangle.FORWARD:1.2.3.4:0=TCPMSS     MSS clamp of forwarded SYNs
+//!   1.2.3.4:22=JUMP:ADMIN_IN            jump into a user-maintained chain
+//!   filter.DOCKER-USER:1.2.3.4:443=RETURN
+//!   1.2.3.4:443@eu                      kubernetes, policy of [credentials eu]
+
+use std::fmt;
+use std::net::Ipv4Addr;
+
+use crate::{kube, parse_mark, profile};
+
+/// iptables table
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Table {
+    Filter,
+    Mangle,
+    Raw,
+    Nat,
+}
+
+impl Table {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "filter" => Some(Table::Filter),
+            "mangle" => Some(Table::Mangle),
+            "raw" => Some(Table::Raw),
+            "nat" => Some(Table::Nat),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Table::Filter => "filter",
+            Table::Mangle => "mangle",
+            Table::Raw => "raw",
+            Table::Nat => "nat",
+        }
+    }
+
+    /// Chain inbound traffic is matched in for this table
+    pub fn default_chain(&self) -> &'static str {
+        match self {
+            Table::Filter => "INPUT",
+            Table::Mangle | Table::Raw | Table::Nat => "PREROUTING",
+        }
+    }
+
+    /// Chains the kernel creates in this table
+    pub fn builtin_chains(&self) -> &'static [&'static str] {
+        match self {
+            Table::Filter => &["INPUT", "FORWARD", "OUTPUT"],
+            Table::Mangle => &["PREROUTING", "INPUT", "FORWARD", "OUTPUT", "POSTROUTING"],
+            Table::Raw => &["PREROUTING", "OUTPUT"],
+            Table::Nat => &["PREROUTING", "INPUT", "OUTPUT", "POSTROUTING"],
+        }
+    }
+}
+
+/// Where an added rule goes in its chain
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Position {
+    /// Inserted first, ahead of everything (the default)
+    Top,
+    /// Inserted as rule N (1-based)
+    At(u32),
+    /// Appended, after the rules already there
+    Bottom,
+}
+
+impl Position {
+    /// `top`, `bottom` or a rule number
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "top" => Some(Position::Top),
+            "bottom" => Some(Position::Bottom),
+            _ => match s.parse::<u32>().ok()? {
+                0 => None,
+                1 => Some(Position::Top),
+                n => Some(Position::At(n)),
+            },
+        }
+    }
+}
+
+/// Transport protocol
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Proto {
+    Tcp,
+    Udp,
+    Esp,
+}
+
+impl Proto {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "tcp" => Some(Proto::Tcp),
+            "udp" => Some(Proto::Udp),
+            "esp" => Some(Proto::Esp),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Proto::Tcp => "tcp",
+            Proto::Udp => "udp",
+            Proto::Esp => "esp",
+        }
+    }
+
+    /// Whether the protocol has ports to match on
+    pub fn has_ports(&self) -> bool {
+        *self != Proto::Esp
+    }
+}
+
+/// Targets built into iptables (or its extensions), never user chains
+const BUILTIN_TARGETS: &[&str] = &[
+    "ACCEPT", "DROP", "REJECT", "RETURN", "QUEUE", "NFQUEUE", "LOG", "NFLOG", "MARK", "CONNMARK", "CT", "TCPMSS",
+    "DNAT", "SNAT", "MASQUERADE", "REDIRECT", "TRACE", "AUDIT",
+];
+
+/// User chain names: up to 28 characters (the kernel limit), no builtin
+/// target names, nothing that needs quoting in -S output
+pub fn is_valid_chain_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 28
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        && !BUILTIN_TARGETS.contains(&name)
+}
+
+/// What a managed rule does with matching traffic
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Target {
+    Accept,
+    Mark(u32),
+    /// Bypass conntrack (raw table), for high-throughput flows
+    NoTrack,
+    /// Clamp the MSS of TCP SYNs to the path MTU (mangle table, VPN peers)
+    ClampMss,
+    /// Hand over to a user-maintained chain (logging, extra filtering)
+    Jump(String),
+    /// Back to the calling chain (DOCKER-USER hands over to Docker's rules)
+    Return,
+}
+
+impl Target {
+    fn parse(s: &str) -> Option<Self> {
+        match s.split_once(':') {
+            None if s == "ACCEPT" => Some(Target::Accept),
+            None if s == "NOTRACK" => Some(Target::NoTrack),
+            None if s == "TCPMSS" => Some(Target::ClampMss),
+            None if s == "RETURN" => Some(Target::Return),
+            Some(("MARK", mark)) => parse_mark(mark).map(Target::Mark),
+            Some(("JUMP", chain)) if is_valid_chain_name(chain) => Some(Target::Jump(chain.to_string())),
+            _ => None,
+        }
+    }
+
+    fn args(&self) -> Vec<String> {
+        match self {
+            Target::Accept => vec![String::from("ACCEPT")],
+            Target::Mark(mark) => vec![
+                String::from("MARK"),
+                String::from("--set-mark"),
+                format!("{:#x}", mark),
+            ],
+            Target::NoTrack => vec![String::from("CT"), String::from("--notrack")],
+            Target::ClampMss => vec![String::from("TCPMSS"), String::from("--clamp-mss-to-pmtu")],
+            Target::Jump(chain) => vec![chain.clone()],
+            Target::Return => vec![String::from("RETURN")],
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Target::Accept => write!(f, "ACCEPT"),
+            Target::Mark(mark) => write!(f, "MARK:{:#x}", mark),
+            Target::NoTrack => write!(f, "NOTRACK"),
+            Target::ClampMss => write!(f, "TCPMSS"),
+            Target::Jump(chain) => write!(f, "JUMP:{}", chain),
+            Target::Return => write!(f, "RETURN"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Rule {
+    pub table: Table,
+    pub chain: String,
+    pub ip: Ipv4Addr,
+    pub port: u16,
+    pub proto: Proto,
+    pub target: Target,
+    pub credentials: Option<String>,  // kubernetes profile; None: the default policy
+}
+
+impl Rule {
+    /// The classic rule: filter/INPUT, tcp, ACCEPT
+    pub fn allow(ip: Ipv4Addr, port: u16) -> Self {
+        Rule {
+            table: Table::Filter,
+            chain: String::from("INPUT"),
+            ip,
+            port,
+            proto: Proto::Tcp,
+            target: Target::Accept,
+            credentials: None,
+        }
+    }
+
+    /// True for rules every backend can express (a plain allow)
+    pub fn is_plain(&self) -> bool {
+        *self == Rule::allow(self.ip, self.port)
+    }
+
+    pub fn scope(&self) -> (Table, String) {
+        (self.table, self.chain.clone())
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        let s = s.trim();
+        if s.is_empty() {
+            return None;
+        }
+
+        let (s, credentials) = match s.rsplit_once('@') {
+            Some((rest, name)) if kube::is_valid_name(name) => (rest, Some(name.to_string())),
+            Some(_) => return None,
+            None => (s, None),
+        };
+
+        let (s, target) = match s.split_once('=') {
+            Some((rest, target)) => (rest, Target::parse(target)?),
+            None => (s, Target::Accept),
+        };
+        let (s, proto) = match s.rsplit_once('/') {
+            Some((rest, proto)) => (rest, Proto::parse(proto)?),
+            None => (s, Proto::Tcp),
+        };
+        let (s, port) = s.rsplit_once(':')?;
+        let port: u16 = port.parse().ok()?;
+        let (table, chain, ip) = match s.split_once(':') {
+            Some((scope, ip)) => {
+                let (table, chain) = scope.split_once('.')?;
+                (Table::parse(table)?, chain.to_string(), ip)
+            }
+            None => (Table::Filter, String::from("INPUT"), s),
+        };
+
+        Some(Rule {
+            table,
+            chain,
+            ip: ip.parse().ok()?,
+            port,
+            proto,
+            target,
+            credentials,
+        })
+    }
+
+    /// Full iptables argument list for `action` (-I inserts at position 1)
+    pub fn iptables_args(&self, action: &str) -> Vec<String> {
+        let mut args = vec![
+            String::from("-t"),
+            self.table.name().to_string(),
+            action.to_string(),
+            self.chain.clone(),
+        ];
+        if action == "-I" {
+            args.push(String::from("1"));  // Insert at 1 for priority over other rules
+        }
+        for arg in ["-s", &format!("{}/32", self.ip), "-p", self.proto.name()] {
+            args.push(arg.to_string());
+        }
+        if self.port != 0 {
+            for arg in ["-m", self.proto.name(), "--dport", &self.port.to_string()] {
+                args.push(arg.to_string());
+            }
+        }
+        if self.target == Target::ClampMss {
+            for arg in ["-m", "tcp", "--tcp-flags", "SYN,RST", "SYN"] {
+                args.push(arg.to_string());
+            }
+        }
+        for arg in ["-m", "comment", "--comment", profile::current().comment(), "-j"] {
+            args.push(arg.to_string());
+        }
+        args.extend(self.target.args());
+        args
+    }
+
+    /// iptables arguments adding the rule at `position`
+    pub fn add_args(&self, position: Position) -> Vec<String> {
+        match position {
+            Position::Top => self.iptables_args("-I"),
+            Position::At(n) => {
+                let mut args = self.iptables_args("-I");
+                args[4] = n.to_string();
+                args
+            }
+            Position::Bottom => self.iptables_args("-A"),
+        }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.table != Table::Filter || self.chain != "INPUT" {
+            write!(f, "{}.{}:", self.table.name(), self.chain)?;
+        }
+        write!(f, "{}:{}", self.ip, self.port)?;
+        if self.proto != Proto::Tcp {
+            write!(f, "/{}", self.proto.name())?;
+        }
+        if self.target != Target::Accept {
+            write!(f, "={}", self.target)?;
+        }
+        if let Some(name) = &self.credentials {
+            write!(f, "@{}", name)?;
+        }
+        Ok(())
+    }
+}
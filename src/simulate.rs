@@ -0,0 +1,170 @@
+//! `ddnsfw simulate-change <hostname> <ip>`: IP-change drill, dry run.
+//!
+//! Pretends the hostname now resolves to `ip` and walks the sync pipeline
+//! for its entries against the live firewall and cache: the checks a real
+//! answer would go through (usable address, this server's own address, a
+//! CGNAT address (see cgnat), the change-rate freeze), the existence check, the adds (run first), the
+//! removal of the old rules, and the events and escalation hooks the change
+//! would trigger. Each firewall
+//! operation is printed as the exact command the backend would run. Nothing
+//! is changed: the firewall is only listed and the cache only read, so it is
+//! safe to run on a production host while the timer keeps syncing.
+
+use std::collections::HashSet;
+use std::net::Ipv4Addr;
+
+use crate::rule::Rule;
+use crate::{
+    cgnat, churn, exit_err, is_usable_ipv4, managed_scopes, now_secs, open_backend, parse_config, provider, srv, sys, Backend,
+    Cache, DdnsEntry,
+};
+
+pub fn command(args: &[String]) {
+    let [hostname, ip] = args else {
+        exit_err("Usage: ddnsfw simulate-change <hostname> <ip>");
+    };
+    let ip: Ipv4Addr = ip.parse().unwrap_or_else(|_| exit_err(&format!("Invalid IPv4 address '{}'", ip)));
+
+    let mut config = parse_config();
+    provider::expand(&mut config);
+    srv::expand(&mut config);
+    let entries: Vec<&DdnsEntry> = config.entries.iter().filter(|e| e.hostname.eq_ignore_ascii_case(hostname)).collect();
+    if entries.is_empty() {
+        exit_err(&format!("No entry for {} in the config", hostname));
+    }
+    let Some(backend) = open_backend(&config) else {
+        exit_err(&format!("{} not found", config.backend.tool()));
+    };
+    let tool = config.backend.tool();
+    let cache = Cache::load();
+
+    println!("[ddnsfw] Simulating {} -> {} (dry run, nothing is changed)", hostname, ip);
+
+    // The checks a real answer goes through before any rule work
+    if !is_usable_ipv4(&ip) {
+        println!("[ddnsfw] {} is not a usable address: the sync would ignore it (DNS failure, keeping existing rules)", ip);
+        return;
+    }
+    let mut own_addrs = sys::local_ipv4_addrs();
+    own_addrs.extend(&config.self_ips);
+    if own_addrs.contains(&ip) {
+        println!("[ddnsfw] {} is this server's own address: the sync would warn and keep the existing rules", ip);
+        return;
+    }
+    // Entries with an IPv6 side leave a CGNAT address out
+    let shared_space = cgnat::Check::new(config.cgnat, &own_addrs);
+    let iptables = matches!(backend, Backend::Iptables(_));
+    let mut v6_only: Vec<String> = Vec::new();
+    if !shared_space.shared(&[ip]).is_empty() {
+        for entry in &entries {
+            if shared_space.prefers_v6(&config, entry, iptables) {
+                println!("[ddnsfw] {}: {} is a CGNAT/DS-Lite address: the sync would leave it out and rely on the IPv6 rule", entry.label(), ip);
+                v6_only.push(entry.key());
+            } else {
+                println!("[ddnsfw] {}: {} is a CGNAT/DS-Lite address: the sync would allow it with a warning (it cannot match the connection)", entry.label(), ip);
+            }
+        }
+    }
+
+    let churn = churn::State::load();
+    for entry in &entries {
+        let limit = entry.max_changes.unwrap_or(config.max_changes_per_day);
+        let changes = churn.peek(&entry.key(), &[ip], now_secs());
+        if limit > 0 && changes > limit {
+            println!(
+                "[ddnsfw] {}: {} changes in 24h exceed max_changes_per_day={}: the sync would freeze it and keep its rules",
+                entry.label(),
+                changes,
+                limit
+            );
+        }
+    }
+
+    let existing = backend.existing_rules(&managed_scopes(&config.entries, &cache));
+    let labels: Vec<String> = entries.iter().map(|e| e.key()).collect();
+
+    let mut desired: Vec<Rule> = Vec::new();
+    for entry in entries.iter().filter(|entry| !v6_only.contains(&entry.key())) {
+        for rule in entry.rules_for(ip) {
+            if !backend.supports(&rule) {
+                println!("[ddnsfw] {}: {} would be ignored (needs iptables backend)", entry.label(), rule);
+            } else if !desired.contains(&rule) {
+                desired.push(rule);
+            }
+        }
+    }
+    let desired_set: HashSet<&Rule> = desired.iter().collect();
+
+    // Rules of these entries that no other entry (or grant) also wants
+    let mut current: Vec<&Rule> = existing
+        .iter()
+        .filter(|rule| cache.owners.get(*rule).is_some_and(|o| o.iter().any(|l| labels.contains(l))))
+        .collect();
+    current.sort_by_key(|rule| rule.to_string());
+    for rule in &current {
+        println!("[ddnsfw] Current: {} ({})", rule, cache.owners[*rule].join(", "));
+    }
+    let removals: Vec<&Rule> = current
+        .iter()
+        .copied()
+        .filter(|rule| !desired_set.contains(rule))
+        .filter(|rule| cache.owners[*rule].iter().all(|l| labels.contains(l)))
+        .collect();
+    let shared = current.len() - removals.len() - current.iter().filter(|r| desired_set.contains(**r)).count();
+
+    let mut adds = 0;
+    for rule in &desired {
+        println!("[ddnsfw] Check {}: {}", rule, describe(&backend, tool, rule, "-C"));
+        if existing.contains(rule) {
+            println!("[ddnsfw]   exists, no change");
+            continue;
+        }
+        adds += 1;
+        println!("[ddnsfw]   missing -> add: {}", describe(&backend, tool, rule, "-I"));
+        println!("[ddnsfw]   event: add (hostname {}, new_ip {})", hostname, ip);
+    }
+    for rule in &removals {
+        println!("[ddnsfw] Remove old {} (after the adds succeeded): {}", rule, describe(&backend, tool, rule, "-D"));
+        println!("[ddnsfw]   event: delete (hostname {}, old_ip {}, new_ip {})", hostname, rule.ip, ip);
+    }
+    if adds > 0 && entries.iter().any(|e| e.max_conns.is_some() || e.knock.is_some()) {
+        println!("[ddnsfw] Companions (max_conns, knock) would then move to {}", ip);
+    }
+    if shared > 0 {
+        println!("[ddnsfw] {} current rules stay (also wanted by other entries)", shared);
+    }
+
+    // Escalation hooks a successful resolution would trigger
+    let policy = &config.escalation;
+    if let Some(streak) = cache.failures.get(&entries[0].hostname) {
+        if policy.is_notified(streak) {
+            println!("[ddnsfw] Escalation cleared after {} failed syncs: event escalate_clear", streak.count);
+            if let Some(command) = &policy.notify_command {
+                println!("[ddnsfw]   would run: {} clear {} {}", command, hostname, streak.count);
+            }
+            if let Some(command) = policy.alert_command.as_ref().filter(|_| policy.is_alerting(streak)) {
+                println!("[ddnsfw]   would run: {} clear {} {}", command, hostname, streak.count);
+            }
+        }
+    }
+
+    println!(
+        "[ddnsfw] Simulation: {} adds, {} removals{}",
+        adds,
+        removals.len(),
+        if adds > 0 && !removals.is_empty() { " (new access is live before the old one goes)" } else { "" }
+    );
+}
+
+/// The command a backend would run for `action` (-C, -I, -D)
+fn describe(backend: &Backend, tool: &str, rule: &Rule, action: &str) -> String {
+    if let Backend::Iptables(bin) = backend {
+        return format!("{} {}", bin, rule.iptables_args(action).join(" "));
+    }
+    let verb = match action {
+        "-C" => "check",
+        "-I" => "add",
+        _ => "delete",
+    };
+    format!("{} {} {}:{}", tool, verb, rule.ip, rule.port)
+}
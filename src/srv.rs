@@ -0,0 +1,173 @@
+//! SRV entries: the service record names both host and port.
+//!
+//!   _ssh._tcp.home.example.com            max_conns=3
+//!   _wireguard._udp.home.example.com
+//!
+//! Each sync looks the record up (`dig SRV`) and expands the line into one
+//! ordinary entry per advertised target, `target:port` with the line's
+//! options (`target:port/udp` for `_udp` records). A new target or port is allowed on that sync; the rule of the
+//! old one loses its owner and goes with the usual removal, after the new
+//! one is in place. The last answer is kept in `service.srv`: when the
+//! lookup fails (timeout, SERVFAIL, NXDOMAIN, no records) that answer is
+//! used, so a DNS outage never removes access. Only a published "service
+//! not available" record (target `.`) withdraws the entries.
+
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::process::{Command, Stdio};
+
+use crate::provider::is_valid_hostname;
+use crate::{execs, exit_err, profile, push_entries, Config, DNS_TIMEOUT_SECS, MAX_ENTRIES};
+
+/// Targets taken from one record set
+const MAX_TARGETS: usize = 16;
+
+#[derive(Debug, Clone)]
+pub struct SrvEntry {
+    name: String,
+    options: String,
+    origin: String,
+    udp: bool,
+}
+
+impl SrvEntry {
+    /// None if `line` is not an SRV entry; an unsupported one is fatal
+    /// (dropping it would silently delete its rules)
+    pub fn parse(line: &str, origin: &str) -> Option<Self> {
+        let (name, options) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let mut labels = name.split('.');
+        let (service, proto) = (labels.next()?, labels.next()?);
+        if !service.starts_with('_') || name.contains(':') {
+            return None;
+        }
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        let udp = match proto.to_ascii_lowercase().as_str() {
+            "_tcp" => false,
+            "_udp" => true,
+            _ => exit_err(&format!("{}: invalid SRV entry '{}' (use _service._tcp.domain)", origin, name)),
+        };
+        if !is_valid_hostname(&name.replace('_', "")) {
+            exit_err(&format!("{}: invalid SRV entry '{}' (use _service._tcp.domain)", origin, name));
+        }
+        Some(SrvEntry { name, options: options.trim().to_string(), origin: origin.to_string(), udp })
+    }
+}
+
+/// The record set as (target, port); None when there is no usable answer,
+/// Some(empty) when the service is published as not available
+fn lookup(name: &str) -> Option<Vec<(String, u16)>> {
+    let output = execs::output(
+        Command::new("dig")
+            .args(["+short", "+tries=1", &format!("+time={}", DNS_TIMEOUT_SECS), "SRV", name])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null()),
+    )
+    .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    // `10 5 22 host.example.com.` (priority, weight, port, target)
+    let mut targets = Vec::new();
+    let mut withdrawn = false;
+    for line in String::from_utf8_lossy(&output.stdout).lines().take(MAX_TARGETS) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [_, _, port, target] = fields[..] else {
+            continue;
+        };
+        let Ok(port) = port.parse::<u16>() else {
+            continue;
+        };
+        let target = target.trim_end_matches('.').to_ascii_lowercase();
+        if target.is_empty() {
+            withdrawn = true;
+        } else if port > 0 && is_valid_hostname(&target) && !targets.contains(&(target.clone(), port)) {
+            targets.push((target, port));
+        }
+    }
+    (!targets.is_empty() || withdrawn).then_some(targets)
+}
+
+fn load_known() -> HashMap<String, Vec<(String, u16)>> {
+    fs::read_to_string(&profile::current().srv_path)
+        .unwrap_or_default()
+        .lines()
+        .take(MAX_ENTRIES)
+        .map(|line| {
+            let (name, targets) = line.split_once(' ').unwrap_or((line, ""));
+            let targets = targets
+                .split(',')
+                .filter_map(|t| {
+                    let (host, port) = t.rsplit_once(':')?;
+                    Some((host.to_string(), port.parse().ok()?))
+                })
+                .filter(|(host, _)| is_valid_hostname(host))
+                .take(MAX_TARGETS)
+                .collect();
+            (name.to_string(), targets)
+        })
+        .collect()
+}
+
+fn save_known(known: &HashMap<String, Vec<(String, u16)>>) {
+    let content: String = known
+        .iter()
+        .map(|(name, targets)| {
+            let targets: Vec<String> = targets.iter().map(|(host, port)| format!("{}:{}", host, port)).collect();
+            format!("{} {}\n", name, targets.join(","))
+        })
+        .collect();
+    let path = &profile::current().srv_path;
+    let temp_path = format!("{}.tmp", path);
+    if let Ok(mut file) = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&temp_path) {
+        if file.write_all(content.as_bytes()).is_ok() {
+            let _ = fs::rename(&temp_path, path);
+        }
+    }
+}
+
+/// Looks up every SRV entry and adds one entry per advertised target
+pub fn expand(config: &mut Config) {
+    if config.srv_entries.is_empty() {
+        return;
+    }
+    let previous = load_known();
+    let mut known: HashMap<String, Vec<(String, u16)>> = HashMap::new();
+    let mut lines: Vec<(String, String)> = Vec::new();
+
+    for srv in &config.srv_entries {
+        if !known.contains_key(&srv.name) {
+            let last = previous.get(&srv.name).cloned().unwrap_or_default();
+            let targets = match lookup(&srv.name) {
+                Some(targets) => {
+                    if targets != last {
+                        let list: Vec<String> = targets.iter().map(|(host, port)| format!("{}:{}", host, port)).collect();
+                        let list = if list.is_empty() { "not available".to_string() } else { list.join(", ") };
+                        println!("[ddnsfw] SRV {} -> {}", srv.name, list);
+                    }
+                    targets
+                }
+                None => {
+                    eprintln!(
+                        "[ddnsfw] WARN: SRV lookup of {} failed, using the last {} known targets",
+                        srv.name,
+                        last.len()
+                    );
+                    last
+                }
+            };
+            known.insert(srv.name.clone(), targets);
+        }
+        let suffix = if srv.udp { "/udp" } else { "" };
+        for (host, port) in &known[&srv.name] {
+            let line = format!("{}:{}{} {}", host, port, suffix, srv.options);
+            lines.push((line.trim_end().to_string(), srv.origin.clone()));
+        }
+    }
+
+    save_known(&known);
+    for (line, origin) in lines {
+        push_entries(config, &line, &origin);
+    }
+}
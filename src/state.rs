@@ -0,0 +1,332 @@
+//! Crash-safety state machine of a sync.
+//!
+//! Every firewall change is bracketed by persisted cache states, so a crash
+//! at any point leaves enough behind to recover:
+//!
+//!   Idle --BeginAdd(r)--> Adding(r) --Succeeded--> Idle   (r recorded)
+//!                                   --Failed-----> Idle
+//!   Idle --BeginDelete(r)-> Deleting(r) --Succeeded--> Idle (r forgotten)
+//!                                       --Failed-----> Idle
+//!   any  --Reset--> Idle
+//!
+//! Anything else (nesting operations, completing nothing) is an invalid
+//! transition. On startup `recovery` decides what an interrupted state
+//! needs: an interrupted add is re-checked and completed, an interrupted
+//! delete is dropped (the rule, if still there, is reconciled by the sync).
+//!
+//! Persisted in the cache as `STATE:IDLE|ADDING|DELETING` + `PENDING:rule`.
+
+use std::fmt;
+
+use crate::rule::Rule;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyncState {
+    Idle,
+    Adding(Rule),
+    Deleting(Rule),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    BeginAdd(Rule),
+    BeginDelete(Rule),
+    Succeeded,
+    Failed,
+    Reset,
+}
+
+/// What a transition does to the cached rule set
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Effect {
+    None,
+    Insert(Rule),
+    Remove(Rule),
+}
+
+#[derive(Debug)]
+pub struct InvalidTransition {
+    from: Box<SyncState>,
+    event: Box<Event>,
+}
+
+impl fmt::Display for InvalidTransition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid state transition {:?} on {:?}", self.from, self.event)
+    }
+}
+
+impl SyncState {
+    /// From the persisted fields; an operation without its rule is unusable
+    pub fn parse(state: &str, pending: Option<Rule>) -> Self {
+        match (state, pending) {
+            ("ADDING", Some(rule)) => SyncState::Adding(rule),
+            ("DELETING", Some(rule)) => SyncState::Deleting(rule),
+            _ => SyncState::Idle,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SyncState::Idle => "IDLE",
+            SyncState::Adding(_) => "ADDING",
+            SyncState::Deleting(_) => "DELETING",
+        }
+    }
+
+    pub fn pending(&self) -> Option<&Rule> {
+        match self {
+            SyncState::Idle => None,
+            SyncState::Adding(rule) | SyncState::Deleting(rule) => Some(rule),
+        }
+    }
+
+    pub fn is_idle(&self) -> bool {
+        *self == SyncState::Idle
+    }
+
+    pub fn on(&self, event: Event) -> Result<(SyncState, Effect), InvalidTransition> {
+        match (self, event) {
+            (_, Event::Reset) => Ok((SyncState::Idle, Effect::None)),
+            (SyncState::Idle, Event::BeginAdd(rule)) => Ok((SyncState::Adding(rule), Effect::None)),
+            (SyncState::Idle, Event::BeginDelete(rule)) => Ok((SyncState::Deleting(rule), Effect::None)),
+            (SyncState::Adding(rule), Event::Succeeded) => Ok((SyncState::Idle, Effect::Insert(rule.clone()))),
+            (SyncState::Deleting(rule), Event::Succeeded) => Ok((SyncState::Idle, Effect::Remove(rule.clone()))),
+            (SyncState::Adding(_) | SyncState::Deleting(_), Event::Failed) => Ok((SyncState::Idle, Effect::None)),
+            (from, event @ (Event::BeginAdd(_) | Event::BeginDelete(_)))
+            | (from @ SyncState::Idle, event @ (Event::Succeeded | Event::Failed)) => {
+                Err(InvalidTransition { from: Box::new(from.clone()), event: Box::new(event) })
+            }
+        }
+    }
+}
+
+/// What an interrupted state needs after a crash
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Recovery {
+    Nothing,
+    /// The add went through before the crash: record it
+    Adopt(Rule),
+    /// The add never happened: do it now
+    Readd(Rule),
+    /// A delete was interrupted: drop it, the sync reconciles the rule
+    Forget(Rule),
+}
+
+/// `exists` asks the firewall whether a rule is present
+pub fn recovery(state: &SyncState, exists: impl FnOnce(&Rule) -> bool) -> Recovery {
+    match state {
+        SyncState::Idle => Recovery::Nothing,
+        SyncState::Adding(rule) if exists(rule) => Recovery::Adopt(rule.clone()),
+        SyncState::Adding(rule) => Recovery::Readd(rule.clone()),
+        SyncState::Deleting(rule) => Recovery::Forget(rule.clone()),
+    }
+}
+
+// These test the transition table and `recovery`, not SyncRun: the World
+// below is a hand-written per-rule model that brackets each add and delete
+// on its own, as the per-rule path does. Batched phases record their rules
+// once restore::Batch::commit returns; restore.rs tests the batches.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::collections::HashSet;
+    use std::net::Ipv4Addr;
+
+    /// Persisted world: firewall, cached rules and machine state
+    #[derive(Debug, Clone, PartialEq)]
+    struct World {
+        firewall: HashSet<Rule>,
+        cached: HashSet<Rule>,
+        state: SyncState,
+    }
+
+    struct Crash;
+
+    impl World {
+        fn apply(&mut self, event: Event) {
+            let (state, effect) = self.state.on(event).expect("sync only makes valid transitions");
+            match effect {
+                Effect::None => {}
+                Effect::Insert(rule) => {
+                    self.cached.insert(rule);
+                }
+                Effect::Remove(rule) => {
+                    self.cached.remove(&rule);
+                }
+            }
+            self.state = state;
+        }
+
+        fn recover(&mut self, add_works: bool) {
+            let firewall = self.firewall.clone();
+            match recovery(&self.state, |rule| firewall.contains(rule)) {
+                Recovery::Nothing => {}
+                Recovery::Adopt(_) => self.apply(Event::Succeeded),
+                Recovery::Readd(rule) => {
+                    if add_works {
+                        self.firewall.insert(rule);
+                        self.apply(Event::Succeeded);
+                    } else {
+                        self.apply(Event::Failed);
+                    }
+                }
+                Recovery::Forget(_) => self.apply(Event::Failed),
+            }
+        }
+
+        /// Per-rule model of a sync driven through the table: adds first,
+        /// then deletes of unwanted rules, skipping ports where an add failed
+        /// (fail-safe). `steps` counts down to a crash; `failing` adds never
+        /// apply.
+        fn sync(&mut self, desired: &HashSet<Rule>, failing: &HashSet<Rule>, steps: &mut usize) -> Result<(), Crash> {
+            let tick = |steps: &mut usize| {
+                if *steps == 0 {
+                    return Err(Crash);
+                }
+                *steps -= 1;
+                Ok(())
+            };
+            self.recover(true);
+            let existing = self.firewall.clone();
+            let mut kept_ports = HashSet::new();
+
+            let mut adds: Vec<&Rule> = desired.difference(&existing).collect();
+            adds.sort_by_key(|r| r.to_string());
+            for rule in adds {
+                self.apply(Event::BeginAdd(rule.clone()));
+                tick(steps)?;
+                if failing.contains(rule) {
+                    self.apply(Event::Failed);
+                    kept_ports.insert(rule.port);
+                    continue;
+                }
+                self.firewall.insert(rule.clone());
+                tick(steps)?;
+                self.apply(Event::Succeeded);
+                tick(steps)?;
+            }
+
+            let mut deletes: Vec<&Rule> =
+                existing.difference(desired).filter(|r| !kept_ports.contains(&r.port)).collect();
+            deletes.sort_by_key(|r| r.to_string());
+            for rule in deletes {
+                self.apply(Event::BeginDelete(rule.clone()));
+                tick(steps)?;
+                self.firewall.remove(rule);
+                tick(steps)?;
+                self.apply(Event::Succeeded);
+                tick(steps)?;
+            }
+            self.apply(Event::Reset);
+            Ok(())
+        }
+
+        /// A sync that is not interrupted
+        fn complete(&mut self, desired: &HashSet<Rule>, failing: &HashSet<Rule>) -> bool {
+            let mut steps = usize::MAX;
+            self.sync(desired, failing, &mut steps).is_ok()
+        }
+    }
+
+    fn rule() -> impl Strategy<Value = Rule> {
+        (1u8..8, prop_oneof![Just(22u16), Just(443u16)])
+            .prop_map(|(host, port)| Rule::allow(Ipv4Addr::new(198, 51, 100, host), port))
+    }
+
+    fn rules() -> impl Strategy<Value = HashSet<Rule>> {
+        prop::collection::hash_set(rule(), 0..6)
+    }
+
+    fn state() -> impl Strategy<Value = SyncState> {
+        prop_oneof![
+            Just(SyncState::Idle),
+            rule().prop_map(SyncState::Adding),
+            rule().prop_map(SyncState::Deleting),
+        ]
+    }
+
+    fn event() -> impl Strategy<Value = Event> {
+        prop_oneof![
+            rule().prop_map(Event::BeginAdd),
+            rule().prop_map(Event::BeginDelete),
+            Just(Event::Succeeded),
+            Just(Event::Failed),
+            Just(Event::Reset),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn transitions_are_exhaustive_and_consistent(from in state(), event in event()) {
+            match from.on(event.clone()) {
+                Ok((to, effect)) => {
+                    match &event {
+                        Event::BeginAdd(rule) => prop_assert_eq!(to, SyncState::Adding(rule.clone())),
+                        Event::BeginDelete(rule) => prop_assert_eq!(to, SyncState::Deleting(rule.clone())),
+                        _ => prop_assert!(to.is_idle()),
+                    }
+                    // Only a completed operation changes the cached rules
+                    let expected = match (&from, &event) {
+                        (SyncState::Adding(rule), Event::Succeeded) => Effect::Insert(rule.clone()),
+                        (SyncState::Deleting(rule), Event::Succeeded) => Effect::Remove(rule.clone()),
+                        _ => Effect::None,
+                    };
+                    prop_assert_eq!(effect, expected);
+                }
+                Err(_) => {
+                    let invalid = match &event {
+                        Event::BeginAdd(_) | Event::BeginDelete(_) => !from.is_idle(),
+                        Event::Succeeded | Event::Failed => from.is_idle(),
+                        Event::Reset => false,
+                    };
+                    prop_assert!(invalid);
+                }
+            }
+        }
+
+        #[test]
+        fn persisted_form_round_trips(state in state()) {
+            prop_assert_eq!(SyncState::parse(state.name(), state.pending().cloned()), state);
+        }
+
+        #[test]
+        fn table_model_reaches_the_desired_rules(existing in rules(), desired in rules()) {
+            let mut world = World { firewall: existing.clone(), cached: existing, state: SyncState::Idle };
+            prop_assert!(world.complete(&desired, &HashSet::new()));
+            prop_assert_eq!(&world.firewall, &desired);
+            prop_assert_eq!(&world.cached, &desired);
+        }
+
+        #[test]
+        fn table_model_converges_after_a_crash_anywhere(existing in rules(), desired in rules(), crash_at in 0usize..40) {
+            let mut world = World { firewall: existing.clone(), cached: existing, state: SyncState::Idle };
+            let mut steps = crash_at;
+            let crashed = world.sync(&desired, &HashSet::new(), &mut steps).is_err();
+            // Restart: recovery plus a full sync
+            prop_assert!(world.complete(&desired, &HashSet::new()));
+            prop_assert!(desired.is_subset(&world.firewall), "crashed={} world={:?}", crashed, world);
+            prop_assert!(world.state.is_idle());
+        }
+
+        #[test]
+        fn table_model_never_empties_a_port_on_failed_adds(existing in rules(), desired in rules(), failing in rules()) {
+            let mut world = World { firewall: existing.clone(), cached: existing.clone(), state: SyncState::Idle };
+            prop_assert!(world.complete(&desired, &failing));
+            for rule in &existing {
+                prop_assert!(world.firewall.iter().any(|r| r.port == rule.port) || !desired.iter().any(|r| r.port == rule.port),
+                    "port {} lost every rule", rule.port);
+            }
+        }
+
+        #[test]
+        fn recovery_is_idempotent(firewall in rules(), state in state(), add_works in any::<bool>()) {
+            let mut once = World { firewall: firewall.clone(), cached: firewall, state };
+            once.recover(add_works);
+            let mut twice = once.clone();
+            twice.recover(add_works);
+            prop_assert_eq!(once, twice);
+        }
+    }
+}
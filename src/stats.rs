@@ -0,0 +1,159 @@
+//! Per-sync timing and counters, persisted for status and metrics.
+//!
+//! The state file holds the last completed sync as `key=value` lines:
+//!
+//!   finished=1767225600
+//!   list_ms=12
+//!   dns_ms=340
+//!   ...
+//!   execs=14
+//!   execs_iptables=11
+//!   syncs_total=812
+//!   dns_failures_total=3
+//!
+//! The `_total` lines are running sums over every completed sync (the
+//! counters of metrics.rs), carried over from the previous file. It is
+//! rewritten atomically at the end of each sync, so readers never need
+//! the lock.
+
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::time::Instant;
+
+use crate::{execs, profile};
+
+/// Phases timed during a sync, in execution order
+#[derive(Clone, Copy)]
+pub enum Phase {
+    List,
+    Dns,
+    Add,
+    Delete,
+    Companions,
+}
+
+impl Phase {
+    pub const ALL: [Phase; 5] = [Phase::List, Phase::Dns, Phase::Add, Phase::Delete, Phase::Companions];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Phase::List => "list",
+            Phase::Dns => "dns",
+            Phase::Add => "add",
+            Phase::Delete => "delete",
+            Phase::Companions => "companions",
+        }
+    }
+}
+
+pub struct SyncStats {
+    started: Instant,
+    phase_ms: [u64; 5],
+    pub added: u64,
+    pub removed: u64,
+    pub failed: u64,
+    pub dns_failures: u64,  // Hostnames no entry could resolve
+    /// Process-wide exec counts when the sync started
+    execs_before: BTreeMap<String, u64>,
+}
+
+impl SyncStats {
+    pub fn start() -> Self {
+        SyncStats {
+            started: Instant::now(),
+            phase_ms: [0; 5],
+            added: 0,
+            removed: 0,
+            failed: 0,
+            dns_failures: 0,
+            execs_before: execs::counts(),
+        }
+    }
+
+    /// Adds the time since `since` to a phase (phases may be split)
+    pub fn record(&mut self, phase: Phase, since: Instant) {
+        self.phase_ms[phase as usize] += since.elapsed().as_millis() as u64;
+    }
+
+    pub fn total_ms(&self) -> u64 {
+        self.started.elapsed().as_millis() as u64
+    }
+
+    /// External commands run since the start of the sync, by program
+    pub fn execs(&self) -> BTreeMap<String, u64> {
+        let mut execs = execs::counts();
+        for (program, n) in execs.iter_mut() {
+            *n -= self.execs_before.get(program).copied().unwrap_or(0);
+        }
+        execs.retain(|_, n| *n > 0);
+        execs
+    }
+
+    /// One-line summary: `list 12ms, dns 340ms, ..., total 410ms, 14 execs`
+    pub fn summary(&self) -> String {
+        let mut parts: Vec<String> = Phase::ALL
+            .iter()
+            .map(|p| format!("{} {}ms", p.name(), self.phase_ms[*p as usize]))
+            .collect();
+        parts.push(format!("total {}ms", self.total_ms()));
+        parts.push(format!("{} execs", self.execs().values().sum::<u64>()));
+        parts.join(", ")
+    }
+
+    pub fn save(&self, finished: u64) {
+        let mut content = format!("finished={}\ntotal_ms={}\n", finished, self.total_ms());
+        for phase in Phase::ALL {
+            content.push_str(&format!("{}_ms={}\n", phase.name(), self.phase_ms[phase as usize]));
+        }
+        content.push_str(&format!("added={}\nremoved={}\nfailed={}\n", self.added, self.removed, self.failed));
+        let execs = self.execs();
+        content.push_str(&format!("execs={}\n", execs.values().sum::<u64>()));
+        for (program, n) in &execs {
+            content.push_str(&format!("execs_{}={}\n", program, n));
+        }
+        let totals = [
+            ("syncs_total", 1),
+            ("dns_failures_total", self.dns_failures),
+            ("added_total", self.added),
+            ("removed_total", self.removed),
+            ("failed_total", self.failed),
+        ];
+        for (key, n) in totals {
+            content.push_str(&format!("{}={}\n", key, last(key).unwrap_or(0).saturating_add(n)));
+        }
+
+        let temp_path = format!("{}.tmp", profile::current().state_path);
+        if let Ok(mut file) = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&temp_path)
+        {
+            let _ = file.write_all(content.as_bytes());
+            let _ = fs::rename(&temp_path, &profile::current().state_path);
+        }
+    }
+}
+
+/// External commands of the last completed sync, by program
+pub fn last_execs() -> Vec<(String, u64)> {
+    fs::read_to_string(&profile::current().state_path)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|l| l.split_once('='))
+        .filter_map(|(k, v)| Some((k.strip_prefix("execs_")?.to_string(), v.parse().ok()?)))
+        .collect()
+}
+
+/// Numeric value of the last completed sync (`finished`, `failed`, ...)
+pub fn last(key: &str) -> Option<u64> {
+    fs::read_to_string(&profile::current().state_path)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|l| l.split_once('='))
+        .find(|(k, _)| *k == key)
+        .and_then(|(_, v)| v.parse().ok())
+}
@@ -0,0 +1,250 @@
+//! `ddnsfw status [--json]`: what is installed where and how the last sync
+//! went.
+//!
+//! A read-only summary for people and scripts: version, profile, effective
+//! paths (including a relocated state dir or a tmpfs cache), timer state
+//! and next run, the last completed sync with the external commands it
+//! ran, the cache state, the managed rules found in the firewall against
+//! the cache, and each configured entry with the addresses it currently
+//! allows (what the last sync resolved; status itself does no lookups).
+//! Exits 1 when the profile is not installed. For a diagnosis with
+//! suggested fixes, use `doctor`.
+//!
+//! `--json` prints the same as one object, for monitoring:
+//!
+//!   {"version":"2.2.1","profile":"default","installed":true,...,
+//!    "last_sync":{"finished":1767225600,"ago_secs":42,"result":"ok",...},
+//!    "firewall":{"rules":3,"missing":0,"untracked":0},
+//!    "entries":[{"entry":"home.dyndns.org:22","label":"home.dyndns.org:22","ips":["203.0.113.7"],"failing":false,"frozen":false}]}
+//!
+//! `last_sync` is null before the first completed sync, `firewall` when
+//! the backend's tool cannot be found, and `next_run` when systemd has no
+//! run scheduled (daemon mode).
+
+use std::collections::HashSet;
+use std::net::Ipv4Addr;
+
+use crate::doctor::{next_elapse, systemctl};
+use crate::events::json_escape;
+use crate::rule::Rule;
+use crate::{
+    churn, escalate, format_utc, grants, is_installed, managed_scopes, mirror, now_secs, open_backend, parse_config, profile,
+    stats, exit_err, Cache, BINARY_PATH,
+};
+
+const USAGE: &str = "Usage: ddnsfw status [--json]";
+
+/// A configured entry and what it currently allows
+struct EntryStatus {
+    key: String,
+    label: String,  // hostname:port[/udp], the key unless the entry has an id
+    ips: Vec<Ipv4Addr>,
+    failing: bool,  // Resolution failures past the alert threshold
+    frozen: bool,   // Held by max_changes_per_day
+}
+
+fn json_str(value: &str) -> String {
+    format!("\"{}\"", json_escape(value))
+}
+
+fn json_opt(value: Option<&str>) -> String {
+    value.map_or_else(|| String::from("null"), json_str)
+}
+
+pub fn command(args: &[String]) {
+    let json = match args {
+        [] => false,
+        [flag] if flag == "--json" => true,
+        _ => exit_err(USAGE),
+    };
+    let profile = profile::current();
+    let name = profile.name.as_deref().unwrap_or("default");
+    if !is_installed() {
+        if json {
+            println!("{{\"version\":\"{}\",\"profile\":{},\"installed\":false}}", env!("CARGO_PKG_VERSION"), json_str(name));
+        } else {
+            println!("[ddnsfw] Status for profile {}", name);
+            println!("  Version:    {}", env!("CARGO_PKG_VERSION"));
+            println!("  Installed:  no (install with: sudo ./ddnsfw install)");
+        }
+        std::process::exit(1);
+    }
+
+    let config = parse_config();
+    let cache = Cache::load();
+    let timer = format!("{}.timer", profile.unit());
+    let state = |value: String| if value.is_empty() { String::from("unknown") } else { value };
+    let (enabled, active) = (state(systemctl(&["is-enabled", &timer])), state(systemctl(&["is-active", &timer])));
+    let next = next_elapse(&timer);
+    let finished = stats::last("finished");
+    let (added, removed, failed) = (
+        stats::last("added").unwrap_or(0),
+        stats::last("removed").unwrap_or(0),
+        stats::last("failed").unwrap_or(0),
+    );
+    let result = if failed == 0 { "ok" } else { "failed" };
+    let grants = grants::Ledger::load().grants.len();
+
+    // The managed rules in the firewall right now, against the cache
+    let live: Option<HashSet<Rule>> =
+        open_backend(&config).map(|backend| backend.existing_rules(&managed_scopes(&config.entries, &cache)));
+    let (missing, untracked) = match &live {
+        Some(live) => (cache.rules.difference(live).count(), live.difference(&cache.rules).count()),
+        None => (0, 0),
+    };
+    let installed = live.as_ref().unwrap_or(&cache.rules);
+
+    let alerting = escalate::alerting(&cache.failures, &config.escalation);
+    let churn = churn::State::load();
+    let frozen = churn.frozen();
+    let mut entries: Vec<EntryStatus> = Vec::new();
+    for entry in &config.entries {
+        let key = entry.key();
+        if entries.iter().any(|e| e.key == key) {
+            continue;
+        }
+        let mut ips: Vec<Ipv4Addr> = installed
+            .iter()
+            .filter(|rule| cache.owners.get(*rule).is_some_and(|owners| owners.contains(&key)))
+            .map(|rule| rule.ip)
+            .collect();
+        ips.sort_unstable();
+        ips.dedup();
+        entries.push(EntryStatus {
+            failing: alerting.contains(&entry.hostname.as_str()),
+            frozen: frozen.contains(&key.as_str()),
+            key,
+            label: entry.label(),
+            ips,
+        });
+    }
+
+    if json {
+        let last_sync = match finished {
+            Some(finished) => format!(
+                "{{\"finished\":{},\"ago_secs\":{},\"result\":\"{}\",\"added\":{},\"removed\":{},\"failed\":{},\"total_ms\":{},\"execs\":{}}}",
+                finished,
+                now_secs().saturating_sub(finished),
+                result,
+                added,
+                removed,
+                failed,
+                stats::last("total_ms").unwrap_or(0),
+                stats::last("execs").unwrap_or(0)
+            ),
+            None => String::from("null"),
+        };
+        let firewall = match &live {
+            Some(live) => format!("{{\"rules\":{},\"missing\":{},\"untracked\":{}}}", live.len(), missing, untracked),
+            None => String::from("null"),
+        };
+        let items: Vec<String> = entries
+            .iter()
+            .map(|entry| {
+                let ips: Vec<String> = entry.ips.iter().map(|ip| format!("\"{}\"", ip)).collect();
+                format!(
+                    "{{\"entry\":{},\"label\":{},\"ips\":[{}],\"failing\":{},\"frozen\":{}}}",
+                    json_str(&entry.key),
+                    json_str(&entry.label),
+                    ips.join(","),
+                    entry.failing,
+                    entry.frozen
+                )
+            })
+            .collect();
+        println!(
+            "{{\"version\":\"{}\",\"profile\":{},\"installed\":true,\"binary\":{},\"config\":{},\"state_dir\":{},\"cache_path\":{},\
+             \"timer\":{{\"unit\":{},\"enabled\":{},\"active\":{},\"next_run\":{}}},\"last_sync\":{},\
+             \"cache\":{{\"state\":\"{}\",\"rules\":{},\"rules6\":{},\"retries\":{},\"grants\":{}}},\"firewall\":{},\"entries\":[{}]}}",
+            env!("CARGO_PKG_VERSION"),
+            json_str(name),
+            json_str(BINARY_PATH),
+            json_str(&profile.config_path),
+            json_str(&profile.state_dir),
+            json_str(mirror::cache_path()),
+            json_str(&timer),
+            json_str(&enabled),
+            json_str(&active),
+            json_opt(next.as_deref()),
+            last_sync,
+            cache.state.name(),
+            cache.rules.len(),
+            cache.rules6.len(),
+            cache.retries.len(),
+            grants,
+            firewall,
+            items.join(",")
+        );
+        return;
+    }
+
+    println!("[ddnsfw] Status for profile {}", name);
+    println!("  Version:    {}", env!("CARGO_PKG_VERSION"));
+    println!("  Installed:  {}", BINARY_PATH);
+    println!("  Config:     {} ({} entries)", profile.config_path, config.entries.len());
+    if profile.is_relocated() {
+        println!("  State:      {} ({} is read-only)", profile.state_dir, profile.dir);
+    } else {
+        println!("  State:      {}", profile.state_dir);
+    }
+    println!("  Cache:      {}", mirror::cache_path());
+    println!("  Timer:      {} {} / {}", timer, enabled, active);
+    println!("  Next run:   {}", next.as_deref().unwrap_or("not scheduled"));
+
+    match finished {
+        Some(finished) => println!(
+            "  Last sync:  {} UTC ({}s ago, {}: {} added, {} removed, {} failed)",
+            format_utc(finished),
+            now_secs().saturating_sub(finished),
+            result,
+            added,
+            removed,
+            failed
+        ),
+        None => println!("  Last sync:  never completed"),
+    }
+    if let Some(total) = stats::last("execs") {
+        let by_program: Vec<String> =
+            stats::last_execs().iter().map(|(program, n)| format!("{} {}", program, n)).collect();
+        if by_program.is_empty() {
+            println!("  Execs:      {}", total);
+        } else {
+            println!("  Execs:      {} ({})", total, by_program.join(", "));
+        }
+    }
+
+    println!(
+        "  Rules:      {} IPv4, {} IPv6 ({} queued retries, {} grants, state {})",
+        cache.rules.len(),
+        cache.rules6.len(),
+        cache.retries.len(),
+        grants,
+        cache.state.name()
+    );
+    match &live {
+        Some(live) if missing == 0 && untracked == 0 => println!("  Firewall:   {} managed rules, as cached", live.len()),
+        Some(live) => println!(
+            "  Firewall:   {} managed rules ({} cached but missing, {} not in the cache; the next sync reconciles)",
+            live.len(),
+            missing,
+            untracked
+        ),
+        None => println!("  Firewall:   {} not found", config.backend.tool()),
+    }
+    for entry in &entries {
+        let ips: Vec<String> = entry.ips.iter().map(Ipv4Addr::to_string).collect();
+        let ips = if ips.is_empty() { String::from("-") } else { ips.join(" ") };
+        if entry.key == entry.label {
+            println!("  Entry:      {} -> {}", entry.key, ips);
+        } else {
+            println!("  Entry:      {} ({}) -> {}", entry.key, entry.label, ips);
+        }
+    }
+    for hostname in alerting {
+        let streak = &cache.failures[hostname];
+        println!("  Alert:      {} failing to resolve ({} syncs since {} UTC)", hostname, streak.count, format_utc(streak.since));
+    }
+    for label in frozen {
+        println!("  Frozen:     {} (IP changed more than max_changes_per_day)", label);
+    }
+}
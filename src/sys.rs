@@ -0,0 +1,174 @@
+//! Thin OS compatibility layer over rustix.
+//!
+//! Keeps raw syscalls (and their per-platform constants) out of the rest of
+//! the code, so musl and aarch64 static builds need no libc shims. Signal
+//! masks and interface addresses, which rustix does not cover, go through
+//! the libc crate.
+
+use std::env;
+use std::fs::File;
+use std::net::Ipv4Addr;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::Duration;
+
+use rustix::fs::{flock, FlockOperation};
+
+/// Exclusive advisory lock; `wait` blocks until it is available
+pub fn lock_exclusive(file: &File, wait: bool) -> bool {
+    let operation = if wait {
+        FlockOperation::LockExclusive
+    } else {
+        FlockOperation::NonBlockingLockExclusive
+    };
+    flock(file, operation).is_ok()
+}
+
+pub fn is_root() -> bool {
+    rustix::process::geteuid().is_root()
+}
+
+/// Signals the daemon turns into actions
+pub enum DaemonSignal {
+    SyncNow,    // SIGUSR1
+    DumpStatus, // SIGUSR2
+    Reload,     // SIGHUP
+    Stop,       // SIGTERM, SIGINT
+}
+
+fn daemon_sigset() -> libc::sigset_t {
+    // SAFETY: sigemptyset initializes the set before anything reads it
+    unsafe {
+        let mut set = std::mem::MaybeUninit::<libc::sigset_t>::uninit();
+        libc::sigemptyset(set.as_mut_ptr());
+        let mut set = set.assume_init();
+        libc::sigaddset(&mut set, libc::SIGUSR1);
+        libc::sigaddset(&mut set, libc::SIGUSR2);
+        libc::sigaddset(&mut set, libc::SIGHUP);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        set
+    }
+}
+
+/// Blocks the daemon signals (SIGUSR1, SIGUSR2, SIGHUP, SIGTERM, SIGINT) so
+/// they queue for `wait_signal` instead of killing the process: a SIGTERM
+/// during a sync ends the daemon only once the sync is done. Call before
+/// any thread is spawned (threads inherit the mask).
+pub fn block_daemon_signals() -> bool {
+    let set = daemon_sigset();
+    // SAFETY: valid set, no old mask requested
+    unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut()) == 0 }
+}
+
+/// Waits up to `timeout` for a blocked daemon signal
+pub fn wait_signal(timeout: Duration) -> Option<DaemonSignal> {
+    let set = daemon_sigset();
+    let timeout = libc::timespec {
+        tv_sec: timeout.as_secs().min(i32::MAX as u64) as libc::time_t,
+        tv_nsec: timeout.subsec_nanos() as _,
+    };
+    // SAFETY: valid set and timeout, no siginfo requested
+    match unsafe { libc::sigtimedwait(&set, std::ptr::null_mut(), &timeout) } {
+        libc::SIGUSR1 => Some(DaemonSignal::SyncNow),
+        libc::SIGUSR2 => Some(DaemonSignal::DumpStatus),
+        libc::SIGHUP => Some(DaemonSignal::Reload),
+        libc::SIGTERM | libc::SIGINT => Some(DaemonSignal::Stop),
+        _ => None, // Timeout or EINTR
+    }
+}
+
+/// Queues SIGUSR1 for this process: the daemon syncs as soon as it can
+pub fn request_sync() {
+    // SAFETY: plain syscalls; SIGUSR1 is blocked and collected by wait_signal
+    unsafe {
+        libc::kill(libc::getpid(), libc::SIGUSR1);
+    }
+}
+
+/// Sends a state (`READY=1`, `WATCHDOG=1`, `STATUS=...`) to the service
+/// manager of a `Type=notify` unit; false when not started as one
+pub fn sd_notify(state: &str) -> bool {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return false;
+    };
+    let path = path.to_string_lossy();
+    // `@name` is a socket in the abstract namespace
+    let addr = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name.as_bytes()),
+        None => SocketAddr::from_pathname(path.as_ref()),
+    };
+    let Ok(addr) = addr else {
+        return false;
+    };
+    UnixDatagram::unbound().and_then(|socket| socket.send_to_addr(state.as_bytes(), &addr)).is_ok()
+}
+
+/// How often to send `WATCHDOG=1`: half of the unit's WatchdogSec, when it
+/// is set for this process
+pub fn watchdog_interval() -> Option<Duration> {
+    let pid_matches = env::var("WATCHDOG_PID").map_or(true, |pid| pid.parse() == Ok(std::process::id()));
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok().filter(|&usec| usec > 0)?;
+    pid_matches.then(|| Duration::from_micros(usec / 2))
+}
+
+/// IPv4 addresses configured on this host's interfaces (empty if unknown)
+pub fn local_ipv4_addrs() -> Vec<Ipv4Addr> {
+    let mut addrs = Vec::new();
+    let mut list: *mut libc::ifaddrs = std::ptr::null_mut();
+    // SAFETY: getifaddrs fills `list` on success; it is walked read-only and
+    // released with freeifaddrs
+    unsafe {
+        if libc::getifaddrs(&mut list) != 0 {
+            return addrs;
+        }
+        let mut entry = list;
+        while !entry.is_null() {
+            let addr = (*entry).ifa_addr;
+            if !addr.is_null() && i32::from((*addr).sa_family) == libc::AF_INET {
+                let sin = &*(addr as *const libc::sockaddr_in);
+                let ip = Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr));
+                if !addrs.contains(&ip) {
+                    addrs.push(ip);
+                }
+            }
+            entry = (*entry).ifa_next;
+        }
+        libc::freeifaddrs(list);
+    }
+    addrs
+}
+
+/// Whether `path` lives on a read-only filesystem (EROFS; not a permission
+/// problem, and false when it does not exist)
+pub fn is_read_only(path: &str) -> bool {
+    rustix::fs::access(path, rustix::fs::Access::WRITE_OK) == Err(rustix::io::Errno::ROFS)
+}
+
+pub fn euid() -> u32 {
+    rustix::process::geteuid().as_raw()
+}
+
+/// uid and primary gid of a local user
+pub fn lookup_user(name: &str) -> Option<(u32, u32)> {
+    let name = std::ffi::CString::new(name).ok()?;
+    // SAFETY: getpwnam returns NULL or a pointer to static storage, which is
+    // read right away (no other thread looks users up)
+    unsafe {
+        let entry = libc::getpwnam(name.as_ptr());
+        if entry.is_null() {
+            return None;
+        }
+        Some(((*entry).pw_uid, (*entry).pw_gid))
+    }
+}
+
+/// Permanently becomes `uid`/`gid` (no supplementary groups). Call before any
+/// thread is spawned; true only if root cannot be regained.
+pub fn drop_privileges(uid: u32, gid: u32) -> bool {
+    // SAFETY: plain syscalls on integers; the order (groups, gid, uid) is
+    // required, since the uid change removes the right to the others
+    let dropped = unsafe { libc::setgroups(0, std::ptr::null()) == 0 && libc::setgid(gid) == 0 && libc::setuid(uid) == 0 };
+    // SAFETY: as above; must fail now
+    dropped && uid != 0 && unsafe { libc::setuid(0) } != 0
+}
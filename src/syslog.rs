@@ -0,0 +1,166 @@
+//! Optional remote syslog (RFC 5424) for centralized change logging.
+//!
+//!   syslog_target = udp://logs.example.net:514
+//!   syslog_target = tcp://logs.example.net:601
+//!   syslog_target = tls://logs.example.net:6514
+//!
+//! Every event except routine resolves is sent with its fields as
+//! structured data, e.g.
+//!
+//!   <29>1 2026-10-16T10:00:00Z fw1 ddnsfw 4242 add [ddnsfw@32473 hostname="home.dyndns.org" old_ip="203.0.113.7" new_ip="203.0.113.9" ...] add 203.0.113.9:22
+//!
+//! TCP and TLS use octet-counting framing (RFC 6587); TLS goes through
+//! `openssl s_client` with certificate verification. Delivery is best
+//! effort and never blocks a sync for longer than SEND_TIMEOUT_SECS.
+
+use std::fs;
+use std::io::Write;
+use std::net::{TcpStream, ToSocketAddrs, UdpSocket};
+use std::process::{Command, Stdio};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use crate::events::Value;
+use crate::{execs, format_utc, now_secs};
+
+const SEND_TIMEOUT_SECS: u64 = 3;
+const FACILITY_DAEMON: u8 = 3;
+const SEVERITY_CRITICAL: u8 = 2;
+const SEVERITY_WARNING: u8 = 4;
+const SEVERITY_NOTICE: u8 = 5;
+/// SD-ID enterprise number (RFC 5612 documentation PEN)
+const SD_ID: &str = "ddnsfw@32473";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Transport {
+    Udp,
+    Tcp,
+    Tls,
+}
+
+#[derive(Debug, Clone)]
+pub struct Target {
+    transport: Transport,
+    host: String,
+    port: u16,
+}
+
+static TARGET: OnceLock<Option<Target>> = OnceLock::new();
+
+impl Target {
+    pub fn parse(s: &str) -> Option<Self> {
+        let (scheme, rest) = s.split_once("://")?;
+        let transport = match scheme {
+            "udp" => Transport::Udp,
+            "tcp" => Transport::Tcp,
+            "tls" => Transport::Tls,
+            _ => return None,
+        };
+        let (host, port) = rest.rsplit_once(':')?;
+        let port: u16 = port.parse().ok().filter(|&p| p > 0)?;
+        if host.is_empty() || !host.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-') {
+            return None;
+        }
+        Some(Target { transport, host: host.to_string(), port })
+    }
+}
+
+/// Sets the destination for this process (once, from the config)
+pub fn configure(target: Option<Target>) {
+    let _ = TARGET.set(target);
+}
+
+/// SD-PARAM value escaping (RFC 5424 section 6.3.3)
+fn sd_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"").replace(']', "\\]")
+}
+
+fn local_hostname() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|h| h.trim().to_string())
+        .ok()
+        .filter(|h| !h.is_empty())
+        .unwrap_or_else(|| String::from("-"))
+}
+
+fn format_message(event: &str, fields: &[(&str, Value)]) -> String {
+    let severity = match event {
+        "escalate_alert" | "change_frozen" => SEVERITY_CRITICAL,
+        "escalate_notify" | "skip" => SEVERITY_WARNING,
+        _ if event.ends_with("_failed") => SEVERITY_WARNING,
+        _ => SEVERITY_NOTICE,
+    };
+    let timestamp = format_utc(now_secs()).replacen(' ', "T", 1) + "Z";
+
+    let mut sd = format!("[{}", SD_ID);
+    let mut summary = String::from(event);
+    for (key, value) in fields {
+        let value = match value {
+            Value::Str(s) => s.to_string(),
+            Value::Num(n) => n.to_string(),
+        };
+        sd.push_str(&format!(" {}=\"{}\"", key, sd_escape(&value)));
+        if *key == "rule" || *key == "host" || *key == "reason" || *key == "description" {
+            summary.push(' ');
+            summary.push_str(&value);
+        }
+    }
+    sd.push(']');
+
+    format!(
+        "<{}>1 {} {} ddnsfw {} {} {} {}",
+        FACILITY_DAEMON * 8 + severity,
+        timestamp,
+        local_hostname(),
+        std::process::id(),
+        event,
+        sd,
+        summary
+    )
+}
+
+fn send(target: &Target, message: &str) -> Option<()> {
+    let timeout = Duration::from_secs(SEND_TIMEOUT_SECS);
+    let addr = (target.host.as_str(), target.port).to_socket_addrs().ok()?.next()?;
+    let framed = format!("{} {}", message.len(), message);
+
+    match target.transport {
+        Transport::Udp => {
+            let socket = UdpSocket::bind(if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }).ok()?;
+            socket.send_to(message.as_bytes(), addr).ok()?;
+        }
+        Transport::Tcp => {
+            let mut stream = TcpStream::connect_timeout(&addr, timeout).ok()?;
+            stream.set_write_timeout(Some(timeout)).ok()?;
+            stream.write_all(framed.as_bytes()).ok()?;
+        }
+        Transport::Tls => {
+            let connect = format!("{}:{}", target.host, target.port);
+            let mut child = execs::spawn(
+                Command::new("timeout")
+                    .args([&SEND_TIMEOUT_SECS.to_string(), "openssl", "s_client", "-quiet", "-verify_return_error"])
+                    .args(["-connect", &connect, "-servername", &target.host])
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null()),
+            )
+            .ok()?;
+            child.stdin.take()?.write_all(framed.as_bytes()).ok()?;
+            child.wait().ok()?;
+        }
+    }
+    Some(())
+}
+
+/// Forwards an event to the configured collector, if any
+pub fn forward(event: &str, fields: &[(&str, Value)]) {
+    let Some(Some(target)) = TARGET.get() else {
+        return;
+    };
+    if event == "resolve" {
+        return;
+    }
+    if send(target, &format_message(event, fields)).is_none() {
+        eprintln!("[ddnsfw] WARN: syslog delivery to {}:{} failed", target.host, target.port);
+    }
+}
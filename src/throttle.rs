@@ -0,0 +1,122 @@
+//! Staying within DDNS providers' query limits.
+//!
+//!   jitter = 30                          # sleep 0-30s before a scheduled sync
+//!   provider_interval = duckdns.org:300  # resolve *.duckdns.org at most every 5 min
+//!
+//! Jitter spreads the timer runs of many hosts so they do not all hit the
+//! provider at the same second. A hostname under a provider with a minimum
+//! interval is resolved at most once per interval; in between, the last
+//! answer is reused. Answers are kept in the profile's `service.throttle`
+//! (`hostname resolved_at ip,ip,...` per line) so the limit holds across runs.
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::net::Ipv4Addr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{profile, MAX_ENTRIES};
+
+pub const MAX_JITTER_SECS: u64 = 600;
+const MAX_INTERVAL_SECS: u64 = 86400;
+
+/// `duckdns.org:300` -> (domain suffix, seconds)
+pub fn parse_interval(value: &str) -> Option<(String, u64)> {
+    let (domain, secs) = value.rsplit_once(':')?;
+    let domain = domain.trim().trim_start_matches('.').to_ascii_lowercase();
+    let secs: u64 = secs.trim().parse().ok().filter(|s| (1..=MAX_INTERVAL_SECS).contains(s))?;
+    if domain.is_empty() || !domain.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-') {
+        return None;
+    }
+    Some((domain, secs))
+}
+
+/// Minimum interval for `hostname` (the longest matching suffix wins)
+pub fn interval_for(intervals: &[(String, u64)], hostname: &str) -> Option<u64> {
+    let hostname = hostname.to_ascii_lowercase();
+    intervals
+        .iter()
+        .filter(|(domain, _)| hostname == *domain || hostname.ends_with(&format!(".{}", domain)))
+        .max_by_key(|(domain, _)| domain.len())
+        .map(|&(_, secs)| secs)
+}
+
+/// Random delay in [0, max_secs]; /dev/urandom, falling back to the clock
+pub fn jitter(max_secs: u64) -> Duration {
+    if max_secs == 0 {
+        return Duration::ZERO;
+    }
+    let mut bytes = [0u8; 8];
+    let seed = match File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut bytes)) {
+        Ok(()) => u64::from_le_bytes(bytes),
+        Err(_) => SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos() as u64).unwrap_or(0),
+    };
+    Duration::from_millis(seed % (max_secs * 1000 + 1))
+}
+
+pub fn sleep_jitter(max_secs: u64) {
+    let delay = jitter(max_secs);
+    if !delay.is_zero() {
+        println!("[ddnsfw] Jitter: waiting {:.1}s", delay.as_secs_f64());
+        thread::sleep(delay);
+    }
+}
+
+/// Last answers per hostname
+pub struct State {
+    answers: HashMap<String, (u64, Vec<Ipv4Addr>)>,
+}
+
+impl State {
+    pub fn load() -> Self {
+        let content = fs::read_to_string(&profile::current().throttle_path).unwrap_or_default();
+        let mut answers = HashMap::new();
+        for line in content.lines().take(MAX_ENTRIES) {
+            let mut fields = line.split_whitespace();
+            let (Some(host), Some(at), Some(ips)) = (fields.next(), fields.next(), fields.next()) else {
+                continue;
+            };
+            let Ok(at) = at.parse() else {
+                continue;
+            };
+            let ips: Vec<Ipv4Addr> = ips.split(',').filter_map(|ip| ip.parse().ok()).collect();
+            if !ips.is_empty() {
+                answers.insert(host.to_string(), (at, ips));
+            }
+        }
+        State { answers }
+    }
+
+    /// The stored answer if it is younger than `interval`
+    pub fn fresh(&self, hostname: &str, interval: u64, now: u64) -> Option<Vec<Ipv4Addr>> {
+        let (at, ips) = self.answers.get(hostname)?;
+        // An answer "from the future" predates a clock step back: stale
+        (*at <= now && now - *at < interval).then(|| ips.clone())
+    }
+
+    pub fn record(&mut self, hostname: &str, now: u64, ips: &[Ipv4Addr]) {
+        if !ips.is_empty() {
+            self.answers.insert(hostname.to_string(), (now, ips.to_vec()));
+        }
+    }
+
+    /// Keeps the hostnames still configured and writes the file atomically
+    pub fn save(&mut self, hostnames: &[&str]) {
+        self.answers.retain(|host, _| hostnames.contains(&host.as_str()));
+        let mut content = String::new();
+        for (host, (at, ips)) in self.answers.iter().take(MAX_ENTRIES) {
+            let ips: Vec<String> = ips.iter().map(Ipv4Addr::to_string).collect();
+            content.push_str(&format!("{} {} {}\n", host, at, ips.join(",")));
+        }
+
+        let path = &profile::current().throttle_path;
+        let temp_path = format!("{}.tmp", path);
+        if let Ok(mut file) = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&temp_path) {
+            if file.write_all(content.as_bytes()).is_ok() {
+                let _ = fs::rename(&temp_path, path);
+            }
+        }
+    }
+}
@@ -0,0 +1,584 @@
+//! Structured configuration: `config.toml`, with one table per entry.
+//!
+//!   schema = 1
+//!   backend = "iptables"
+//!   watchdog = ["10.0.0.1:22", "10.0.0.2:22"]
+//!
+//!   # Admin laptop, SSH only
+//!   [[entry]]
+//!   host = "home.example.net"
+//!   port = 22
+//!   priority = "critical"
+//!   description = "Alice's home office"
+//!
+//!   [[entry]]
+//!   host = "game.example.net"
+//!   ports = [27015, 27016]
+//!   proto = "udp"
+//!   chain = "GAME_IN"
+//!   enabled = false
+//!
+//! When the profile dir has a `config.toml`, it is the config and
+//! `conf.conf` is not read (`config validate` reports one left behind).
+//! Top-level keys are the settings of conf.conf: strings, integers and
+//! booleans (`yes`/`no`) as their value, arrays as one comma-separated
+//! value, or, for the settings that may be repeated (watchdog, self_ip,
+//! provider_interval, provider_account), one setting per element.
+//! `include` and `include_if_exists` take a path or an array of paths; an
+//! included file ending in `.toml` is read as TOML, any other in the
+//! classic format.
+//!
+//! Each `[[entry]]` needs `host`, and `port` or a `ports` array unless it
+//! is an SRV name. `proto` defaults to tcp, `enabled = false` keeps the
+//! entry in the file without its rules. Every other key is the entry
+//! option of the same name (`chain`, `priority`, `knock = [7000, 8000]`,
+//! ...): the table is turned into the classic entry line and read by the
+//! same parser, so both formats accept exactly the same entries. Values
+//! with spaces need no quoting; a value containing `"` is refused.
+//!
+//! Each `[[credentials]]` table is a `[credentials NAME]` section (see
+//! kube): `name`, then `kubeconfig`, `context`, `namespace`, `policy` and
+//! `selector` as strings.
+//!
+//! The parser covers the part of TOML this needs: comments, bare keys,
+//! basic and literal strings, integers, booleans and arrays (over several
+//! lines too). Other tables, dotted keys, floats and dates are refused
+//! with the file and line, and so is any syntax error: a half-read config
+//! would delete the rules of what it missed.
+//!
+//! `ddnsfw migrate-config` converts an existing conf.conf (see migrate).
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::{apply_setting, exit_err, include_config_file, kube, profile, push_entries, Config, MAX_CONFIG_LINES, MAX_LOOP_ITERATIONS};
+
+/// Settings applied once per array element instead of comma-joined
+pub const REPEATABLE: &[&str] = &["watchdog", "self_ip", "provider_interval", "provider_account"];
+/// `[[entry]]` keys that are not entry options
+pub const ENTRY_KEYS: &[&str] = &["host", "port", "ports", "proto", "enabled"];
+
+enum Value {
+    Str(String),
+    Int(i64),
+    Bool(bool),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    fn kind(&self) -> &'static str {
+        match self {
+            Value::Str(_) => "a string",
+            Value::Int(_) => "an integer",
+            Value::Bool(_) => "a boolean",
+            Value::Array(_) => "an array",
+        }
+    }
+
+    /// A scalar as a conf.conf value; None for arrays
+    fn scalar(&self) -> Option<String> {
+        match self {
+            Value::Str(s) => Some(s.clone()),
+            Value::Int(n) => Some(n.to_string()),
+            Value::Bool(b) => Some(String::from(if *b { "yes" } else { "no" })),
+            Value::Array(_) => None,
+        }
+    }
+
+    /// The conf.conf value: scalars as is, arrays comma-joined
+    fn joined(&self) -> Option<String> {
+        match self {
+            Value::Array(items) => items.iter().map(Value::scalar).collect::<Option<Vec<_>>>().map(|items| items.join(",")),
+            value => value.scalar(),
+        }
+    }
+}
+
+/// `key = value` with the line it is on
+struct Pair {
+    key: String,
+    value: Value,
+    line: usize,
+}
+
+/// A parsed file: the top-level keys, the `[[entry]]` and the
+/// `[[credentials]]` tables (with the line of their header)
+#[derive(Default)]
+struct Document {
+    globals: Vec<Pair>,
+    entries: Vec<(usize, Vec<Pair>)>,
+    credentials: Vec<(usize, Vec<Pair>)>,
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == '\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    fn skip_blanks(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t')) {
+            self.bump();
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        if self.peek() == Some('#') {
+            while !matches!(self.peek(), None | Some('\n')) {
+                self.bump();
+            }
+        }
+    }
+
+    /// Blanks, comments and newlines (inside arrays)
+    fn skip_space(&mut self) {
+        loop {
+            self.skip_blanks();
+            self.skip_comment();
+            match self.peek() {
+                Some('\n' | '\r') => {
+                    self.bump();
+                }
+                _ => return,
+            }
+        }
+    }
+
+    /// The rest of the line must be blank or a comment
+    fn end_of_line(&mut self) -> Result<(), String> {
+        self.skip_blanks();
+        self.skip_comment();
+        if self.peek() == Some('\r') {
+            self.bump();
+        }
+        match self.bump() {
+            None | Some('\n') => Ok(()),
+            Some(c) => Err(format!("unexpected '{}' after the value", c)),
+        }
+    }
+
+    fn key(&mut self) -> Result<String, String> {
+        let mut key = String::new();
+        while let Some(c) = self.peek().filter(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '-') {
+            key.push(c);
+            self.bump();
+        }
+        match self.peek() {
+            _ if key.is_empty() => Err(String::from("expected a key")),
+            Some('.') => Err(format!("dotted key '{}.' not supported", key)),
+            _ => Ok(key),
+        }
+    }
+
+    fn string(&mut self, quote: char) -> Result<String, String> {
+        self.bump();
+        let mut out = String::new();
+        loop {
+            if matches!(self.peek(), None | Some('\n')) {
+                return Err(String::from("unterminated string"));
+            }
+            match self.bump() {
+                Some(c) if c == quote => return Ok(out),
+                Some('\\') if quote == '"' => {
+                    let escaped = match self.bump() {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        c => return Err(format!("unsupported escape '\\{}'", c.unwrap_or(' '))),
+                    };
+                    out.push(escaped);
+                }
+                c => out.extend(c),
+            }
+        }
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value, String> {
+        match self.peek() {
+            Some(quote @ ('"' | '\'')) => {
+                if self.chars.get(self.pos + 1) == Some(&quote) && self.chars.get(self.pos + 2) == Some(&quote) {
+                    return Err(String::from("multi-line strings not supported"));
+                }
+                self.string(quote).map(Value::Str)
+            }
+            Some('[') if depth == 0 => {
+                self.bump();
+                let mut items = Vec::new();
+                loop {
+                    self.skip_space();
+                    if self.peek() == Some(']') {
+                        self.bump();
+                        return Ok(Value::Array(items));
+                    }
+                    if items.len() >= MAX_LOOP_ITERATIONS {
+                        return Err(String::from("array too long"));
+                    }
+                    items.push(self.value(depth + 1)?);
+                    self.skip_space();
+                    match self.bump() {
+                        Some(',') => {}
+                        Some(']') => return Ok(Value::Array(items)),
+                        _ => return Err(String::from("expected ',' or ']' in array")),
+                    }
+                }
+            }
+            Some('[') => Err(String::from("nested arrays not supported")),
+            _ => {
+                let mut word = String::new();
+                while let Some(c) = self.peek().filter(|c| !matches!(c, ' ' | '\t' | '\r' | '\n' | ',' | ']' | '#')) {
+                    word.push(c);
+                    self.bump();
+                }
+                match word.as_str() {
+                    "true" => Ok(Value::Bool(true)),
+                    "false" => Ok(Value::Bool(false)),
+                    _ => match word.replace('_', "").parse::<i64>() {
+                        Ok(n) if !word.starts_with('_') && !word.ends_with('_') => Ok(Value::Int(n)),
+                        _ if word.is_empty() => Err(String::from("expected a value")),
+                        _ => Err(format!("unsupported value '{}' (strings need quotes)", word)),
+                    },
+                }
+            }
+        }
+    }
+
+    fn document(&mut self) -> Result<Document, String> {
+        let mut doc = Document::default();
+        let mut in_credentials = false;  // Whether the last table is [[credentials]]
+        loop {
+            self.skip_space();
+            let line = self.line;
+            match self.peek() {
+                None => return Ok(doc),
+                Some('[') => {
+                    let mut header = String::new();
+                    while !matches!(self.peek(), None | Some('\n' | '#')) {
+                        header.extend(self.bump());
+                    }
+                    let header = header.trim_end();
+                    in_credentials = match header.strip_prefix("[[").and_then(|h| h.strip_suffix("]]")).map(str::trim) {
+                        Some("entry") => false,
+                        Some("credentials") => true,
+                        _ => return Err(format!("unsupported table {} (only [[entry]] and [[credentials]])", header)),
+                    };
+                    let tables = if in_credentials { &mut doc.credentials } else { &mut doc.entries };
+                    tables.push((line, Vec::new()));
+                    self.end_of_line()?;
+                }
+                Some(_) => {
+                    let key = self.key()?;
+                    self.skip_blanks();
+                    if self.bump() != Some('=') {
+                        return Err(format!("expected '=' after '{}'", key));
+                    }
+                    self.skip_blanks();
+                    let value = self.value(0)?;
+                    let last = if in_credentials { doc.credentials.last_mut() } else { doc.entries.last_mut() };
+                    let table = match last {
+                        Some((_, table)) => table,
+                        None => &mut doc.globals,
+                    };
+                    // Before the newline: the error names this line
+                    if table.iter().any(|pair| pair.key == key) {
+                        return Err(format!("duplicate key '{}'", key));
+                    }
+                    table.push(Pair { key, value, line });
+                    self.end_of_line()?;
+                }
+            }
+        }
+    }
+}
+
+/// Parses TOML text; errors carry the line number
+fn parse(content: &str) -> Result<Document, (usize, String)> {
+    let mut parser = Parser { chars: content.chars().collect(), pos: 0, line: 1 };
+    parser.document().map_err(|msg| (parser.line, msg))
+}
+
+/// Whether the profile's config is config.toml
+pub fn is_active() -> bool {
+    let profile = profile::current();
+    profile.config_path != profile.legacy_config_path
+}
+
+/// The classic entry lines of an `[[entry]]` table (one per port), or None
+/// for a disabled entry
+fn entry_lines(table: &[Pair]) -> Result<Option<Vec<String>>, String> {
+    let get = |key: &str| table.iter().find(|pair| pair.key == key).map(|pair| &pair.value);
+    if let Some(enabled) = get("enabled") {
+        match enabled {
+            Value::Bool(true) => {}
+            Value::Bool(false) => return Ok(None),
+            value => return Err(format!("enabled must be a boolean, not {}", value.kind())),
+        }
+    }
+    let host = match get("host") {
+        Some(Value::Str(host)) if !host.is_empty() && !host.contains(|c: char| c.is_whitespace() || c == '"') => host,
+        Some(Value::Str(host)) => return Err(format!("invalid host '{}'", host)),
+        Some(value) => return Err(format!("host must be a string, not {}", value.kind())),
+        None => return Err(String::from("entry without host")),
+    };
+    let port = |value: &Value| match value {
+        Value::Int(n) if (1..=65535).contains(n) => Ok(n.to_string()),
+        value => Err(format!("invalid port {} (1-65535)", value.joined().unwrap_or_default())),
+    };
+    let ports: Vec<String> = match (get("port"), get("ports")) {
+        (Some(_), Some(_)) => return Err(String::from("both port and ports")),
+        (Some(value), None) => vec![port(value)?],
+        (None, Some(Value::Array(items))) if !items.is_empty() => items.iter().map(port).collect::<Result<_, _>>()?,
+        (None, Some(_)) => return Err(String::from("ports must be a non-empty array of ports")),
+        (None, None) => Vec::new(),
+    };
+    let proto = match get("proto") {
+        Some(Value::Str(proto)) if ports.is_empty() => return Err(format!("proto '{}' without a port", proto)),
+        Some(Value::Str(proto)) => format!("/{}", proto),
+        Some(value) => return Err(format!("proto must be a string, not {}", value.kind())),
+        None => String::new(),
+    };
+
+    let mut options = Vec::new();
+    for pair in table.iter().filter(|pair| !ENTRY_KEYS.contains(&pair.key.as_str())) {
+        let Some(value) = pair.value.joined() else {
+            return Err(format!("{} cannot hold nested arrays", pair.key));
+        };
+        if value.contains('"') {
+            return Err(format!("{} cannot contain '\"'", pair.key));
+        }
+        if value.contains(char::is_whitespace) {
+            options.push(format!("{}=\"{}\"", pair.key, value));
+        } else {
+            options.push(format!("{}={}", pair.key, value));
+        }
+    }
+    let options = options.join(" ");
+    let targets = if ports.is_empty() {
+        vec![host.clone()]
+    } else {
+        ports.iter().map(|port| format!("{}:{}{}", host, port, proto)).collect()
+    };
+    Ok(Some(targets.into_iter().map(|target| format!("{} {}", target, options).trim_end().to_string()).collect()))
+}
+
+/// Applies a parsed document to `config`
+fn apply(path: &Path, doc: &Document, config: &mut Config, depth: usize) {
+    let fail = |line: usize, msg: &str| -> ! { exit_err(&format!("{}:{}: {}", path.display(), line, msg)) };
+
+    for pair in &doc.globals {
+        let key = pair.key.as_str();
+        if key == "include" || key == "include_if_exists" {
+            let targets = match &pair.value {
+                Value::Array(items) => items.iter().collect(),
+                value => vec![value],
+            };
+            for target in targets {
+                let Value::Str(target) = target else {
+                    fail(pair.line, &format!("{} takes paths, not {}", key, target.kind()));
+                };
+                // Classic includes have no variables to share
+                include_config_file(path, target, key == "include", config, &mut HashMap::new(), depth);
+            }
+            continue;
+        }
+        match &pair.value {
+            Value::Array(items) if REPEATABLE.contains(&key) => {
+                for item in items {
+                    let Some(value) = item.scalar() else {
+                        fail(pair.line, &format!("{} cannot hold nested arrays", key));
+                    };
+                    apply_setting(config, key, &value);
+                }
+            }
+            value => match value.joined() {
+                Some(value) => apply_setting(config, key, &value),
+                None => fail(pair.line, &format!("{} cannot hold nested arrays", key)),
+            },
+        }
+    }
+
+    for (line, table) in &doc.credentials {
+        let name = match table.iter().find(|pair| pair.key == "name").map(|pair| &pair.value) {
+            Some(Value::Str(name)) if kube::is_valid_name(name) => name,
+            Some(value) => fail(*line, &format!("invalid credentials name {}", value.joined().unwrap_or_default())),
+            None => fail(*line, "credentials without name"),
+        };
+        let profile = config.kube.profiles.entry(name.clone()).or_default();
+        for pair in table.iter().filter(|pair| pair.key != "name") {
+            if !kube::PROFILE_KEYS.contains(&pair.key.as_str()) {
+                fail(pair.line, &format!("unknown credentials key '{}'", pair.key));
+            }
+            let Value::Str(value) = &pair.value else {
+                fail(pair.line, &format!("{} must be a string, not {}", pair.key, pair.value.kind()));
+            };
+            if let Err(reason) = profile.set(&pair.key, value) {
+                fail(pair.line, &reason);
+            }
+        }
+    }
+
+    for (line, table) in &doc.entries {
+        let origin = format!("{}:{}", path.display(), line);
+        match entry_lines(table) {
+            Ok(lines) => {
+                for entry_line in lines.unwrap_or_default() {
+                    push_entries(config, &entry_line, &origin);
+                }
+            }
+            Err(msg) => fail(*line, &msg),
+        }
+    }
+}
+
+/// Reads a TOML config (or include) into `config`
+pub fn load(path: &Path, config: &mut Config, depth: usize) {
+    let Ok(content) = fs::read_to_string(path) else {
+        if depth > 0 {
+            exit_err(&format!("Cannot read included config {}", path.display()));
+        }
+        return;
+    };
+    // Truncating would cut tables in half
+    if content.lines().count() > MAX_CONFIG_LINES {
+        exit_err(&format!("Config file {} too large (more than {} lines)", path.display(), MAX_CONFIG_LINES));
+    }
+    match parse(&content) {
+        Ok(doc) => apply(path, &doc, config, depth),
+        Err((line, msg)) => exit_err(&format!("{}:{}: {}", path.display(), line, msg)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(content: &str) -> (usize, String) {
+        match parse(content) {
+            Ok(_) => panic!("parsed: {:?}", content),
+            Err(err) => err,
+        }
+    }
+
+    fn global<'a>(doc: &'a Document, key: &str) -> &'a Value {
+        &doc.globals.iter().find(|pair| pair.key == key).unwrap().value
+    }
+
+    fn text(value: &Value) -> &str {
+        match value {
+            Value::Str(s) => s,
+            value => panic!("{} instead of a string", value.kind()),
+        }
+    }
+
+    #[test]
+    fn strings_keep_quotes_and_escapes() {
+        let doc = parse(concat!(
+            "basic = \"a \\\"quoted\\\" word\\tand\\\\ back\"\n",
+            "literal = 'C:\\path\\n \"as is\"'\n",
+            "hash = \"not # a comment\"  # but this is\n",
+        ))
+        .unwrap();
+        assert_eq!(text(global(&doc, "basic")), "a \"quoted\" word\tand\\ back");
+        assert_eq!(text(global(&doc, "literal")), "C:\\path\\n \"as is\"");
+        assert_eq!(text(global(&doc, "hash")), "not # a comment");
+
+        assert_eq!(error("k = \"open\n").1, "unterminated string");
+        assert_eq!(error("k = \"bad \\x escape\"").1, "unsupported escape '\\x'");
+        assert_eq!(error("k = \"\"\"multi\"\"\"").1, "multi-line strings not supported");
+    }
+
+    #[test]
+    fn scalars_and_arrays() {
+        let doc = parse(concat!(
+            "n = 1_000\n",
+            "yes = true\n",
+            "ports = [22, 443,\n  # the admin UI\n  9090,\n]\n",
+            "names = ['a', \"b\"]\n",
+        ))
+        .unwrap();
+        assert!(matches!(global(&doc, "n"), Value::Int(1000)));
+        assert!(matches!(global(&doc, "yes"), Value::Bool(true)));
+        assert_eq!(global(&doc, "ports").joined().as_deref(), Some("22,443,9090"));
+        assert_eq!(global(&doc, "names").joined().as_deref(), Some("a,b"));
+        assert_eq!(global(&doc, "yes").joined().as_deref(), Some("yes"));
+
+        assert_eq!(error("a = [[1], [2]]").1, "nested arrays not supported");
+        assert_eq!(error("a = [1 2]").1, "expected ',' or ']' in array");
+        assert_eq!(error("a = [1,\n2").1, "expected ',' or ']' in array");
+        assert_eq!(error("f = 1.5").1, "unsupported value '1.5' (strings need quotes)");
+        assert_eq!(error("s = bare").1, "unsupported value 'bare' (strings need quotes)");
+        assert_eq!(error("n = _1").1, "unsupported value '_1' (strings need quotes)");
+    }
+
+    #[test]
+    fn only_entry_and_credentials_tables() {
+        let doc = parse("schema = 1\n\n[[entry]]\nhost = \"a.example\"\nport = 22\n\n[[ entry ]] # second\nhost = \"b.example\"\n").unwrap();
+        assert_eq!(doc.globals.len(), 1);
+        assert_eq!(doc.entries.iter().map(|(line, table)| (*line, table.len())).collect::<Vec<_>>(), [(3, 2), (7, 1)]);
+
+        // Keys go to the table opened last, whichever kind it is
+        let doc = parse("[[credentials]]\nname = \"eu\"\ncontext = \"eu-west\"\n[[entry]]\nhost = \"a\"\n[[credentials]]\nname = \"us\"\n").unwrap();
+        assert_eq!(doc.credentials.iter().map(|(line, table)| (*line, table.len())).collect::<Vec<_>>(), [(1, 2), (6, 1)]);
+        assert_eq!(doc.entries.iter().map(|(line, table)| (*line, table.len())).collect::<Vec<_>>(), [(4, 1)]);
+
+        assert_eq!(error("[server]\nport = 1\n"), (1, String::from("unsupported table [server] (only [[entry]] and [[credentials]])")));
+        assert_eq!(error("[[entry]]\n[[entry.rules]]\n").1, "unsupported table [[entry.rules]] (only [[entry]] and [[credentials]])");
+        assert_eq!(error("[[entries]]\n").1, "unsupported table [[entries]] (only [[entry]] and [[credentials]])");
+        assert_eq!(error("a.b = 1").1, "dotted key 'a.' not supported");
+    }
+
+    #[test]
+    fn duplicate_keys_per_table() {
+        assert_eq!(error("grace_seconds = 1\ngrace_seconds = 2\n"), (2, String::from("duplicate key 'grace_seconds'")));
+        assert_eq!(error("[[entry]]\nhost = \"a\"\nhost = \"b\"\n").1, "duplicate key 'host'");
+        // The same key in two tables, or global and entry, is fine
+        assert!(parse("chain = \"A\"\n[[entry]]\nchain = \"B\"\n[[entry]]\nchain = \"C\"\n").is_ok());
+    }
+
+    #[test]
+    fn malformed_input_names_the_line() {
+        assert_eq!(error("a = 1\nb 2\n"), (2, String::from("expected '=' after 'b'")));
+        assert_eq!(error("a = 1 2\n").1, "unexpected '2' after the value");
+        assert_eq!(error("= 1\n").1, "expected a key");
+        assert_eq!(error("a =\n").1, "expected a value");
+        assert_eq!(error("[[entry]] host = \"a\"\n").1, "unsupported table [[entry]] host = \"a\" (only [[entry]] and [[credentials]])");
+        assert!(parse("a = 1\r\nb = 'x'\r\n").is_ok());
+    }
+
+    #[test]
+    fn entry_tables_become_entry_lines() {
+        let lines = |content: &str| {
+            let doc = parse(content).unwrap();
+            entry_lines(&doc.entries[0].1)
+        };
+        assert_eq!(
+            lines("[[entry]]\nhost = \"h.example\"\nports = [22, 443]\nproto = \"tcp\"\nnote = \"ops team\"\nknock = [7000, 8000]\n"),
+            Ok(Some(vec![
+                String::from("h.example:22/tcp note=\"ops team\" knock=7000,8000"),
+                String::from("h.example:443/tcp note=\"ops team\" knock=7000,8000"),
+            ]))
+        );
+        assert_eq!(lines("[[entry]]\nhost = \"_ssh._tcp.example\"\n"), Ok(Some(vec![String::from("_ssh._tcp.example")])));
+        assert_eq!(lines("[[entry]]\nhost = \"h\"\nport = 22\nenabled = false\n"), Ok(None));
+        assert_eq!(lines("[[entry]]\nport = 22\n"), Err(String::from("entry without host")));
+        assert_eq!(lines("[[entry]]\nhost = \"h\"\nport = 22\nports = [1]\n"), Err(String::from("both port and ports")));
+        assert_eq!(lines("[[entry]]\nhost = \"h\"\nport = 70000\n"), Err(String::from("invalid port 70000 (1-65535)")));
+        assert_eq!(lines("[[entry]]\nhost = \"h\"\nport = 22\nnote = 'say \"hi\"'\n"), Err(String::from("note cannot contain '\"'")));
+    }
+}
@@ -0,0 +1,202 @@
+//! `ddnsfw uninstall [--keep-config] [--keep-rules]`: the reverse of
+//! install, for the selected profile.
+//!
+//! Stops and disables the profile's timer, waits for a running sync, then
+//! deletes every rule tagged with the profile's comment (IPv4 and IPv6,
+//! companions, watchdogs and the dedicated chain included; entries of
+//! other backends through the backend), and removes its config, state (wherever it lives: the
+//! profile dir, a relocated state dir, the tmpfs cache and its checkpoint)
+//! and the units nothing else uses. The default profile goes last: it owns
+//! the binary and /etc/ddnsfw, so it refuses while named profiles are
+//! installed.
+//!
+//! `--keep-config` leaves the config files (and anything else not written
+//! by ddnsfw) in place for a later reinstall. `--keep-rules` leaves the
+//! firewall as it is, for when the session running the uninstall depends
+//! on a managed rule; the rules then go at the next reboot unless the
+//! firewall is persisted.
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use crate::rule::Table;
+use crate::{
+    acquire_lock, dedicated, exit_err, managed_scopes, mirror, open_backend, parse_config, privsep, profile, purge_comment, sys, v6,
+    Backend, Cache, BINARY_PATH, INSTALL_DIR, SERVICE_PATH, TEMPLATE_SERVICE_PATH, TEMPLATE_TIMER_PATH, TIMER_PATH,
+};
+
+/// Named profiles with a config (installed)
+fn installed_profiles() -> Vec<String> {
+    let Ok(entries) = fs::read_dir(format!("{}/profiles", INSTALL_DIR)) else {
+        return Vec::new();
+    };
+    let mut names: Vec<String> = entries
+        .flatten()
+        .filter(|entry| [profile::LEGACY_CONFIG, profile::TOML_CONFIG].iter().any(|name| entry.path().join(name).exists()))
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .collect();
+    names.sort();
+    names
+}
+
+fn remove_dir(path: &str) -> bool {
+    match fs::remove_dir_all(path) {
+        Ok(()) => {
+            println!("[ddnsfw] Removed {}", path);
+            true
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => true,
+        Err(_) => {
+            eprintln!("[ddnsfw] WARN: Could not remove {}", path);
+            false
+        }
+    }
+}
+
+fn remove_file(path: &str) -> bool {
+    match fs::remove_file(path) {
+        Ok(()) => {
+            println!("[ddnsfw] Removed {}", path);
+            true
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => true,
+        Err(_) => {
+            eprintln!("[ddnsfw] WARN: Could not remove {}", path);
+            false
+        }
+    }
+}
+
+/// Deletes the profile's rules from the firewall. True if all went.
+fn remove_rules() -> bool {
+    let config = parse_config();
+    let cache = Cache::load();
+    let Some(mut backend) = open_backend(&config) else {
+        eprintln!("[ddnsfw] WARN: {} not found, rules not removed", config.backend.tool());
+        return false;
+    };
+    let comment = profile::current().comment().to_string();
+    println!("[ddnsfw] Removing rules tagged {}", comment);
+
+    let mut ok = true;
+    match &mut backend {
+        Backend::Iptables(bin) => {
+            let scopes = managed_scopes(&config.entries, &cache);
+            // An interrupted comment_prefix change leaves rules under the old one
+            let mut comments = vec![comment.clone()];
+            comments.extend(cache.comment.clone().filter(|c| *c != comment));
+            for comment in &comments {
+                ok &= purge_comment(bin, &scopes, comment);
+                ok &= purge_comment(bin, &scopes, &format!("{}-WATCHDOG", comment));
+            }
+            ok &= dedicated::retire(bin);
+            if let Some(bin6) = v6::find_ip6tables() {
+                let input = [(Table::Filter, String::from("INPUT"))].into_iter().collect();
+                for comment in &comments {
+                    ok &= purge_comment(bin6, &input, comment);
+                }
+            }
+        }
+        Backend::Ipset(ipset) => ok &= ipset.remove_all(),
+        Backend::Firewalld(firewalld) => ok &= firewalld.remove_all(),
+        backend => {
+            let rules = backend.existing_rules(&managed_scopes(&config.entries, &cache));
+            for rule in &rules {
+                if !backend.delete_rule(rule) {
+                    eprintln!("[ddnsfw] WARN: Could not remove {}", rule);
+                    ok = false;
+                }
+            }
+        }
+    }
+    ok
+}
+
+/// Removes what ddnsfw wrote to a profile dir, leaving its config
+fn remove_state_files(dir: &str) -> bool {
+    let mut ok = true;
+    for file in profile::STATE_FILES.iter().chain(&[".lock", "events.jsonl.1"]) {
+        ok &= remove_file(&format!("{}/{}", dir, file));
+    }
+    ok
+}
+
+pub fn command(args: &[String]) {
+    let mut keep_config = false;
+    let mut keep_rules = false;
+    for arg in args {
+        match arg.as_str() {
+            "--keep-config" => keep_config = true,
+            "--keep-rules" => keep_rules = true,
+            _ => exit_err("Usage: ddnsfw uninstall [--keep-config] [--keep-rules]"),
+        }
+    }
+    if !sys::is_root() {
+        exit_err("Must run as root");
+    }
+    let profile = profile::current();
+    if !Path::new(&profile.config_path).exists() {
+        exit_err(&format!("Not installed ({} not found)", profile.config_path));
+    }
+    let others: Vec<String> = installed_profiles().into_iter().filter(|name| Some(name) != profile.name.as_ref()).collect();
+    if profile.name.is_none() && !others.is_empty() {
+        exit_err(&format!(
+            "Profiles still installed: {}. Uninstall them first (ddnsfw --profile NAME uninstall)",
+            others.join(", ")
+        ));
+    }
+
+    let unit = profile.unit();
+    let _ = Command::new("systemctl").args(["disable", "--now", &format!("{}.timer", unit)]).output();
+    let _ = Command::new("systemctl").args(["stop", &format!("{}.service", unit)]).output();
+    println!("[ddnsfw] Stopped and disabled {}.timer", unit);
+    // A sync started before the timer stopped finishes first
+    let Some(_lock) = acquire_lock() else {
+        exit_err("Could not acquire lock");
+    };
+
+    // Rules first: the config and cache say where they are
+    let mut ok = true;
+    if keep_rules {
+        println!(
+            "[ddnsfw] Firewall rules were left in place; list them with: iptables -S | grep -w {}",
+            profile.comment()
+        );
+    } else {
+        ok &= remove_rules();
+    }
+
+    if profile.name.is_some() {
+        ok &= mirror::remove();
+        if profile.is_relocated() {
+            ok &= remove_dir(&profile.state_dir);
+        }
+        ok &= if keep_config { remove_state_files(&profile.dir) } else { remove_dir(&profile.dir) };
+        if others.is_empty() {
+            ok &= remove_file(TEMPLATE_SERVICE_PATH) & remove_file(TEMPLATE_TIMER_PATH);
+        }
+    } else {
+        ok &= privsep::remove_sudoers();
+        for path in [SERVICE_PATH, TIMER_PATH, TEMPLATE_SERVICE_PATH, TEMPLATE_TIMER_PATH] {
+            ok &= remove_file(path);
+        }
+        for dir in [mirror::RUN_DIR, mirror::MIRROR_DIR] {
+            ok &= remove_dir(dir);
+        }
+        ok &= if keep_config {
+            remove_file(BINARY_PATH) & remove_state_files(INSTALL_DIR)
+        } else {
+            remove_dir(INSTALL_DIR)
+        };
+    }
+    let _ = Command::new("systemctl").arg("daemon-reload").output();
+
+    if keep_config {
+        println!("[ddnsfw] Kept the config in {}", profile.dir);
+    }
+    if !ok {
+        exit_err("Uninstall incomplete, see the warnings above");
+    }
+    println!("[ddnsfw] Uninstalled");
+}
@@ -0,0 +1,299 @@
+//! IPv6: AAAA records, allowed through ip6tables.
+//!
+//!   ipv6 = yes                              # every entry (default no)
+//!   home.example.net:22 ipv6=yes            # or per entry (ipv6=no opts out)
+//!   home.example.net:22 ipv6=yes v6_prefix=64
+//!
+//! A path parallel to the IPv4 sync, for the iptables backend. The AAAA
+//! records of each opted-in entry come from the system resolver (`getent
+//! ahostsv6`) and are allowed by ip6tables rules in filter/INPUT tagged with
+//! the same comment as the IPv4 rules; with `v6_prefix` the rule covers the
+//! whole /N around the address (privacy addresses rotate within a /64).
+//! The IPv4 guarantees hold: new rules go in before stale ones come out, and
+//! a port whose lookup fails keeps its existing rules. The ip6tables listing
+//! is the source of truth, the cache keeps a copy (`RULES6:`).
+//!
+//! Only plain TCP allow entries get an IPv6 side. UDP entries and options
+//! IPv6 would not honour (table=, chain=, position=, action=, mark=,
+//! notrack=, vpn=, jump=, max_conns=, knock=, beacon=, lockdown=) keep an
+//! entry IPv4-only, with a warning, rather than open a wider door.
+//!
+//! An entry behind CGNAT or DS-Lite publishes an IPv4 address the server
+//! never sees; with an IPv6 side, the sync leaves that address out and
+//! relies on the AAAA rule (see cgnat).
+
+use std::collections::HashSet;
+use std::fmt;
+use std::io::{self, Write};
+use std::net::{IpAddr, Ipv6Addr};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::Instant;
+
+use crate::events::{self, Value};
+use crate::rule::{Position, Proto, Table};
+use crate::stats::{Phase, SyncStats};
+use crate::{execs, has_managed_comment, iptables, iptables_run, is_verbose, profile, Cache, Config, DdnsEntry, MAX_LISTING_LINES, MAX_RULES};
+
+const IP6TABLES_PATHS: &[&str] = &["/usr/sbin/ip6tables", "/sbin/ip6tables", "/usr/bin/ip6tables"];
+const DEFAULT_PREFIX: u8 = 128;
+
+pub fn find_ip6tables() -> Option<&'static str> {
+    IP6TABLES_PATHS.iter().find(|p| Path::new(p).exists()).copied()
+}
+
+/// An IPv6 allow: filter/INPUT, tcp, ACCEPT from a source network
+///
+/// Text form (cache and logs): `[2001:db8::]/64:22`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Rule6 {
+    pub net: Ipv6Addr,
+    pub prefix: u8,
+    pub port: u16,
+}
+
+impl Rule6 {
+    /// The rule allowing `ip`'s /`prefix` (host bits cleared)
+    pub fn new(ip: Ipv6Addr, prefix: u8, port: u16) -> Self {
+        let mask = u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+        Rule6 { net: Ipv6Addr::from(u128::from(ip) & mask), prefix, port }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        let (net, rest) = s.trim().strip_prefix('[')?.split_once("]/")?;
+        let (prefix, port) = rest.split_once(':')?;
+        let (net, prefix, port) = (net.parse().ok()?, prefix.parse().ok()?, port.parse().ok()?);
+        let rule = Rule6::new(net, prefix, port);
+        (prefix <= 128 && port != 0 && rule.net == net).then_some(rule)
+    }
+
+    /// ip6tables arguments for `action` (-I inserts at position 1, as IPv4)
+    fn args(&self, action: &str) -> Vec<String> {
+        let mut args = vec![action.to_string(), String::from("INPUT")];
+        if action == "-I" {
+            args.push(String::from("1"));
+        }
+        let source = format!("{}/{}", self.net, self.prefix);
+        let port = self.port.to_string();
+        for arg in ["-s", &source, "-p", "tcp", "-m", "tcp", "--dport", &port, "-m", "comment"] {
+            args.push(arg.to_string());
+        }
+        for arg in ["--comment", profile::current().comment(), "-j", "ACCEPT"] {
+            args.push(arg.to_string());
+        }
+        args
+    }
+
+    fn run(&self, bin: &str, action: &str) -> bool {
+        let args = self.args(action);
+        iptables_run(bin, &args.iter().map(String::as_str).collect::<Vec<_>>())
+    }
+}
+
+impl fmt::Display for Rule6 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{}]/{}:{}", self.net, self.prefix, self.port)
+    }
+}
+
+/// Addresses that can never be a remote peer's public source address
+fn is_usable(ip: &Ipv6Addr) -> bool {
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        || (ip.segments()[0] & 0xffc0) == 0xfe80  // link-local
+        || ip.to_ipv4_mapped().is_some())
+}
+
+/// First usable AAAA answer of `hostname`: Some(None) when the lookup
+/// answered with IPv4 (mapped) addresses only, None when it failed
+fn resolve(hostname: &str) -> Option<Option<Ipv6Addr>> {
+    let output = execs::output(
+        Command::new("getent")
+            .args(["ahostsv6", hostname])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null()),
+    )
+    .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let answers: Vec<Ipv6Addr> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| match line.split_whitespace().next()?.parse::<IpAddr>().ok()? {
+            IpAddr::V6(ip) => Some(ip),
+            IpAddr::V4(_) => None,
+        })
+        .collect();
+    if let Some(ip) = answers.iter().find(|ip| is_usable(ip)) {
+        return Some(Some(*ip));
+    }
+    answers.iter().all(|ip| ip.to_ipv4_mapped().is_some()).then_some(None)
+}
+
+/// Tagged ip6tables rules in filter/INPUT of exactly the shape this module
+/// writes (anything else carrying the comment is left alone); None when the
+/// listing failed
+fn existing(bin: &str) -> Option<HashSet<Rule6>> {
+    let output = iptables(bin, &["-S", "INPUT"])?;
+    let mut rules = HashSet::new();
+    for line in output.lines().take(MAX_LISTING_LINES).filter(|l| has_managed_comment(l)) {
+        let tokens: Vec<&str> = line.split_whitespace().map(|t| t.trim_matches('"')).collect();
+        let value = |flag: &str| tokens.iter().position(|t| *t == flag).and_then(|i| tokens.get(i + 1));
+        let Some((net, prefix)) = value("-s").and_then(|s| s.split_once('/')) else {
+            continue;
+        };
+        let (Ok(net), Ok(prefix), Some(Ok(port))) = (net.parse(), prefix.parse(), value("--dport").map(|p| p.parse())) else {
+            continue;
+        };
+        let rule = Rule6::new(net, prefix, port);
+        if rule.args("-A") == tokens {
+            if rules.len() >= MAX_RULES {
+                eprintln!("[ddnsfw] WARN: Too many ip6tables rules, truncating");
+                break;
+            }
+            rules.insert(rule);
+        }
+    }
+    Some(rules)
+}
+
+/// Options that keep an entry IPv4-only
+fn ipv4_only_options(entry: &DdnsEntry) -> Vec<&'static str> {
+    let options = [
+        ("table", entry.table != Table::Filter),
+        ("mark", entry.mark.is_some()),
+        ("notrack", entry.notrack),
+        ("vpn", entry.vpn.is_some()),
+        ("udp", entry.proto != Proto::Tcp),
+        ("jump", entry.jump.is_some()),
+        ("action", entry.returns),
+        ("chain", entry.chain.is_some()),
+        ("position", entry.position != Position::Top),
+        ("max_conns", entry.max_conns.is_some()),
+        ("knock", entry.knock.is_some()),
+        ("beacon", entry.beacon.is_some()),
+        ("lockdown", entry.lockdown.is_some()),
+    ];
+    options.into_iter().filter(|(_, set)| *set).map(|(name, _)| name).collect()
+}
+
+/// Whether the entry gets an IPv6 side: opted in, and no IPv4-only option
+pub fn is_enabled_for(config: &Config, entry: &DdnsEntry) -> bool {
+    entry.ipv6.unwrap_or(config.ipv6) && ipv4_only_options(entry).is_empty()
+}
+
+/// Fail-safe: keep every existing IPv6 rule of a port whose state is unknown
+fn keep_existing_port(desired: &mut HashSet<Rule6>, existing: &HashSet<Rule6>, port: u16) {
+    desired.extend(existing.iter().filter(|r| r.port == port).cloned());
+}
+
+/// Syncs the IPv6 side of the opted-in entries; returns the hostnames whose
+/// AAAA lookup failed
+pub fn sync(config: &Config, entries: &[DdnsEntry], cache: &mut Cache, stats: &mut SyncStats) -> Vec<String> {
+    let opted: Vec<&DdnsEntry> = entries.iter().filter(|e| e.ipv6.unwrap_or(config.ipv6)).collect();
+    // Nothing opted in and nothing left over to remove
+    if opted.is_empty() && cache.rules6.is_empty() {
+        return Vec::new();
+    }
+    let Some(bin) = find_ip6tables() else {
+        eprintln!("[ddnsfw] WARN: ip6tables not found, IPv6 rules not synced");
+        return Vec::new();
+    };
+
+    let phase_start = Instant::now();
+    let Some(existing) = existing(bin) else {
+        eprintln!("[ddnsfw] WARN: Cannot list ip6tables rules, IPv6 rules not synced");
+        return Vec::new();
+    };
+    stats.record(Phase::List, phase_start);
+    cache.rules6 = existing.clone();
+
+    let verbose = is_verbose(config);
+    let mut desired: HashSet<Rule6> = HashSet::new();
+    let mut to_add: Vec<(Rule6, String)> = Vec::new();
+    let mut failed = Vec::new();
+    for entry in opted {
+        let line = format!("[ddnsfw] {}:{} (IPv6) -> ", entry.hostname, entry.port);
+        let excluded = ipv4_only_options(entry);
+        if !excluded.is_empty() {
+            eprintln!("[ddnsfw] WARN: {} stays IPv4-only ({} not supported for IPv6)", entry.label(), excluded.join(", "));
+            continue;
+        }
+        let phase_start = Instant::now();
+        let resolved = resolve(&entry.hostname);
+        stats.record(Phase::Dns, phase_start);
+        let Some(answer) = resolved else {
+            println!("{}SKIP (AAAA lookup failed, keeping existing)", line);
+            events::emit("resolve_failed", &[("host", Value::Str(&entry.hostname)), ("port", Value::Num(u64::from(entry.port)))]);
+            keep_existing_port(&mut desired, &existing, entry.port);
+            if !failed.contains(&entry.hostname) {
+                failed.push(entry.hostname.clone());
+            }
+            continue;
+        };
+        // IPv4 only: this entry's IPv6 rules go, as after an address change
+        let Some(ip) = answer else {
+            if verbose {
+                println!("{}no AAAA record", line);
+            }
+            continue;
+        };
+        let rule = Rule6::new(ip, entry.v6_prefix.or(config.v6_prefix).unwrap_or(DEFAULT_PREFIX), entry.port);
+        if existing.contains(&rule) {
+            if verbose {
+                println!("{}{} OK (no change)", line, ip);
+            }
+        } else if !desired.contains(&rule) {
+            println!("{}{} PENDING", line, ip);
+            to_add.push((rule.clone(), entry.hostname.clone()));
+        }
+        desired.insert(rule);
+    }
+
+    // Add first (retried once), keeping the port's old rules on failure
+    let phase_start = Instant::now();
+    for (rule, hostname) in to_add.iter().take(MAX_RULES) {
+        print!("[ddnsfw] Adding {} ... ", rule);
+        let _ = io::stdout().flush();
+        let rule_str = rule.to_string();
+        let fields = [("rule", Value::Str(&rule_str)), ("port", Value::Num(u64::from(rule.port))), ("hostname", Value::Str(hostname))];
+        if cache.rules6.len() >= MAX_RULES {
+            println!("SKIPPED (capacity rules={}/{}, keeping existing)", cache.rules6.len(), MAX_RULES);
+            keep_existing_port(&mut desired, &existing, rule.port);
+        } else if rule.run(bin, "-I") || rule.run(bin, "-I") {
+            println!("OK");
+            cache.rules6.insert(rule.clone());
+            stats.added += 1;
+            events::emit("add", &fields);
+        } else {
+            println!("FAILED (keeping existing)");
+            stats.failed += 1;
+            events::emit("add_failed", &fields);
+            keep_existing_port(&mut desired, &existing, rule.port);
+        }
+    }
+    stats.record(Phase::Add, phase_start);
+    cache.save();
+
+    let phase_start = Instant::now();
+    for rule in existing.iter().filter(|r| !desired.contains(r)) {
+        print!("[ddnsfw] Removing old {} ... ", rule);
+        let _ = io::stdout().flush();
+        let rule_str = rule.to_string();
+        let fields = [("rule", Value::Str(&rule_str)), ("port", Value::Num(u64::from(rule.port)))];
+        if rule.run(bin, "-D") {
+            println!("OK");
+            cache.rules6.remove(rule);
+            stats.removed += 1;
+            events::emit("delete", &fields);
+        } else {
+            println!("FAILED (rule remains)");
+            stats.failed += 1;
+            events::emit("delete_failed", &fields);
+        }
+    }
+    stats.record(Phase::Delete, phase_start);
+    cache.save();
+    failed
+}
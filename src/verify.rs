@@ -0,0 +1,154 @@
+//! `ddnsfw verify-install [--sha256 HEX]`: post-provisioning checks for CI
+//! and image builds.
+//!
+//! Non-interactive, unlike `doctor`: it checks only what a provisioned
+//! machine must have, and prints one JSON object on stdout:
+//!
+//!   {"ok":false,"profile":"default","checks":[{"check":"binary","ok":true,"detail":"..."},...]}
+//!
+//! - binary: the installed binary's SHA-256 is `--sha256`, or else that of
+//!   the binary running the check (run the release artifact itself)
+//! - service, timer: the unit files exist, the timer is enabled and active
+//! - schedule: systemd has a next run for the timer
+//! - config: `config validate` passes (diagnostics are only noted)
+//! - plan: a dry-run sync (`plan`) completes; pending changes are fine,
+//!   a fresh image has not synced yet
+//!
+//! The config and the plan run in children, so a fatal error is a failed
+//! check rather than the end of the report. Exit status: 0 when every
+//! check passed, 1 when one failed, 2 on a usage error.
+
+use std::env;
+use std::fs;
+use std::path::Path;
+
+use crate::crypto::{hex, sha256};
+use crate::doctor::{next_elapse, self_command, systemctl};
+use crate::events::json_escape;
+use crate::{profile, BINARY_PATH, SERVICE_PATH, TEMPLATE_SERVICE_PATH, TEMPLATE_TIMER_PATH, TIMER_PATH};
+
+const USAGE: &str = "Usage: ddnsfw verify-install [--sha256 HEX]";
+const EXIT_FAILED: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+/// `plan` exit status when changes are pending
+const PLAN_DRIFT: i32 = 2;
+
+struct Check {
+    name: &'static str,
+    ok: bool,
+    detail: String,
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    std::process::exit(EXIT_USAGE);
+}
+
+fn file_sha256(path: &Path) -> Option<String> {
+    fs::read(path).ok().map(|data| hex(&sha256(&data)))
+}
+
+fn check_binary(expected: Option<&str>) -> Check {
+    let Some(installed) = file_sha256(Path::new(BINARY_PATH)) else {
+        return Check { name: "binary", ok: false, detail: format!("{} missing or unreadable", BINARY_PATH) };
+    };
+    let expected = match expected {
+        Some(hash) => Some(hash.to_ascii_lowercase()),
+        None => env::current_exe().ok().and_then(|exe| file_sha256(&exe)),
+    };
+    match expected {
+        Some(hash) if hash == installed => Check { name: "binary", ok: true, detail: format!("{} sha256 {}", BINARY_PATH, installed) },
+        Some(hash) => Check { name: "binary", ok: false, detail: format!("{} sha256 {}, expected {}", BINARY_PATH, installed, hash) },
+        None => Check { name: "binary", ok: false, detail: String::from("cannot read the running binary to compare") },
+    }
+}
+
+fn check_units() -> Vec<Check> {
+    let (service, timer) = match profile::current().name {
+        Some(_) => (TEMPLATE_SERVICE_PATH, TEMPLATE_TIMER_PATH),
+        None => (SERVICE_PATH, TIMER_PATH),
+    };
+    let unit = format!("{}.timer", profile::current().unit());
+    let mut checks = Vec::new();
+    let service_ok = Path::new(service).is_file();
+    checks.push(Check { name: "service", ok: service_ok, detail: format!("{} {}", service, if service_ok { "present" } else { "missing" }) });
+
+    let enabled = systemctl(&["is-enabled", &unit]);
+    let active = systemctl(&["is-active", &unit]);
+    let timer_ok = Path::new(timer).is_file() && enabled == "enabled" && active == "active";
+    let detail = format!("{} is {} / {}", unit, if enabled.is_empty() { "unknown" } else { &enabled }, if active.is_empty() { "unknown" } else { &active });
+    checks.push(Check { name: "timer", ok: timer_ok, detail });
+
+    let next = next_elapse(&unit);
+    let detail = match &next {
+        Some(at) => format!("next run {}", at),
+        None => String::from("no next run scheduled"),
+    };
+    checks.push(Check { name: "schedule", ok: next.is_some(), detail });
+    checks
+}
+
+/// The first line a child printed, without the `[ddnsfw] ` tag
+fn first_line(stdout: &[u8], stderr: &[u8]) -> String {
+    let (stdout, stderr) = (String::from_utf8_lossy(stdout), String::from_utf8_lossy(stderr));
+    let line = stdout.lines().chain(stderr.lines()).find(|line| !line.trim().is_empty()).unwrap_or_default();
+    line.trim().trim_start_matches("[ddnsfw] ").trim_start_matches("ERROR: ").to_string()
+}
+
+fn check_config() -> Check {
+    match self_command(&["config", "validate"]).output() {
+        Ok(output) if output.status.success() => Check { name: "config", ok: true, detail: first_line(&output.stdout, &[]) },
+        Ok(output) => {
+            // Diagnostics only: the config still loads
+            let ok = String::from_utf8_lossy(&output.stderr).contains("Config has problems");
+            Check { name: "config", ok, detail: first_line(&output.stdout, &output.stderr) }
+        }
+        Err(e) => Check { name: "config", ok: false, detail: format!("cannot run config validate: {}", e) },
+    }
+}
+
+fn check_plan() -> Check {
+    match self_command(&["plan"]).output() {
+        Ok(output) => match output.status.code() {
+            Some(0) => Check { name: "plan", ok: true, detail: String::from("dry-run sync: firewall up to date") },
+            Some(PLAN_DRIFT) => Check { name: "plan", ok: true, detail: String::from("dry-run sync: changes pending") },
+            _ => Check { name: "plan", ok: false, detail: format!("dry-run sync failed: {}", first_line(&[], &output.stderr)) },
+        },
+        Err(e) => Check { name: "plan", ok: false, detail: format!("cannot run plan: {}", e) },
+    }
+}
+
+pub fn command(args: &[String]) {
+    let expected = match args {
+        [] => None,
+        [flag, hash] if flag == "--sha256" => {
+            if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                usage();
+            }
+            Some(hash.as_str())
+        }
+        _ => usage(),
+    };
+
+    let mut checks = vec![check_binary(expected)];
+    checks.extend(check_units());
+    let config = check_config();
+    let config_ok = config.ok;
+    checks.push(config);
+    checks.push(if config_ok {
+        check_plan()
+    } else {
+        Check { name: "plan", ok: false, detail: String::from("skipped: config unusable") }
+    });
+
+    let ok = checks.iter().all(|check| check.ok);
+    let items: Vec<String> = checks
+        .iter()
+        .map(|check| format!("{{\"check\":\"{}\",\"ok\":{},\"detail\":\"{}\"}}", check.name, check.ok, json_escape(&check.detail)))
+        .collect();
+    let profile = profile::current().name.as_deref().unwrap_or("default");
+    println!("{{\"ok\":{},\"profile\":\"{}\",\"checks\":[{}]}}", ok, json_escape(profile), items.join(","));
+    if !ok {
+        std::process::exit(EXIT_FAILED);
+    }
+}
@@ -0,0 +1,65 @@
+//! VPN entries: the extra rules a WireGuard or IPsec peer needs.
+//!
+//!   home.example.net:51820 vpn=wireguard
+//!   branch.example.net:500 vpn=ipsec
+//!
+//! The entry's own rule allows UDP on its port (WireGuard, IKE). Tied to the
+//! same resolved address, the sync also manages:
+//!
+//! - both: an MSS clamp (`mangle FORWARD ... -j TCPMSS --clamp-mss-to-pmtu`)
+//!   for TCP connections forwarded from the peer, so the tunnel overhead does
+//!   not stall large transfers
+//! - ipsec: UDP 4500 (NAT traversal) and ESP
+//!
+//! They are ordinary managed rules: added before the old address loses
+//! access, removed together with the entry's rule, and kept with it when the
+//! hostname fails to resolve.
+
+use std::net::Ipv4Addr;
+
+use crate::dedicated;
+use crate::rule::{Proto, Rule, Table, Target};
+
+const NAT_T_PORT: u16 = 4500;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    WireGuard,
+    Ipsec,
+}
+
+impl Kind {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "wireguard" | "wg" => Some(Kind::WireGuard),
+            "ipsec" => Some(Kind::Ipsec),
+            _ => None,
+        }
+    }
+}
+
+/// Rules the peer needs besides the entry's UDP allow
+pub fn companions(kind: Kind, ip: Ipv4Addr) -> Vec<Rule> {
+    let mut clamp = Rule::allow(ip, 0);
+    clamp.table = Table::Mangle;
+    clamp.chain = String::from("FORWARD");
+    clamp.target = Target::ClampMss;
+
+    let mut rules = vec![clamp];
+    if kind == Kind::Ipsec {
+        let mut nat_t = Rule::allow(ip, NAT_T_PORT);
+        nat_t.chain = dedicated::inbound().to_string();
+        nat_t.proto = Proto::Udp;
+        let mut esp = Rule::allow(ip, 0);
+        esp.chain = dedicated::inbound().to_string();
+        esp.proto = Proto::Esp;
+        rules.extend([nat_t, esp]);
+    }
+    rules
+}
+
+/// Whether a rule is one of the companions above (kept alongside the
+/// rules of its address when the sync has to keep existing rules)
+pub fn is_companion(rule: &Rule) -> bool {
+    rule.port == 0 || (rule.port == NAT_T_PORT && rule.proto == Proto::Udp)
+}
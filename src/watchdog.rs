@@ -0,0 +1,124 @@
+//! Watchdog rules: a guaranteed management path independent of DNS.
+//!
+//!   watchdog = 10.8.0.0/24:22        # VPN subnet may always reach SSH
+//!   watchdog = 192.0.2.10:623/udp    # IPMI jump host
+//!
+//! Each watchdog is a plain ACCEPT at the top of INPUT tagged
+//! `<comment>-WATCHDOG`. The managed-rule listing matches the comment
+//! exactly, so the sync phases never see (or delete) these rules. They are
+//! re-asserted at the start of every sync, before anything else happens,
+//! and only removed once their `watchdog` line is gone from the config.
+
+use std::fmt;
+use std::io::{self, Write};
+use std::net::Ipv4Addr;
+
+use crate::rule::Proto;
+use crate::{has_comment, iptables, iptables_run, profile, MAX_LISTING_LINES, MAX_LOOP_ITERATIONS};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Watchdog {
+    pub ip: Ipv4Addr,
+    pub prefix: u8,
+    pub port: u16,
+    pub proto: Proto,
+}
+
+impl Watchdog {
+    /// `ip[/len]:port[/proto]`
+    pub fn parse(s: &str) -> Option<Self> {
+        let (source, service) = s.trim().rsplit_once(':')?;
+        let (port, proto) = match service.split_once('/') {
+            Some((port, proto)) => (port, Proto::parse(proto).filter(Proto::has_ports)?),
+            None => (service, Proto::Tcp),
+        };
+        let port: u16 = port.parse().ok().filter(|&p| p > 0)?;
+        let (ip, prefix) = match source.split_once('/') {
+            Some((ip, len)) => (ip.parse().ok()?, len.parse().ok().filter(|&l| l <= 32)?),
+            None => (source.parse().ok()?, 32),
+        };
+        Some(Watchdog { ip, prefix, port, proto })
+    }
+
+    fn args(&self, action: &str) -> Vec<String> {
+        let mut args = vec![action.to_string(), String::from("INPUT")];
+        if action == "-I" {
+            args.push(String::from("1"));
+        }
+        for arg in [
+            "-s", &format!("{}/{}", self.network(), self.prefix),
+            "-p", self.proto.name(),
+            "-m", self.proto.name(),
+            "--dport", &self.port.to_string(),
+            "-m", "comment",
+            "--comment", &comment(),
+            "-j", "ACCEPT",
+        ] {
+            args.push(arg.to_string());
+        }
+        args
+    }
+
+    /// Network address (iptables prints sources masked)
+    fn network(&self) -> Ipv4Addr {
+        let mask = if self.prefix == 0 { 0 } else { u32::MAX << (32 - self.prefix) };
+        Ipv4Addr::from(u32::from(self.ip) & mask)
+    }
+}
+
+impl fmt::Display for Watchdog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}:{}", self.network(), self.prefix, self.port)?;
+        if self.proto != Proto::Tcp {
+            write!(f, "/{}", self.proto.name())?;
+        }
+        Ok(())
+    }
+}
+
+fn comment() -> String {
+    format!("{}-WATCHDOG", profile::current().comment())
+}
+
+fn run(bin: &str, args: &[String]) -> bool {
+    iptables_run(bin, &args.iter().map(String::as_str).collect::<Vec<_>>())
+}
+
+/// Ensures every configured watchdog rule exists, then drops unconfigured ones
+pub fn sync(bin: &str, watchdogs: &[Watchdog]) {
+    for watchdog in watchdogs.iter().take(MAX_LOOP_ITERATIONS) {
+        if run(bin, &watchdog.args("-C")) {
+            continue;
+        }
+        print!("[ddnsfw] Adding watchdog {} ... ", watchdog);
+        let _ = io::stdout().flush();
+        println!("{}", if run(bin, &watchdog.args("-I")) { "OK" } else { "FAILED" });
+    }
+
+    // Stale: tagged as watchdog but no longer configured
+    let Some(output) = iptables(bin, &["-S", "INPUT"]) else {
+        return;
+    };
+    let comment = comment();
+    let wanted: Vec<Vec<String>> = watchdogs.iter().map(|w| w.args("-A")).collect();
+    for line in output.lines().take(MAX_LISTING_LINES).filter(|l| has_comment(l, &comment)) {
+        let mut args: Vec<String> = line.split_whitespace().map(|t| t.trim_matches('"').to_string()).collect();
+        if wanted.iter().any(|w| same_rule(w, &args)) {
+            continue;
+        }
+        print!("[ddnsfw] Removing watchdog {} ... ", line);
+        let _ = io::stdout().flush();
+        args[0] = String::from("-D");
+        println!("{}", if run(bin, &args) { "OK" } else { "FAILED" });
+    }
+}
+
+/// Compares our `-A` arguments with an `iptables -S` line (same tokens,
+/// order-insensitive since -S may reorder matches)
+fn same_rule(wanted: &[String], listed: &[String]) -> bool {
+    let mut a: Vec<&String> = wanted.iter().collect();
+    let mut b: Vec<&String> = listed.iter().collect();
+    a.sort();
+    b.sort();
+    a == b
+}
@@ -0,0 +1,217 @@
+//! Provider webhooks: a record update triggers a sync of that hostname.
+//!
+//!   webhook_listen = 0.0.0.0:8854     # HTTP, `ddnsfw daemon` only
+//!   webhook_token = <secret>          # at least 16 of [A-Za-z0-9_-]
+//!
+//! Providers that call a URL when a record changes (Cloudflare
+//! notifications, dynu, a DDNS client's post-update hook) are pointed at
+//!
+//!   http://server:8854/webhook/<webhook_token>[?host=home.example.net]
+//!
+//! GET or POST. The hostnames are the `host=` parameters, or else every
+//! configured hostname mentioned in the request body (payloads differ by
+//! provider, but all carry the record name). The daemon then syncs at once,
+//! looking up only those hostnames, without the provider_intervals
+//! throttle or the shared DNS cache; every other entry keeps the address
+//! of the last sync, and the daemon's schedule is left alone. The polling
+//! interval can then be stretched to hours while an update still lands
+//! within seconds.
+//!
+//! A webhook only says when to look: the address always comes from the
+//! entry's resolvers, never from the request, so a leaked token buys DNS
+//! lookups, not access. A wrong token gets 403 (logged at most once a
+//! minute); a request naming no configured hostname gets 200 and is
+//! ignored, so providers do not retry it. Plain HTTP: put a TLS reverse
+//! proxy in front when the provider calls across the Internet.
+//!
+//! The token and entries are those the daemon last read (at the start, on
+//! SIGHUP and after every sync); the token is checked before anything
+//! else. Requests are served one at a time, so each must arrive whole
+//! within REQUEST_DEADLINE, however slowly its bytes trickle in.
+
+use std::collections::HashSet;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::crypto::ct_eq;
+use crate::events::{self, Value};
+use crate::push::Rejections;
+use crate::{daemon, exit_err, sys, Config, MAX_ENTRIES};
+
+pub const MIN_TOKEN_LEN: usize = 16;
+const MAX_REQUEST_BYTES: usize = 64 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// The whole request, not each read: the listener serves one at a time
+const REQUEST_DEADLINE: Duration = Duration::from_secs(10);
+const PATH_PREFIX: &str = "/webhook/";
+
+/// Hostnames named by webhooks since the daemon last took them
+static PENDING: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Tokens go in the URL path: no characters that need escaping
+pub fn is_valid_token(s: &str) -> bool {
+    s.len() >= MIN_TOKEN_LEN && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// The hostnames to sync for the webhooks received so far; None when no
+/// webhook asked for a sync
+pub fn take_pending() -> Option<HashSet<String>> {
+    let mut pending = PENDING.lock().unwrap_or_else(|e| e.into_inner());
+    (!pending.is_empty()).then(|| pending.drain(..).collect())
+}
+
+pub struct Request {
+    pub method: String,
+    pub target: String,  // Path and query
+    pub body: String,
+}
+
+/// Reads what the stream has before `deadline`; None at the deadline, on
+/// end of stream or an error
+fn read_before(stream: &mut TcpStream, buf: &mut [u8], deadline: Instant) -> Option<usize> {
+    let left = deadline.checked_duration_since(Instant::now()).filter(|left| !left.is_zero())?;
+    stream.set_read_timeout(Some(left.min(REQUEST_TIMEOUT))).ok()?;
+    stream.read(buf).ok().filter(|&n| n > 0)
+}
+
+/// One HTTP request, up to MAX_REQUEST_BYTES and within REQUEST_DEADLINE;
+/// None when malformed or late
+pub fn read_request(stream: &mut TcpStream) -> Option<Request> {
+    let deadline = Instant::now() + REQUEST_DEADLINE;
+    let mut data = Vec::new();
+    let mut buf = [0u8; 4096];
+    let header_end = loop {
+        if let Some(pos) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        if data.len() >= MAX_REQUEST_BYTES {
+            return None;
+        }
+        let n = read_before(stream, &mut buf, deadline)?;
+        data.extend_from_slice(&buf[..n]);
+    };
+
+    let head = String::from_utf8_lossy(&data[..header_end]).into_owned();
+    let mut lines = head.lines();
+    let mut request_line = lines.next()?.split_whitespace();
+    let (method, target) = (request_line.next()?.to_string(), request_line.next()?.to_string());
+    let length = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+        .unwrap_or(0)
+        .min(MAX_REQUEST_BYTES);
+    while data.len() < header_end + length {
+        match read_before(stream, &mut buf, deadline) {
+            Some(n) => data.extend_from_slice(&buf[..n]),
+            None => break,
+        }
+    }
+    let end = data.len().min(header_end + length);
+    Some(Request { method, target, body: String::from_utf8_lossy(&data[header_end..end]).into_owned() })
+}
+
+fn respond(stream: &mut TcpStream, status: &str, text: &str) {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}\n",
+        status,
+        text.len() + 1,
+        text
+    );
+    let _ = stream.write_all(response.as_bytes());
+}
+
+/// Configured hostnames the request names (`host=`) or, without any,
+/// mentions in its body
+fn affected(config: &Config, query: &str, body: &str) -> Vec<String> {
+    let named: HashSet<String> = query
+        .split('&')
+        .filter_map(|param| param.strip_prefix("host="))
+        .map(|host| host.trim_end_matches('.').to_ascii_lowercase())
+        .collect();
+    let mentioned = || -> HashSet<String> {
+        body.split(|c: char| !(c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_'))
+            .map(|word| word.trim_matches('.').to_ascii_lowercase())
+            .filter(|word| !word.is_empty())
+            .collect()
+    };
+    let candidates = if named.is_empty() { mentioned() } else { named };
+
+    let mut hostnames: Vec<String> = Vec::new();
+    for entry in config.entries.iter().take(MAX_ENTRIES) {
+        if candidates.contains(&entry.hostname.to_ascii_lowercase()) && !hostnames.contains(&entry.hostname) {
+            hostnames.push(entry.hostname.clone());
+        }
+    }
+    hostnames
+}
+
+fn handle(mut stream: TcpStream, from: SocketAddr, rejections: &mut Rejections) {
+    let _ = stream.set_write_timeout(Some(REQUEST_TIMEOUT));
+    let Some(request) = read_request(&mut stream) else {
+        rejections.note("malformed request", from);
+        return respond(&mut stream, "400 Bad Request", "bad request");
+    };
+    let (path, query) = request.target.split_once('?').unwrap_or((&request.target, ""));
+
+    // The config the daemon last read: never re-read here, a broken file
+    // would end the daemon
+    let Some(config) = daemon::current_config() else {
+        return respond(&mut stream, "503 Service Unavailable", "starting");
+    };
+    let Some(token) = config.webhook_token.as_deref() else {
+        rejections.note("webhook_token removed", from);
+        return respond(&mut stream, "403 Forbidden", "forbidden");
+    };
+    if !ct_eq(path.strip_prefix(PATH_PREFIX).unwrap_or_default().as_bytes(), token.as_bytes()) {
+        rejections.note("wrong token", from);
+        return respond(&mut stream, "403 Forbidden", "forbidden");
+    }
+    if request.method != "GET" && request.method != "POST" {
+        return respond(&mut stream, "405 Method Not Allowed", "use GET or POST");
+    }
+
+    let hostnames = affected(&config, query, &request.body);
+    if hostnames.is_empty() {
+        return respond(&mut stream, "200 OK", "ignored: no configured hostname");
+    }
+    let list = hostnames.join(", ");
+    println!("[ddnsfw] Webhook from {}: {} updated, syncing it now", from, list);
+    events::emit("webhook", &[("hosts", Value::Str(&list))]);
+    {
+        let mut pending = PENDING.lock().unwrap_or_else(|e| e.into_inner());
+        for hostname in hostnames {
+            if !pending.contains(&hostname) {
+                pending.push(hostname);
+            }
+        }
+    }
+    sys::request_sync();
+    respond(&mut stream, "200 OK", &format!("syncing {}", list));
+}
+
+/// Starts the webhook listener when `webhook_listen` is set. Call after the
+/// daemon signals are blocked.
+pub fn listen(config: &Config) {
+    let Some(addr) = config.webhook_listen else {
+        return;
+    };
+    let listener =
+        TcpListener::bind(addr).unwrap_or_else(|e| exit_err(&format!("Cannot listen for webhooks on {}: {}", addr, e)));
+    println!("[ddnsfw] Listening for provider webhooks on http {}", addr);
+    thread::spawn(move || {
+        let mut rejections = Rejections::new("Webhook");
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else {
+                continue;
+            };
+            let Ok(from) = stream.peer_addr() else {
+                continue;
+            };
+            handle(stream, from, &mut rejections);
+        }
+    });
+}
@@ -0,0 +1,146 @@
+//! `ddnsfw why <ip> [port[/udp]]`: why an address gets in, or does not.
+//!
+//! Combines the live firewall listing (the source of truth) with the cache
+//! (which entries own each rule, when it was first installed), the config
+//! (the entry behind an owner and the options that qualify its allow) and
+//! the grants ledger (who granted it, until when). With a port it also
+//! says what happens to a new connection from the address: accepted by a
+//! managed rule, handed to a `jump=` chain, dropped or rejected by a
+//! lockdown, or left to the rest of the firewall (other INPUT rules and the
+//! chain policy). Read-only; DNS is not queried, the owners are those of
+//! the last sync.
+
+use std::net::Ipv4Addr;
+
+use crate::grants::Ledger;
+use crate::rule::{Proto, Rule, Table, Target};
+use crate::{
+    dedicated, exit_err, format_utc, iptables, lockdown, managed_scopes, now_secs, open_backend, parse_config, parse_entry_proto,
+    provider, srv, Backend, Cache, DdnsEntry,
+};
+
+const USAGE: &str = "Usage: ddnsfw why <ip> [port[/udp]]";
+
+pub fn command(args: &[String]) {
+    let (ip, port) = match args {
+        [ip] => (ip, None),
+        [ip, port] => (ip, Some(parse_port(port).unwrap_or_else(|| exit_err(USAGE)))),
+        _ => exit_err(USAGE),
+    };
+    let ip: Ipv4Addr = ip.parse().unwrap_or_else(|_| exit_err(&format!("Invalid IPv4 address '{}'", ip)));
+
+    let mut config = parse_config();
+    provider::expand(&mut config);
+    srv::expand(&mut config);
+    let Some(backend) = open_backend(&config) else {
+        exit_err(&format!("{} not found", config.backend.tool()));
+    };
+    let cache = Cache::load();
+    let live = backend.existing_rules(&managed_scopes(&config.entries, &cache));
+    let ledger = Ledger::load();
+    let now = now_secs();
+
+    let mut rules: Vec<&Rule> = live.iter().filter(|rule| rule.ip == ip).collect();
+    rules.sort_by_key(|rule| rule.to_string());
+    if rules.is_empty() {
+        println!("[ddnsfw] No managed rule matches {}", ip);
+    }
+    for rule in &rules {
+        println!("{}", rule);
+        let rule_owners = cache.owners.get(*rule).map(Vec::as_slice).unwrap_or_default();
+        if rule_owners.is_empty() {
+            println!("  Owner:    unknown (not in the cache: tagged by another run or added by hand)");
+        }
+        for owner in rule_owners {
+            if let Some(entry) = find_entry(&config.entries, owner) {
+                let about = entry.meta.summary();
+                let about = if about.is_empty() { String::new() } else { format!(", {}", about) };
+                println!("  Entry:    {} ({}{})", owner, entry.origin, about);
+                println!("  Expires:  when {} stops resolving to {} (checked every sync)", entry.hostname, ip);
+            } else if let Some(grant) = ledger.grants.iter().find(|g| g.rule() == **rule && g.kind.owner_label() == *owner) {
+                println!("  Grant:    {} by {}: {}", grant.kind.name(), grant.creator, grant.reason);
+                if grant.expires > now {
+                    println!("  Expires:  {}", format_utc(grant.expires));
+                } else {
+                    println!("  Expires:  expired {}, removed by the next sync", format_utc(grant.expires));
+                }
+            } else {
+                println!("  Owner:    {} (no longer configured, removed by the next sync)", owner);
+            }
+        }
+        match cache.since.get(*rule) {
+            Some(&since) => println!("  Added:    {}", format_utc(since)),
+            None => println!("  Added:    unknown"),
+        }
+    }
+    let mut missing: Vec<&Rule> = cache.rules.iter().filter(|rule| rule.ip == ip && !live.contains(*rule)).collect();
+    missing.sort_by_key(|rule| rule.to_string());
+    for rule in missing {
+        println!("{}", rule);
+        println!("  Missing:  cached but not in the firewall (re-added by the next sync if still wanted)");
+    }
+
+    let Some((port, proto)) = port else {
+        return;
+    };
+    let allows: Vec<&Rule> = rules
+        .iter()
+        .copied()
+        .filter(|rule| rule.table == Table::Filter && rule.chain == dedicated::inbound() && rule.proto == proto && rule.port == port)
+        .collect();
+    println!();
+    print!("[ddnsfw] {} -> {}/{}: ", ip, port, proto.name());
+    let Some(rule) = allows.first() else {
+        println!("{}", verdict_without_allow(&backend, proto, port));
+        return;
+    };
+    match &rule.target {
+        Target::Jump(chain) => println!("handed to {} by {}: that chain decides", chain, rule),
+        _ => {
+            println!("ACCEPT by {}", rule);
+            // Companion rules narrow the allow of the entries that set them
+            for owner in cache.owners.get(*rule).into_iter().flatten() {
+                let Some(entry) = find_entry(&config.entries, owner) else {
+                    continue;
+                };
+                if let Some(knock) = &entry.knock {
+                    let sequence: Vec<String> = knock.iter().map(u16::to_string).collect();
+                    println!("  only right after the knock sequence {} ({})", sequence.join(","), owner);
+                }
+                if let Some(limit) = entry.max_conns {
+                    println!("  up to {} concurrent connections ({})", limit, owner);
+                }
+            }
+        }
+    }
+}
+
+/// `22`, `22/tcp` or `51820/udp`
+fn parse_port(s: &str) -> Option<(u16, Proto)> {
+    let (port, proto) = match s.split_once('/') {
+        Some((port, proto)) => (port, parse_entry_proto(proto)?),
+        None => (s, Proto::Tcp),
+    };
+    Some((port.parse().ok().filter(|&p| p > 0)?, proto))
+}
+
+fn find_entry<'a>(entries: &'a [DdnsEntry], key: &str) -> Option<&'a DdnsEntry> {
+    entries.iter().find(|entry| entry.key() == key)
+}
+
+/// What decides when no managed rule allows the address
+fn verdict_without_allow(backend: &Backend, proto: Proto, port: u16) -> String {
+    let Backend::Iptables(bin) = backend else {
+        return String::from("no managed allow; the rest of the firewall decides");
+    };
+    let lockdowns = lockdown::existing(bin);
+    for mode in [lockdown::Mode::Drop, lockdown::Mode::Reject] {
+        if lockdowns.contains(&(proto, port, mode)) {
+            return format!("{} by the lockdown of port {} (lockdown={})", mode.target(), port, mode.name());
+        }
+    }
+    let policy = iptables(bin, &["-S", "INPUT"])
+        .and_then(|output| output.lines().find_map(|l| l.strip_prefix("-P INPUT ").map(str::to_string)))
+        .unwrap_or_else(|| String::from("unknown"));
+    format!("no managed allow; other INPUT rules and the chain policy ({}) decide", policy)
+}
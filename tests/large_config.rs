@@ -0,0 +1,16 @@
+//! Large configs: a 1000-entry sync (cold, steady and churn) must converge
+//! within the timer interval. Runs the hidden `--bench` mode of the built
+//! binary, in a /tmp sandbox with mock DNS and firewall (no root needed).
+
+use std::process::Command;
+
+#[test]
+fn thousand_entries_sync_within_timer_interval() {
+    let output = Command::new(env!("CARGO_BIN_EXE_ddnsfw"))
+        .args(["--bench", "1000"])
+        .output()
+        .expect("run ddnsfw --bench");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "bench failed:\n{}", stdout);
+    assert_eq!(stdout.matches(" OK").count(), 3, "unexpected bench output:\n{}", stdout);
+}